@@ -0,0 +1,4 @@
+Copies a line of input to the output
+(input ending without a line break is an error)
+
+,----------[++++++++++.,----------]++++++++++.
@@ -0,0 +1,225 @@
+/*
+ *  Textual assembly format for the bytecode, one opcode per line:
+ *
+ *      0001  JZ 4
+ *      0002  MULADD 3 @+1
+ *      0005  FILL 3 42 @-2
+ *
+ *  The leading pc is only there for reading jump targets and is ignored when assembling. `FILL` takes
+ *  the length and the value, `@` gives the cell offset and `;` starts a comment.
+ */
+
+use std::{collections::HashMap, fmt::Write};
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::{
+    opcodes::{OpCode, OpCodeType},
+    parser::{self, Program},
+};
+
+pub fn disassemble(program: &Program) -> String {
+    let mut out = String::new();
+    let width = pc_width(program);
+
+    for (pc, op) in program.iter().enumerate() {
+        writeln!(out, "{:0width$}  {}", pc, instruction(op), width = width).unwrap();
+    }
+
+    out
+}
+
+/// One opcode as `disassemble` writes it, without the pc.
+pub fn instruction(op: &OpCode) -> String {
+    let mut out = op.ty.mnemonic().to_string();
+
+    match op.ty {
+        OpCodeType::FillRange => write!(out, " {} {}", op.data >> 8, op.data & 0xff),
+        _ => write!(out, " {}", op.data),
+    }
+    .unwrap();
+    write_offset(&mut out, op);
+
+    out
+}
+
+/// Same as `disassemble`, with loops labeled and jumps going to labels instead of pcs: `Ln:` is the
+/// start of a loop body, `Ln.end:` the instruction after the loop. Meant for reading, it does not
+/// assemble.
+pub fn listing(program: &Program) -> String {
+    let mut labels = HashMap::new();
+    let loops = program
+        .iter()
+        .enumerate()
+        .filter(|(_, op)| is_loop_start(op.ty));
+    for (label, (pc, op)) in loops.enumerate() {
+        labels.insert(pc, label);
+        labels.insert(op.data, label);
+    }
+
+    let mut out = String::new();
+    let width = pc_width(program);
+    let indent = " ".repeat(width + 2);
+
+    for (pc, op) in program.iter().enumerate() {
+        write!(out, "{:0width$}  {}", pc, op.ty.mnemonic(), width = width).unwrap();
+
+        match op.ty {
+            ty if is_loop_start(ty) => write!(out, " L{}.end", labels[&pc]),
+            OpCodeType::JmpNotZero => write!(out, " L{}", labels[&pc]),
+            OpCodeType::FillRange => write!(out, " {} {}", op.data >> 8, op.data & 0xff),
+            _ => write!(out, " {}", op.data),
+        }
+        .unwrap();
+        write_offset(&mut out, op);
+        out.push('\n');
+
+        match op.ty {
+            ty if is_loop_start(ty) => writeln!(out, "{}L{}:", indent, labels[&pc]).unwrap(),
+            OpCodeType::JmpNotZero => writeln!(out, "{}L{}.end:", indent, labels[&pc]).unwrap(),
+            _ => {}
+        }
+    }
+
+    out
+}
+
+fn is_loop_start(ty: OpCodeType) -> bool {
+    matches!(
+        ty,
+        OpCodeType::JmpZero | OpCodeType::ClearLoop | OpCodeType::MulLoop
+    )
+}
+
+fn pc_width(program: &Program) -> usize {
+    program.len().saturating_sub(1).to_string().len().max(4)
+}
+
+fn write_offset(out: &mut String, op: &OpCode) {
+    if op.offset != 0 {
+        write!(out, " @{:+}", op.offset).unwrap();
+    }
+}
+
+pub fn assemble(src: &str) -> Result<Program> {
+    let mut program = Program::new();
+
+    for (line_no, line) in src.lines().enumerate() {
+        let code = line.split(';').next().unwrap();
+        if code.trim().is_empty() {
+            continue;
+        }
+
+        let op = parse_line(code).map_err(|err| anyhow!("line {}: {}", line_no + 1, err))?;
+        program.push(op);
+    }
+
+    parser::verify_jumps(&program)?;
+
+    Ok(program)
+}
+
+fn parse_line(code: &str) -> Result<OpCode> {
+    let mut words = code.split_whitespace().peekable();
+
+    if words
+        .peek()
+        .is_some_and(|word| word.bytes().all(|b| b.is_ascii_digit()))
+    {
+        words.next();
+    }
+
+    let mnemonic = words.next().ok_or_else(|| anyhow!("missing opcode"))?;
+    let ty = OpCodeType::from_mnemonic(mnemonic)
+        .ok_or_else(|| anyhow!("unknown opcode `{}`", mnemonic))?;
+
+    let mut operands = vec![];
+    let mut offset = 0;
+
+    for word in words {
+        match word.strip_prefix('@') {
+            Some(value) => {
+                offset = value
+                    .parse()
+                    .map_err(|_| anyhow!("invalid offset `{}`", word))?
+            }
+            None => operands.push(
+                word.parse::<usize>()
+                    .map_err(|_| anyhow!("invalid operand `{}`", word))?,
+            ),
+        }
+    }
+
+    let data = match (ty, operands.as_slice()) {
+        (OpCodeType::FillRange, &[len, value]) if value <= u8::MAX as usize => len << 8 | value,
+        (OpCodeType::FillRange, _) => bail!("FILL takes a length and a byte value"),
+        (_, &[data]) => data,
+        _ => bail!("{} takes one operand", ty.mnemonic()),
+    };
+
+    Ok(OpCode::with_offset(ty, data, offset))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        asm::{assemble, disassemble, listing},
+        opcodes::{OpCode, OpCodeType::*},
+        vm,
+    };
+
+    #[test]
+    fn disassembles_listing() {
+        let program = vec![
+            OpCode::new(JmpZero, 2),
+            OpCode::with_offset(MulAdd, 3, 1),
+            OpCode::new(JmpNotZero, 0),
+            OpCode::with_offset(FillRange, 3 << 8 | 42, -2),
+        ];
+
+        let expected = "0000  JZ 2\n0001  MULADD 3 @+1\n0002  JNZ 0\n0003  FILL 3 42 @-2\n";
+        assert_eq!(disassemble(&program), expected);
+    }
+
+    #[test]
+    fn lists_loops_with_labels() {
+        let program = vm::compile("+[>[-]<,]").unwrap();
+
+        let expected = "0000  ADD 1
+0001  JZ L0.end
+      L0:
+0002  SET 0 @+1
+0003  IN 1
+0004  JNZ L0
+      L0.end:
+";
+        assert_eq!(listing(&program), expected);
+    }
+
+    #[test]
+    fn round_trip_keeps_program() {
+        let program = vm::compile("+++[->++<]>>,[-]<<.>>>>[+>]>>+>+>+>+").unwrap();
+
+        assert_eq!(assemble(&disassemble(&program)).unwrap(), program);
+    }
+
+    #[test]
+    fn assembles_hand_written_code() {
+        let src =
+            "; adds two cells\n  add 2\n  jz 4 ; skip if zero\n\n  sub 1\n  add 1 @+1\n  jnz 1\n";
+
+        let expected = vec![
+            OpCode::new(Add, 2),
+            OpCode::new(JmpZero, 4),
+            OpCode::new(Sub, 1),
+            OpCode::with_offset(Add, 1, 1),
+            OpCode::new(JmpNotZero, 1),
+        ];
+        assert_eq!(assemble(src).unwrap(), expected);
+
+        assert!(assemble("ADD").is_err());
+        assert!(assemble("NOP 1").is_err());
+        assert!(assemble("FILL 3 256").is_err());
+        assert!(assemble("JZ 1\nJNZ 1").is_err());
+    }
+}
@@ -0,0 +1,370 @@
+/*
+ *  Execution backends.
+ *  One interface over everything that runs a program: the bytecode VM with each of its engines, the
+ *  JITs in front of it and the reference interpreter. `by_name` picks one the way the CLI does.
+ */
+
+use std::io::{Read, Write};
+
+use anyhow::{bail, Result};
+
+use crate::{
+    ir::ProgramIr,
+    lexer,
+    parser::{self, Program},
+    reference::Reference,
+    vm::{Stats, Vm},
+};
+
+pub trait ExecutionBackend {
+    /// Compiles `src` and loads it, replacing the program that was loaded before.
+    fn compile(&mut self, src: &str) -> Result<()>;
+
+    /// Loads a program compiled before, such as one from the cache.
+    fn load(&mut self, program: Program) -> Result<()>;
+
+    /// Resets the tape, the pointer and the pc, so the program runs from the start again.
+    fn prepare(&mut self);
+
+    /// Runs the program from where it is to the end.
+    fn run(&mut self, input: &mut dyn Read, output: &mut dyn Write) -> Result<()>;
+
+    /// Runs a single step, returns false if the program had already ended.
+    fn step(&mut self, input: &mut dyn Read, output: &mut dyn Write) -> Result<bool>;
+
+    /// The tape as the program left it.
+    fn tape(&self) -> &[u8];
+
+    /// Puts `cells` at the start of the tape, after the program is loaded and before it runs.
+    fn load_tape(&mut self, cells: &[u8]) -> Result<()>;
+
+    /// The cell the pointer is on.
+    fn ptr(&self) -> usize;
+
+    /// The pc of the program loaded, where it stopped, for backends running it as it is. A failed run
+    /// stops at the instruction that failed.
+    fn pc(&self) -> Option<usize> {
+        None
+    }
+
+    /// Same as `run`, with statistics of the run if the backend keeps them.
+    fn run_stats(&mut self, input: &mut dyn Read, output: &mut dyn Write) -> Result<Option<Stats>> {
+        self.run(input, output).map(|_| None)
+    }
+}
+
+/// Names accepted by `by_name`.
+pub const BACKENDS: [&str; 7] = [
+    "vm",
+    "threaded",
+    "blocks",
+    "tail-call",
+    "jit",
+    "jit-x64",
+    "reference",
+];
+
+/// False for backends whose feature was not built in, they run on the VM instead.
+pub fn is_available(name: &str) -> bool {
+    match name {
+        "tail-call" => cfg!(feature = "tail-call"),
+        "jit" => cfg!(feature = "jit"),
+        "jit-x64" => cfg!(feature = "jit-x64"),
+        name => BACKENDS.contains(&name),
+    }
+}
+
+pub fn by_name(name: &str) -> Result<Box<dyn ExecutionBackend>> {
+    with_vm(name, Vm::from_program(vec![])?)
+}
+
+/// Same as `by_name`, VM based backends use `vm` and keep its settings (quickening, tiering,
+/// tape size) and the reference interpreter gets a tape of the same size.
+pub fn with_vm(name: &str, vm: Vm) -> Result<Box<dyn ExecutionBackend>> {
+    Ok(match name {
+        "vm" => Box::new(vm),
+        "threaded" => Box::new(VmEngine::new(vm, |vm, input, output| {
+            vm.run_threaded(input, output)
+        })),
+        "blocks" => Box::new(VmEngine::new(vm, |vm, input, output| {
+            vm.run_blocks(input, output)
+        })),
+        "tail-call" => Box::new(VmEngine::new(vm, |vm, input, output| {
+            vm.run_tail_call(input, output)
+        })),
+        "jit" => Box::new(VmEngine::new(vm, |vm, input, output| {
+            vm.run_jit(input, output)
+        })),
+        "jit-x64" => Box::new(VmEngine::new(vm, |vm, input, output| {
+            vm.run_jit_x64(input, output)
+        })),
+        "reference" => Box::new(Reference::with_tape_size(vm.mem().len())),
+        _ => bail!("unknown backend `{}`, expected one of {:?}", name, BACKENDS),
+    })
+}
+
+/// The VM running through the `match` loop of `Vm::run_with`.
+impl ExecutionBackend for Vm {
+    fn compile(&mut self, src: &str) -> Result<()> {
+        Vm::load(self, crate::vm::compile(src)?)
+    }
+
+    fn load(&mut self, program: Program) -> Result<()> {
+        Vm::load(self, program)
+    }
+
+    fn prepare(&mut self) {
+        self.reset();
+    }
+
+    fn run(&mut self, mut input: &mut dyn Read, mut output: &mut dyn Write) -> Result<()> {
+        self.run_with(&mut input, &mut output)
+    }
+
+    fn step(&mut self, mut input: &mut dyn Read, mut output: &mut dyn Write) -> Result<bool> {
+        Vm::step(self, &mut input, &mut output)
+    }
+
+    fn tape(&self) -> &[u8] {
+        self.mem()
+    }
+
+    fn load_tape(&mut self, cells: &[u8]) -> Result<()> {
+        Vm::load_tape(self, cells)
+    }
+
+    fn ptr(&self) -> usize {
+        self.mem_ptr()
+    }
+
+    fn pc(&self) -> Option<usize> {
+        Some(Vm::pc(self))
+    }
+
+    fn run_stats(
+        &mut self,
+        mut input: &mut dyn Read,
+        mut output: &mut dyn Write,
+    ) -> Result<Option<Stats>> {
+        Vm::run_stats(self, &mut input, &mut output).map(Some)
+    }
+}
+
+type RunFn = fn(&mut Vm, &mut &mut dyn Read, &mut &mut dyn Write) -> Result<()>;
+
+/// The VM run another way, such as one of the other dispatch engines or a JIT. Stepping always goes
+/// through the interpreter.
+pub struct VmEngine {
+    vm: Vm,
+    run: RunFn,
+}
+
+impl VmEngine {
+    pub fn new(vm: Vm, run: RunFn) -> Self {
+        Self { vm, run }
+    }
+}
+
+impl ExecutionBackend for VmEngine {
+    fn compile(&mut self, src: &str) -> Result<()> {
+        ExecutionBackend::compile(&mut self.vm, src)
+    }
+
+    fn load(&mut self, program: Program) -> Result<()> {
+        self.vm.load(program)
+    }
+
+    fn prepare(&mut self) {
+        self.vm.reset();
+    }
+
+    fn run(&mut self, mut input: &mut dyn Read, mut output: &mut dyn Write) -> Result<()> {
+        (self.run)(&mut self.vm, &mut input, &mut output)
+    }
+
+    fn step(&mut self, input: &mut dyn Read, output: &mut dyn Write) -> Result<bool> {
+        ExecutionBackend::step(&mut self.vm, input, output)
+    }
+
+    fn tape(&self) -> &[u8] {
+        self.vm.mem()
+    }
+
+    fn load_tape(&mut self, cells: &[u8]) -> Result<()> {
+        self.vm.load_tape(cells)
+    }
+
+    fn ptr(&self) -> usize {
+        self.vm.mem_ptr()
+    }
+
+    fn pc(&self) -> Option<usize> {
+        Some(self.vm.pc())
+    }
+}
+
+/// Runs the unoptimized program.
+impl ExecutionBackend for Reference {
+    fn compile(&mut self, src: &str) -> Result<()> {
+        ExecutionBackend::load(self, parser::parse(lexer::parse(src))?)
+    }
+
+    /// Runs `program` as it is, optimized programs included.
+    fn load(&mut self, program: Program) -> Result<()> {
+        Reference::load(self, ProgramIr::from_program(&program)?.body);
+
+        Ok(())
+    }
+
+    fn prepare(&mut self) {
+        self.reset();
+    }
+
+    fn run(&mut self, input: &mut dyn Read, output: &mut dyn Write) -> Result<()> {
+        Reference::run(self, input, output)
+    }
+
+    fn step(&mut self, input: &mut dyn Read, output: &mut dyn Write) -> Result<bool> {
+        Reference::step(self, input, output)
+    }
+
+    fn tape(&self) -> &[u8] {
+        self.mem()
+    }
+
+    fn load_tape(&mut self, cells: &[u8]) -> Result<()> {
+        Reference::load_tape(self, cells)
+    }
+
+    fn ptr(&self) -> usize {
+        Reference::ptr(self)
+    }
+
+    fn pc(&self) -> Option<usize> {
+        Some(Reference::pc(self))
+    }
+}
+
+/// Runs the program loaded in `backend`, then `src` on the reference interpreter with the same input,
+/// and fails if they behaved differently: other output, other errors or, with `compare_tape`, other
+/// tapes at the end. Only then is the output written. The error of the program, if both agree on one,
+/// is returned after it.
+pub fn verify(
+    name: &str,
+    backend: &mut dyn ExecutionBackend,
+    src: &str,
+    input: &[u8],
+    output: &mut dyn Write,
+    compare_tape: bool,
+) -> Result<()> {
+    let mut actual = vec![];
+    let result = backend.run(&mut &input[..], &mut actual);
+
+    let mut reference = Reference::with_tape_size(backend.tape().len());
+    let mut expected = vec![];
+    ExecutionBackend::compile(&mut reference, src)?;
+    let expected_result = Reference::run(&mut reference, &mut &input[..], &mut expected);
+
+    let describe = |result: &Result<()>| match result {
+        Ok(()) => "ended".to_string(),
+        Err(err) => format!("failed with `{}`", err),
+    };
+    let excerpt = |bytes: &[u8], at: usize| {
+        let end = (at + 16).min(bytes.len());
+        format!("{:?}", String::from_utf8_lossy(&bytes[at.min(end)..end]))
+    };
+
+    if let Some(at) = first_difference(&actual, &expected) {
+        bail!(
+            "verify: the output differs from the reference at byte {}, {} wrote {} and the reference {}",
+            at,
+            name,
+            excerpt(&actual, at),
+            excerpt(&expected, at)
+        );
+    }
+
+    if describe(&result) != describe(&expected_result) {
+        bail!(
+            "verify: the program {} on {} but {} on the reference",
+            describe(&result),
+            name,
+            describe(&expected_result)
+        );
+    }
+
+    if compare_tape {
+        if let Some(at) = first_difference(backend.tape(), reference.mem()) {
+            bail!(
+                "verify: cell {} is {} on {} but {} on the reference",
+                at,
+                backend.tape()[at],
+                name,
+                reference.mem()[at]
+            );
+        }
+    }
+
+    output.write_all(&actual)?;
+    result
+}
+
+fn first_difference(a: &[u8], b: &[u8]) -> Option<usize> {
+    match a.iter().zip(b).position(|(a, b)| a != b) {
+        Some(at) => Some(at),
+        None if a.len() != b.len() => Some(a.len().min(b.len())),
+        None => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::backend::{self, BACKENDS};
+
+    const SRC: &str = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.,.";
+
+    #[test]
+    fn backends_agree() {
+        for name in BACKENDS {
+            let mut backend = backend::by_name(name).unwrap();
+            backend.compile(SRC).unwrap();
+
+            let mut output = vec![];
+            backend.run(&mut &b"!"[..], &mut output).unwrap();
+            assert_eq!(output, b"Hello World!!", "{}", name);
+
+            // Stepping from the start again gives the same output.
+            backend.prepare();
+            let mut stepped = vec![];
+            while backend.step(&mut &b"!"[..], &mut stepped).unwrap() {}
+            assert_eq!(stepped, output, "{}", name);
+        }
+
+        assert!(backend::by_name("nope").is_err());
+    }
+
+    #[test]
+    fn verify_catches_differences() {
+        let verify = |loaded: &str, src: &str, compare_tape: bool| {
+            let mut backend = backend::by_name("vm").unwrap();
+            backend.compile(loaded).unwrap();
+
+            let mut output = vec![];
+            backend::verify("vm", &mut *backend, src, b"x", &mut output, compare_tape)
+                .map(|_| output)
+                .map_err(|err| err.to_string())
+        };
+
+        assert_eq!(verify(SRC, SRC, true).unwrap(), b"Hello World!x");
+        assert!(verify("+++.", "++.", false)
+            .unwrap_err()
+            .contains("differs from the reference at byte 0"));
+        assert!(verify("+>+", "+>", false).is_ok());
+        assert!(verify("+>+", "+>", true)
+            .unwrap_err()
+            .contains("cell 1 is 1 on vm but 0"));
+        assert!(verify("+[>+]", "+", false)
+            .unwrap_err()
+            .contains("failed with `memory overflowed"));
+    }
+}
@@ -0,0 +1,529 @@
+/*
+ *  Batch runs, for `bf run-batch`: many programs run at once on a pool of threads, each with its own
+ *  input, its output kept, and limits on how long it runs and how much it writes.
+ *
+ *  A manifest lists the programs in a small part of TOML, `[[program]]` tables of strings and
+ *  integers. Keys before the first table are the defaults of every program.
+ *
+ *      timeout_ms = 2000           wall time a program can run for
+ *
+ *      [[program]]
+ *      file = "hello.bf"           paths are from the directory of the manifest
+ *      input = "hello.in"          none if left out
+ *      expected = "hello.out"      output the program has to write, anything goes if left out
+ *      max_steps = 1_000_000       instructions it can run
+ *      max_output = 65536          bytes it can write
+ *      tape_size = 30000
+ *
+ *  Programs given as files instead take `foo.in` and `foo.out` next to `foo.bf`, like `bf test`.
+ *  Runs go one instruction at a time so the limits are checked, slower than `bf run`. A file listed
+ *  more than once, with other inputs, is compiled once and its runs share the program.
+ */
+
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::{
+    golden,
+    parser::Program,
+    vm::{self, Observer, Vm, DEFAULT_VM_MEM_SIZE},
+};
+
+/// Steps between two looks at the clock.
+const CLOCK_EVERY: u64 = 1 << 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub max_steps: Option<u64>,
+    pub timeout: Option<Duration>,
+    pub max_output: Option<usize>,
+    pub tape_size: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_steps: None,
+            timeout: None,
+            max_output: None,
+            tape_size: DEFAULT_VM_MEM_SIZE,
+        }
+    }
+}
+
+/// A program to run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Job {
+    pub file: PathBuf,
+    pub input: Option<PathBuf>,
+    pub expected: Option<PathBuf>,
+    pub limits: Limits,
+}
+
+impl Job {
+    /// `file` with `foo.in` and `foo.out` next to it if they are there.
+    pub fn from_file(file: &Path, limits: Limits) -> Self {
+        let next_to = |ext| Some(file.with_extension(ext)).filter(|path| path.is_file());
+
+        Self {
+            file: file.to_path_buf(),
+            input: next_to("in"),
+            expected: next_to("out"),
+            limits,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// Wrote the output expected.
+    Pass,
+    /// Ended, with no output expected.
+    Ran,
+    /// Why, the diff of the outputs or the error of the program.
+    Fail(String),
+    /// Stopped at a limit, which one.
+    Limit(&'static str),
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Outcome::Pass => write!(f, "pass"),
+            Outcome::Ran => write!(f, "ran"),
+            Outcome::Fail(_) => write!(f, "fail"),
+            Outcome::Limit(limit) => write!(f, "{} limit", limit),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Report {
+    pub file: PathBuf,
+    pub outcome: Outcome,
+    pub steps: u64,
+    pub time: Duration,
+    pub output: Vec<u8>,
+}
+
+/// The programs of the manifest at `path`, see `parse_manifest`.
+pub fn read_manifest(path: &Path, limits: Limits) -> Result<Vec<Job>> {
+    let text = fs::read_to_string(path)?;
+    let dir = path.parent().unwrap_or(Path::new(""));
+
+    parse_manifest(&text, dir, limits).map_err(|err| anyhow!("{}: {}", path.display(), err))
+}
+
+/// The programs of a manifest, `limits` being the ones of programs that don't set theirs.
+pub fn parse_manifest(text: &str, dir: &Path, mut limits: Limits) -> Result<Vec<Job>> {
+    // The keys of the current table, where it starts.
+    let mut tables: Vec<(usize, Vec<(String, Value)>)> = vec![];
+    let mut top = vec![];
+
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line == "[[program]]" {
+            tables.push((i + 1, vec![]));
+            continue;
+        }
+
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| anyhow!("expected `key = value` or `[[program]]` at line {}", i + 1))?;
+        let value =
+            Value::parse(value.trim()).map_err(|err| anyhow!("{} at line {}", err, i + 1))?;
+        let keys = match tables.last_mut() {
+            Some((_, keys)) => keys,
+            None => &mut top,
+        };
+        keys.push((key.trim().to_string(), value));
+    }
+
+    for (key, value) in top {
+        if !set_limit(&mut limits, &key, value)? {
+            bail!("`{}` is set for every program, it can only be a limit", key);
+        }
+    }
+
+    tables
+        .into_iter()
+        .map(|(line, keys)| {
+            let mut job = Job {
+                file: PathBuf::new(),
+                input: None,
+                expected: None,
+                limits,
+            };
+            for (key, value) in keys {
+                match key.as_str() {
+                    "file" => job.file = dir.join(value.string(&key)?),
+                    "input" => job.input = Some(dir.join(value.string(&key)?)),
+                    "expected" => job.expected = Some(dir.join(value.string(&key)?)),
+                    _ if set_limit(&mut job.limits, &key, value)? => {}
+                    _ => bail!("unknown key `{}` in the program at line {}", key, line),
+                }
+            }
+            if job.file.as_os_str().is_empty() {
+                bail!("the program at line {} has no `file`", line);
+            }
+
+            Ok(job)
+        })
+        .collect()
+}
+
+/// Sets the limit `key` if it is one.
+fn set_limit(limits: &mut Limits, key: &str, value: Value) -> Result<bool> {
+    match key {
+        "max_steps" => limits.max_steps = Some(value.integer(key)?),
+        "timeout_ms" => limits.timeout = Some(Duration::from_millis(value.integer(key)?)),
+        "max_output" => limits.max_output = Some(value.integer(key)? as usize),
+        "tape_size" => limits.tape_size = value.integer(key)? as usize,
+        _ => return Ok(false),
+    }
+
+    Ok(true)
+}
+
+/// A value of the manifest, also read by `project`.
+pub(crate) enum Value {
+    String(String),
+    Integer(u64),
+    Bool(bool),
+}
+
+impl Value {
+    /// A `"string"`, an integer, with `_` between digits, or `true` or `false`, then maybe a comment.
+    pub(crate) fn parse(text: &str) -> Result<Self> {
+        let Some(rest) = text.strip_prefix('"') else {
+            let word = text.split('#').next().unwrap_or_default().trim();
+            return match word {
+                "true" => Ok(Value::Bool(true)),
+                "false" => Ok(Value::Bool(false)),
+                _ => word
+                    .replace('_', "")
+                    .parse()
+                    .map(Value::Integer)
+                    .map_err(|_| {
+                        anyhow!("expected a string, a number or a boolean, not `{}`", word)
+                    }),
+            };
+        };
+
+        let mut string = String::new();
+        let mut chars = rest.chars();
+        loop {
+            match chars.next() {
+                Some('"') => break,
+                Some('\\') => match chars.next() {
+                    Some('n') => string.push('\n'),
+                    Some('t') => string.push('\t'),
+                    Some(ch @ ('"' | '\\')) => string.push(ch),
+                    _ => bail!("unknown escape in `{}`", text),
+                },
+                Some(ch) => string.push(ch),
+                None => bail!("unterminated string `{}`", text),
+            }
+        }
+        let rest = chars.as_str().trim();
+        if !rest.is_empty() && !rest.starts_with('#') {
+            bail!("unexpected `{}` after the string", rest);
+        }
+
+        Ok(Value::String(string))
+    }
+
+    pub(crate) fn string(self, key: &str) -> Result<String> {
+        match self {
+            Value::String(string) => Ok(string),
+            _ => bail!("`{}` has to be a string", key),
+        }
+    }
+
+    fn integer(self, key: &str) -> Result<u64> {
+        match self {
+            Value::Integer(integer) => Ok(integer),
+            _ => bail!("`{}` has to be a number", key),
+        }
+    }
+}
+
+/// Runs `jobs` on `threads` threads, the reports in the order of the jobs.
+pub fn run(jobs: &[Job], threads: usize) -> Vec<Report> {
+    let next = AtomicUsize::new(0);
+    let reports = Mutex::new(Vec::with_capacity(jobs.len()));
+    // Compiled by the first job running it.
+    let programs: HashMap<_, OnceLock<_>> = jobs
+        .iter()
+        .map(|job| (&job.file, OnceLock::new()))
+        .collect();
+
+    thread::scope(|scope| {
+        for _ in 0..threads.clamp(1, jobs.len().max(1)) {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(job) = jobs.get(i) else {
+                    break;
+                };
+                let report = run_job(job, &programs[&job.file]);
+                reports.lock().unwrap().push((i, report));
+            });
+        }
+    });
+
+    let mut reports = reports.into_inner().unwrap();
+    reports.sort_by_key(|(i, _)| *i);
+    reports.into_iter().map(|(_, report)| report).collect()
+}
+
+/// The program of `file`, the error as text for the other jobs running it.
+type Compiled = std::result::Result<Arc<Program>, String>;
+
+fn run_job(job: &Job, program: &OnceLock<Compiled>) -> Report {
+    let start = Instant::now();
+    let run = (|| -> Result<Run> {
+        let input = match &job.input {
+            Some(path) => fs::read(path)?,
+            None => vec![],
+        };
+        let program = program.get_or_init(|| {
+            fs::read_to_string(&job.file)
+                .map_err(anyhow::Error::from)
+                .and_then(|src| vm::compile(&src))
+                .map(Arc::new)
+                .map_err(|err| err.to_string())
+        });
+        let program = program.clone().map_err(|err| anyhow!(err))?;
+
+        run_limited(&program, &input, &job.limits, start)
+    })();
+
+    let (outcome, steps, output) = match run {
+        Err(err) => (Outcome::Fail(format!("error: {}", err)), 0, vec![]),
+        Ok(run) => {
+            let outcome = match (run.result, run.limit) {
+                (_, Some(limit)) => Outcome::Limit(limit),
+                (Err(err), None) => Outcome::Fail(format!("error: {}", err)),
+                (Ok(()), None) => match job.expected.as_ref().map(fs::read) {
+                    None => Outcome::Ran,
+                    Some(Err(err)) => Outcome::Fail(format!("error: {}", err)),
+                    Some(Ok(expected)) if expected == run.output => Outcome::Pass,
+                    Some(Ok(expected)) => Outcome::Fail(golden::diff(&expected, &run.output)),
+                },
+            };
+            (outcome, run.steps, run.output)
+        }
+    };
+
+    Report {
+        file: job.file.clone(),
+        outcome,
+        steps,
+        time: start.elapsed(),
+        output,
+    }
+}
+
+/// A run within limits.
+#[derive(Debug)]
+pub struct Run {
+    /// How the program ended, the error of the limit if it stopped at one.
+    pub result: Result<()>,
+    /// The limit it stopped at: `step`, `time` or `output`.
+    pub limit: Option<&'static str>,
+    pub steps: u64,
+    pub output: Vec<u8>,
+}
+
+/// Runs `program` on `input` within `limits`, the timeout counted from `start`. Fails if the tape
+/// can't be made.
+pub fn run_limited(
+    program: &Arc<Program>,
+    input: &[u8],
+    limits: &Limits,
+    start: Instant,
+) -> Result<Run> {
+    let mut vm = Vm::from_shared(program.clone())?;
+    vm.set_tape_size(limits.tape_size)?;
+
+    Ok(run_limited_on(&mut vm, input, limits, start))
+}
+
+/// Same as `run_limited` on `vm`, with its program and its tape: `limits.tape_size` is left alone.
+pub fn run_limited_on(vm: &mut Vm, input: &[u8], limits: &Limits, start: Instant) -> Run {
+    let mut limit = Limit {
+        max_steps: limits.max_steps,
+        deadline: limits.timeout.map(|timeout| start + timeout),
+        steps: 0,
+        reached: None,
+    };
+    let mut output = Capped {
+        out: vec![],
+        max: limits.max_output.unwrap_or(usize::MAX),
+        full: false,
+    };
+
+    let result = vm.run_observed(&mut &input[..], &mut output, &mut limit);
+    let stopped = match output.full {
+        true => Some("output"),
+        false => limit.reached.filter(|_| result.is_err()),
+    };
+
+    Run {
+        result,
+        limit: stopped,
+        steps: limit.steps,
+        output: output.out,
+    }
+}
+
+/// Stops the run at the step limit or the deadline.
+struct Limit {
+    max_steps: Option<u64>,
+    deadline: Option<Instant>,
+    steps: u64,
+    /// The one that stopped it.
+    reached: Option<&'static str>,
+}
+
+impl Observer for Limit {
+    fn instruction(&mut self, _: &Vm) -> Result<()> {
+        if self.max_steps == Some(self.steps) {
+            self.reached = Some("step");
+            bail!("ran more than {} steps", self.steps);
+        }
+        self.steps += 1;
+        let clock = self.steps.is_multiple_of(CLOCK_EVERY);
+        if clock
+            && self
+                .deadline
+                .is_some_and(|deadline| Instant::now() > deadline)
+        {
+            self.reached = Some("time");
+            bail!("ran out of time");
+        }
+
+        Ok(())
+    }
+}
+
+/// Keeps up to `max` bytes, failing the write past them.
+struct Capped {
+    out: Vec<u8>,
+    max: usize,
+    full: bool,
+}
+
+impl Write for Capped {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.out.len() + buf.len() > self.max {
+            self.full = true;
+            return Err(io::Error::other("output limit reached"));
+        }
+        self.out.extend_from_slice(buf);
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{env, fs, path::Path, process, time::Duration};
+
+    use crate::batch::{self, Job, Limits, Outcome};
+
+    #[test]
+    fn reads_manifests() {
+        let manifest = "# Shared.\ntimeout_ms = 1_500\n\n[[program]]\nfile = \"a.bf\"  # first\ninput = \"a \\\"in\\\"\"\n\n[[program]]\nfile = \"b.bf\"\nmax_steps = 10\n";
+        let jobs = batch::parse_manifest(manifest, Path::new("dir"), Limits::default()).unwrap();
+
+        let limits = Limits {
+            timeout: Some(Duration::from_millis(1500)),
+            ..Limits::default()
+        };
+        assert_eq!(
+            jobs[0],
+            Job {
+                file: "dir/a.bf".into(),
+                input: Some("dir/a \"in\"".into()),
+                expected: None,
+                limits,
+            }
+        );
+        assert_eq!(jobs[1].limits.max_steps, Some(10));
+        assert_eq!(jobs[1].limits.timeout, limits.timeout);
+
+        let err = |text| {
+            batch::parse_manifest(text, Path::new(""), Limits::default())
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(err("[[program]]\nfile = 1"), "`file` has to be a string");
+        assert_eq!(
+            err("file = \"a.bf\""),
+            "`file` is set for every program, it can only be a limit"
+        );
+        assert_eq!(
+            err("[[program]]\nmax_steps = 1"),
+            "the program at line 1 has no `file`"
+        );
+    }
+
+    #[test]
+    fn runs_programs_with_limits() {
+        let dir = env::temp_dir().join(format!("bf-batch-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for (name, src) in [
+            ("echo.bf", ",.,."),
+            ("loop.bf", "+[]"),
+            ("loud.bf", "+[.]"),
+            ("bad.bf", "]"),
+        ] {
+            fs::write(dir.join(name), src).unwrap();
+        }
+        fs::write(dir.join("echo.in"), "hi").unwrap();
+        fs::write(dir.join("echo.out"), "hi").unwrap();
+
+        let limits = Limits {
+            max_steps: Some(1000),
+            max_output: Some(10),
+            ..Limits::default()
+        };
+        let jobs: Vec<_> = ["echo.bf", "loop.bf", "loud.bf", "bad.bf"]
+            .iter()
+            .map(|name| Job::from_file(&dir.join(name), limits))
+            .collect();
+        let reports = batch::run(&jobs, 3);
+
+        assert_eq!(reports[0].outcome, Outcome::Pass);
+        assert_eq!(reports[0].output, b"hi");
+        assert_eq!(reports[1].outcome, Outcome::Limit("step"));
+        assert_eq!(reports[1].steps, 1000);
+        assert_eq!(reports[2].outcome, Outcome::Limit("output"));
+        assert_eq!(reports[2].output, [1; 10]);
+        assert!(
+            matches!(&reports[3].outcome, Outcome::Fail(why) if why.starts_with("error: unexpected"))
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
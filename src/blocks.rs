@@ -0,0 +1,331 @@
+/*
+ *  Block engine: the runs of opcodes between two jumps or I/O, only moving the pointer and changing
+ *  cells, are made into blocks before running. A block checks once that every cell it reaches is on
+ *  the tape, then runs without a bounds check, a check of the pc or a move of the pointer per
+ *  opcode: the moves are folded into where each opcode is, the pointer moves once at the end.
+ *
+ *  Loops jump from item to item, the other opcodes and the blocks reaching off the tape, which fail,
+ *  clamp at the left edge or grow the tape, are run one at a time by `Vm::step`.
+ *
+ *  Loops with long bodies of adds at many offsets run ~40% faster than on the `match` loop of
+ *  `Vm::run_inner` (0.29s against 0.50s for 454M instructions). On mandelbrot, whose loops are mostly
+ *  a few opcodes, it makes no difference.
+ */
+
+use crate::{
+    opcodes::{OpCode, OpCodeType},
+    parser::Program,
+};
+
+/// What a block does to a cell, at its index in the cells the block reaches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Add(usize, u8),
+    Set(usize, u8),
+    /// Adds `from` times `factor` to `to`.
+    MulAdd {
+        from: usize,
+        to: usize,
+        factor: u8,
+    },
+    Fill {
+        start: usize,
+        len: usize,
+        value: u8,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Block {
+    end: usize,
+    min: isize,
+    max: isize,
+    shift: isize,
+    ops: Vec<Op>,
+}
+
+impl Block {
+    /// The pc after the block.
+    pub fn end(&self) -> usize {
+        self.end
+    }
+
+    /// The first and last cell the block reaches, from the pointer.
+    pub fn reach(&self) -> (isize, isize) {
+        (self.min, self.max)
+    }
+
+    /// Where the pointer ends up, from where it was. Between the cells `reach` gives.
+    pub fn shift(&self) -> isize {
+        self.shift
+    }
+
+    /// Runs the block on `cells`, the ones `reach` gives.
+    #[inline]
+    pub fn run(&self, cells: &mut [u8]) {
+        assert_eq!(cells.len(), (self.max - self.min) as usize + 1);
+
+        for &op in &self.ops {
+            // SAFETY: every index is one of a cell between `min` and `max`, see `BlockCode::block`.
+            unsafe {
+                match op {
+                    Op::Add(at, amount) => {
+                        let cell = cells.get_unchecked_mut(at);
+                        *cell = cell.wrapping_add(amount);
+                    }
+                    Op::Set(at, value) => *cells.get_unchecked_mut(at) = value,
+                    Op::MulAdd { from, to, factor } => {
+                        let value = *cells.get_unchecked(from);
+                        let cell = cells.get_unchecked_mut(to);
+                        *cell = cell.wrapping_add(value.wrapping_mul(factor));
+                    }
+                    Op::Fill { start, len, value } => {
+                        cells.get_unchecked_mut(start..start + len).fill(value)
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A block or an opcode left as it is, jumps going to items instead of pcs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Item {
+    Block(Block),
+    Op(OpCode),
+}
+
+pub struct BlockCode {
+    items: Vec<Item>,
+    /// The pc each item starts at, in order.
+    pcs: Vec<usize>,
+    /// Opcodes in the program.
+    len: usize,
+}
+
+impl BlockCode {
+    pub fn new(program: &Program) -> Self {
+        let mut code = Self {
+            items: vec![],
+            pcs: vec![],
+            len: program.len(),
+        };
+        let mut pc = 0;
+
+        while pc < program.len() {
+            let len = program[pc..]
+                .iter()
+                .position(|op| !in_block(op.ty))
+                .unwrap_or(program.len() - pc);
+            code.pcs.push(pc);
+            if len > 0 {
+                code.items
+                    .push(Item::Block(Self::block(&program[pc..pc + len], pc + len)));
+                pc += len;
+            } else {
+                code.items.push(Item::Op(program[pc]));
+                pc += 1;
+            }
+        }
+
+        for i in 0..code.items.len() {
+            if let Item::Op(op) = &code.items[i] {
+                if matches!(op.ty, OpCodeType::JmpZero | OpCodeType::JmpNotZero) {
+                    let to = code
+                        .item(op.data)
+                        .expect("jumps go to opcodes out of blocks");
+                    if let Item::Op(op) = &mut code.items[i] {
+                        op.data = to;
+                    }
+                }
+            }
+        }
+
+        code
+    }
+
+    pub fn items(&self) -> &[Item] {
+        &self.items
+    }
+
+    /// The pc item `i` starts at, the end of the program past the last one.
+    pub fn pc(&self, i: usize) -> usize {
+        self.pcs.get(i).copied().unwrap_or(self.len)
+    }
+
+    /// The item starting at `pc`, `items().len()` for the end of the program. None inside a block.
+    pub fn item(&self, pc: usize) -> Option<usize> {
+        match self.pcs.binary_search(&pc) {
+            Ok(i) => Some(i),
+            Err(i) if pc >= self.len => Some(i),
+            Err(_) => None,
+        }
+    }
+
+    /// The block of `ops`, all of them `in_block`.
+    fn block(ops: &[OpCode], end: usize) -> Block {
+        // Cells from the pointer as it was before the block.
+        let mut ptr = 0isize;
+        let (mut min, mut max) = (0, 0);
+        let mut reached = |cell: isize| {
+            min = cell.min(min);
+            max = cell.max(max);
+            cell
+        };
+
+        let mut at = vec![];
+        for op in ops {
+            let cell = ptr + op.offset as isize;
+            let data = op.data;
+            at.push(match op.ty {
+                OpCodeType::ShiftLeft => {
+                    ptr -= data as isize;
+                    reached(ptr);
+                    continue;
+                }
+                OpCodeType::ShiftRight => {
+                    ptr += data as isize;
+                    reached(ptr);
+                    continue;
+                }
+                OpCodeType::Add => (Op::Add(0, data as u8), reached(cell), 0),
+                OpCodeType::Sub => (Op::Add(0, (data as u8).wrapping_neg()), reached(cell), 0),
+                OpCodeType::Set => (Op::Set(0, data as u8), reached(cell), 0),
+                OpCodeType::MulAdd => {
+                    let from = reached(ptr);
+                    (
+                        Op::MulAdd {
+                            from: 0,
+                            to: 0,
+                            factor: data as u8,
+                        },
+                        reached(cell),
+                        from,
+                    )
+                }
+                OpCodeType::FillRange | OpCodeType::ClearRange => {
+                    let (len, value) = match op.ty {
+                        OpCodeType::FillRange => (data >> 8, data as u8),
+                        _ => (data, 0),
+                    };
+                    if len == 0 {
+                        continue;
+                    }
+                    reached(cell + len as isize - 1);
+                    (
+                        Op::Fill {
+                            start: 0,
+                            len,
+                            value,
+                        },
+                        reached(cell),
+                        0,
+                    )
+                }
+                ty => unreachable!("{:?} is not in blocks", ty),
+            });
+        }
+
+        // Indexes in the cells from `min`, all of them between `min` and `max`.
+        let index = |cell: isize| (cell - min) as usize;
+        let ops = at
+            .into_iter()
+            .map(|(op, cell, from)| match op {
+                Op::Add(_, amount) => Op::Add(index(cell), amount),
+                Op::Set(_, value) => Op::Set(index(cell), value),
+                Op::MulAdd { factor, .. } => Op::MulAdd {
+                    from: index(from),
+                    to: index(cell),
+                    factor,
+                },
+                Op::Fill { len, value, .. } => Op::Fill {
+                    start: index(cell),
+                    len,
+                    value,
+                },
+            })
+            .collect();
+
+        Block {
+            end,
+            min,
+            max,
+            shift: ptr,
+            ops,
+        }
+    }
+}
+
+/// Whether `ty` only moves the pointer or changes cells, which can't fail on the tape.
+fn in_block(ty: OpCodeType) -> bool {
+    use OpCodeType::*;
+
+    matches!(
+        ty,
+        Add | Sub | ShiftLeft | ShiftRight | Set | MulAdd | FillRange | ClearRange
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        blocks::{BlockCode, Item},
+        lexer,
+        opcodes::{OpCode, OpCodeType},
+        parser,
+        vm::{self, Vm},
+    };
+
+    #[test]
+    fn folds_moves_into_blocks() {
+        let program = parser::parse(lexer::parse("+>>++<<<-[.]")).unwrap();
+        let code = BlockCode::new(&program);
+        let Item::Block(block) = &code.items()[0] else {
+            panic!("no block at the start");
+        };
+        assert_eq!(
+            (block.end, block.min, block.max, block.shift),
+            (5, -1, 2, -1)
+        );
+        // The jumps go to the items after the block.
+        assert_eq!(
+            code.items()[1],
+            Item::Op(OpCode::new(OpCodeType::JmpZero, 3))
+        );
+        assert_eq!(
+            (code.item(5), code.item(3), code.item(8)),
+            (Some(1), None, Some(4))
+        );
+
+        let mut cells = [1, 2, 3, 4];
+        block.run(&mut cells);
+        assert_eq!(cells, [0, 3, 3, 6]);
+    }
+
+    #[test]
+    fn runs_like_the_match_loop() {
+        for (src, input) in [
+            ("++++++++[>++++[>++>+++<<-]>[-]<<-]>>.>.", ""),
+            (",[>+>+<<-]>>[<<+>>-]<<.>.", "A"),
+            // Off the left edge, clamped one opcode at a time.
+            ("+<<<+>.", ""),
+        ] {
+            let program = vm::compile(src).unwrap();
+            let run = |blocks: bool| {
+                let mut vm = Vm::from_program(program.clone()).unwrap();
+                let mut output = vec![];
+                let result = match blocks {
+                    true => vm.run_blocks(&mut input.as_bytes(), &mut output),
+                    false => vm.run_with(&mut input.as_bytes(), &mut output),
+                };
+                (result.is_ok(), output, vm.mem()[..8].to_vec(), vm.mem_ptr())
+            };
+            assert_eq!(run(true), run(false), "{}", src);
+        }
+
+        let mut vm = Vm::from_program(vm::compile("+[>+]").unwrap()).unwrap();
+        vm.set_tape_size(4).unwrap();
+        assert!(vm.run_blocks(&mut &b""[..], &mut vec![]).is_err());
+        assert_eq!(vm.mem_ptr(), 3);
+    }
+}
@@ -0,0 +1,93 @@
+/*
+ *  Brainfork: Brainfuck with `Y`, which forks the running thread. The thread that ran it has its cell
+ *  cleared, the new one gets a copy of the tape with the pointer one cell to the right and that cell
+ *  set to 1, both go on past the `Y`.
+ *
+ *  Threads are VMs, run by the `Scheduler` one instruction each in turn, unoptimized. They read the
+ *  same input and write to the same output, so what they print is interleaved the way they ran. A
+ *  new thread starts on the next turn, the program ends when every thread has.
+ */
+
+use std::io::{Read, Write};
+
+use anyhow::{bail, Result};
+
+use crate::{opcodes::OpCodeType, vm::Vm};
+
+/// Threads that can be running at once.
+pub const MAX_THREADS: usize = 1 << 10;
+
+pub struct Scheduler {
+    /// The threads still running, in the order they take turns.
+    threads: Vec<Vm>,
+    /// The thread that ran last, the one that failed after an error.
+    current: usize,
+}
+
+impl Scheduler {
+    /// Runs the program loaded in `vm`, as the first thread.
+    pub fn new(vm: Vm) -> Self {
+        Self {
+            threads: vec![vm],
+            current: 0,
+        }
+    }
+
+    pub fn run<R: Read, W: Write>(&mut self, input: &mut R, output: &mut W) -> Result<()> {
+        while !self.threads.is_empty() {
+            // Threads forked in this round start in the next one.
+            let running = self.threads.len();
+            let mut i = 0;
+
+            for _ in 0..running {
+                self.current = i;
+                let vm = &mut self.threads[i];
+                let forks = vm.program().get(vm.pc()).map(|op| op.ty) == Some(OpCodeType::Fork);
+
+                if forks {
+                    if self.threads.len() == MAX_THREADS {
+                        bail!("too many threads: {} are running", MAX_THREADS);
+                    }
+                    let child = self.threads[i].fork()?;
+                    self.threads.push(child);
+                    i += 1;
+                } else if self.threads[i].step(input, output)? {
+                    i += 1;
+                } else {
+                    self.threads.remove(i);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The thread that ran last, the one that failed after an error. None once all of them ended.
+    pub fn current(&self) -> Option<&Vm> {
+        self.threads.get(self.current)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{brainfork::Scheduler, lexer, vm};
+
+    fn run(src: &str) -> Vec<u8> {
+        let (program, _) = vm::compile_tokens_with_map(lexer::parse_brainfork(src)).unwrap();
+        let mut output = vec![];
+        Scheduler::new(vm::Vm::from_program(program).unwrap())
+            .run(&mut &b""[..], &mut output)
+            .unwrap();
+
+        output
+    }
+
+    #[test]
+    fn forks_threads() {
+        // One instruction of each thread in turn, the new thread with a copy of the tape from before
+        // the fork, where cell 0 is still 3.
+        assert_eq!(run("+++Y.<."), [0, 1, 0, 3]);
+        // Both threads fork again, the forked ones print 1.
+        assert_eq!(run("YY."), [0, 0, 1, 1]);
+    }
+}
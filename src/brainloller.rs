@@ -0,0 +1,165 @@
+/*
+ *  Brainloller: Brainfuck as an image, every pixel a command by its color.
+ *
+ *      red     255,0,0      >      dark red     128,0,0      <
+ *      green   0,255,0      +      dark green   0,128,0      -
+ *      blue    0,0,255      .      dark blue    0,0,128      ,
+ *      yellow  255,255,0    [      dark yellow  128,128,0    ]
+ *      cyan    0,255,255    turn right            dark cyan    0,128,128    turn left
+ *
+ *  Other colors do nothing. The program is read from the top left pixel going right, turning on cyan
+ *  pixels, up to where it leaves the image.
+ *
+ *  The path is walked once, up front, into Brainfuck with a line for each stretch between two turns.
+ *  The rest of the pipeline runs that like any program, its errors point into it. Reading PNG files
+ *  needs the `brainloller` feature.
+ */
+
+use anyhow::{bail, Result};
+
+/// The pixels of an image, as RGB.
+pub struct Image {
+    width: usize,
+    height: usize,
+    /// Row after row.
+    pixels: Vec<[u8; 3]>,
+}
+
+impl Image {
+    pub fn new(width: usize, height: usize, pixels: Vec<[u8; 3]>) -> Result<Self> {
+        if width.checked_mul(height) != Some(pixels.len()) {
+            bail!(
+                "{} pixels don't make a {}x{} image",
+                pixels.len(),
+                width,
+                height
+            );
+        }
+
+        Ok(Self {
+            width,
+            height,
+            pixels,
+        })
+    }
+
+    fn pixel(&self, x: usize, y: usize) -> [u8; 3] {
+        self.pixels[y * self.width + x]
+    }
+}
+
+/// The program of `image` as Brainfuck.
+pub fn to_brainfuck(image: &Image) -> String {
+    let mut out = String::new();
+    let (mut x, mut y) = (0, 0);
+    let (mut dx, mut dy) = (1isize, 0isize);
+
+    // Always ends: every step can be undone, so the path can't come back to where it was going the
+    // same way without going back to where it came in.
+    while x < image.width && y < image.height {
+        let turn = match image.pixel(x, y) {
+            [0, 255, 255] => Some((-dy, dx)),
+            [0, 128, 128] => Some((dy, -dx)),
+            color => {
+                if let Some(command) = command(color) {
+                    out.push(command);
+                }
+                None
+            }
+        };
+        if let Some(direction) = turn {
+            (dx, dy) = direction;
+            if !out.is_empty() && !out.ends_with('\n') {
+                out.push('\n');
+            }
+        }
+
+        // Off the left or top edge wraps to usize::MAX, which ends the walk too.
+        x = x.wrapping_add_signed(dx);
+        y = y.wrapping_add_signed(dy);
+    }
+
+    out
+}
+
+fn command(color: [u8; 3]) -> Option<char> {
+    Some(match color {
+        [255, 0, 0] => '>',
+        [128, 0, 0] => '<',
+        [0, 255, 0] => '+',
+        [0, 128, 0] => '-',
+        [0, 0, 255] => '.',
+        [0, 0, 128] => ',',
+        [255, 255, 0] => '[',
+        [128, 128, 0] => ']',
+        _ => return None,
+    })
+}
+
+/// The pixels of a PNG file, alpha left out.
+#[cfg(feature = "brainloller")]
+pub fn read_png(bytes: &[u8]) -> Result<Image> {
+    use png::{ColorType, Decoder, Transformations};
+
+    let mut decoder = Decoder::new(bytes);
+    decoder.set_transformations(Transformations::EXPAND | Transformations::STRIP_16);
+    let mut reader = decoder.read_info()?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf)?;
+
+    let (width, height) = (info.width as usize, info.height as usize);
+    let samples = info.color_type.samples();
+    let mut pixels = Vec::with_capacity(width * height);
+    for row in buf.chunks(info.line_size).take(height) {
+        for pixel in row.chunks_exact(samples).take(width) {
+            pixels.push(match info.color_type {
+                ColorType::Grayscale | ColorType::GrayscaleAlpha => [pixel[0]; 3],
+                _ => [pixel[0], pixel[1], pixel[2]],
+            });
+        }
+    }
+
+    Image::new(width, height, pixels)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::brainloller::{to_brainfuck, Image};
+
+    const R: [u8; 3] = [255, 0, 0];
+    const G: [u8; 3] = [0, 255, 0];
+    const C: [u8; 3] = [0, 255, 255];
+    const W: [u8; 3] = [255, 255, 255];
+
+    #[test]
+    fn walks_the_path() {
+        // Right along the top, down the right edge, then left along the bottom and off the image.
+        #[rustfmt::skip]
+        let image = Image::new(3, 3, vec![
+            G, G, C,
+            W, W, R,
+            W, G, C,
+        ])
+        .unwrap();
+        assert_eq!(to_brainfuck(&image), "++\n>\n+");
+
+        // Round and out at the top, through the first pixel again.
+        let image = Image::new(2, 2, vec![G, C, C, C]).unwrap();
+        assert_eq!(to_brainfuck(&image), "+\n+");
+        assert!(Image::new(2, 2, vec![G]).is_err());
+    }
+
+    #[cfg(feature = "brainloller")]
+    #[test]
+    fn reads_png() {
+        let mut png = vec![];
+        let mut encoder = png::Encoder::new(&mut png, 2, 1);
+        encoder.set_color(png::ColorType::Rgb);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&[0, 255, 0, 0, 0, 255]).unwrap();
+        writer.finish().unwrap();
+
+        let image = crate::brainloller::read_png(&png).unwrap();
+        assert_eq!(to_brainfuck(&image), "+.");
+    }
+}
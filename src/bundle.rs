@@ -0,0 +1,121 @@
+/*
+ *  Standalone executables: a copy of the `bf` binary with a compiled program appended to it.
+ *  Layout of the appended part: the program in the .bfc format, its length as a little endian u64, then
+ *  `MAGIC`.
+ *  On startup `bf` looks for the trailer in its own executable and runs the program it finds.
+ */
+
+use std::{
+    fs::{self, File},
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use anyhow::{bail, Result};
+
+use crate::{bytecode, parser::Program};
+
+const MAGIC: &[u8; 8] = b"BFBUNDLE";
+const TRAILER_LEN: u64 = 16;
+
+/// Writes `runtime` with `program` appended to `out` and makes the result executable.
+pub fn write_executable(runtime: &Path, program: &Program, out: &Path) -> Result<()> {
+    let mut bytes = fs::read(runtime)?;
+
+    // Building from a bundled binary must not stack a second program on top of the first.
+    if bytes.len() as u64 >= TRAILER_LEN {
+        let at = bytes.len() - TRAILER_LEN as usize;
+        if let Some(len) = payload_len(&bytes[at..], at as u64)? {
+            bytes.truncate(at - len as usize);
+        }
+    }
+
+    let payload = bytecode::encode(program);
+    bytes.extend_from_slice(&payload);
+    bytes.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    bytes.extend_from_slice(MAGIC);
+
+    fs::write(out, bytes)?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        fs::set_permissions(out, fs::Permissions::from_mode(0o755))?;
+    }
+
+    Ok(())
+}
+
+/// The program appended to the executable at `path`, `None` for a plain `bf` binary.
+pub fn embedded_program(path: &Path) -> Result<Option<Program>> {
+    let mut file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+
+    if file_len < TRAILER_LEN {
+        return Ok(None);
+    }
+
+    let mut trailer = [0; TRAILER_LEN as usize];
+    file.seek(SeekFrom::End(-(TRAILER_LEN as i64)))?;
+    file.read_exact(&mut trailer)?;
+
+    let Some(len) = payload_len(&trailer, file_len - TRAILER_LEN)? else {
+        return Ok(None);
+    };
+
+    let mut payload = vec![0; len as usize];
+    file.seek(SeekFrom::End(-((len + TRAILER_LEN) as i64)))?;
+    file.read_exact(&mut payload)?;
+
+    bytecode::decode(&payload).map(Some)
+}
+
+/// Length of the payload if `trailer` is a bundle trailer, `available` is how many bytes precede it.
+fn payload_len(trailer: &[u8], available: u64) -> Result<Option<u64>> {
+    if &trailer[8..] != MAGIC {
+        return Ok(None);
+    }
+
+    let len = u64::from_le_bytes(trailer[..8].try_into().unwrap());
+    if len > available {
+        bail!("bundled program is longer than the executable");
+    }
+
+    Ok(Some(len))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{env, fs};
+
+    use crate::{
+        bundle::{embedded_program, write_executable},
+        bytecode, vm,
+    };
+
+    #[test]
+    fn executable_carries_program() {
+        let dir = env::temp_dir().join(format!("bf-bundle-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (runtime, out, rebuilt) = (dir.join("runtime"), dir.join("out"), dir.join("rebuilt"));
+
+        fs::write(&runtime, b"\x7fELF not really a runtime").unwrap();
+        assert_eq!(embedded_program(&runtime).unwrap(), None);
+
+        let program = vm::compile("++[->+<]>.").unwrap();
+        write_executable(&runtime, &program, &out).unwrap();
+        assert_eq!(embedded_program(&out).unwrap(), Some(program));
+
+        // Building from a bundled binary replaces its program.
+        let program = vm::compile(",.").unwrap();
+        write_executable(&out, &program, &rebuilt).unwrap();
+        assert_eq!(
+            fs::metadata(&rebuilt).unwrap().len(),
+            fs::metadata(&runtime).unwrap().len() + bytecode::encode(&program).len() as u64 + 16
+        );
+        assert_eq!(embedded_program(&rebuilt).unwrap(), Some(program));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,215 @@
+/*
+ *  Binary bytecode format (.bfc), so compiled programs can be run without lexing and parsing again.
+ *
+ *  Layout, all integers little endian:
+ *      magic       b"BFC\0"
+ *      version     u16
+ *      opcodes     u8 count, then per entry a u8 id and the opcode type name (u8 length + bytes)
+ *      program     u64 length, then per opcode a u8 id, data as u64 and offset as i32
+ *      locations   optional, a u8 1 then per opcode the source line and column as u32, 0 for none
+ *
+ *  Opcode types are stored by name in the table, files stay readable when `OpCodeType` gets reordered.
+ */
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::{
+    lexer::TokenLoc,
+    opcodes::{OpCode, OpCodeType},
+    parser::{self, Program},
+    srcmap::SourceMap,
+};
+
+pub const MAGIC: &[u8; 4] = b"BFC\0";
+pub const VERSION: u16 = 2;
+
+/// Tag of the locations section.
+const LOCATIONS: u8 = 1;
+
+pub fn encode(program: &Program) -> Vec<u8> {
+    encode_with_map(program, None)
+}
+
+/// Same as `encode`, with the source map of the program when given, for `bf compile -g`.
+pub fn encode_with_map(program: &Program, map: Option<&SourceMap>) -> Vec<u8> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+
+    bytes.push(OpCodeType::ALL.len() as u8);
+    for ty in OpCodeType::ALL {
+        let name = format!("{:?}", ty);
+        bytes.push(ty as u8);
+        bytes.push(name.len() as u8);
+        bytes.extend_from_slice(name.as_bytes());
+    }
+
+    bytes.extend_from_slice(&(program.len() as u64).to_le_bytes());
+    for op in program {
+        bytes.push(op.ty as u8);
+        bytes.extend_from_slice(&(op.data as u64).to_le_bytes());
+        bytes.extend_from_slice(&op.offset.to_le_bytes());
+    }
+
+    if let Some(map) = map {
+        bytes.push(LOCATIONS);
+        for pc in 0..program.len() {
+            let (line, col) = map.get(pc).map_or((0, 0), |loc| (loc.line(), loc.col()));
+            bytes.extend_from_slice(&(line as u32).to_le_bytes());
+            bytes.extend_from_slice(&(col as u32).to_le_bytes());
+        }
+    }
+
+    bytes
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Program> {
+    Ok(decode_with_map(bytes)?.0)
+}
+
+/// Same as `decode`, with the source map if the file has one.
+pub fn decode_with_map(bytes: &[u8]) -> Result<(Program, Option<SourceMap>)> {
+    let mut reader = Reader { bytes };
+
+    if reader.take(MAGIC.len())? != MAGIC {
+        bail!("not a bytecode file");
+    }
+
+    let version = u16::from_le_bytes(reader.array()?);
+    if version != VERSION {
+        bail!(
+            "unsupported bytecode version {}, expected {}",
+            version,
+            VERSION
+        );
+    }
+
+    let mut table = [None; 256];
+    for _ in 0..reader.byte()? {
+        let id = reader.byte()?;
+        let len = reader.byte()? as usize;
+        let name = reader.take(len)?;
+
+        let ty = OpCodeType::ALL
+            .into_iter()
+            .find(|ty| format!("{:?}", ty).as_bytes() == name)
+            .ok_or_else(|| anyhow!("unknown opcode {:?}", String::from_utf8_lossy(name)))?;
+        table[id as usize] = Some(ty);
+    }
+
+    let len = u64::from_le_bytes(reader.array()?);
+    let mut program = Program::new();
+
+    for _ in 0..len {
+        let id = reader.byte()?;
+        let ty =
+            table[id as usize].ok_or_else(|| anyhow!("opcode id {} is not in the table", id))?;
+        let data = u64::from_le_bytes(reader.array()?);
+        let offset = i32::from_le_bytes(reader.array()?);
+
+        program.push(OpCode::with_offset(ty, data as usize, offset));
+    }
+
+    let mut map = None;
+    if reader.bytes.first() == Some(&LOCATIONS) {
+        reader.byte()?;
+
+        let locs = (0..len)
+            .map(|_| {
+                let line = u32::from_le_bytes(reader.array()?) as usize;
+                let col = u32::from_le_bytes(reader.array()?) as usize;
+                Ok((line > 0).then(|| TokenLoc::from_col_line(col, line)))
+            })
+            .collect::<Result<_>>()?;
+        map = Some(locs);
+    }
+
+    if !reader.bytes.is_empty() {
+        bail!("{} trailing bytes after the program", reader.bytes.len());
+    }
+
+    // The VM trusts jump targets, a damaged file must not send it out of the program.
+    parser::verify_jumps(&program)?;
+
+    Ok((program, map))
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.bytes.len() < len {
+            bail!("unexpected end of bytecode");
+        }
+
+        let (head, tail) = self.bytes.split_at(len);
+        self.bytes = tail;
+
+        Ok(head)
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        bytecode::{decode, decode_with_map, encode, encode_with_map},
+        opcodes::{OpCode, OpCodeType::*},
+        vm,
+    };
+
+    #[test]
+    fn round_trip_keeps_program() {
+        let program = vm::compile("+++[->++<]>>,[-]<<.>>>>[+>]").unwrap();
+
+        assert_eq!(decode(&encode(&program)).unwrap(), program);
+    }
+
+    #[test]
+    fn round_trip_keeps_source_map() {
+        let (program, map) = vm::compile_with_map("+++\n[->++<]>>,[-]<<.>>>>[+>]").unwrap();
+
+        let (decoded, decoded_map) =
+            decode_with_map(&encode_with_map(&program, Some(&map))).unwrap();
+        assert_eq!(decoded, program);
+        assert_eq!(decoded_map, Some(map));
+        assert_eq!(decode_with_map(&encode(&program)).unwrap().1, None);
+    }
+
+    #[test]
+    fn opcodes_are_looked_up_by_name() {
+        let mut bytes = encode(&vec![OpCode::new(Add, 1)]);
+
+        // Swap the ids of Add and Sub in the table, the program now reads as a Sub.
+        bytes[7] = 1;
+        bytes[7 + 2 + "Add".len()] = 0;
+
+        assert_eq!(decode(&bytes).unwrap(), vec![OpCode::new(Sub, 1)]);
+    }
+
+    #[test]
+    fn broken_files_are_rejected() {
+        let program = vec![OpCode::new(JmpZero, 1), OpCode::new(JmpNotZero, 0)];
+        let bytes = encode(&program);
+
+        assert!(decode(&bytes[..bytes.len() - 1]).is_err());
+        assert!(decode(&[bytes.as_slice(), &[0]].concat()).is_err());
+
+        let mut wrong_version = bytes.clone();
+        wrong_version[4] = 3;
+        assert!(decode(&wrong_version).is_err());
+
+        let mut broken_jump = bytes.clone();
+        let at = broken_jump.len() - 12;
+        broken_jump[at] = 5;
+        assert!(decode(&broken_jump).is_err());
+    }
+}
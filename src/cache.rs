@@ -0,0 +1,171 @@
+/*
+ *  On-disk cache of compiled programs.
+ *  Entries are named after a hash of their key: the bf version, the names of the passes the program
+ *  is optimized with and the source. They hold the key itself before the bytecode, an entry whose key
+ *  isn't the one looked up is a miss, so a changed source, other passes or a new bf version can't be
+ *  given the bytecode of another program even when the hashes collide. Unreadable entries are
+ *  compiled again and overwritten, failing to write one is not an error.
+ */
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process,
+};
+
+use anyhow::{bail, Result};
+
+use crate::{bytecode, debug, optimizer::PassRegistry, parser::Program, vm};
+
+pub struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// `$BF_CACHE_DIR`, else `bf` in `$XDG_CACHE_HOME` or `~/.cache`.
+    pub fn default_dir() -> Option<PathBuf> {
+        if let Some(dir) = env::var_os("BF_CACHE_DIR") {
+            return Some(dir.into());
+        }
+
+        let cache = match env::var_os("XDG_CACHE_HOME") {
+            Some(dir) => PathBuf::from(dir),
+            None => Path::new(&env::var_os("HOME")?).join(".cache"),
+        };
+
+        Some(cache.join("bf"))
+    }
+
+    /// Same as `vm::compile_with_passes`, going through the cache.
+    pub fn compile(&self, src: &str, passes: &PassRegistry) -> Result<Program> {
+        let key = key(src, passes);
+        let path = self.dir.join(format!("{:016x}.bfc", hash(&key)));
+
+        if let Ok(program) = fs::read(&path)
+            .map_err(Into::into)
+            .and_then(|bytes| load(&bytes, &key))
+        {
+            debug!("cache hit {}", path.display());
+            return Ok(program);
+        }
+
+        debug!("cache miss {}", path.display());
+        let program = vm::compile_with_passes(src, passes)?;
+        let _ = self.store(&path, &key, &program);
+
+        Ok(program)
+    }
+
+    fn store(&self, path: &Path, key: &[u8], program: &Program) -> Result<()> {
+        fs::create_dir_all(&self.dir)?;
+
+        let mut bytes = (key.len() as u64).to_le_bytes().to_vec();
+        bytes.extend_from_slice(key);
+        bytes.extend_from_slice(&bytecode::encode(program));
+
+        // Renamed into place, another bf reading the entry never sees half of it.
+        let tmp = path.with_extension(format!("{}.tmp", process::id()));
+        fs::write(&tmp, bytes)?;
+        fs::rename(&tmp, path).inspect_err(|_| {
+            let _ = fs::remove_file(&tmp);
+        })?;
+
+        Ok(())
+    }
+}
+
+/// The program of an entry, if it is the one of `key`.
+fn load(bytes: &[u8], key: &[u8]) -> Result<Program> {
+    let len = (key.len() as u64).to_le_bytes();
+    let Some(program) = (bytes.strip_prefix(&len[..])).and_then(|rest| rest.strip_prefix(key))
+    else {
+        bail!("the entry is of another program");
+    };
+
+    bytecode::decode(program)
+}
+
+/// Everything the compiler output depends on: the bf version, the names of the passes and the
+/// source. Options changing the compiler output go in here too.
+fn key(src: &str, passes: &PassRegistry) -> Vec<u8> {
+    let mut key = concat!(env!("CARGO_PKG_VERSION"), "\0").as_bytes().to_vec();
+    for name in passes.pass_names() {
+        key.extend_from_slice(name.as_bytes());
+        key.push(0);
+    }
+    key.extend_from_slice(src.as_bytes());
+
+    key
+}
+
+/// 64 bit FNV-1a, only to name entries.
+fn hash(key: &[u8]) -> u64 {
+    key.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::{env, fs, process};
+
+    use crate::{
+        cache::{self, Cache},
+        optimizer::PassRegistry,
+        vm,
+    };
+
+    #[test]
+    fn reuses_and_repairs_entries() {
+        let dir = env::temp_dir().join(format!("bf-cache-test-{}", process::id()));
+        let cache = Cache::new(&dir);
+        let src = "+++[->++<]>.";
+        let passes = PassRegistry::default();
+
+        let program = cache.compile(src, &passes).unwrap();
+        assert_eq!(program, vm::compile(src).unwrap());
+
+        let entries: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(cache.compile(src, &passes).unwrap(), program);
+
+        // A broken entry is compiled again and replaced.
+        fs::write(&entries[0], b"BFC\0junk").unwrap();
+        assert_eq!(cache.compile(src, &passes).unwrap(), program);
+        assert_ne!(fs::read(&entries[0]).unwrap(), b"BFC\0junk");
+
+        // Another source gets its own entry, and so do other passes.
+        cache.compile("+.", &passes).unwrap();
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+        let mut fewer = PassRegistry::default();
+        fewer.remove("dead-loops");
+        let program = cache.compile("[.]+.", &fewer).unwrap();
+        assert_eq!(program.len(), 5);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+        assert_ne!(cache.compile("[.]+.", &passes).unwrap(), program);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 4);
+
+        // An entry of another source under the name of this one is not trusted.
+        let forged = fs::read(&entries[0]).unwrap();
+        let other = dir.join(format!(
+            "{:016x}.bfc",
+            cache::hash(&cache::key("+.", &passes))
+        ));
+        fs::write(&other, &forged).unwrap();
+        assert_eq!(
+            cache.compile("+.", &passes).unwrap(),
+            vm::compile("+.").unwrap()
+        );
+        assert_ne!(fs::read(&other).unwrap(), forged);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
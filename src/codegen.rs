@@ -1 +1,544 @@
-// JIT
+/*
+ *  Minimal x86-64 machine code emitter.
+ *  Compiles whole programs, or single I/O free loops, into native functions working directly on the VM
+ *  memory. I/O calls back into `native_io`. A failing check (going off the end of the tape, input
+ *  errors) leaves the native code and hands the pc of that instruction back to the interpreter, which
+ *  then runs it itself and reports the error, if any. Going off the left edge is left to the interpreter
+ *  as well, it is rare enough.
+ *
+ *  Registers: rdi = memory, rsi = memory length, rdx = where the pointer lives, rcx = pointer,
+ *  r8 = I/O context.
+ */
+
+#[cfg(not(all(target_arch = "x86_64", unix)))]
+compile_error!("native code generation is only supported on x86-64 unix");
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    io, ptr,
+};
+
+use std::io::{Read, Write};
+
+use anyhow::{bail, Result};
+
+use crate::{
+    native_io::{self, IoContext},
+    opcodes::OpCodeType,
+    parser::Program,
+};
+
+type NativeFn = unsafe extern "sysv64" fn(*mut u8, usize, *mut usize, *mut IoContext) -> usize;
+
+/// Native code of one loop, from its `JmpZero` to its `JmpNotZero`.
+pub struct NativeLoop {
+    code: ExecutableBuffer,
+}
+
+impl NativeLoop {
+    /// Runs the loop and returns the pc the interpreter has to continue at.
+    pub fn run(&self, mem: &mut [u8], mem_ptr: &mut usize) -> usize {
+        // SAFETY: loops are compiled without I/O, the context is never used.
+        unsafe { self.code.call(mem, mem_ptr, ptr::null_mut()) }
+    }
+}
+
+/// Native code of a whole program.
+pub struct NativeProgram {
+    code: ExecutableBuffer,
+}
+
+impl NativeProgram {
+    /// Runs the program from its start. Returns the pc the interpreter has to continue at, which is the
+    /// length of the program when it ran to the end.
+    pub fn run(
+        &self,
+        mem: &mut [u8],
+        mem_ptr: &mut usize,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+    ) -> usize {
+        let mut ctx = IoContext { input, output };
+
+        // SAFETY: the context lives for the whole call.
+        unsafe { self.code.call(mem, mem_ptr, &mut ctx) }
+    }
+}
+
+struct ExecutableBuffer {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl ExecutableBuffer {
+    fn new(code: &[u8]) -> Result<Self> {
+        let len = code.len();
+
+        // SAFETY: a fresh private mapping, written before it becomes executable and never after.
+        unsafe {
+            let ptr = libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+
+            if ptr == libc::MAP_FAILED {
+                bail!(
+                    "cannot map memory for native code: {}",
+                    io::Error::last_os_error()
+                );
+            }
+
+            ptr::copy_nonoverlapping(code.as_ptr(), ptr as *mut u8, len);
+
+            if libc::mprotect(ptr, len, libc::PROT_READ | libc::PROT_EXEC) != 0 {
+                let err = io::Error::last_os_error();
+                libc::munmap(ptr, len);
+                bail!("cannot make native code executable: {}", err);
+            }
+
+            Ok(Self {
+                ptr: ptr as *mut u8,
+                len,
+            })
+        }
+    }
+}
+
+impl ExecutableBuffer {
+    /// # Safety
+    /// `ctx` must be valid if the code does I/O.
+    unsafe fn call(&self, mem: &mut [u8], mem_ptr: &mut usize, ctx: *mut IoContext) -> usize {
+        assert!(*mem_ptr < mem.len());
+
+        // SAFETY:
+        //      the code only touches mem[ptr + offset] after checking it is less than mem.len(),
+        //      and the pointer itself never leaves 0..mem.len().
+        let entry: NativeFn = std::mem::transmute(self.ptr);
+        entry(mem.as_mut_ptr(), mem.len(), mem_ptr, ctx)
+    }
+}
+
+impl Drop for ExecutableBuffer {
+    fn drop(&mut self) {
+        // SAFETY: mapped in `new` with the same length.
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.len);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+enum Label {
+    /// Native code of the instruction at that pc.
+    Code(usize),
+    /// Stores the pointer and returns that pc to the interpreter.
+    Exit(usize),
+}
+
+#[derive(Default)]
+struct Assembler {
+    code: Vec<u8>,
+    labels: HashMap<Label, usize>,
+    fixups: Vec<(usize, Label)>,
+}
+
+impl Assembler {
+    fn emit(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    fn emit_i32(&mut self, value: i32) {
+        self.emit(&value.to_le_bytes());
+    }
+
+    /// `[rdi + rcx + offset]` as ModRM (with the given reg field), SIB and disp32.
+    fn emit_cell(&mut self, reg: u8, offset: i32) {
+        self.emit(&[0b10_000_100 | reg << 3, 0b00_001_111]);
+        self.emit_i32(offset);
+    }
+
+    fn emit_jump(&mut self, opcode: &[u8], target: Label) {
+        self.emit(opcode);
+        self.fixups.push((self.code.len(), target));
+        self.emit_i32(0);
+    }
+
+    /// Leaves through `Exit(pc)` unless `ptr + offset` is a valid cell.
+    fn emit_check(&mut self, offset: i32, pc: usize) {
+        if offset != 0 {
+            // lea rax, [rcx + offset]; cmp rax, rsi; jae exit
+            self.emit(&[0x48, 0x8d, 0x81]);
+            self.emit_i32(offset);
+            self.emit(&[0x48, 0x39, 0xf0]);
+            self.emit_jump(&[0x0f, 0x83], Label::Exit(pc));
+        }
+    }
+
+    fn emit_exit(&mut self, pc: usize) {
+        self.labels.insert(Label::Exit(pc), self.code.len());
+
+        // mov [rdx], rcx; mov rax, pc; ret
+        self.emit(&[0x48, 0x89, 0x0a, 0x48, 0xb8]);
+        self.emit(&(pc as u64).to_le_bytes());
+        self.emit(&[0xc3]);
+    }
+
+    /// Saves rdi, rsi, rdx, rcx and r8 before setting up arguments for `emit_call`.
+    /// Five pushes also keep the stack 16 byte aligned for the call.
+    fn emit_save(&mut self) {
+        // push rdi; push rsi; push rdx; push rcx; push r8
+        self.emit(&[0x57, 0x56, 0x52, 0x51, 0x41, 0x50]);
+    }
+
+    /// Calls `f` and restores what `emit_save` saved.
+    fn emit_call(&mut self, f: *const ()) {
+        // mov rax, f; call rax
+        self.emit(&[0x48, 0xb8]);
+        self.emit(&(f as u64).to_le_bytes());
+        self.emit(&[0xff, 0xd0]);
+
+        // pop r8; pop rcx; pop rdx; pop rsi; pop rdi
+        self.emit(&[0x41, 0x58, 0x59, 0x5a, 0x5e, 0x5f]);
+    }
+
+    /// Resolves jumps, `None` if one goes somewhere without code.
+    fn finish(mut self) -> Option<Vec<u8>> {
+        for (pos, target) in self.fixups {
+            let target = *self.labels.get(&target)?;
+            let rel = target as i64 - (pos as i64 + 4);
+
+            self.code[pos..pos + 4].copy_from_slice(&(rel as i32).to_le_bytes());
+        }
+
+        Some(self.code)
+    }
+}
+
+/// Compiles the loop starting at `start`. Returns `None` if it does I/O or cannot be compiled.
+pub fn compile_loop(program: &Program, start: usize) -> Option<NativeLoop> {
+    let end = match program.get(start) {
+        Some(op) if op.ty.is_loop_start() => op.data,
+        _ => return None,
+    };
+
+    let code = compile_range(program, start, end + 1, false)?;
+
+    Some(NativeLoop { code })
+}
+
+/// Compiles the whole program, `None` if it cannot be compiled.
+pub fn compile_program(program: &Program) -> Option<NativeProgram> {
+    let code = compile_range(program, 0, program.len(), true)?;
+
+    Some(NativeProgram { code })
+}
+
+fn compile_range(
+    program: &Program,
+    start: usize,
+    end: usize,
+    with_io: bool,
+) -> Option<ExecutableBuffer> {
+    let mut asm = Assembler::default();
+
+    // mov r8, rcx; mov rcx, [rdx]
+    asm.emit(&[0x49, 0x89, 0xc8, 0x48, 0x8b, 0x0a]);
+
+    for (pc, op) in program.get(start..end)?.iter().enumerate() {
+        let pc = start + pc;
+        let offset = op.offset;
+
+        asm.labels.insert(Label::Code(pc), asm.code.len());
+
+        match op.ty {
+            OpCodeType::Add | OpCodeType::Sub | OpCodeType::Set => {
+                asm.emit_check(offset, pc);
+
+                let (opcode, reg) = match op.ty {
+                    OpCodeType::Add => (0x80, 0),
+                    OpCodeType::Sub => (0x80, 5),
+                    _ => (0xc6, 0),
+                };
+
+                asm.emit(&[opcode]);
+                asm.emit_cell(reg, offset);
+                asm.emit(&[op.data as u8]);
+            }
+            OpCodeType::MulAdd => {
+                // movzx eax, byte [rdi + rcx]; test al, al; jz skip
+                asm.emit(&[0x0f, 0xb6]);
+                asm.emit_cell(0, 0);
+                asm.emit(&[0x84, 0xc0, 0x0f, 0x84]);
+                let skip = asm.code.len();
+                asm.emit_i32(0);
+
+                asm.emit_check(offset, pc);
+
+                // imul eax, eax, factor; add [rdi + rcx + offset], al
+                asm.emit(&[0x69, 0xc0]);
+                asm.emit_i32(op.data as i32);
+                asm.emit(&[0x00]);
+                asm.emit_cell(0, offset);
+
+                let rel = (asm.code.len() - (skip + 4)) as i32;
+                asm.code[skip..skip + 4].copy_from_slice(&rel.to_le_bytes());
+            }
+            OpCodeType::ShiftRight => {
+                let amount = i32::try_from(op.data).ok()?;
+
+                // lea rax, [rcx + amount]; cmp rax, rsi; jae exit; mov rcx, rax
+                asm.emit(&[0x48, 0x8d, 0x81]);
+                asm.emit_i32(amount);
+                asm.emit(&[0x48, 0x39, 0xf0]);
+                asm.emit_jump(&[0x0f, 0x83], Label::Exit(pc));
+                asm.emit(&[0x48, 0x89, 0xc1]);
+            }
+            OpCodeType::ShiftLeft => {
+                let amount = i32::try_from(op.data).ok()?;
+
+                // cmp rcx, amount; jb exit; sub rcx, amount
+                asm.emit(&[0x48, 0x81, 0xf9]);
+                asm.emit_i32(amount);
+                asm.emit_jump(&[0x0f, 0x82], Label::Exit(pc));
+                asm.emit(&[0x48, 0x81, 0xe9]);
+                asm.emit_i32(amount);
+            }
+            // The commands of dialects and the optional ones are left to the VM.
+            OpCodeType::ProcStart
+            | OpCodeType::ProcEnd
+            | OpCodeType::Call
+            | OpCodeType::End
+            | OpCodeType::Store
+            | OpCodeType::Retrieve
+            | OpCodeType::BitsLeft
+            | OpCodeType::BitsRight
+            | OpCodeType::Not
+            | OpCodeType::Xor
+            | OpCodeType::And
+            | OpCodeType::Or
+            | OpCodeType::Fork
+            | OpCodeType::PrevTape
+            | OpCodeType::NextTape
+            | OpCodeType::CopyToTape
+            | OpCodeType::Random
+            | OpCodeType::Dump
+            | OpCodeType::OpenFile
+            | OpCodeType::WriteFile
+            | OpCodeType::ReadFile => return None,
+            // Quickened loops still have their body, running them as plain loops is fine.
+            OpCodeType::JmpZero
+            | OpCodeType::ClearLoop
+            | OpCodeType::MulLoop
+            | OpCodeType::JmpNotZero => {
+                asm.emit_check(offset, pc);
+
+                // cmp byte [rdi + rcx + offset], 0; je/jne target
+                asm.emit(&[0x80]);
+                asm.emit_cell(7, offset);
+                asm.emit(&[0x00]);
+
+                let target = if op.data + 1 >= end {
+                    Label::Exit(op.data + 1)
+                } else {
+                    Label::Code(op.data + 1)
+                };
+
+                match op.ty {
+                    OpCodeType::JmpNotZero => asm.emit_jump(&[0x0f, 0x85], target),
+                    _ => asm.emit_jump(&[0x0f, 0x84], target),
+                }
+            }
+            OpCodeType::FillRange | OpCodeType::ClearRange => {
+                let (len, value) = match op.ty {
+                    OpCodeType::FillRange => (op.data >> 8, op.data as u8),
+                    _ => (op.data, 0),
+                };
+
+                if len == 0 {
+                    continue;
+                }
+
+                let len = u32::try_from(len).ok()?;
+                let last = i32::try_from(offset as i64 + len as i64 - 1).ok()?;
+
+                asm.emit_check(offset, pc);
+                asm.emit_check(last, pc);
+
+                // push rdi; push rcx; lea rdi, [rdi + rcx + offset]; mov ecx, len; mov al, value;
+                // rep stosb; pop rcx; pop rdi
+                asm.emit(&[0x57, 0x51, 0x48, 0x8d]);
+                asm.emit_cell(7, offset);
+                asm.emit(&[0xb9]);
+                asm.emit(&len.to_le_bytes());
+                asm.emit(&[0xb0, value, 0xf3, 0xaa, 0x59, 0x5f]);
+            }
+            OpCodeType::PrintChar if with_io => {
+                asm.emit_check(offset, pc);
+
+                // movzx eax, byte [rdi + rcx + offset]
+                asm.emit(&[0x0f, 0xb6]);
+                asm.emit_cell(0, offset);
+
+                asm.emit_save();
+
+                // output(ctx, ch, count): mov rdi, r8; mov esi, eax; mov rdx, count
+                asm.emit(&[0x4c, 0x89, 0xc7, 0x89, 0xc6, 0x48, 0xba]);
+                asm.emit(&(op.data as u64).to_le_bytes());
+                asm.emit_call(native_io::output as *const ());
+            }
+            OpCodeType::InputChar if with_io => {
+                asm.emit_check(offset, pc);
+
+                asm.emit_save();
+
+                // input(ctx, &cell): lea rsi, [rdi + rcx + offset]; mov rdi, r8
+                asm.emit(&[0x48, 0x8d]);
+                asm.emit_cell(6, offset);
+                asm.emit(&[0x4c, 0x89, 0xc7]);
+                asm.emit_call(native_io::input as *const ());
+
+                // test rax, rax; jz exit
+                asm.emit(&[0x48, 0x85, 0xc0]);
+                asm.emit_jump(&[0x0f, 0x84], Label::Exit(pc));
+            }
+            OpCodeType::PrintChar | OpCodeType::InputChar => return None,
+        }
+    }
+
+    // Falling out of the last instruction is leaving the native code.
+    asm.emit_jump(&[0xe9], Label::Exit(end));
+
+    let exits = asm
+        .fixups
+        .iter()
+        .filter_map(|&(_, label)| match label {
+            Label::Exit(pc) => Some(pc),
+            Label::Code(_) => None,
+        })
+        .collect::<BTreeSet<_>>();
+
+    for pc in exits {
+        asm.emit_exit(pc);
+    }
+
+    ExecutableBuffer::new(&asm.finish()?).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        codegen::{compile_loop, compile_program},
+        lexer, parser, vm,
+    };
+
+    #[test]
+    fn runs_multiplication_loop() {
+        let program = vm::compile("+[->+++<]").unwrap();
+        let native = compile_loop(&program, 1).unwrap();
+
+        let mut mem = vec![7, 1, 0];
+        let mut mem_ptr = 0;
+
+        assert_eq!(native.run(&mut mem, &mut mem_ptr), program.len());
+        assert_eq!(mem, [0, 22, 0]);
+        assert_eq!(mem_ptr, 0);
+    }
+
+    #[test]
+    fn runs_nested_and_moving_loops() {
+        let program = parser::parse(lexer::parse("[>[>]+<[<]>-]")).unwrap();
+        let native = compile_loop(&program, 0).unwrap();
+
+        let mut mem = vec![0, 2, 0, 0, 0, 0];
+        let mut mem_ptr = 1;
+
+        assert_eq!(native.run(&mut mem, &mut mem_ptr), program.len());
+        assert_eq!(mem, [0, 0, 1, 1, 0, 0]);
+        assert_eq!(mem_ptr, 1);
+    }
+
+    #[test]
+    fn runs_fill_ranges() {
+        let program = vm::compile("+[>[-]>[-]>[-]>[-]<<<<-]").unwrap();
+        let native = compile_loop(&program, 1).unwrap();
+
+        let mut mem = vec![1, 9, 9, 9, 9, 9];
+        let mut mem_ptr = 0;
+
+        assert_eq!(native.run(&mut mem, &mut mem_ptr), program.len());
+        assert_eq!(mem, [0, 0, 0, 0, 0, 9]);
+
+        // The last cell of the range is past the end of memory.
+        let mut mem = vec![1, 9, 9, 9];
+        assert_eq!(native.run(&mut mem, &mut mem_ptr), 2);
+        assert_eq!(mem, [1, 9, 9, 9]);
+    }
+
+    #[test]
+    fn runs_whole_programs() {
+        let program = vm::compile("++++++++[>++++++++<-]>+.,[->+<]>..<<+").unwrap();
+        let native = compile_program(&program).unwrap();
+
+        let mut mem = vec![0; 4];
+        let mut mem_ptr = 0;
+        let mut output = vec![];
+
+        let pc = native.run(&mut mem, &mut mem_ptr, &mut &b"\x02"[..], &mut output);
+
+        assert_eq!(pc, program.len());
+        assert_eq!(output, b"A\x02\x02");
+        assert_eq!(mem, [1, 0, 2, 0]);
+        assert_eq!(mem_ptr, 0);
+
+        // Out of input, and off the left edge.
+        let program = parser::parse(lexer::parse("+.,<")).unwrap();
+        let native = compile_program(&program).unwrap();
+        let mut output = vec![];
+
+        assert_eq!(
+            native.run(&mut mem, &mut mem_ptr, &mut &b""[..], &mut output),
+            2
+        );
+        assert_eq!(output, [2]);
+
+        let mut input = &b"x"[..];
+        assert_eq!(
+            native.run(&mut mem, &mut mem_ptr, &mut input, &mut output),
+            3
+        );
+        assert_eq!(mem[0], b'x');
+    }
+
+    #[test]
+    fn leaves_at_the_edge_of_memory() {
+        let program = parser::parse(lexer::parse("[>]")).unwrap();
+        let native = compile_loop(&program, 0).unwrap();
+
+        let mut mem = vec![1, 1, 1];
+        let mut mem_ptr = 0;
+
+        // Stops in front of the `>` which would go past the last cell.
+        assert_eq!(native.run(&mut mem, &mut mem_ptr), 1);
+        assert_eq!(mem_ptr, 2);
+
+        // Same for `<` at the first cell, the interpreter decides what that means.
+        let program = parser::parse(lexer::parse("[<]")).unwrap();
+        let native = compile_loop(&program, 0).unwrap();
+
+        assert_eq!(native.run(&mut mem, &mut mem_ptr), 1);
+        assert_eq!(mem_ptr, 0);
+    }
+
+    #[test]
+    fn only_io_free_loops_are_compiled() {
+        let program = parser::parse(lexer::parse("[.-]+[,]")).unwrap();
+
+        assert!(compile_loop(&program, 0).is_none());
+        assert!(compile_loop(&program, 4).is_none());
+        assert!(compile_loop(&program, 5).is_none());
+    }
+}
@@ -0,0 +1,111 @@
+/*
+ *  Compatibility profiles, for `bf run --compat`: the tape and the end of input of well-known
+ *  interpreters, set in one go.
+ *
+ *      classic     30000 cells, `,` leaves the cell as it is at the end of input, like the first
+ *                  interpreter, going past the last cell fails
+ *      portable    what portable programs can count on: a tape as long as they need, growing from
+ *                  30000 cells, and the cell left as it is at the end of input
+ *      busybeaver  a tape growing from 64K cells, for programs going far along it, and 0 at the end
+ *                  of input
+ *      eof0        bf's own tape, `,` reads 0 at the end of input
+ *      eofneg1     the same reading 255, -1 as a signed byte
+ *
+ *  Cells are 8 bits, wrapping, in all of them: the only cells bf has. A pointer moving left of the
+ *  first cell stays on it.
+ */
+
+use anyhow::Result;
+
+use crate::vm::{Eof, TapeEdge, Vm, DEFAULT_VM_MEM_SIZE};
+
+/// How the tape and the input of a run behave.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Settings {
+    pub tape_size: usize,
+    pub eof: Eof,
+    pub tape_edge: TapeEdge,
+}
+
+/// bf's own: the end of input and going past the last cell fail the run.
+pub const DEFAULT: Settings = Settings {
+    tape_size: DEFAULT_VM_MEM_SIZE,
+    eof: Eof::Error,
+    tape_edge: TapeEdge::Error,
+};
+
+pub const CLASSIC: Settings = Settings {
+    eof: Eof::Unchanged,
+    ..DEFAULT
+};
+
+pub const PORTABLE: Settings = Settings {
+    eof: Eof::Unchanged,
+    tape_edge: TapeEdge::Grow,
+    ..DEFAULT
+};
+
+pub const BUSY_BEAVER: Settings = Settings {
+    tape_size: 1 << 16,
+    eof: Eof::Zero,
+    tape_edge: TapeEdge::Grow,
+};
+
+pub const EOF_ZERO: Settings = Settings {
+    eof: Eof::Zero,
+    ..DEFAULT
+};
+
+pub const EOF_NEG_ONE: Settings = Settings {
+    eof: Eof::NegOne,
+    ..DEFAULT
+};
+
+impl Settings {
+    pub fn apply(&self, vm: &mut Vm) -> Result<()> {
+        vm.set_tape_size(self.tape_size)?;
+        vm.set_eof(self.eof);
+        vm.set_tape_edge(self.tape_edge);
+
+        Ok(())
+    }
+
+    /// Whether only the VM runs programs this way: the other backends fail at the end of input and
+    /// past the last cell.
+    pub fn needs_vm(&self) -> bool {
+        self.eof != Eof::Error || self.tape_edge != TapeEdge::Error
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        compat, lexer,
+        vm::{self, Vm},
+    };
+
+    fn run(settings: compat::Settings, src: &str, input: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut vm = Vm::from_program(vm::compile_tokens_with_map(lexer::parse(src))?.0)?;
+        settings.apply(&mut vm)?;
+        let mut output = vec![];
+        vm.run_with(&mut &input[..], &mut output)?;
+
+        Ok(output)
+    }
+
+    #[test]
+    fn sets_the_end_of_input_and_the_tape() {
+        let read_twice = "+,.,.";
+        assert_eq!(run(compat::CLASSIC, read_twice, b"a").unwrap(), b"aa");
+        assert_eq!(run(compat::EOF_ZERO, read_twice, b"a").unwrap(), b"a\0");
+        assert_eq!(
+            run(compat::EOF_NEG_ONE, read_twice, b"a").unwrap(),
+            b"a\xff"
+        );
+        assert!(run(compat::DEFAULT, read_twice, b"a").is_err());
+
+        let far = ">".repeat(40_000) + "+.";
+        assert!(run(compat::CLASSIC, &far, b"").is_err());
+        assert_eq!(run(compat::PORTABLE, &far, b"").unwrap(), [1]);
+    }
+}
@@ -0,0 +1,155 @@
+/*
+ *  Defaults of `bf run` from outside the command line, each going over the ones before it:
+ *
+ *  - the user config, `$BF_CONFIG` or else `bf/config.toml` in `$XDG_CONFIG_HOME` or `~/.config`, a
+ *    `[run]` table of flags like the one of a project,
+ *  - the `[run]` table of the project's `bf.toml`, see `project`,
+ *  - environment variables, `BF_TAPE_SIZE=1M` for `--tape-size=1M`, for the flags of `ENV_FLAGS`,
+ *  - the directives at the top of the program, see `pragma`.
+ *
+ *  Flags given on the command line go over all of them. Cells are 8 bits, a cell size of anything
+ *  else is an error rather than ignored.
+ */
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::project::{self, Project};
+
+/// Flags of `bf run` set by the environment variable of the same name, `BF_` and in capitals.
+pub const ENV_FLAGS: [&str; 8] = [
+    "tape-size",
+    "cell-size",
+    "eof",
+    "tape-edge",
+    "compat",
+    "dialect",
+    "backend",
+    "engine",
+];
+
+/// A flag of `bf run` set outside the command line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Setting {
+    /// Where it is set, for errors: a file or an environment variable.
+    pub from: String,
+    pub flag: String,
+    /// None for switches.
+    pub value: Option<String>,
+}
+
+/// The settings of the user config, the `[run]` table of `project`, the environment and the
+/// `directives` of the program, in order.
+pub fn settings(project: Option<&Project>, directives: Vec<Setting>) -> Result<Vec<Setting>> {
+    let mut settings = user_config()?;
+    if let Some(project) = project {
+        settings.extend(from_flags(project::FILE_NAME, &project.flags));
+    }
+    settings.extend(from_env(|name| env::var(name).ok()));
+    settings.extend(directives);
+
+    // There is no flag for the cell size, it can only be 8 bits.
+    let cell_size = |setting: &Setting| setting.flag == "cell-size";
+    if let Some(setting) = (settings.iter())
+        .find(|setting| cell_size(setting) && setting.value.as_deref() != Some("8"))
+    {
+        bail!(
+            "{}: cells are 8 bits, not {}",
+            setting.from,
+            setting.value.as_deref().unwrap_or("nothing")
+        );
+    }
+    settings.retain(|setting| !cell_size(setting));
+
+    Ok(settings)
+}
+
+/// Where the user config is, None without a home directory.
+pub fn user_config_path() -> Option<PathBuf> {
+    if let Some(path) = env::var_os("BF_CONFIG") {
+        return Some(path.into());
+    }
+
+    let config = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => Path::new(&env::var_os("HOME")?).join(".config"),
+    };
+    Some(config.join("bf").join("config.toml"))
+}
+
+/// The settings of the user config, none if there is no such file.
+fn user_config() -> Result<Vec<Setting>> {
+    let Some(path) = user_config_path().filter(|path| path.is_file()) else {
+        return Ok(vec![]);
+    };
+
+    let text = fs::read_to_string(&path)?;
+    let config = project::parse(&text, Path::new(""))
+        .map_err(|err| anyhow!("{}: {}", path.display(), err))?;
+    if !config.programs.is_empty() || config.tests.is_some() {
+        bail!("{}: only a [run] table can be in it", path.display());
+    }
+
+    Ok(from_flags(&path.display().to_string(), &config.flags))
+}
+
+fn from_flags(from: &str, flags: &[(String, Option<String>)]) -> Vec<Setting> {
+    flags
+        .iter()
+        .map(|(flag, value)| Setting {
+            from: from.to_string(),
+            flag: flag.clone(),
+            value: value.clone(),
+        })
+        .collect()
+}
+
+/// The settings of the environment variables of `ENV_FLAGS`, read with `var`.
+pub fn from_env(var: impl Fn(&str) -> Option<String>) -> Vec<Setting> {
+    ENV_FLAGS
+        .iter()
+        .filter_map(|flag| {
+            let name = format!("BF_{}", flag.replace('-', "_").to_uppercase());
+            let value = var(&name)?;
+            Some(Setting {
+                from: name,
+                flag: flag.to_string(),
+                value: Some(value),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::{self, Setting};
+
+    #[test]
+    fn reads_the_environment() {
+        let settings = config::from_env(|name| match name {
+            "BF_TAPE_SIZE" => Some("1M".to_string()),
+            "BF_EOF" => Some("zero".to_string()),
+            _ => None,
+        });
+
+        assert_eq!(
+            settings,
+            [
+                Setting {
+                    from: "BF_TAPE_SIZE".to_string(),
+                    flag: "tape-size".to_string(),
+                    value: Some("1M".to_string()),
+                },
+                Setting {
+                    from: "BF_EOF".to_string(),
+                    flag: "eof".to_string(),
+                    value: Some("zero".to_string()),
+                },
+            ]
+        );
+    }
+}
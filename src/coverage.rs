@@ -0,0 +1,202 @@
+/*
+ *  Source coverage, for `--coverage`: which commands of the program ran. The program runs unoptimized,
+ *  so every instruction is a command or a run of one in the source.
+ *
+ *  The annotated source has a count per line, like gcov: how many times its busiest instruction ran,
+ *  `#####` when none of them did and `-` for lines without commands. Under a line that only partly ran
+ *  the commands that never did are marked with `^`.
+ */
+
+use std::{collections::BTreeMap, fmt::Write};
+
+use anyhow::Result;
+
+use crate::{
+    lexer::{self, TokenLoc},
+    parser::Span,
+    source::Source,
+    vm::{Observer, Vm},
+};
+
+#[derive(Default)]
+pub struct Coverage {
+    hits: Vec<u64>,
+}
+
+#[derive(Default)]
+struct LineCoverage {
+    /// Runs of the busiest instruction starting on the line.
+    max: u64,
+    /// Columns of the instructions that never ran, from and to, 1-based and inclusive.
+    dead: Vec<(usize, usize)>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Instructions that ran, out of all of them.
+    pub fn covered(&self, spans: &[Span]) -> (usize, usize) {
+        let ran = (0..spans.len()).filter(|&pc| self.hits(pc) > 0).count();
+        (ran, spans.len())
+    }
+
+    /// `src` with the count of every line in front, see the top of the file.
+    pub fn annotate(&self, src: &str, spans: &[Span]) -> String {
+        let lines = self.lines(spans);
+        let mut out = String::new();
+
+        for (i, text) in src.lines().enumerate() {
+            let Some(line) = lines.get(&(i + 1)) else {
+                writeln!(out, "{:>9}:  {}", "-", text).unwrap();
+                continue;
+            };
+
+            match line.max {
+                0 => writeln!(out, "{:>9}:  {}", "#####", text).unwrap(),
+                max => writeln!(out, "{:>9}:  {}", max, text).unwrap(),
+            }
+
+            if line.max > 0 && !line.dead.is_empty() {
+                let cols = lexer::columns(text);
+                let marks: String = text
+                    .char_indices()
+                    .map(|(i, ch)| {
+                        let col = cols[i];
+                        match ch {
+                            _ if line
+                                .dead
+                                .iter()
+                                .any(|&(from, to)| (from..=to).contains(&col)) =>
+                            {
+                                '^'
+                            }
+                            '\t' => '\t',
+                            _ => ' ',
+                        }
+                    })
+                    .collect();
+                writeln!(out, "{:>9}   {}", "", marks.trim_end()).unwrap();
+            }
+        }
+
+        let (ran, total) = self.covered(spans);
+        writeln!(
+            out,
+            "\n{} of {} instructions ran ({:.1}%)",
+            ran,
+            total,
+            100.0 * ran as f64 / total.max(1) as f64
+        )
+        .unwrap();
+
+        out
+    }
+
+    /// The coverage in the lcov tracefile format, a record per file of `source`.
+    pub fn lcov(&self, source: &Source, spans: &[Span]) -> String {
+        let mut files: Vec<(String, Vec<(usize, u64)>)> = vec![];
+
+        for (line, coverage) in self.lines(spans) {
+            let loc = TokenLoc::from_col_line(1, line);
+            let (name, line) = match source.locate(loc) {
+                Some((name, loc)) => (name.to_string(), loc.line()),
+                None => ("-".to_string(), line),
+            };
+
+            match files.last_mut() {
+                Some((last, lines)) if *last == name => lines.push((line, coverage.max)),
+                _ => files.push((name, vec![(line, coverage.max)])),
+            }
+        }
+
+        let mut out = String::new();
+        for (name, lines) in files {
+            writeln!(out, "TN:\nSF:{}", name).unwrap();
+            for &(line, count) in &lines {
+                writeln!(out, "DA:{},{}", line, count).unwrap();
+            }
+            let hit = lines.iter().filter(|&&(_, count)| count > 0).count();
+            writeln!(out, "LF:{}\nLH:{}\nend_of_record", lines.len(), hit).unwrap();
+        }
+
+        out
+    }
+
+    fn hits(&self, pc: usize) -> u64 {
+        self.hits.get(pc).copied().unwrap_or(0)
+    }
+
+    /// The lines with instructions on them.
+    fn lines(&self, spans: &[Span]) -> BTreeMap<usize, LineCoverage> {
+        let mut lines: BTreeMap<usize, LineCoverage> = BTreeMap::new();
+
+        for (pc, &(start, end)) in spans.iter().enumerate() {
+            let line = lines.entry(start.line()).or_default();
+            let hits = self.hits(pc);
+
+            line.max = line.max.max(hits);
+            if hits == 0 {
+                // A run going on to the next lines is marked on its first one only.
+                let to = if end.line() == start.line() {
+                    end.col()
+                } else {
+                    usize::MAX
+                };
+                line.dead.push((start.col(), to));
+            }
+        }
+
+        lines
+    }
+}
+
+impl Observer for Coverage {
+    fn instruction(&mut self, vm: &Vm) -> Result<()> {
+        if self.hits.is_empty() {
+            self.hits = vec![0; vm.program().len()];
+        }
+        self.hits[vm.pc()] += 1;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{coverage::Coverage, lexer, parser, source::Source, vm::Vm};
+
+    #[test]
+    fn annotates_source() {
+        let src = "+[-]\ncomment\n[>+<-] +\n";
+        let tokens = lexer::parse(src);
+        let spans = parser::spans(&tokens);
+
+        let mut coverage = Coverage::new();
+        Vm::from_program(parser::parse(tokens).unwrap())
+            .unwrap()
+            .run_observed(&mut &b""[..], &mut vec![], &mut coverage)
+            .unwrap();
+
+        let expected = [
+            "        1:  +[-]",
+            "        -:  comment",
+            "        1:  [>+<-] +",
+            "             ^^^^^",
+            "",
+            "6 of 11 instructions ran (54.5%)",
+        ];
+        assert_eq!(
+            coverage.annotate(src, &spans).lines().collect::<Vec<_>>(),
+            expected
+        );
+
+        let mut source = Source::default();
+        source.push("a.bf", src);
+        assert_eq!(
+            coverage.lcov(&source, &spans),
+            "TN:\nSF:a.bf\nDA:1,1\nDA:3,1\nLF:2\nLH:2\nend_of_record\n"
+        );
+    }
+}
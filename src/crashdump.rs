@@ -0,0 +1,330 @@
+/*
+ *  Crash dumps, for `--crash-dump DIR`: when a run fails, a text file with what it takes to look into
+ *  the failure later, for batch runs that had no debugger attached.
+ *
+ *  The run itself goes at full speed and only keeps the input it read. When it fails, the program is
+ *  run again unoptimized on the VM with that input, and the dump is made from where that run stops:
+ *  the error, the pc and the pointer, the last instructions run, the output after the last newline
+ *  (which standard output holds back until the line ends), the tape, the program and its source.
+ *
+ *  `bf debug --core` reads a dump back, the program is compiled again from the source in it. Being
+ *  unoptimized, every instruction is a command of the source like in the debugger.
+ */
+
+use std::{
+    collections::VecDeque,
+    fmt::Write as _,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process,
+    str::Lines,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::{
+    asm, hexdump, lexer,
+    parser::{self, Program},
+    source::Source,
+    srcmap::SourceMap,
+    trace,
+    vm::{self, Observer, Snapshot, Vm},
+};
+
+/// Instructions kept for the dump, the last ones run.
+const RECENT: usize = 32;
+/// Bytes of an unfinished line kept, the last ones.
+const PENDING: usize = 4096;
+/// The first line of a dump.
+const HEADER: &str = "bf crash dump";
+
+pub struct CrashDump {
+    error: String,
+    src: String,
+    program: Program,
+    pc: usize,
+    ptr: usize,
+    tape: Vec<u8>,
+    /// Trace records of the last instructions, oldest first.
+    recent: Vec<String>,
+    pending: Vec<u8>,
+}
+
+impl CrashDump {
+    /// Runs `source` again with `input`, what the failed run read, and keeps the state it fails in.
+    /// `err` is the error of the failed run, kept if the VM gets through.
+    pub fn capture(
+        source: &Source,
+        tape_size: usize,
+        input: &[u8],
+        pending: &[u8],
+        err: &anyhow::Error,
+    ) -> Result<Self> {
+        let tokens = lexer::parse(&source.text);
+        let map = SourceMap::from_spans(&parser::spans(&tokens));
+        let program = parser::parse(tokens)?;
+        let mut vm = Vm::from_program(program.clone())?;
+        vm.set_tape_size(tape_size)?;
+
+        let mut recent = Recent::default();
+        let result = vm.run_observed(&mut &input[..], &mut io::sink(), &mut recent);
+        let error = match result {
+            Err(err) => source
+                .locate_error(vm::locate_error(err, &program, &map, vm.pc()))
+                .to_string(),
+            Ok(()) => format!("{} (the VM ran the program again to its end)", err),
+        };
+
+        Ok(Self {
+            error,
+            src: source.text.clone(),
+            recent: recent
+                .records
+                .iter()
+                .map(|&(pc, ptr, cell)| trace::text_line(&program, pc, ptr, cell))
+                .collect(),
+            program,
+            pc: vm.pc(),
+            ptr: vm.mem_ptr(),
+            tape: vm.mem().to_vec(),
+            pending: pending.to_vec(),
+        })
+    }
+
+    pub fn text(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "{}", HEADER).unwrap();
+        writeln!(out, "error: {}", self.error).unwrap();
+        match self.program.get(self.pc) {
+            Some(op) => writeln!(out, "pc: {}  {}", self.pc, asm::instruction(op)).unwrap(),
+            None => writeln!(out, "pc: {}, past the end", self.pc).unwrap(),
+        }
+        writeln!(out, "pointer: {}", self.ptr).unwrap();
+
+        writeln!(out, "\nlast instructions run, oldest first:").unwrap();
+        for record in &self.recent {
+            writeln!(out, "{}", record).unwrap();
+        }
+
+        writeln!(out, "\npending output, {} bytes:", self.pending.len()).unwrap();
+        write!(out, "{}", hexdump::hexdump(&self.pending, 0)).unwrap();
+
+        // The cells up to the last one used, or to the pointer if that is further.
+        let used = self
+            .tape
+            .iter()
+            .rposition(|&cell| cell != 0)
+            .map_or(0, |i| i + 1)
+            .max(self.ptr + 1)
+            .min(self.tape.len());
+        writeln!(out, "\ntape, {} of {} cells:", used, self.tape.len()).unwrap();
+        write!(out, "{}", hexdump::hexdump(&self.tape[..used], 0)).unwrap();
+
+        writeln!(out, "\nprogram:").unwrap();
+        write!(out, "{}", asm::listing(&self.program)).unwrap();
+
+        // Last, so it is the rest of the file as it is.
+        write!(out, "\nsource:\n{}", self.src).unwrap();
+
+        out
+    }
+
+    /// Reads back a dump `text` wrote.
+    pub fn parse(text: &str) -> Result<Self> {
+        let (head, src) = text
+            .strip_prefix(HEADER)
+            .and_then(|text| text.split_once("\nsource:\n"))
+            .ok_or_else(|| anyhow!("not a crash dump"))?;
+
+        let mut lines = head.lines();
+        let (mut error, mut pc, mut ptr) = (None, None, None);
+        let (mut tape, mut pending, mut recent) = (None, vec![], vec![]);
+
+        while let Some(line) = lines.next() {
+            if let Some(text) = line.strip_prefix("error: ") {
+                error = Some(text.to_string());
+            } else if let Some(text) = line.strip_prefix("pc: ") {
+                pc = text.split([' ', ',']).next().and_then(|pc| pc.parse().ok());
+            } else if let Some(text) = line.strip_prefix("pointer: ") {
+                ptr = text.parse().ok();
+            } else if line.starts_with("last instructions run") {
+                recent = section(&mut lines).into_iter().map(Into::into).collect();
+            } else if line.starts_with("pending output") {
+                pending = hexdump::parse(&section(&mut lines).join("\n"))?;
+            } else if let Some(text) = line.strip_prefix("tape, ") {
+                // `tape, USED of CELLS cells:`
+                let cells: usize = text
+                    .split(' ')
+                    .nth(2)
+                    .and_then(|cells| cells.parse().ok())
+                    .ok_or_else(|| anyhow!("bad tape line `{}`", line))?;
+                let mut used = hexdump::parse(&section(&mut lines).join("\n"))?;
+                if used.len() > cells {
+                    bail!("the tape has more than {} cells", cells);
+                }
+                used.resize(cells, 0);
+                tape = Some(used);
+            }
+        }
+
+        let missing = |what: &str| anyhow!("the crash dump has no {}", what);
+        let program = parser::parse(lexer::parse(src))?;
+        let pc = pc.ok_or_else(|| missing("pc"))?;
+        if pc > program.len() {
+            bail!("pc {} is past the end of the program", pc);
+        }
+
+        Ok(Self {
+            error: error.ok_or_else(|| missing("error"))?,
+            src: src.to_string(),
+            program,
+            pc,
+            ptr: ptr.ok_or_else(|| missing("pointer"))?,
+            tape: tape.ok_or_else(|| missing("tape"))?,
+            recent,
+            pending,
+        })
+    }
+
+    pub fn error(&self) -> &str {
+        &self.error
+    }
+
+    pub fn src(&self) -> &str {
+        &self.src
+    }
+
+    /// The state of the machine when the program failed, for the program compiled from `src`.
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        Snapshot::new(self.pc, self.tape.clone(), self.ptr)
+    }
+
+    /// Writes the dump to a new file in `dir`, made if needed, and returns its path.
+    pub fn write(&self, dir: &Path) -> Result<PathBuf> {
+        fs::create_dir_all(dir)?;
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs());
+        let path = dir.join(format!("bf-crash-{}-{}.bfdump", time, process::id()));
+        fs::write(&path, self.text())?;
+
+        Ok(path)
+    }
+}
+
+/// The lines up to the next empty one, which ends a part of a dump.
+fn section<'a>(lines: &mut Lines<'a>) -> Vec<&'a str> {
+    lines.take_while(|line| !line.is_empty()).collect()
+}
+
+/// Keeps the pc, the pointer and the cell of the last `RECENT` instructions.
+#[derive(Default)]
+struct Recent {
+    records: VecDeque<(usize, usize, u8)>,
+}
+
+impl Observer for Recent {
+    fn instruction(&mut self, vm: &Vm) -> Result<()> {
+        if self.records.len() == RECENT {
+            self.records.pop_front();
+        }
+        let ptr = vm.mem_ptr();
+        self.records.push_back((vm.pc(), ptr, vm.mem()[ptr]));
+
+        Ok(())
+    }
+}
+
+/// Writes through to `inner` and keeps what was written after the last newline.
+pub struct PendingOutput<W: Write> {
+    inner: W,
+    line: Vec<u8>,
+}
+
+impl<W: Write> PendingOutput<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            line: vec![],
+        }
+    }
+
+    pub fn pending(&self) -> &[u8] {
+        &self.line
+    }
+}
+
+impl<W: Write> Write for PendingOutput<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        let buf = &buf[..written];
+
+        match buf.iter().rposition(|&ch| ch == b'\n') {
+            Some(i) => {
+                self.line.clear();
+                self.line.extend_from_slice(&buf[i + 1..]);
+            }
+            None => self.line.extend_from_slice(buf),
+        }
+        if self.line.len() > PENDING {
+            self.line.drain(..self.line.len() - PENDING);
+        }
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use anyhow::anyhow;
+
+    use crate::{
+        crashdump::{CrashDump, PendingOutput},
+        source::Source,
+    };
+
+    #[test]
+    fn dumps_the_state_of_a_failed_run() {
+        let mut output = PendingOutput::new(vec![]);
+        output.write_all(b"ok\nhalf").unwrap();
+        assert_eq!(output.pending(), b"half");
+
+        let mut source = Source::default();
+        source.push("a.bf", ",>+\n[<.>>+]");
+        let dump =
+            CrashDump::capture(&source, 4, b"x", output.pending(), &anyhow!("failed")).unwrap();
+        let text = dump.text();
+        let lines: Vec<_> = text.lines().collect();
+
+        assert_eq!(lines[0], "bf crash dump");
+        assert!(lines[1].starts_with("error: memory overflowed"));
+        assert!(lines[1].ends_with("at a.bf:2:4, in the loop at a.bf:2:1"));
+        assert_eq!(lines[2..4], ["pc: 6  SHR 2", "pointer: 2"]);
+        assert_eq!(
+            lines[8..10],
+            [
+                "000002  ADD 1              ptr 1      cell 0",
+                "000003  JZ 8               ptr 1      cell 1"
+            ]
+        );
+        assert!(text.contains("pending output, 4 bytes:\n00000000  68 61 6c 66"));
+        assert!(text.contains("tape, 4 of 4 cells:\n00000000  78 01 01 01"));
+        assert!(text.contains("program:\n0000  IN 1\n"));
+        assert!(text.ends_with("\nsource:\n,>+\n[<.>>+]"));
+
+        let read = CrashDump::parse(&text).unwrap();
+        assert_eq!(read.text(), text);
+        assert_eq!(read.snapshot().unwrap(), dump.snapshot().unwrap());
+        assert!(CrashDump::parse("error: x\n").is_err());
+        let off_the_tape = text.replace("pointer: 2", "pointer: 4");
+        assert!(CrashDump::parse(&off_the_tape).unwrap().snapshot().is_err());
+    }
+}
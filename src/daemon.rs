@@ -0,0 +1,733 @@
+/*
+ *  Daemon keeping programs compiled, for `bf daemon` and `bf client`: running a large program again
+ *  skips reading, parsing and optimizing it, and the machine of a run before is cleared for it rather
+ *  than allocated again. The daemon listens on a Unix socket, one request on each connection: a line
+ *  of JSON followed by the input,
+ *
+ *      {"command": "run", "program": ",.,.", "tape_size": 30000, "eof": "error", "input_bytes": 2}
+ *      hi
+ *
+ *  answered with a line of JSON followed by the output,
+ *
+ *      {"ok": true, "error": null, "kind": null, "output_bytes": 2}
+ *      hi
+ *
+ *  the kind of an error being `syntax`, `memory`, `io` or `other`, for the exit status of the client.
+ *  The input is all read before the program runs and the output sent once it ends, programs talking
+ *  with the user as they run are for `bf run`. `{"command": "stop"}` stops the daemon. Requests longer
+ *  than the daemon takes, asking for a larger tape or with programs nesting loops deeper, are refused.
+ *  Every run is held to the limits of the daemon, like the ones of `bf serve`: one that stops at a
+ *  limit fails with the kind `other`.
+ *
+ *  Programs are kept by their source, the one run least recently leaving first when there are too
+ *  many. Each thread handling requests keeps the machines it ran them on, cleared for the next run.
+ */
+
+use std::{
+    collections::HashMap,
+    env, fmt, fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    ptr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::{
+    batch::{self, Limits},
+    cache::Cache,
+    json::Json,
+    optimizer::PassRegistry,
+    parser::{self, ParseError, Program},
+    vm::{self, Eof, MemoryError, Vm},
+};
+
+/// How long a connection can wait on the client.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Bytes of the line answering a request, read by the client.
+const MAX_REPLY_HEAD: u64 = 8 << 10;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Options {
+    /// Requests handled at once.
+    pub threads: usize,
+    /// Programs kept compiled.
+    pub max_programs: usize,
+    /// How deep the loops of a program can nest.
+    pub max_depth: usize,
+    /// Bytes of a request, the line with the program and the input.
+    pub max_request: usize,
+    /// Limits of every run, the tape size being the most cells a request can ask for.
+    pub limits: Limits,
+}
+
+/// A program to run, sent by the client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub program: String,
+    pub input: Vec<u8>,
+    pub tape_size: usize,
+    pub eof: Eof,
+}
+
+/// What a program run by the daemon wrote and how it failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reply {
+    pub output: Vec<u8>,
+    pub error: Option<Failed>,
+}
+
+/// The error of a program run by the daemon.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Failed {
+    pub message: String,
+    pub kind: Kind,
+}
+
+impl fmt::Display for Failed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for Failed {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Syntax,
+    Memory,
+    Io,
+    Other,
+}
+
+impl Kind {
+    const NAMES: [(Kind, &'static str); 4] = [
+        (Kind::Syntax, "syntax"),
+        (Kind::Memory, "memory"),
+        (Kind::Io, "io"),
+        (Kind::Other, "other"),
+    ];
+
+    pub fn of(err: &anyhow::Error) -> Self {
+        if err.is::<ParseError>() {
+            Kind::Syntax
+        } else if err.is::<MemoryError>() {
+            Kind::Memory
+        } else if err.is::<io::Error>() {
+            Kind::Io
+        } else {
+            Kind::Other
+        }
+    }
+
+    fn name(self) -> &'static str {
+        Self::NAMES
+            .iter()
+            .find(|(kind, _)| *kind == self)
+            .unwrap()
+            .1
+    }
+
+    fn from_name(name: &str) -> Self {
+        (Self::NAMES.iter())
+            .find(|(_, other)| *other == name)
+            .map_or(Kind::Other, |(kind, _)| *kind)
+    }
+}
+
+const EOF_NAMES: [(Eof, &str); 4] = [
+    (Eof::Error, "error"),
+    (Eof::Unchanged, "unchanged"),
+    (Eof::Zero, "zero"),
+    (Eof::NegOne, "neg1"),
+];
+
+/// The socket of the daemon when none is given: `bf.sock` in `$XDG_RUNTIME_DIR`, else in the
+/// temporary directory with the name of the user.
+pub fn default_socket() -> PathBuf {
+    if let Some(dir) = env::var_os("XDG_RUNTIME_DIR") {
+        return Path::new(&dir).join("bf.sock");
+    }
+
+    let user = env::var("USER").unwrap_or_default();
+    env::temp_dir().join(format!("bf-{}.sock", user))
+}
+
+/// Listens on `path`, taking the place of a daemon that didn't remove its socket when it ended.
+pub fn listen(path: &Path) -> Result<UnixListener> {
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            bail!("a daemon is already listening on {}", path.display());
+        }
+        fs::remove_file(path)?;
+    }
+
+    Ok(UnixListener::bind(path)?)
+}
+
+struct Kept {
+    program: Arc<Program>,
+    /// When it was last run, in requests.
+    used: u64,
+}
+
+/// The programs kept compiled, by their source.
+pub struct State {
+    options: Options,
+    /// The cache of `bf run`, programs not kept are looked for in it.
+    cache: Option<Cache>,
+    programs: Mutex<(u64, HashMap<String, Kept>)>,
+    stopping: AtomicBool,
+}
+
+impl State {
+    pub fn new(options: Options) -> Self {
+        Self {
+            options,
+            cache: Cache::default_dir().map(Cache::new),
+            programs: Mutex::default(),
+            stopping: AtomicBool::new(false),
+        }
+    }
+
+    /// The program of `src`, compiled if it isn't kept.
+    fn program(&self, src: &str) -> Result<Arc<Program>> {
+        let mut programs = self.programs.lock().unwrap();
+        let (clock, kept) = &mut *programs;
+        *clock += 1;
+        let used = *clock;
+        if let Some(program) = kept.get_mut(src) {
+            program.used = used;
+            return Ok(program.program.clone());
+        }
+
+        // Compiled without the lock, other programs go on being run meanwhile.
+        drop(programs);
+        let depth = parser::nesting(src);
+        if depth > self.options.max_depth {
+            bail!(
+                "the program nests loops {} deep, more than the {} taken",
+                depth,
+                self.options.max_depth
+            );
+        }
+        let program = Arc::new(match &self.cache {
+            Some(cache) => cache.compile(src, &PassRegistry::default())?,
+            None => vm::compile(src)?,
+        });
+
+        let kept = &mut self.programs.lock().unwrap().1;
+        if kept.len() >= self.options.max_programs {
+            let oldest = kept.iter().min_by_key(|(_, program)| program.used);
+            if let Some(oldest) = oldest.map(|(src, _)| src.clone()) {
+                kept.remove(&oldest);
+            }
+        }
+        let kept_program = Kept {
+            program: program.clone(),
+            used,
+        };
+        kept.insert(src.to_string(), kept_program);
+
+        Ok(program)
+    }
+}
+
+/// The machines of a thread handling requests, cleared after the programs they ran, one for each
+/// program kept at most. Machines with native code can't go to other threads.
+pub struct Machines {
+    idle: Vec<Vm>,
+    max: usize,
+}
+
+impl Machines {
+    pub fn new(max: usize) -> Self {
+        Self { idle: vec![], max }
+    }
+
+    /// A machine with `program` loaded on a tape of `tape_size` cells, which the daemon takes.
+    fn take(&mut self, program: &Arc<Program>, tape_size: usize) -> Result<Vm> {
+        let idle = self
+            .idle
+            .iter()
+            .position(|vm| ptr::eq(vm.program(), &**program) && vm.mem().len() == tape_size);
+        if let Some(i) = idle {
+            return Ok(self.idle.remove(i));
+        }
+
+        let mut vm = Vm::from_shared(program.clone())?;
+        vm.set_tape_size(tape_size)?;
+        Ok(vm)
+    }
+
+    /// Keeps `vm` for the next run of its program, in place of the one used least recently.
+    fn give_back(&mut self, mut vm: Vm) {
+        vm.reset();
+        if self.idle.len() >= self.max {
+            self.idle.remove(0);
+        }
+        self.idle.push(vm);
+    }
+}
+
+/// Handles the connections of `listener`, on `path`, until a client stops the daemon. The socket
+/// is removed then.
+pub fn serve(listener: &UnixListener, path: &Path, options: &Options) -> Result<()> {
+    let state = State::new(*options);
+    let (sender, receiver) = mpsc::channel::<UnixStream>();
+    let receiver = Mutex::new(receiver);
+
+    let result = thread::scope(|scope| {
+        for _ in 0..options.threads.max(1) {
+            scope.spawn(|| {
+                let mut machines = Machines::new(options.max_programs);
+                loop {
+                    // The lock is let go before the request is handled, for the others to take the
+                    // next.
+                    let stream = receiver.lock().unwrap().recv();
+                    let Ok(stream) = stream else {
+                        return;
+                    };
+                    if let Err(err) = connection(stream, &state, &mut machines) {
+                        crate::debug!("{}", err);
+                    }
+                    if state.stopping.load(Ordering::SeqCst) {
+                        // Wakes the loop below, waiting on the next connection.
+                        let _ = UnixStream::connect(path);
+                    }
+                }
+            });
+        }
+
+        let mut result = Ok(());
+        for stream in listener.incoming() {
+            if state.stopping.load(Ordering::SeqCst) {
+                break;
+            }
+            match stream {
+                Ok(stream) => {
+                    if sender.send(stream).is_err() {
+                        break;
+                    }
+                }
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
+            }
+        }
+        drop(sender);
+        result
+    });
+    fs::remove_file(path)?;
+
+    Ok(result?)
+}
+
+fn connection(stream: UnixStream, state: &State, machines: &mut Machines) -> Result<()> {
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    stream.set_write_timeout(Some(IDLE_TIMEOUT))?;
+
+    let mut output = stream.try_clone()?;
+    handle(&mut BufReader::new(stream), &mut output, state, machines)
+}
+
+/// Reads one request from `input` and writes the answer to `output`, running programs on
+/// `machines`.
+pub fn handle(
+    input: &mut dyn BufRead,
+    output: &mut dyn Write,
+    state: &State,
+    machines: &mut Machines,
+) -> Result<()> {
+    let max_request = state.options.max_request;
+    let mut line = String::new();
+    (&mut *input)
+        .take(max_request as u64)
+        .read_line(&mut line)?;
+    if !line.ends_with('\n') {
+        let why = format!("the request is longer than the {} bytes taken", max_request);
+        return write_reply(output, &refused(why));
+    }
+    let request = Json::parse(&line)?;
+
+    let reply = match request.get("command").as_str() {
+        Some("run") => {
+            let room = max_request - line.len();
+            match read_request(&request, input, room, &state.options.limits) {
+                Ok(request) => run(&request, state, machines),
+                Err(err) => refused(err.to_string()),
+            }
+        }
+        Some("stop") => {
+            state.stopping.store(true, Ordering::SeqCst);
+            Reply {
+                output: vec![],
+                error: None,
+            }
+        }
+        command => refused(format!("unknown command {:?}", command.unwrap_or_default())),
+    };
+
+    write_reply(output, &reply)
+}
+
+/// The answer to a request that isn't run.
+fn refused(why: String) -> Reply {
+    Reply {
+        output: vec![],
+        error: Some(Failed {
+            message: why,
+            kind: Kind::Other,
+        }),
+    }
+}
+
+/// The request of the line `request`, with its input from `input`: `room` bytes of it at most and a
+/// tape within `limits`.
+fn read_request(
+    request: &Json,
+    input: &mut dyn BufRead,
+    room: usize,
+    limits: &Limits,
+) -> Result<Request> {
+    let program = request
+        .get("program")
+        .as_str()
+        .ok_or_else(|| anyhow!("expected the program as a string in `program`"))?;
+    let tape_size = request
+        .get("tape_size")
+        .as_usize()
+        .ok_or_else(|| anyhow!("expected the cells of the tape in `tape_size`"))?;
+    let eof = request.get("eof").as_str();
+    let eof = (EOF_NAMES.iter())
+        .find(|(_, name)| Some(*name) == eof)
+        .map(|(eof, _)| *eof)
+        .ok_or_else(|| anyhow!("expected what `,` does at the end of the input in `eof`"))?;
+    let input_bytes = request
+        .get("input_bytes")
+        .as_usize()
+        .ok_or_else(|| anyhow!("expected the length of the input in `input_bytes`"))?;
+    if tape_size > limits.tape_size {
+        bail!(
+            "a tape of {} cells is more than the {} taken",
+            tape_size,
+            limits.tape_size
+        );
+    }
+    if input_bytes > room {
+        bail!(
+            "the input is {} bytes, more than the {} left of the request",
+            input_bytes,
+            room
+        );
+    }
+
+    let mut bytes = vec![0; input_bytes];
+    input.read_exact(&mut bytes)?;
+
+    Ok(Request {
+        program: program.to_string(),
+        input: bytes,
+        tape_size,
+        eof,
+    })
+}
+
+fn run(request: &Request, state: &State, machines: &mut Machines) -> Reply {
+    let start = Instant::now();
+    let run = state.program(&request.program).and_then(|program| {
+        let mut vm = machines.take(&program, request.tape_size)?;
+        vm.set_eof(request.eof);
+        let run = batch::run_limited_on(&mut vm, &request.input, &state.options.limits, start);
+        machines.give_back(vm);
+        Ok(run)
+    });
+
+    match run {
+        Ok(run) => Reply {
+            output: run.output,
+            error: run.result.err().map(|err| Failed {
+                message: err.to_string(),
+                kind: match run.limit {
+                    Some(_) => Kind::Other,
+                    None => Kind::of(&err),
+                },
+            }),
+        },
+        Err(err) => Reply {
+            output: vec![],
+            error: Some(Failed {
+                message: err.to_string(),
+                kind: Kind::of(&err),
+            }),
+        },
+    }
+}
+
+fn write_reply(output: &mut dyn Write, reply: &Reply) -> Result<()> {
+    let (error, kind) = match &reply.error {
+        Some(failed) => (failed.message.as_str().into(), failed.kind.name().into()),
+        None => (Json::Null, Json::Null),
+    };
+    let head = Json::object([
+        ("ok", reply.error.is_none().into()),
+        ("error", error),
+        ("kind", kind),
+        ("output_bytes", reply.output.len().into()),
+    ]);
+
+    writeln!(output, "{}", head)?;
+    output.write_all(&reply.output)?;
+    output.flush()?;
+
+    Ok(())
+}
+
+/// Has the daemon on `socket` run `request`.
+pub fn send(socket: &Path, request: &Request) -> Result<Reply> {
+    let eof = EOF_NAMES
+        .iter()
+        .find(|(eof, _)| *eof == request.eof)
+        .unwrap()
+        .1;
+    let head = Json::object([
+        ("command", "run".into()),
+        ("program", request.program.as_str().into()),
+        ("tape_size", request.tape_size.into()),
+        ("eof", eof.into()),
+        ("input_bytes", request.input.len().into()),
+    ]);
+
+    let mut stream = connect(socket)?;
+    writeln!(stream, "{}", head)?;
+    stream.write_all(&request.input)?;
+    read_reply(&mut BufReader::new(stream))
+}
+
+/// Stops the daemon on `socket`, once the requests it is handling are answered.
+pub fn stop(socket: &Path) -> Result<()> {
+    let mut stream = connect(socket)?;
+    writeln!(stream, "{}", Json::object([("command", "stop".into())]))?;
+    read_reply(&mut BufReader::new(stream))?;
+
+    Ok(())
+}
+
+fn connect(socket: &Path) -> Result<UnixStream> {
+    UnixStream::connect(socket).map_err(|err| {
+        anyhow!(
+            "no daemon is listening on {}, start one with `bf daemon`: {}",
+            socket.display(),
+            err
+        )
+    })
+}
+
+fn read_reply(input: &mut dyn BufRead) -> Result<Reply> {
+    let mut line = String::new();
+    (&mut *input).take(MAX_REPLY_HEAD).read_line(&mut line)?;
+    if !line.ends_with('\n') {
+        bail!(
+            "the daemon answered with a line of more than {} bytes",
+            MAX_REPLY_HEAD
+        );
+    }
+    let head = Json::parse(&line)?;
+
+    let output_bytes = head
+        .get("output_bytes")
+        .as_usize()
+        .ok_or_else(|| anyhow!("the daemon answered without the length of the output"))?;
+    // Read as it comes rather than made room for first, the length is only what the daemon says.
+    let mut output = vec![];
+    (&mut *input)
+        .take(output_bytes as u64)
+        .read_to_end(&mut output)?;
+    if output.len() < output_bytes {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+
+    let error = head.get("error").as_str().map(|message| Failed {
+        message: message.to_string(),
+        kind: Kind::from_name(head.get("kind").as_str().unwrap_or_default()),
+    });
+
+    Ok(Reply { output, error })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        batch::Limits,
+        daemon::{self, Failed, Kind, Machines, Options, Reply, Request, State},
+        vm::Eof,
+    };
+
+    /// What the daemon answers `request`, written and read back as on a socket.
+    fn send(state: &State, machines: &mut Machines, request: &Request) -> Reply {
+        let head = format!(
+            "{{\"command\": \"run\", \"program\": {:?}, \"tape_size\": {}, \"eof\": \"zero\", \"input_bytes\": {}}}\n",
+            request.program,
+            request.tape_size,
+            request.input.len()
+        );
+        let mut input = head.into_bytes();
+        input.extend(&request.input);
+
+        let mut output = vec![];
+        daemon::handle(&mut &input[..], &mut output, state, machines).unwrap();
+        daemon::read_reply(&mut &output[..]).unwrap()
+    }
+
+    #[test]
+    fn runs_programs_kept_compiled() {
+        let mut state = State::new(Options {
+            threads: 1,
+            max_programs: 1,
+            max_depth: 10,
+            max_request: 1 << 10,
+            limits: Limits {
+                max_steps: Some(1000),
+                tape_size: 10,
+                ..Limits::default()
+            },
+        });
+        state.cache = None;
+        let request = |program: &str, input: &[u8]| Request {
+            program: program.to_string(),
+            input: input.to_vec(),
+            tape_size: 10,
+            eof: Eof::Zero,
+        };
+
+        let machines = &mut Machines::new(1);
+        let echo = request(",[.,]", b"hi");
+        for _ in 0..2 {
+            assert_eq!(
+                send(&state, machines, &echo),
+                Reply {
+                    output: b"hi".to_vec(),
+                    error: None,
+                }
+            );
+        }
+        assert_eq!(machines.idle.len(), 1);
+
+        let reply = send(&state, machines, &request("+[>+]", b""));
+        assert_eq!(reply.error.map(|failed| failed.kind), Some(Kind::Memory));
+        assert!(!state.programs.lock().unwrap().1.contains_key(",[.,]"));
+
+        assert_eq!(
+            send(&state, machines, &request("[", b"")).error,
+            Some(Failed {
+                message: "unclosed delimiter '[' at 1:1.".to_string(),
+                kind: Kind::Syntax,
+            })
+        );
+    }
+
+    #[test]
+    fn refuses_what_is_past_the_limits() {
+        let mut state = State::new(Options {
+            threads: 1,
+            max_programs: 1,
+            max_depth: 10,
+            max_request: 200,
+            limits: Limits {
+                max_steps: Some(1000),
+                max_output: Some(4),
+                tape_size: 10,
+                ..Limits::default()
+            },
+        });
+        state.cache = None;
+        let request = |program: &str, input: &[u8], tape_size| Request {
+            program: program.to_string(),
+            input: input.to_vec(),
+            tape_size,
+            eof: Eof::Zero,
+        };
+        let machines = &mut Machines::new(1);
+        let failed = |message: &str| {
+            Some(Failed {
+                message: message.to_string(),
+                kind: Kind::Other,
+            })
+        };
+
+        assert_eq!(
+            send(&state, machines, &request("+", b"", 1 << 40)).error,
+            failed("a tape of 1099511627776 cells is more than the 10 taken")
+        );
+        let reply = send(&state, machines, &request(",", &[0; 200], 10));
+        assert!(reply
+            .error
+            .unwrap()
+            .message
+            .starts_with("the input is 200 bytes"));
+        assert_eq!(
+            send(&state, machines, &request(&"+".repeat(200), b"", 10)).error,
+            failed("the request is longer than the 200 bytes taken")
+        );
+
+        assert_eq!(
+            send(&state, machines, &request("+[]", b"", 10)).error,
+            failed("ran more than 1000 steps")
+        );
+        assert_eq!(
+            send(&state, machines, &request("+[.]", b"", 10)),
+            Reply {
+                output: vec![1; 4],
+                error: failed("output limit reached"),
+            }
+        );
+        assert_eq!(send(&state, machines, &request("+.", b"", 10)).output, [1]);
+    }
+
+    #[test]
+    fn runs_deep_nesting() {
+        let depth = 100_000;
+        let mut state = State::new(Options {
+            threads: 1,
+            max_programs: 1,
+            max_depth: depth,
+            max_request: 1 << 20,
+            limits: Limits {
+                tape_size: 10,
+                ..Limits::default()
+            },
+        });
+        state.cache = None;
+        let request = |depth| Request {
+            program: format!("+{}.-{}", "[".repeat(depth), "]".repeat(depth)),
+            input: vec![],
+            tape_size: 10,
+            eof: Eof::Zero,
+        };
+
+        let machines = &mut Machines::new(1);
+        assert_eq!(
+            send(&state, machines, &request(depth)),
+            Reply {
+                output: vec![1],
+                error: None,
+            }
+        );
+        assert_eq!(
+            send(&state, machines, &request(depth + 1)).error,
+            Some(Failed {
+                message: "the program nests loops 100001 deep, more than the 100000 taken"
+                    .to_string(),
+                kind: Kind::Other,
+            })
+        );
+    }
+}
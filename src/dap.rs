@@ -0,0 +1,502 @@
+/*
+ *  Debug Adapter Protocol server, for `bf dap`: editors such as VS Code debug a program through it, with
+ *  `Debugger` doing the work. Messages are JSON objects after a `Content-Length` header.
+ *
+ *  There is one thread. Its stack is the instruction it is at, then the loops that instruction is in,
+ *  innermost first, each at its `[`. The variables are the pointer and the cells around it. Standard
+ *  input carries the protocol, so the program's input is the `input` launch argument, and its output
+ *  goes to the editor as output events.
+ *
+ *  Launch arguments: `program` (the path, required), `input` and `stopOnEntry`.
+ */
+
+use std::{
+    fs,
+    io::{self, BufRead, Write},
+    path::Path,
+};
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::{
+    asm,
+    debugger::{Debugger, Resume, Stop},
+    json::Json,
+    lexer::TokenLoc,
+};
+
+const THREAD_ID: usize = 1;
+/// Cells shown on each side of the pointer.
+const TAPE_WINDOW: usize = 8;
+const TAPE_REFERENCE: usize = 1;
+
+pub fn serve(input: &mut dyn BufRead, output: &mut dyn Write) -> Result<()> {
+    let mut server = Server {
+        out: output,
+        seq: 0,
+        session: None,
+    };
+
+    while let Some(message) = read_message(input)? {
+        let request = Json::parse(&message)?;
+        if !server.request(&request)? {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// The next message, `None` at the end of the input. The language server's are framed the same.
+pub fn read_message(input: &mut dyn BufRead) -> Result<Option<String>> {
+    let mut len = None;
+
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            len = Some(value.trim().parse::<usize>()?);
+        }
+    }
+
+    let len = len.ok_or_else(|| anyhow!("message without a Content-Length"))?;
+    let mut body = vec![0; len];
+    input.read_exact(&mut body)?;
+
+    Ok(Some(String::from_utf8(body)?))
+}
+
+struct Session {
+    debugger: Debugger,
+    path: String,
+    input: io::Cursor<Vec<u8>>,
+    stop_on_entry: bool,
+}
+
+struct Server<'a> {
+    out: &'a mut dyn Write,
+    seq: usize,
+    session: Option<Session>,
+}
+
+impl Server<'_> {
+    /// Answers `request`, returns false once the editor is done.
+    fn request(&mut self, request: &Json) -> Result<bool> {
+        let command = request.get("command").as_str().unwrap_or_default();
+        let args = request.get("arguments");
+
+        let result = match command {
+            "initialize" => Ok(Json::object([
+                ("supportsConfigurationDoneRequest", true.into()),
+                ("supportsStepBack", true.into()),
+                ("supportsEvaluateForHovers", false.into()),
+            ])),
+            "launch" => self.launch(args),
+            "setBreakpoints" => self.set_breakpoints(args),
+            "setExceptionBreakpoints" => Ok(Json::object([("breakpoints", vec![].into())])),
+            "threads" => Ok(Json::object([(
+                "threads",
+                vec![Json::object([
+                    ("id", THREAD_ID.into()),
+                    ("name", "main".into()),
+                ])]
+                .into(),
+            )])),
+            "stackTrace" => self.stack_trace(),
+            "scopes" => Ok(Json::object([(
+                "scopes",
+                vec![Json::object([
+                    ("name", "Tape".into()),
+                    ("variablesReference", TAPE_REFERENCE.into()),
+                    ("expensive", false.into()),
+                ])]
+                .into(),
+            )])),
+            "variables" => self.variables(),
+            "evaluate" => self.evaluate(args),
+            "configurationDone" | "continue" | "next" | "stepIn" | "stepOut" | "stepBack"
+            | "reverseContinue" | "pause" => Ok(Json::object([])),
+            "disconnect" | "terminate" => {
+                self.respond(request, Ok(Json::object([])))?;
+                return Ok(false);
+            }
+            _ => Err(anyhow!("`{}` is not supported", command)),
+        };
+
+        let ok = result.is_ok();
+        self.respond(request, result)?;
+        if command == "initialize" {
+            self.event("initialized", Json::object([]))?;
+        }
+
+        // Running the program comes after the response, the stop is an event of its own.
+        let how = match command {
+            "configurationDone" if self.session().is_ok_and(|session| session.stop_on_entry) => {
+                return self.stopped("entry").map(|()| true);
+            }
+            "configurationDone" | "continue" => Resume::Continue,
+            "next" => Resume::Next,
+            "stepIn" => Resume::Step(1),
+            "stepOut" => Resume::Finish,
+            "stepBack" => Resume::ReverseStep(1),
+            "reverseContinue" => Resume::ReverseContinue,
+            _ => return Ok(true),
+        };
+        if ok {
+            self.resume(how)?;
+        }
+
+        Ok(true)
+    }
+
+    fn launch(&mut self, args: &Json) -> Result<Json> {
+        let path = args
+            .get("program")
+            .as_str()
+            .ok_or_else(|| anyhow!("launch needs the path of the `program`"))?;
+
+        self.session = Some(Session {
+            debugger: Debugger::new(&fs::read_to_string(path)?)?,
+            path: path.to_string(),
+            input: io::Cursor::new(args.get("input").as_str().unwrap_or_default().into()),
+            stop_on_entry: args.get("stopOnEntry").as_bool().unwrap_or(false),
+        });
+
+        Ok(Json::object([]))
+    }
+
+    fn set_breakpoints(&mut self, args: &Json) -> Result<Json> {
+        let debugger = &mut self.session_mut()?.debugger;
+        debugger.clear_breakpoints();
+
+        let breakpoints = args.get("breakpoints").as_array().iter().map(|breakpoint| {
+            let line = breakpoint.get("line").as_usize().unwrap_or(1);
+            let col = breakpoint.get("column").as_usize().unwrap_or(1);
+
+            match debugger.pc_at(TokenLoc::from_col_line(col, line)) {
+                Some(pc) => {
+                    debugger.set_breakpoint(pc);
+                    // Moved to the instruction it stops at, which can be on a later line.
+                    let (loc, _) = debugger.span(pc).unwrap();
+                    Json::object([
+                        ("verified", true.into()),
+                        ("line", loc.line().into()),
+                        ("column", loc.col().into()),
+                    ])
+                }
+                None => Json::object([
+                    ("verified", false.into()),
+                    ("message", "no instruction at or after this line".into()),
+                ]),
+            }
+        });
+
+        Ok(Json::object([(
+            "breakpoints",
+            breakpoints.collect::<Vec<_>>().into(),
+        )]))
+    }
+
+    fn stack_trace(&self) -> Result<Json> {
+        let session = self.session()?;
+        let debugger = &session.debugger;
+        let source = Json::object([
+            (
+                "name",
+                Path::new(&session.path)
+                    .file_name()
+                    .map_or(String::new(), |name| name.to_string_lossy().into())
+                    .into(),
+            ),
+            ("path", session.path.as_str().into()),
+        ]);
+
+        let pc = debugger.vm().pc();
+        let frames: Vec<Json> = debugger
+            .span(pc)
+            .map(|(loc, _)| (asm::instruction(&debugger.vm().program()[pc]), loc))
+            .into_iter()
+            .chain(debugger.loops().into_iter().map(|start| {
+                let (loc, _) = debugger.span(start).unwrap();
+                (format!("loop at {}", loc), loc)
+            }))
+            .enumerate()
+            .map(|(id, (name, loc))| {
+                Json::object([
+                    ("id", id.into()),
+                    ("name", name.into()),
+                    ("source", source.clone()),
+                    ("line", loc.line().into()),
+                    ("column", loc.col().into()),
+                ])
+            })
+            .collect();
+
+        Ok(Json::object([
+            ("totalFrames", frames.len().into()),
+            ("stackFrames", frames.into()),
+        ]))
+    }
+
+    fn variables(&self) -> Result<Json> {
+        let vm = self.session()?.debugger.vm();
+        let (mem, ptr) = (vm.mem(), vm.mem_ptr());
+
+        let variable = |name: String, value: String| {
+            Json::object([
+                ("name", name.into()),
+                ("value", value.into()),
+                ("variablesReference", 0.into()),
+            ])
+        };
+
+        let start = ptr.saturating_sub(TAPE_WINDOW);
+        let end = (ptr + TAPE_WINDOW + 1).min(mem.len());
+        let cells = (start..end).map(|n| {
+            let value = match mem[n] {
+                ch @ 0x20..=0x7e => format!("{} '{}'", ch, ch as char),
+                value => value.to_string(),
+            };
+            variable(format!("mem[{}]", n), value)
+        });
+
+        let variables: Vec<Json> = [variable("ptr".to_string(), ptr.to_string())]
+            .into_iter()
+            .chain(cells)
+            .collect();
+
+        Ok(Json::object([("variables", variables.into())]))
+    }
+
+    /// Expressions are what the debugger's `print` takes: `ptr`, `mem[n]` or `mem[n..m]`.
+    fn evaluate(&mut self, args: &Json) -> Result<Json> {
+        let expression = args.get("expression").as_str().unwrap_or_default();
+        let session = self.session_mut()?;
+
+        let mut out = vec![];
+        session.debugger.command(
+            &format!("print {}", expression),
+            &mut io::empty(),
+            &mut io::sink(),
+            &mut out,
+        )?;
+        let result = String::from_utf8_lossy(&out).trim_end().to_string();
+        if let Some(err) = result.strip_prefix("error: ") {
+            bail!("{}", err);
+        }
+
+        Ok(Json::object([
+            ("result", result.into()),
+            ("variablesReference", 0.into()),
+        ]))
+    }
+
+    fn resume(&mut self, how: Resume) -> Result<()> {
+        let session = self.session_mut()?;
+        let mut output = vec![];
+        let result = session
+            .debugger
+            .resume(how, &mut session.input, &mut output);
+
+        if !output.is_empty() {
+            self.output("stdout", &String::from_utf8_lossy(&output))?;
+        }
+
+        match result {
+            Ok(Stop::Done | Stop::Start) => self.stopped("step"),
+            Ok(Stop::Breakpoint) => self.stopped("breakpoint"),
+            Ok(Stop::Ended) => {
+                self.event("exited", Json::object([("exitCode", 0.into())]))?;
+                self.event("terminated", Json::object([]))
+            }
+            // The program stays at the instruction that failed, to be looked at.
+            Err(err) => {
+                self.output("stderr", &format!("error: {}\n", err))?;
+                self.event(
+                    "stopped",
+                    Json::object([
+                        ("reason", "exception".into()),
+                        ("text", err.to_string().into()),
+                        ("threadId", THREAD_ID.into()),
+                    ]),
+                )
+            }
+        }
+    }
+
+    fn session(&self) -> Result<&Session> {
+        self.session
+            .as_ref()
+            .ok_or_else(|| anyhow!("no program launched"))
+    }
+
+    fn session_mut(&mut self) -> Result<&mut Session> {
+        self.session
+            .as_mut()
+            .ok_or_else(|| anyhow!("no program launched"))
+    }
+
+    fn respond(&mut self, request: &Json, result: Result<Json>) -> Result<()> {
+        let mut fields = vec![
+            ("type", "response".into()),
+            (
+                "request_seq",
+                request.get("seq").as_usize().unwrap_or(0).into(),
+            ),
+            ("command", request.get("command").clone()),
+            ("success", result.is_ok().into()),
+        ];
+        match result {
+            Ok(body) => fields.push(("body", body)),
+            Err(err) => fields.push(("message", err.to_string().into())),
+        }
+
+        self.send(fields)
+    }
+
+    fn stopped(&mut self, reason: &str) -> Result<()> {
+        self.event(
+            "stopped",
+            Json::object([
+                ("reason", reason.into()),
+                ("threadId", THREAD_ID.into()),
+                ("allThreadsStopped", true.into()),
+            ]),
+        )
+    }
+
+    fn output(&mut self, category: &str, text: &str) -> Result<()> {
+        self.event(
+            "output",
+            Json::object([("category", category.into()), ("output", text.into())]),
+        )
+    }
+
+    fn event(&mut self, event: &str, body: Json) -> Result<()> {
+        self.send(vec![
+            ("type", "event".into()),
+            ("event", event.into()),
+            ("body", body),
+        ])
+    }
+
+    fn send(&mut self, fields: Vec<(&str, Json)>) -> Result<()> {
+        self.seq += 1;
+        let message = Json::object([("seq", self.seq.into())].into_iter().chain(fields));
+
+        let body = message.to_string();
+        write!(self.out, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+        Ok(self.out.flush()?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{env, fs, process};
+
+    use crate::{dap, json::Json};
+
+    #[test]
+    fn debugs_a_session() {
+        let path = env::temp_dir().join(format!("bf-dap-test-{}.bf", process::id()));
+        fs::write(&path, "+++\n[>+\n  .<-]\n").unwrap();
+        let path = path.to_str().unwrap();
+
+        let requests = [
+            r#"{"command":"initialize","arguments":{}}"#.to_string(),
+            format!(
+                r#"{{"command":"launch","arguments":{{"program":{}}}}}"#,
+                Json::from(path)
+            ),
+            format!(
+                r#"{{"command":"setBreakpoints","arguments":{{"source":{{"path":{}}},"breakpoints":[{{"line":3}},{{"line":9}}]}}}}"#,
+                Json::from(path)
+            ),
+            r#"{"command":"configurationDone"}"#.to_string(),
+            r#"{"command":"stackTrace","arguments":{"threadId":1}}"#.to_string(),
+            r#"{"command":"variables","arguments":{"variablesReference":1}}"#.to_string(),
+            r#"{"command":"evaluate","arguments":{"expression":"mem[1]"}}"#.to_string(),
+            r#"{"command":"stepIn","arguments":{"threadId":1}}"#.to_string(),
+            r#"{"command":"setBreakpoints","arguments":{"breakpoints":[]}}"#.to_string(),
+            r#"{"command":"continue","arguments":{"threadId":1}}"#.to_string(),
+            r#"{"command":"disconnect"}"#.to_string(),
+        ];
+        let input: String = requests
+            .iter()
+            .enumerate()
+            .map(|(seq, request)| {
+                let request = request.replacen('{', &format!("{{\"seq\":{},", seq + 1), 1);
+                format!("Content-Length: {}\r\n\r\n{}", request.len(), request)
+            })
+            .collect();
+
+        let mut output = vec![];
+        dap::serve(&mut input.as_bytes(), &mut output).unwrap();
+        fs::remove_file(path).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        let messages: Vec<Json> = output
+            .split("Content-Length: ")
+            .skip(1)
+            .map(|message| Json::parse(message.split_once("\r\n\r\n").unwrap().1).unwrap())
+            .collect();
+        let find = |command: &str| {
+            messages
+                .iter()
+                .find(|message| message.get("command").as_str() == Some(command))
+                .unwrap()
+                .get("body")
+        };
+        let events: Vec<_> = messages
+            .iter()
+            .filter_map(|message| {
+                let body = message.get("body");
+                let what = body.get("reason").as_str().or(body.get("output").as_str());
+                Some(format!(
+                    "{} {}",
+                    message.get("event").as_str()?,
+                    what.unwrap_or("")
+                ))
+            })
+            .collect();
+
+        assert!(messages
+            .iter()
+            .all(|message| message.get("success") != &Json::Bool(false)));
+        assert_eq!(
+            find("setBreakpoints").to_string(),
+            r#"{"breakpoints":[{"verified":true,"line":3,"column":3},{"verified":false,"message":"no instruction at or after this line"}]}"#
+        );
+
+        let frames = find("stackTrace").get("stackFrames").as_array();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].get("name").as_str(), Some("OUT 1"));
+        assert_eq!(frames[1].get("name").as_str(), Some("loop at 2:1"));
+        assert_eq!(frames[1].get("line").as_usize(), Some(2));
+
+        let variables = find("variables").get("variables").as_array();
+        assert_eq!(variables[0].get("value").as_str(), Some("1"));
+        assert_eq!(variables[2].get("value").as_str(), Some("1"));
+        assert_eq!(find("evaluate").get("result").as_str(), Some("mem[1] = 1"));
+
+        assert_eq!(
+            events,
+            [
+                "initialized ",
+                "stopped breakpoint",
+                "output \u{1}",
+                "stopped step",
+                "output \u{2}\u{3}",
+                "exited ",
+                "terminated ",
+            ]
+        );
+    }
+}
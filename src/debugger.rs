@@ -0,0 +1,720 @@
+/*
+ *  Interactive debugger, for `bf debug`. The program runs unoptimized, so every instruction is one
+ *  command or run of commands in the source and stepping follows the file.
+ *
+ *      break LOC           stop before LOC, without LOC lists the breakpoints
+ *      break LOC if-hit M  stop only the Mth time LOC is reached
+ *      tbreak LOC          stop before LOC once, then delete the breakpoint
+ *      delete [LOC]        remove the breakpoint at LOC, or all of them
+ *      step [n]            run n instructions, 1 by default
+ *      next                like step, but runs a loop that is about to be entered to its end
+ *      finish              run to the end of the loop the current instruction is in
+ *      continue            run to the next breakpoint or the end
+ *      reverse-step [n]    go back n instructions, 1 by default
+ *      reverse-continue    go back to the last breakpoint passed, or the start
+ *      print ptr           the pointer
+ *      print mem[n]        a cell, `mem[n..m]` dumps cells n to m
+ *      list                the source around the current instruction
+ *      backtrace           the current instruction and the loops it is in, innermost first
+ *      quit
+ *
+ *  LOC is an instruction number or a `line:col` in the source, which stands for the instruction there
+ *  or the first one after it. An empty line repeats the last command.
+ *
+ *  Going back does not unwrite the output or count the breakpoint hits back down. Going forward again
+ *  reads the same input and does not write the output a second time.
+ *
+ *  A crash dump opened with `bf debug --core` is where the program failed and can only be looked at,
+ *  the commands running the program are errors.
+ */
+
+use std::{
+    collections::BTreeMap,
+    io::{Read, Write},
+};
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::{
+    asm,
+    crashdump::CrashDump,
+    hexdump,
+    history::History,
+    lexer::{self, TokenLoc},
+    opcodes::OpCodeType,
+    parser::{self, Span},
+    vm::Vm,
+};
+
+/// Source lines shown by `list` on each side of the current one.
+const LIST_CONTEXT: usize = 3;
+
+const HELP: &str = "\
+break LOC           stop before LOC, without LOC lists the breakpoints
+break LOC if-hit M  stop only the Mth time LOC is reached
+tbreak LOC          stop before LOC once, then delete the breakpoint
+delete [LOC]        remove the breakpoint at LOC, or all of them
+step [n]            run n instructions, 1 by default
+next                like step, but runs a loop that is about to be entered to its end
+finish              run to the end of the loop the current instruction is in
+continue            run to the next breakpoint or the end
+reverse-step [n]    go back n instructions, 1 by default
+reverse-continue    go back to the last breakpoint passed, or the start
+print ptr           the pointer
+print mem[n]        a cell, `mem[n..m]` dumps cells n to m
+list                the source around the current instruction
+backtrace           the current instruction and the loops it is in, innermost first
+quit
+
+LOC is an instruction number or a line:col in the source.
+";
+
+/// How `Debugger::resume` runs the program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resume {
+    Step(usize),
+    /// Step, running a loop that is about to be entered to its end.
+    Next,
+    /// Run to the end of the innermost loop, or of the program outside of loops.
+    Finish,
+    Continue,
+    ReverseStep(usize),
+    ReverseContinue,
+}
+
+/// Why `Debugger::resume` stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    /// Got where it was going.
+    Done,
+    Breakpoint,
+    /// The program ended.
+    Ended,
+    /// Went back to the start of the program.
+    Start,
+}
+
+#[derive(Debug)]
+struct Breakpoint {
+    /// Stop only when `hits` gets to this, instead of every time.
+    if_hit: Option<u64>,
+    /// Delete after the first stop, for `tbreak`.
+    temporary: bool,
+    /// Times the instruction was reached while running.
+    hits: u64,
+}
+
+pub struct Debugger {
+    vm: Vm,
+    src: String,
+    spans: Vec<Span>,
+    /// One line per instruction, from `asm::disassemble`.
+    listing: Vec<String>,
+    breakpoints: BTreeMap<usize, Breakpoint>,
+    history: History,
+    last_command: String,
+    /// The error of the crash dump the debugger was opened on, which can't be run.
+    core: Option<String>,
+}
+
+impl Debugger {
+    pub fn new(src: &str) -> Result<Self> {
+        let tokens = lexer::parse(src);
+        let spans = parser::spans(&tokens);
+        let vm = Vm::from_program(parser::parse(tokens)?)?;
+        let listing = asm::disassemble(vm.program())
+            .lines()
+            .map(str::to_string)
+            .collect();
+
+        Ok(Self {
+            vm,
+            src: src.to_string(),
+            spans,
+            listing,
+            breakpoints: BTreeMap::new(),
+            history: History::new(),
+            last_command: String::new(),
+            core: None,
+        })
+    }
+
+    /// On the program of a crash dump, stopped where it failed.
+    pub fn from_core(dump: &CrashDump) -> Result<Self> {
+        let mut debugger = Self::new(dump.src())?;
+        debugger.vm.restore(&dump.snapshot()?);
+        debugger.core = Some(dump.error().to_string());
+
+        Ok(debugger)
+    }
+
+    /// The error of the crash dump, if the debugger is on one.
+    pub fn core(&self) -> Option<&str> {
+        self.core.as_deref()
+    }
+
+    pub fn vm(&self) -> &Vm {
+        &self.vm
+    }
+
+    pub fn src(&self) -> &str {
+        &self.src
+    }
+
+    /// Where instruction `pc` is in the source.
+    pub fn span(&self, pc: usize) -> Option<Span> {
+        self.spans.get(pc).copied()
+    }
+
+    /// The starts of the loops the current instruction is in, innermost first.
+    pub fn loops(&self) -> Vec<usize> {
+        parser::enclosing_loops(self.vm.program(), self.vm.pc())
+    }
+
+    /// The instructions with a breakpoint, in order.
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.breakpoints.keys().copied()
+    }
+
+    /// Where the program is, written after every command that runs it.
+    pub fn location(&self) -> String {
+        match self.listing.get(self.vm.pc()) {
+            Some(line) => format!("{}    at {}", line, self.spans[self.vm.pc()].0),
+            None => "program ended".to_string(),
+        }
+    }
+
+    /// Runs one command line, `input` and `output` are the program's. Returns false on `quit`.
+    /// Errors of the command and of the program are written to `out`, the session goes on.
+    pub fn command<R: Read, W: Write>(
+        &mut self,
+        line: &str,
+        input: &mut R,
+        output: &mut W,
+        out: &mut dyn Write,
+    ) -> Result<bool> {
+        let line = match line.trim() {
+            "" => self.last_command.clone(),
+            line => line.to_string(),
+        };
+        self.last_command = line.clone();
+
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else {
+            return Ok(true);
+        };
+        let args: Vec<_> = words.collect();
+        let arg = args.first().copied();
+
+        let result = match command {
+            "break" | "b" => self.break_at(&args, false, out),
+            "tbreak" => self.break_at(&args, true, out),
+            "delete" | "d" => self.delete(arg),
+            "step" | "s" => arg
+                .map_or(Ok(1), parse_number)
+                .and_then(|count| self.run(Resume::Step(count), input, output, out)),
+            "next" | "n" => self.run(Resume::Next, input, output, out),
+            "finish" | "f" if self.loops().is_empty() => Err(anyhow!("not in a loop")),
+            "finish" | "f" => self.run(Resume::Finish, input, output, out),
+            "continue" | "c" => self.run(Resume::Continue, input, output, out),
+            "reverse-step" | "rs" => arg
+                .map_or(Ok(1), parse_number)
+                .and_then(|count| self.run(Resume::ReverseStep(count), input, output, out)),
+            "reverse-continue" | "rc" => self.run(Resume::ReverseContinue, input, output, out),
+            "print" | "p" => self.print(arg.unwrap_or_default(), out),
+            "list" | "l" => self.list(out),
+            "backtrace" | "bt" => self.backtrace(out),
+            "help" | "h" => Ok(write!(out, "{}", HELP)?),
+            "quit" | "q" => return Ok(false),
+            _ => Err(anyhow!("unknown command `{}`, try `help`", command)),
+        };
+
+        if let Err(err) = result {
+            writeln!(out, "error: {}", err)?;
+        }
+
+        Ok(true)
+    }
+
+    fn check_runnable(&self) -> Result<()> {
+        if self.core.is_some() {
+            bail!("a crash dump can only be looked at, not run");
+        }
+
+        Ok(())
+    }
+
+    /// Counts a hit of the breakpoint at the pc, if there is one, and tells whether to stop there.
+    fn hit_breakpoint(&mut self) -> bool {
+        let pc = self.vm.pc();
+        let Some(breakpoint) = self.breakpoints.get_mut(&pc) else {
+            return false;
+        };
+
+        breakpoint.hits += 1;
+        let stop = breakpoint.if_hit.is_none_or(|n| breakpoint.hits == n);
+        if stop && breakpoint.temporary {
+            self.breakpoints.remove(&pc);
+        }
+
+        stop
+    }
+
+    /// The pc of the end of the loop starting at the current instruction, if it is going to be entered.
+    fn loop_end(&self) -> Option<usize> {
+        let op = self.vm.program().get(self.vm.pc())?;
+        let cell = self.vm.mem()[self.vm.mem_ptr()];
+
+        (op.ty == OpCodeType::JmpZero && cell != 0).then_some(op.data)
+    }
+
+    /// Runs the program the way `how` says, until it got there, a breakpoint stops it or it ends.
+    /// `input` and `output` are the program's.
+    pub fn resume<R: Read, W: Write>(
+        &mut self,
+        how: Resume,
+        input: &mut R,
+        output: &mut W,
+    ) -> Result<Stop> {
+        self.check_runnable()?;
+
+        match how {
+            Resume::Step(count) => self.run_until(input, output, |_, steps| steps == count),
+            Resume::Next => {
+                let end = self.loop_end();
+                self.run_until(input, output, |debugger, _| {
+                    end.is_none_or(|end| debugger.vm.pc() > end)
+                })
+            }
+            Resume::Finish => {
+                let end = self
+                    .loops()
+                    .first()
+                    .map(|&start| self.vm.program()[start].data);
+                self.run_until(input, output, |debugger, _| {
+                    end.is_some_and(|end| debugger.vm.pc() > end)
+                })
+            }
+            Resume::Continue => self.run_until(input, output, |_, _| false),
+            Resume::ReverseStep(count) => {
+                let target = self.history.step().saturating_sub(count as u64);
+                self.history.goto(&mut self.vm, target)?;
+
+                Ok(if target == 0 { Stop::Start } else { Stop::Done })
+            }
+            Resume::ReverseContinue => {
+                let breakpoints = &self.breakpoints;
+                let found = self
+                    .history
+                    .find_back(&mut self.vm, |vm| breakpoints.contains_key(&vm.pc()))?;
+
+                match found {
+                    Some(step) => {
+                        self.history.goto(&mut self.vm, step)?;
+                        Ok(Stop::Breakpoint)
+                    }
+                    None => self.resume(Resume::ReverseStep(usize::MAX), input, output),
+                }
+            }
+        }
+    }
+
+    /// Steps until `stop` says so, it is given the number of steps so far, a breakpoint stops it or
+    /// the program ends.
+    fn run_until<R: Read, W: Write>(
+        &mut self,
+        input: &mut R,
+        output: &mut W,
+        mut stop: impl FnMut(&Self, usize) -> bool,
+    ) -> Result<Stop> {
+        let mut steps = 0;
+        let result = loop {
+            match self.history.step_vm(&mut self.vm, input, output) {
+                Ok(true) => steps += 1,
+                Ok(false) => break Ok(Stop::Ended),
+                Err(err) => break Err(err),
+            }
+            if self.hit_breakpoint() {
+                break Ok(Stop::Breakpoint);
+            }
+            if stop(self, steps) {
+                break Ok(match self.vm.pc() < self.listing.len() {
+                    true => Stop::Done,
+                    false => Stop::Ended,
+                });
+            }
+        };
+        output.flush()?;
+
+        result
+    }
+
+    /// `resume`, then where it stopped written to `out`.
+    fn run<R: Read, W: Write>(
+        &mut self,
+        how: Resume,
+        input: &mut R,
+        output: &mut W,
+        out: &mut dyn Write,
+    ) -> Result<()> {
+        self.check_runnable()?;
+        let result = self.resume(how, input, output);
+
+        match result {
+            Ok(Stop::Breakpoint) => write!(out, "breakpoint, ")?,
+            Ok(Stop::Start) => write!(out, "start of the program, ")?,
+            _ => {}
+        }
+        writeln!(out, "{}", self.location())?;
+
+        result.map(drop)
+    }
+
+    /// Stops before instruction `pc` every time it is reached.
+    pub fn set_breakpoint(&mut self, pc: usize) {
+        self.breakpoints.insert(
+            pc,
+            Breakpoint {
+                if_hit: None,
+                temporary: false,
+                hits: 0,
+            },
+        );
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    /// The instruction at `loc` in the source, or the first one after it.
+    pub fn pc_at(&self, loc: TokenLoc) -> Option<usize> {
+        parser::pc_at(&self.spans, loc)
+    }
+
+    /// `break` and `tbreak`, `args` are the ones after the command.
+    fn break_at(&mut self, args: &[&str], temporary: bool, out: &mut dyn Write) -> Result<()> {
+        let (pc, if_hit) = match *args {
+            [] if !temporary => return self.list_breakpoints(out),
+            [pc] => (pc, None),
+            [pc, "if-hit", n] => (pc, Some(parse_number(n)? as u64)),
+            _ => bail!("expected `LOC`, or `LOC if-hit M`"),
+        };
+
+        let pc = self.resolve(pc)?;
+        if if_hit == Some(0) {
+            bail!("hit counts start at 1");
+        }
+        self.breakpoints.insert(
+            pc,
+            Breakpoint {
+                if_hit,
+                temporary,
+                hits: 0,
+            },
+        );
+
+        Ok(writeln!(
+            out,
+            "breakpoint at {}    at {}",
+            self.listing[pc], self.spans[pc].0
+        )?)
+    }
+
+    /// The instruction at `loc`, a number or a `line:col`.
+    fn resolve(&self, loc: &str) -> Result<usize> {
+        let pc = match loc.split_once(':') {
+            Some((line, col)) => {
+                let loc = TokenLoc::from_col_line(parse_number(col)?, parse_number(line)?);
+                parser::pc_at(&self.spans, loc)
+                    .ok_or_else(|| anyhow!("no instruction at or after {}", loc))?
+            }
+            None => parse_number(loc)?,
+        };
+
+        if pc >= self.listing.len() {
+            bail!(
+                "no instruction {}, the program has {}",
+                pc,
+                self.listing.len()
+            );
+        }
+
+        Ok(pc)
+    }
+
+    fn list_breakpoints(&self, out: &mut dyn Write) -> Result<()> {
+        for (&pc, breakpoint) in &self.breakpoints {
+            write!(out, "{}    hit {} times", self.listing[pc], breakpoint.hits)?;
+            if let Some(n) = breakpoint.if_hit {
+                write!(out, ", stops at hit {}", n)?;
+            }
+            if breakpoint.temporary {
+                write!(out, ", temporary")?;
+            }
+            writeln!(out)?;
+        }
+
+        Ok(())
+    }
+
+    fn delete(&mut self, arg: Option<&str>) -> Result<()> {
+        match arg {
+            Some(arg) => {
+                let pc = self.resolve(arg)?;
+                if self.breakpoints.remove(&pc).is_none() {
+                    bail!("no breakpoint at {}", pc);
+                }
+            }
+            None => self.breakpoints.clear(),
+        }
+
+        Ok(())
+    }
+
+    fn print(&self, what: &str, out: &mut dyn Write) -> Result<()> {
+        if what == "ptr" {
+            return Ok(writeln!(out, "ptr = {}", self.vm.mem_ptr())?);
+        }
+
+        let range = what
+            .strip_prefix("mem[")
+            .and_then(|rest| rest.strip_suffix(']'))
+            .ok_or_else(|| anyhow!("can print `ptr`, `mem[n]` or `mem[n..m]`"))?;
+        let mem = self.vm.mem();
+        let cell = |n: usize| {
+            mem.get(n)
+                .ok_or_else(|| anyhow!("no cell {}, the tape has {}", n, mem.len()))
+        };
+
+        match range.split_once("..") {
+            Some((start, end)) => {
+                let (start, end) = (parse_number(start)?, parse_number(end)?);
+                if start > end {
+                    bail!("the range {}..{} is backwards", start, end);
+                }
+                cell(end.saturating_sub(1))?;
+                write!(out, "{}", hexdump::hexdump(&mem[start..end], start))?;
+            }
+            None => {
+                let n = parse_number(range)?;
+                let value = *cell(n)?;
+                match value {
+                    0x20..=0x7e => writeln!(out, "mem[{}] = {} '{}'", n, value, value as char)?,
+                    _ => writeln!(out, "mem[{}] = {}", n, value)?,
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn backtrace(&self, out: &mut dyn Write) -> Result<()> {
+        let pc = self.vm.pc();
+        if pc >= self.listing.len() {
+            bail!("the program ended");
+        }
+
+        for (i, pc) in [pc].into_iter().chain(self.loops()).enumerate() {
+            writeln!(
+                out,
+                "#{}  {}    at {}",
+                i, self.listing[pc], self.spans[pc].0
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn list(&self, out: &mut dyn Write) -> Result<()> {
+        let Some(&(loc, _)) = self.spans.get(self.vm.pc()) else {
+            bail!("the program ended");
+        };
+        let first = loc.line().saturating_sub(LIST_CONTEXT).max(1);
+        let width = (loc.line() + LIST_CONTEXT).to_string().len();
+
+        for (i, text) in self.src.lines().enumerate().skip(first - 1) {
+            let line = i + 1;
+            if line > loc.line() + LIST_CONTEXT {
+                break;
+            }
+
+            let marker = if line == loc.line() { "->" } else { "  " };
+            writeln!(out, "{} {:>width$}  {}", marker, line, text, width = width)?;
+            if line == loc.line() {
+                writeln!(
+                    out,
+                    "   {:width$}  {}^",
+                    "",
+                    caret_indent(text, loc),
+                    width = width
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Whitespace up to the column of `loc` in `text`, tabs kept so the caret lines up.
+fn caret_indent(text: &str, loc: TokenLoc) -> String {
+    text[..lexer::col_offset(text, loc.col())]
+        .chars()
+        .map(|ch| if ch == '\t' { '\t' } else { ' ' })
+        .collect()
+}
+
+fn parse_number(text: &str) -> Result<usize> {
+    text.parse()
+        .map_err(|_| anyhow!("expected a number, got `{}`", text))
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::anyhow;
+
+    use crate::{crashdump::CrashDump, debugger::Debugger, source::Source};
+
+    #[test]
+    fn steps_and_breaks() {
+        let mut debugger = Debugger::new("++\n[->+<]\n>.").unwrap();
+        let mut output = vec![];
+        let mut run = |debugger: &mut Debugger, line: &str| {
+            let mut out = vec![];
+            assert!(debugger
+                .command(line, &mut &b""[..], &mut output, &mut out)
+                .unwrap());
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!(run(&mut debugger, "step"), "0001  JZ 6    at 2:1\n");
+        assert_eq!(run(&mut debugger, "next"), "0007  SHR 1    at 3:1\n");
+        assert_eq!(run(&mut debugger, "print ptr"), "ptr = 0\n");
+        assert_eq!(run(&mut debugger, "p mem[1]"), "mem[1] = 2\n");
+
+        let mut debugger = Debugger::new("++\n[->+<]\n>.").unwrap();
+        assert_eq!(
+            run(&mut debugger, "break 3"),
+            "breakpoint at 0003  SHR 1    at 2:3\n"
+        );
+        assert_eq!(
+            run(&mut debugger, "c"),
+            "breakpoint, 0003  SHR 1    at 2:3\n"
+        );
+        assert_eq!(
+            run(&mut debugger, ""),
+            "breakpoint, 0003  SHR 1    at 2:3\n"
+        );
+        assert_eq!(run(&mut debugger, "p mem[0]"), "mem[0] = 0\n");
+        assert_eq!(
+            run(&mut debugger, "list"),
+            "   1  ++\n-> 2  [->+<]\n        ^\n   3  >.\n"
+        );
+        assert_eq!(run(&mut debugger, "delete"), "");
+        assert_eq!(run(&mut debugger, "continue"), "program ended\n");
+        assert_eq!(
+            run(&mut debugger, "print mem[9"),
+            "error: can print `ptr`, `mem[n]` or `mem[n..m]`\n"
+        );
+        assert!(!debugger
+            .command("quit", &mut &b""[..], &mut vec![], &mut vec![])
+            .unwrap());
+        assert_eq!(output, [2]);
+    }
+
+    #[test]
+    fn hit_counts_and_temporary_breakpoints() {
+        let mut debugger = Debugger::new("+++++[-]").unwrap();
+        let mut run = |line: &str| {
+            let mut out = vec![];
+            debugger
+                .command(line, &mut &b""[..], &mut vec![], &mut out)
+                .unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        run("break 2 if-hit 3");
+        assert_eq!(run("c"), "breakpoint, 0002  SUB 1    at 1:7\n");
+        assert_eq!(run("p mem[0]"), "mem[0] = 3\n");
+        run("tbreak 1:8");
+        assert_eq!(run("c"), "breakpoint, 0003  JNZ 1    at 1:8\n");
+        assert_eq!(run("c"), "program ended\n");
+        assert_eq!(run("delete 1:1"), "error: no breakpoint at 0\n");
+        assert_eq!(run("break 2:1"), "error: no instruction at or after 2:1\n");
+        assert_eq!(run("break"), "0002  SUB 1    hit 5 times, stops at hit 3\n");
+        assert_eq!(run("reverse-step"), "0003  JNZ 1    at 1:8\n");
+        assert_eq!(run("p mem[0]"), "mem[0] = 0\n");
+        assert_eq!(run("rc"), "breakpoint, 0002  SUB 1    at 1:7\n");
+        assert_eq!(run("p mem[0]"), "mem[0] = 1\n");
+        assert_eq!(
+            run("rs 100"),
+            "start of the program, 0000  ADD 5    at 1:1\n"
+        );
+        assert_eq!(
+            run("break 2 if-hit"),
+            "error: expected `LOC`, or `LOC if-hit M`\n"
+        );
+    }
+
+    #[test]
+    fn finish_runs_to_the_end_of_the_loop() {
+        let mut debugger = Debugger::new("++[>+++[-]<-]>.").unwrap();
+        let mut run = |line: &str| {
+            let mut out = vec![];
+            debugger
+                .command(line, &mut &b""[..], &mut vec![], &mut out)
+                .unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!(run("finish"), "error: not in a loop\n");
+        assert_eq!(run("step 5"), "0005  SUB 1    at 1:9\n");
+        assert_eq!(run("finish"), "0007  SHL 1    at 1:11\n");
+        assert_eq!(run("p mem[0]"), "mem[0] = 2\n");
+        assert_eq!(run("f"), "0010  SHR 1    at 1:14\n");
+        assert_eq!(run("p mem[0]"), "mem[0] = 0\n");
+    }
+
+    #[test]
+    fn looks_at_crash_dumps() {
+        let source = Source::unnamed("+[>+\n  [>+]\n]".to_string());
+        let dump = CrashDump::capture(&source, 8, b"", b"", &anyhow!("failed")).unwrap();
+        let mut debugger = Debugger::from_core(&CrashDump::parse(&dump.text()).unwrap()).unwrap();
+        assert!(debugger.core().unwrap().starts_with("memory overflowed"));
+        let mut run = |line: &str| {
+            let mut out = vec![];
+            debugger
+                .command(line, &mut &b""[..], &mut vec![], &mut out)
+                .unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!(
+            run("bt"),
+            "#0  0005  SHR 1    at 2:4\n#1  0004  JZ 7    at 2:3\n#2  0001  JZ 8    at 1:2\n"
+        );
+        assert_eq!(run("p ptr"), "ptr = 7\n");
+        assert_eq!(run("p mem[7]"), "mem[7] = 1\n");
+        assert_eq!(
+            run("step"),
+            "error: a crash dump can only be looked at, not run\n"
+        );
+    }
+
+    #[test]
+    fn reverse_reads_input_and_writes_output_once() {
+        let mut debugger = Debugger::new(",.,.").unwrap();
+        let (mut input, mut output) = (&b"ab"[..], vec![]);
+        let mut run = |line: &str| {
+            let mut out = vec![];
+            debugger
+                .command(line, &mut input, &mut output, &mut out)
+                .unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        run("c");
+        assert_eq!(run("rs 3"), "0001  OUT 1    at 1:2\n");
+        assert_eq!(run("c"), "program ended\n");
+        assert_eq!(output, b"ab");
+    }
+}
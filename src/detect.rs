@@ -0,0 +1,100 @@
+/*
+ *  Telling the language of a program that doesn't say, by the extension of its file and else by what
+ *  it holds.
+ *
+ *      .bf .b          Brainfuck
+ *      .ook .blub      Ook!, Blub
+ *      .spoon          Spoon
+ *      .png            Brainloller, or files starting with the PNG signature
+ *      .bfc            bytecode from `bf compile`, or files starting with `bytecode::MAGIC`
+ *
+ *  Text is Ook! or Blub when it has their words and every Brainfuck command in it is part of one,
+ *  Spoon when it has bits, reads as Spoon and has no Brainfuck commands. Anything else is Brainfuck.
+ */
+
+use std::path::Path;
+
+use crate::{bytecode, lexer, ook, spoon};
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Brainfuck,
+    Ook,
+    Blub,
+    Spoon,
+    Brainloller,
+    Bytecode,
+}
+
+/// The format of the file at `path` holding `bytes`, see `from_extension` and `sniff`.
+pub fn detect(path: Option<&str>, bytes: &[u8]) -> Format {
+    path.and_then(from_extension)
+        .unwrap_or_else(|| sniff(bytes))
+}
+
+/// The format the extension of `path` stands for, if any.
+pub fn from_extension(path: &str) -> Option<Format> {
+    Some(match Path::new(path).extension()?.to_str()? {
+        "bf" | "b" => Format::Brainfuck,
+        "ook" => Format::Ook,
+        "blub" => Format::Blub,
+        "spoon" => Format::Spoon,
+        "png" => Format::Brainloller,
+        "bfc" => Format::Bytecode,
+        _ => return None,
+    })
+}
+
+/// The format `bytes` look like.
+pub fn sniff(bytes: &[u8]) -> Format {
+    if bytes.starts_with(bytecode::MAGIC) {
+        return Format::Bytecode;
+    }
+    if bytes.starts_with(PNG_SIGNATURE) {
+        return Format::Brainloller;
+    }
+    let Ok(text) = std::str::from_utf8(bytes) else {
+        return Format::Brainfuck;
+    };
+
+    for (word, format) in [(ook::OOK, Format::Ook), (ook::BLUB, Format::Blub)] {
+        let rest = [".", "?", "!"].iter().fold(text.to_string(), |rest, end| {
+            rest.replace(&format!("{}{}", word, end), "")
+        });
+        if rest.len() < text.len() && lexer::parse(&rest).is_empty() {
+            return format;
+        }
+    }
+
+    let body = &text[lexer::shebang_len(bytes)..];
+    if body.contains(['0', '1']) && lexer::parse(text).is_empty() && spoon::parse(text).is_ok() {
+        return Format::Spoon;
+    }
+
+    Format::Brainfuck
+}
+
+#[cfg(test)]
+mod test {
+    use crate::detect::{detect, sniff, Format};
+
+    #[test]
+    fn tells_formats_apart() {
+        assert_eq!(detect(Some("hello.b"), b"Ook. Ook?"), Format::Brainfuck);
+        assert_eq!(detect(Some("hello.ook"), b""), Format::Ook);
+        assert_eq!(detect(Some("hello.txt"), b"1 1 010"), Format::Spoon);
+        assert_eq!(detect(None, b"BFC\0\x01"), Format::Bytecode);
+
+        assert_eq!(sniff(b"\x89PNG\r\n\x1a\n\0"), Format::Brainloller);
+        assert_eq!(sniff(b"Ook. Ook? Ook! Ook!\n"), Format::Ook);
+        assert_eq!(sniff(b"Blub. Blub? 1 of them"), Format::Blub);
+        // A Brainfuck command outside of the words.
+        assert_eq!(sniff(b"Ook. Ook? +"), Format::Brainfuck);
+        assert_eq!(sniff(b"#!/usr/bin/env bf\n+[-]"), Format::Brainfuck);
+        // Bits that aren't a whole code.
+        assert_eq!(sniff(b"version 2.0"), Format::Brainfuck);
+        assert_eq!(sniff(b"\xff+"), Format::Brainfuck);
+    }
+}
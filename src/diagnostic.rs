@@ -0,0 +1,306 @@
+/*
+ *  Diagnostics: errors and the warnings of `bf lint` as data, for tools reading `--format json`,
+ *  or rendered for people with the line the error is on and a caret under the spot.
+ *  Parse errors know where they are, and so do runtime errors once `vm::locate_error` found where they
+ *  happened, with the loops they happened in as notes. Anything else (I/O errors) only has a message.
+ */
+
+use crate::{
+    lexer::{self, TokenLoc},
+    lint::Warning,
+    parser::ParseError,
+    vm::RuntimeError,
+};
+
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+const BLUE: &str = "\x1b[1;34m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Severity {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        }
+    }
+
+    fn color(self) -> &'static str {
+        match self {
+            Severity::Error => RED,
+            Severity::Warning => YELLOW,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// The rule of a warning of `bf lint`, such as `dead-loop`.
+    pub code: Option<String>,
+    pub message: String,
+    pub file: Option<String>,
+    pub loc: Option<TokenLoc>,
+    /// The line `loc` is on.
+    pub snippet: Option<String>,
+    /// More about where the error is, printed after it.
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    /// A warning of the linter, `snippet` the line it is on.
+    pub fn from_warning(warning: &Warning, file: Option<&str>, snippet: Option<&str>) -> Self {
+        Self {
+            severity: Severity::Warning,
+            code: Some(warning.rule.name().to_string()),
+            message: warning.message.clone(),
+            file: file.map(Into::into),
+            loc: Some(warning.loc),
+            snippet: snippet.map(Into::into),
+            notes: vec![],
+        }
+    }
+
+    pub fn from_error(err: &anyhow::Error) -> Self {
+        if let Some(err) = err.downcast_ref::<RuntimeError>() {
+            let notes = err.loops.iter().map(|(file, loc)| match file {
+                Some(file) => format!("in the loop at {}:{}", file, loc),
+                None => format!("in the loop at {}", loc),
+            });
+
+            return Self {
+                severity: Severity::Error,
+                code: None,
+                message: err.message.clone(),
+                file: err.file.clone(),
+                loc: Some(err.loc),
+                snippet: err.snippet.clone(),
+                notes: notes.collect(),
+            };
+        }
+
+        match err.downcast_ref::<ParseError>() {
+            Some(err) => Self {
+                severity: Severity::Error,
+                code: None,
+                message: err.message(),
+                file: err.file.clone(),
+                loc: Some(err.loc),
+                snippet: err.snippet.clone(),
+                notes: vec![],
+            },
+            None => Self {
+                severity: Severity::Error,
+                code: None,
+                message: err.to_string(),
+                file: None,
+                loc: None,
+                snippet: None,
+                notes: vec![],
+            },
+        }
+    }
+
+    /// For people, `color` adds ANSI colors:
+    ///
+    /// ```text
+    /// error: unclosed delimiter '['
+    ///  --> main.bf:2:2
+    ///   |
+    /// 2 | >[
+    ///   |  ^
+    /// ```
+    ///
+    /// followed by a `= note: ...` line per note.
+    pub fn render(&self, color: bool) -> String {
+        let paint = |style: &str, text: &str| match color {
+            true => format!("{}{}{}", style, text, RESET),
+            false => text.to_string(),
+        };
+
+        let severity = match &self.code {
+            Some(code) => format!("{}[{}]", self.severity.as_str(), code),
+            None => self.severity.as_str().to_string(),
+        };
+        let mut out = format!(
+            "{}{}\n",
+            paint(self.severity.color(), &severity),
+            paint(BOLD, &format!(": {}", self.message))
+        );
+
+        let Some(loc) = self.loc else {
+            return out;
+        };
+
+        let at = match &self.file {
+            Some(file) => format!("{}:{}", file, loc),
+            None => loc.to_string(),
+        };
+        let number = loc.line().to_string();
+        let gutter = " ".repeat(number.len());
+        out += &format!("{}{} {}\n", gutter, paint(BLUE, "-->"), at);
+
+        let bar = paint(BLUE, "|");
+        let notes = self
+            .notes
+            .iter()
+            .map(|note| format!("{} {} note: {}\n", gutter, paint(BLUE, "="), note))
+            .collect::<String>();
+
+        let Some(line) = &self.snippet else {
+            return out + &notes;
+        };
+
+        let line = line.trim_end_matches('\r');
+
+        // As wide as the line up to the column, tabs kept so the caret lines up.
+        let pad: String = line[..lexer::col_offset(line, loc.col())]
+            .chars()
+            .filter(|&ch| ch != '\u{feff}')
+            .map(|ch| if ch == '\t' { '\t' } else { ' ' })
+            .collect();
+
+        out += &format!("{} {}\n", gutter, bar);
+        out += &format!("{} {} {}\n", paint(BLUE, &number), bar, line);
+        out += &format!(
+            "{} {} {}{}\n",
+            gutter,
+            bar,
+            pad,
+            paint(self.severity.color(), "^")
+        );
+
+        out + &notes
+    }
+
+    /// One JSON object, fields not known are null.
+    pub fn to_json(&self) -> String {
+        let number = |n: Option<usize>| n.map_or("null".to_string(), |n| n.to_string());
+
+        let notes: Vec<String> = self.notes.iter().map(|note| json_string(note)).collect();
+
+        format!(
+            "{{\"severity\":\"{}\",\"code\":{},\"message\":{},\"file\":{},\"line\":{},\"column\":{},\"notes\":[{}]}}",
+            self.severity.as_str(),
+            self.code.as_deref().map_or("null".to_string(), json_string),
+            json_string(&self.message),
+            self.file.as_deref().map_or("null".to_string(), json_string),
+            number(self.loc.map(|loc| loc.line())),
+            number(self.loc.map(|loc| loc.col())),
+            notes.join(","),
+        )
+    }
+}
+
+pub fn json_string(s: &str) -> String {
+    let mut out = String::from('"');
+
+    for ch in s.chars() {
+        match ch {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            ch if (ch as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", ch as u32)),
+            ch => out.push(ch),
+        }
+    }
+    out.push('"');
+
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        diagnostic::Diagnostic,
+        ir::ProgramIr,
+        lexer, parser,
+        reference::Reference,
+        source::Source,
+        vm::{self, RuntimeError, Vm},
+    };
+
+    #[test]
+    fn diagnostics_as_json_and_text() {
+        let mut source = Source::default();
+        source.push("a \"b\".bf", "+\n[[]");
+
+        let err = source.locate_error(parser::parse(lexer::parse(&source.text)).unwrap_err());
+        assert_eq!(
+            Diagnostic::from_error(&err).to_json(),
+            r#"{"severity":"error","code":null,"message":"unclosed delimiter '['","file":"a \"b\".bf","line":2,"column":1,"notes":[]}"#
+        );
+
+        let expected = "error: unclosed delimiter '['
+ --> a \"b\".bf:2:1
+  |
+2 | [[]
+  | ^
+";
+        assert_eq!(Diagnostic::from_error(&err).render(false), expected);
+
+        let err = anyhow::anyhow!("memory overflowed");
+        assert_eq!(
+            Diagnostic::from_error(&err).to_json(),
+            r#"{"severity":"error","code":null,"message":"memory overflowed","file":null,"line":null,"column":null,"notes":[]}"#
+        );
+    }
+
+    #[test]
+    fn runtime_errors_point_at_loops() {
+        let mut source = Source::default();
+        source.push("a.bf", "+\n[>+\n  [>+]\n]");
+
+        let (program, map) = vm::compile_with_map(&source.text).unwrap();
+        let mut vm = Vm::from_program(program).unwrap();
+        let err = vm.run_with(&mut &b""[..], &mut vec![]).unwrap_err();
+        let err = vm::locate_error(err, vm.program(), &map, vm.pc());
+        let err = source.locate_error(err);
+        assert!(err.is::<vm::MemoryError>());
+
+        let expected = "error: memory overflowed: 30000 items => 0 items
+ --> a.bf:3:4
+  |
+3 |   [>+]
+  |    ^
+  = note: in the loop at a.bf:3:3
+  = note: in the loop at a.bf:2:1
+";
+        assert_eq!(Diagnostic::from_error(&err).render(false), expected);
+    }
+
+    #[test]
+    fn runtime_errors_point_at_the_failing_command() {
+        let at = |err: anyhow::Error| err.downcast::<RuntimeError>().unwrap().loc.to_string();
+
+        // The reference interpreter runs the program unoptimized: it fails on the `>` going past
+        // the last cell, the VM on the `+` the balanced loop adds to at an offset.
+        for (src, input, loc, reference_loc) in [
+            ("+\n[\n  >\n  +\n]", &b""[..], "3:3", "3:3"),
+            (",[.,]", b"abc", "1:4", "1:4"),
+            (">>>>+", b"", "1:1", "1:1"),
+            ("+[->>>>+<<<<]", b"", "1:8", "1:4"),
+        ] {
+            let (program, map) = vm::compile_with_map(src).unwrap();
+            let mut vm = Vm::from_program(program).unwrap();
+            vm.set_tape_size(4).unwrap();
+            let err = vm.run_with(&mut &input[..], &mut vec![]).unwrap_err();
+            let err = vm::locate_error(err, vm.program(), &map, vm.pc());
+            assert_eq!(at(err), loc, "{}", src);
+
+            let (program, map) = vm::compile_tokens_with_map(lexer::parse(src)).unwrap();
+            let mut reference = Reference::with_tape_size(4);
+            reference.load(ProgramIr::from_program(&program).unwrap().body);
+            let err = reference.run(&mut &input[..], &mut vec![]).unwrap_err();
+            let err = vm::locate_error(err, &program, &map, reference.pc());
+            assert_eq!(at(err), reference_loc, "{}", src);
+        }
+    }
+}
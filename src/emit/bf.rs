@@ -0,0 +1,192 @@
+/*
+ *  Brainfuck backend: the optimized program written back as the eight commands, to run on any other
+ *  interpreter. Sets become `[-]` and adds, fills a `[-]` for each cell, a fused multiplication loop
+ *  and the clear after it the loop again. Offsets are moves: the pointer only goes where a cell is
+ *  read or written, the moves between two of them are summed like the optimizer does, which only
+ *  behaves differently at the edges of the tape.
+ */
+
+use anyhow::{bail, Result};
+
+use crate::{
+    ir::{self, Ir, ProgramIr, Step},
+    parser::Program,
+};
+
+pub fn emit(program: &Program) -> Result<String> {
+    let ir = ProgramIr::from_program(program)?;
+    let mut out = String::new();
+
+    // Where the pointer of the Brainfuck program is, from the pointer of the optimized one.
+    let mut at = 0;
+    let mut steps = ir::walk(&ir.body).peekable();
+
+    while let Some(step) = steps.next() {
+        let node = match step {
+            Step::Node(node) => node,
+            Step::End { offset, .. } => {
+                move_to(&mut out, &mut at, offset);
+                out.push(']');
+                continue;
+            }
+        };
+
+        match *node {
+            Ir::Add { offset, value, .. } => {
+                move_to(&mut out, &mut at, offset);
+                add(&mut out, value);
+            }
+            Ir::Shift { amount, .. } => at -= amount,
+            Ir::Set { offset, value, .. } => {
+                move_to(&mut out, &mut at, offset);
+                out.push_str("[-]");
+                add(&mut out, value);
+            }
+            Ir::Fill {
+                offset, len, value, ..
+            } => {
+                for cell in offset..offset + len as isize {
+                    move_to(&mut out, &mut at, cell);
+                    out.push_str("[-]");
+                    add(&mut out, value);
+                }
+            }
+            Ir::MulAdd { .. } => {
+                // The loop the optimizer fused: its adds, then the clear of the cell counting down.
+                let mut adds = vec![node];
+                while let Some(&Step::Node(node @ Ir::MulAdd { offset, .. })) = steps.peek() {
+                    if *offset == 0 {
+                        break;
+                    }
+                    adds.push(node);
+                    steps.next();
+                }
+                let cleared = matches!(
+                    steps.next(),
+                    Some(Step::Node(Ir::Set {
+                        offset: 0,
+                        value: 0,
+                        ..
+                    }))
+                );
+                if !cleared || node.offset() == Some(0) {
+                    bail!(
+                        "a multiplication without the clear after it can't be written as Brainfuck"
+                    );
+                }
+
+                move_to(&mut out, &mut at, 0);
+                out.push_str("[-");
+                for node in adds {
+                    if let Ir::MulAdd { offset, factor, .. } = *node {
+                        move_to(&mut out, &mut at, offset);
+                        add(&mut out, factor);
+                    }
+                }
+                move_to(&mut out, &mut at, 0);
+                out.push(']');
+            }
+            Ir::Input { offset, count, .. } => {
+                move_to(&mut out, &mut at, offset);
+                repeat(&mut out, ',', count);
+            }
+            Ir::Output { offset, count, .. } => {
+                move_to(&mut out, &mut at, offset);
+                repeat(&mut out, '.', count);
+            }
+            Ir::Loop { offset, .. } => {
+                move_to(&mut out, &mut at, offset);
+                out.push('[');
+            }
+        }
+    }
+    out.push('\n');
+
+    Ok(out)
+}
+
+fn repeat(out: &mut String, ch: char, count: usize) {
+    out.extend(std::iter::repeat_n(ch, count));
+}
+
+fn move_to(out: &mut String, at: &mut isize, offset: isize) {
+    match offset < *at {
+        true => repeat(out, '<', at.abs_diff(offset)),
+        false => repeat(out, '>', at.abs_diff(offset)),
+    }
+    *at = offset;
+}
+
+fn add(out: &mut String, value: u8) {
+    match value > 128 {
+        true => repeat(out, '-', 256 - value as usize),
+        false => repeat(out, '+', value as usize),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        emit::bf,
+        examples,
+        ir::{Ir, ProgramIr},
+        vm::{self, Vm},
+    };
+
+    fn run(src: &str, input: &[u8]) -> Vec<u8> {
+        let program = crate::parser::parse(crate::lexer::parse(src)).unwrap();
+        let mut vm = Vm::from_program(program).unwrap();
+        let mut output = vec![];
+        vm.run_with(&mut &input[..], &mut output).unwrap();
+        output
+    }
+
+    #[test]
+    fn emits_brainfuck() {
+        let program = vm::compile("+[->+++<]>.>,[-]<<<[>]>>>++++[>]").unwrap();
+        assert_eq!(
+            bf::emit(&program).unwrap(),
+            "+[->+++<]>.>,[-]<<<[>]>>>++++[>]\n"
+        );
+
+        let program = ProgramIr::new(vec![
+            Ir::Add {
+                offset: 2,
+                value: 255,
+                span: None,
+            },
+            Ir::shift(1),
+            Ir::MulAdd {
+                offset: -1,
+                factor: 2,
+                span: None,
+            },
+            Ir::MulAdd {
+                offset: 2,
+                factor: 254,
+                span: None,
+            },
+            Ir::set(0),
+            Ir::Fill {
+                offset: 1,
+                len: 2,
+                value: 3,
+                span: None,
+            },
+        ])
+        .to_program();
+        assert_eq!(
+            bf::emit(&program).unwrap(),
+            ">>-<[-<++>>>--<<]>[-]+++>[-]+++\n"
+        );
+    }
+
+    #[test]
+    fn runs_the_same() {
+        for name in ["hello", "rot13", "sierpinski"] {
+            let src = examples::find(name).unwrap().src;
+            let emitted = bf::emit(&vm::compile(src).unwrap()).unwrap();
+            assert_eq!(run(&emitted, b"Hello\n"), run(src, b"Hello\n"), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,141 @@
+/*
+ *  C backend.
+ *  Emits a standalone C file with the same behavior as the VM: `<` stops at the first cell, going past
+ *  the last one and running out of input are errors. Cells reached through offsets get a margin on both
+ *  sides of the tape instead of bounds checks.
+ */
+
+use std::fmt::Write;
+
+use anyhow::Result;
+
+use crate::{
+    emit::{indent, max_offset},
+    ir::{self, Ir, ProgramIr, Step},
+    parser::Program,
+};
+
+const PRELUDE: &str = r#"#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+static unsigned char tape[MARGIN + TAPE_SIZE + MARGIN];
+
+static void fail(const char *msg) {
+    fprintf(stderr, "error: %s\n", msg);
+    exit(1);
+}
+
+static unsigned char *right(unsigned char *p, size_t n) {
+    if ((size_t)(p - tape - MARGIN) + n >= TAPE_SIZE) fail("memory overflowed");
+    return p + n;
+}
+
+static unsigned char *left(unsigned char *p, size_t n) {
+    return (size_t)(p - tape - MARGIN) < n ? tape + MARGIN : p - n;
+}
+
+static void input(unsigned char *cell) {
+    int ch = getchar();
+    if (ch == EOF) fail("failed to fill whole buffer");
+    *cell = (unsigned char)ch;
+}
+
+static void output(unsigned char ch, size_t count) {
+    while (count--) putchar(ch);
+}
+
+int main(void) {
+    unsigned char *p = tape + MARGIN;
+
+"#;
+
+pub fn emit(program: &Program, tape_size: usize) -> Result<String> {
+    let ir = ProgramIr::from_program(program)?;
+    let mut out = String::new();
+
+    writeln!(out, "/* Generated by bf {} */", env!("CARGO_PKG_VERSION"))?;
+    writeln!(out, "#define TAPE_SIZE {}", tape_size)?;
+    writeln!(out, "#define MARGIN {}", max_offset(&ir.body))?;
+    out.push_str(PRELUDE);
+
+    emit_body(&mut out, &ir.body)?;
+
+    out.push_str("\n    fflush(stdout);\n    return 0;\n}\n");
+
+    Ok(out)
+}
+
+fn emit_body(out: &mut String, body: &[Ir]) -> Result<()> {
+    // The body of `main` is a level deep already.
+    let mut depth = 1;
+
+    for step in ir::walk(body) {
+        let node = match step {
+            Step::Node(node) => node,
+            Step::End { .. } => {
+                depth -= 1;
+                indent(out, depth);
+                out.push_str("}\n");
+                continue;
+            }
+        };
+
+        indent(out, depth);
+        match *node {
+            Ir::Add { offset, value, .. } => writeln!(out, "p[{}] += {};", offset, value)?,
+            Ir::Shift { amount, .. } if amount < 0 => writeln!(out, "p = left(p, {});", -amount)?,
+            Ir::Shift { amount, .. } => writeln!(out, "p = right(p, {});", amount)?,
+            Ir::Set { offset, value, .. } => writeln!(out, "p[{}] = {};", offset, value)?,
+            Ir::MulAdd { offset, factor, .. } => {
+                writeln!(out, "p[{}] += p[0] * {};", offset, factor)?
+            }
+            Ir::Input { offset, .. } => writeln!(out, "input(&p[{}]);", offset)?,
+            Ir::Output { offset, count, .. } => writeln!(out, "output(p[{}], {});", offset, count)?,
+            Ir::Fill {
+                offset, len, value, ..
+            } => writeln!(out, "memset(p + {}, {}, {});", offset, value, len)?,
+            Ir::Loop { offset, .. } => {
+                writeln!(out, "while (p[{}]) {{", offset)?;
+                depth += 1;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{emit::c, vm};
+
+    #[test]
+    fn emits_structured_c() {
+        let program = vm::compile("+[->+++<]>.>,[-]<<<[>]").unwrap();
+        let src = c::emit(&program, 100).unwrap();
+
+        assert!(src.contains("#define TAPE_SIZE 100\n#define MARGIN 1\n"));
+
+        let body = src.split("tape + MARGIN;\n\n").nth(1).unwrap();
+        let expected = "    p[0] += 1;
+    while (p[0]) {
+        p[0] += 255;
+        p[1] += 3;
+    }
+    p = right(p, 1);
+    output(p[0], 1);
+    p = right(p, 1);
+    input(&p[0]);
+    p[0] = 0;
+    p = left(p, 3);
+    while (p[0]) {
+        p = right(p, 1);
+    }
+
+    fflush(stdout);
+    return 0;
+}
+";
+        assert_eq!(body, expected);
+    }
+}
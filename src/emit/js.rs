@@ -0,0 +1,151 @@
+/*
+ *  JavaScript backend.
+ *  Emits an ES module exporting `run(input: Uint8Array): Uint8Array`. Behaves like the C backend: `<`
+ *  stops at the first cell, going past the last one and running out of input throw, offsets are covered
+ *  by margins around the tape.
+ */
+
+use std::fmt::Write;
+
+use anyhow::Result;
+
+use crate::{
+    emit::{indent, max_offset},
+    ir::{self, Ir, ProgramIr, Step},
+    parser::Program,
+};
+
+const PRELUDE: &str = r#"
+export function run(input = new Uint8Array(0)) {
+    const tape = new Uint8Array(MARGIN + TAPE_SIZE + MARGIN);
+    const out = [];
+    let inputPos = 0;
+    let p = MARGIN;
+
+    const end = MARGIN + TAPE_SIZE;
+    const overflow = () => {
+        throw new Error("memory overflowed");
+    };
+    const read = () => {
+        if (inputPos >= input.length) throw new Error("failed to fill whole buffer");
+        return input[inputPos++];
+    };
+    const output = (ch, count) => {
+        for (let i = 0; i < count; i++) out.push(ch);
+    };
+
+"#;
+
+pub fn emit(program: &Program, tape_size: usize) -> Result<String> {
+    let ir = ProgramIr::from_program(program)?;
+    let mut out = String::new();
+
+    writeln!(out, "// Generated by bf {}", env!("CARGO_PKG_VERSION"))?;
+    writeln!(out, "const TAPE_SIZE = {};", tape_size)?;
+    writeln!(out, "const MARGIN = {};", max_offset(&ir.body))?;
+    out.push_str(PRELUDE);
+
+    emit_body(&mut out, &ir.body)?;
+
+    out.push_str("\n    return Uint8Array.from(out);\n}\n");
+
+    Ok(out)
+}
+
+/// `p + offset`, written the way a person would.
+fn index(offset: isize) -> String {
+    match offset {
+        0 => "p".to_string(),
+        offset if offset < 0 => format!("p - {}", -offset),
+        offset => format!("p + {}", offset),
+    }
+}
+
+fn cell(offset: isize) -> String {
+    format!("tape[{}]", index(offset))
+}
+
+fn emit_body(out: &mut String, body: &[Ir]) -> Result<()> {
+    // The body of `run` is a level deep already.
+    let mut depth = 1;
+
+    for step in ir::walk(body) {
+        let node = match step {
+            Step::Node(node) => node,
+            Step::End { .. } => {
+                depth -= 1;
+                indent(out, depth);
+                out.push_str("}\n");
+                continue;
+            }
+        };
+
+        indent(out, depth);
+        match *node {
+            Ir::Add { offset, value, .. } => writeln!(out, "{} += {};", cell(offset), value)?,
+            // The pointer lives in a local, moving it inline keeps it out of any closure.
+            Ir::Shift { amount, .. } if amount < 0 => {
+                writeln!(out, "p = Math.max(p - {}, MARGIN);", -amount)?
+            }
+            Ir::Shift { amount, .. } => writeln!(out, "if ((p += {}) >= end) overflow();", amount)?,
+            Ir::Set { offset, value, .. } => writeln!(out, "{} = {};", cell(offset), value)?,
+            Ir::MulAdd { offset, factor, .. } => {
+                writeln!(out, "{} += tape[p] * {};", cell(offset), factor)?
+            }
+            Ir::Input { offset, .. } => writeln!(out, "{} = read();", cell(offset))?,
+            Ir::Output { offset, count, .. } => {
+                writeln!(out, "output({}, {});", cell(offset), count)?
+            }
+            Ir::Fill {
+                offset, len, value, ..
+            } => writeln!(
+                out,
+                "tape.fill({}, {}, {});",
+                value,
+                index(offset),
+                index(offset + len as isize)
+            )?,
+            Ir::Loop { offset, .. } => {
+                writeln!(out, "while ({}) {{", cell(offset))?;
+                depth += 1;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{emit::js, vm};
+
+    #[test]
+    fn emits_es_module() {
+        let program = vm::compile("+[->+++<]>.>,[-]<<<[>]").unwrap();
+        let src = js::emit(&program, 100).unwrap();
+
+        assert!(src.contains("const TAPE_SIZE = 100;\nconst MARGIN = 1;\n"));
+        assert!(src.contains("export function run(input = new Uint8Array(0)) {"));
+
+        let body = src.split("    };\n\n").last().unwrap();
+        let expected = "    tape[p] += 1;
+    while (tape[p]) {
+        tape[p] += 255;
+        tape[p + 1] += 3;
+    }
+    if ((p += 1) >= end) overflow();
+    output(tape[p], 1);
+    if ((p += 1) >= end) overflow();
+    tape[p] = read();
+    tape[p] = 0;
+    p = Math.max(p - 3, MARGIN);
+    while (tape[p]) {
+        if ((p += 1) >= end) overflow();
+    }
+
+    return Uint8Array.from(out);
+}
+";
+        assert_eq!(body, expected);
+    }
+}
@@ -0,0 +1,364 @@
+/*
+ *  LLVM backend.
+ *  Lowers the IR to textual LLVM IR and drives the LLVM tools on the `PATH`: `opt` for optimization,
+ *  `llc` for object files and `lli` to JIT the program. Behaves like the C backend, errors print the
+ *  VM's message and exit with status 1.
+ *
+ *  The IR uses typed pointers (`i8*`), the syntax every LLVM up to 16 reads.
+ */
+
+use std::{
+    env,
+    fmt::Write,
+    fs,
+    path::{Path, PathBuf},
+    process::{self, Command},
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{
+    emit::max_offset,
+    ir::{self, Ir, ProgramIr, Step},
+    parser::Program,
+};
+
+const OVERFLOW_MSG: &str = "error: memory overflowed\n";
+const EOF_MSG: &str = "error: failed to fill whole buffer\n";
+
+const PRELUDE: &str = r#"declare i32 @putchar(i32)
+declare i32 @getchar()
+declare i64 @write(i32, i8*, i64)
+declare void @exit(i32) noreturn
+declare void @llvm.memset.p0i8.i64(i8*, i8, i64, i1)
+
+define internal void @fail(i8* %msg, i64 %len) noreturn cold {
+  call i64 @write(i32 2, i8* %msg, i64 %len)
+  call void @exit(i32 1)
+  unreachable
+}
+
+define internal void @output(i8 %ch, i64 %count) {
+entry:
+  %c = zext i8 %ch to i32
+  br label %loop
+loop:
+  %i = phi i64 [0, %entry], [%next, %body]
+  %done = icmp eq i64 %i, %count
+  br i1 %done, label %exit, label %body
+body:
+  call i32 @putchar(i32 %c)
+  %next = add i64 %i, 1
+  br label %loop
+exit:
+  ret void
+}
+
+define i32 @main() {
+entry:
+  %p = alloca i64
+"#;
+
+pub fn emit(program: &Program, tape_size: usize) -> Result<String> {
+    let ir = ProgramIr::from_program(program)?;
+    let margin = max_offset(&ir.body);
+
+    let mut main = Function {
+        out: String::new(),
+        next: 0,
+        tape: format!("[{} x i8]", margin + tape_size + margin),
+        margin,
+        end: margin + tape_size,
+    };
+    main.body(&ir.body)?;
+
+    let mut out = String::new();
+    writeln!(out, "; Generated by bf {}", env!("CARGO_PKG_VERSION"))?;
+    writeln!(out, "@tape = internal global {} zeroinitializer", main.tape)?;
+    for (name, msg) in [("overflow_msg", OVERFLOW_MSG), ("eof_msg", EOF_MSG)] {
+        writeln!(
+            out,
+            "@{} = private constant [{} x i8] c\"{}\\0A\"",
+            name,
+            msg.len(),
+            msg.trim_end()
+        )?;
+    }
+    out.push('\n');
+    out.push_str(PRELUDE);
+    writeln!(out, "  store i64 {}, i64* %p", margin)?;
+    out.push_str(&main.out);
+    out.push_str("  ret i32 0\n");
+
+    for (label, name, msg) in [
+        ("overflow", "overflow_msg", OVERFLOW_MSG),
+        ("eof", "eof_msg", EOF_MSG),
+    ] {
+        let len = msg.len();
+        writeln!(out, "{}:", label)?;
+        writeln!(
+            out,
+            "  call void @fail(i8* getelementptr inbounds ([{len} x i8], [{len} x i8]* @{name}, i64 0, i64 0), i64 {len})",
+            len = len,
+            name = name
+        )?;
+        out.push_str("  unreachable\n");
+    }
+    out.push_str("}\n");
+
+    Ok(out)
+}
+
+struct Function {
+    out: String,
+    next: usize,
+    /// Type of `@tape`, margins included.
+    tape: String,
+    /// Index of the first tape cell.
+    margin: usize,
+    /// Index one past the last tape cell.
+    end: usize,
+}
+
+impl Function {
+    /// Emits `body`, going through its loops with `ir::walk`: they nest as deep as the program does.
+    fn body(&mut self, body: &[Ir]) -> Result<()> {
+        // The head and exit labels of each open loop.
+        let mut loops = vec![];
+
+        for step in ir::walk(body) {
+            let node = match step {
+                Step::Node(node) => node,
+                Step::End { .. } => {
+                    let (head, exit) = loops.pop().unwrap();
+                    writeln!(self.out, "  br label %{}", head)?;
+                    writeln!(self.out, "{}:", exit)?;
+                    continue;
+                }
+            };
+
+            match *node {
+                Ir::Add { offset, value, .. } => {
+                    let cell = self.cell(offset)?;
+                    let (old, new) = (self.tmp(), self.tmp());
+                    writeln!(self.out, "  {} = load i8, i8* {}", old, cell)?;
+                    writeln!(self.out, "  {} = add i8 {}, {}", new, old, value)?;
+                    writeln!(self.out, "  store i8 {}, i8* {}", new, cell)?;
+                }
+                Ir::Shift { amount, .. } if amount < 0 => {
+                    // p = max(p - amount, margin), `<` stops at the first cell.
+                    let (p, moved, below, clamped) =
+                        (self.tmp(), self.tmp(), self.tmp(), self.tmp());
+                    let margin = self.margin;
+                    writeln!(self.out, "  {} = load i64, i64* %p", p)?;
+                    writeln!(self.out, "  {} = sub i64 {}, {}", moved, p, -amount)?;
+                    writeln!(self.out, "  {} = icmp slt i64 {}, {}", below, moved, margin)?;
+                    writeln!(
+                        self.out,
+                        "  {} = select i1 {}, i64 {}, i64 {}",
+                        clamped, below, margin, moved
+                    )?;
+                    writeln!(self.out, "  store i64 {}, i64* %p", clamped)?;
+                }
+                Ir::Shift { amount, .. } => {
+                    let (p, moved, over) = (self.tmp(), self.tmp(), self.tmp());
+                    let next = self.label();
+                    writeln!(self.out, "  {} = load i64, i64* %p", p)?;
+                    writeln!(self.out, "  {} = add i64 {}, {}", moved, p, amount)?;
+                    writeln!(self.out, "  store i64 {}, i64* %p", moved)?;
+                    writeln!(
+                        self.out,
+                        "  {} = icmp uge i64 {}, {}",
+                        over, moved, self.end
+                    )?;
+                    writeln!(
+                        self.out,
+                        "  br i1 {}, label %overflow, label %{}",
+                        over, next
+                    )?;
+                    writeln!(self.out, "{}:", next)?;
+                }
+                Ir::Set { offset, value, .. } => {
+                    let cell = self.cell(offset)?;
+                    writeln!(self.out, "  store i8 {}, i8* {}", value, cell)?;
+                }
+                Ir::MulAdd { offset, factor, .. } => {
+                    let (source, target) = (self.cell(0)?, self.cell(offset)?);
+                    let (value, product, old, new) =
+                        (self.tmp(), self.tmp(), self.tmp(), self.tmp());
+                    writeln!(self.out, "  {} = load i8, i8* {}", value, source)?;
+                    writeln!(self.out, "  {} = mul i8 {}, {}", product, value, factor)?;
+                    writeln!(self.out, "  {} = load i8, i8* {}", old, target)?;
+                    writeln!(self.out, "  {} = add i8 {}, {}", new, old, product)?;
+                    writeln!(self.out, "  store i8 {}, i8* {}", new, target)?;
+                }
+                Ir::Input { offset, .. } => {
+                    let (ch, eof, byte) = (self.tmp(), self.tmp(), self.tmp());
+                    let next = self.label();
+                    writeln!(self.out, "  {} = call i32 @getchar()", ch)?;
+                    writeln!(self.out, "  {} = icmp slt i32 {}, 0", eof, ch)?;
+                    writeln!(self.out, "  br i1 {}, label %eof, label %{}", eof, next)?;
+                    writeln!(self.out, "{}:", next)?;
+
+                    let cell = self.cell(offset)?;
+                    writeln!(self.out, "  {} = trunc i32 {} to i8", byte, ch)?;
+                    writeln!(self.out, "  store i8 {}, i8* {}", byte, cell)?;
+                }
+                Ir::Output { offset, count, .. } => {
+                    let cell = self.cell(offset)?;
+                    let ch = self.tmp();
+                    writeln!(self.out, "  {} = load i8, i8* {}", ch, cell)?;
+                    writeln!(self.out, "  call void @output(i8 {}, i64 {})", ch, count)?;
+                }
+                Ir::Fill {
+                    offset, len, value, ..
+                } => {
+                    let cell = self.cell(offset)?;
+                    writeln!(
+                        self.out,
+                        "  call void @llvm.memset.p0i8.i64(i8* {}, i8 {}, i64 {}, i1 false)",
+                        cell, value, len
+                    )?;
+                }
+                Ir::Loop { offset, .. } => {
+                    let (head, inner, exit) = (self.label(), self.label(), self.label());
+                    writeln!(self.out, "  br label %{}", head)?;
+                    writeln!(self.out, "{}:", head)?;
+
+                    let cell = self.cell(offset)?;
+                    let (value, nonzero) = (self.tmp(), self.tmp());
+                    writeln!(self.out, "  {} = load i8, i8* {}", value, cell)?;
+                    writeln!(self.out, "  {} = icmp ne i8 {}, 0", nonzero, value)?;
+                    writeln!(
+                        self.out,
+                        "  br i1 {}, label %{}, label %{}",
+                        nonzero, inner, exit
+                    )?;
+                    writeln!(self.out, "{}:", inner)?;
+                    loops.push((head, exit));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Emits the address of the cell at `offset` from the pointer and returns its name.
+    fn cell(&mut self, offset: isize) -> Result<String> {
+        let (p, index, cell) = (self.tmp(), self.tmp(), self.tmp());
+        writeln!(self.out, "  {} = load i64, i64* %p", p)?;
+        writeln!(self.out, "  {} = add i64 {}, {}", index, p, offset)?;
+        writeln!(
+            self.out,
+            "  {} = getelementptr inbounds {tape}, {tape}* @tape, i64 0, i64 {}",
+            cell,
+            index,
+            tape = self.tape
+        )?;
+
+        Ok(cell)
+    }
+
+    fn tmp(&mut self) -> String {
+        self.next += 1;
+        format!("%t{}", self.next)
+    }
+
+    fn label(&mut self) -> String {
+        self.next += 1;
+        format!("l{}", self.next)
+    }
+}
+
+/// Runs an LLVM tool, `LLVM_BIN` overrides where they are looked up.
+fn tool(name: &str) -> Command {
+    match env::var_os("LLVM_BIN") {
+        Some(dir) => Command::new(Path::new(&dir).join(name)),
+        None => Command::new(name),
+    }
+}
+
+fn check(command: &mut Command, name: &str) -> Result<()> {
+    let status = command
+        .status()
+        .with_context(|| format!("could not run `{}`, is LLVM installed?", name))?;
+
+    if !status.success() {
+        bail!("`{}` failed with {}", name, status);
+    }
+
+    Ok(())
+}
+
+/// Writes `ir` to a temporary file, removed again when dropped.
+struct TempIr(PathBuf);
+
+impl TempIr {
+    fn new(ir: &str) -> Result<Self> {
+        let path = env::temp_dir().join(format!("bf-{}.ll", process::id()));
+        fs::write(&path, ir)?;
+
+        Ok(Self(path))
+    }
+}
+
+impl Drop for TempIr {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Optimizes `ir` with `opt -O3` and writes an object file to `out`.
+pub fn compile_object(ir: &str, out: &Path) -> Result<()> {
+    let input = TempIr::new(ir)?;
+    let optimized = input.0.with_extension("bc");
+
+    let result = check(
+        tool("opt")
+            .arg("-O3")
+            .arg(&input.0)
+            .arg("-o")
+            .arg(&optimized),
+        "opt",
+    )
+    .and_then(|_| {
+        check(
+            tool("llc")
+                .args(["-O3", "-filetype=obj", "-relocation-model=pic"])
+                .arg(&optimized)
+                .arg("-o")
+                .arg(out),
+            "llc",
+        )
+    });
+
+    let _ = fs::remove_file(optimized);
+
+    result
+}
+
+/// JIT compiles and runs `ir` with `lli`, the program inherits stdin and stdout.
+pub fn run(ir: &str) -> Result<()> {
+    let input = TempIr::new(ir)?;
+
+    check(tool("lli").arg("-O3").arg(&input.0), "lli")
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{emit::llvm, vm};
+
+    #[test]
+    fn emits_module() {
+        let program = vm::compile("+[->+++<]>.>,[-]<<<[>]").unwrap();
+        let ir = llvm::emit(&program, 100).unwrap();
+
+        assert!(ir.contains("@tape = internal global [102 x i8] zeroinitializer\n"));
+        assert!(ir.contains("  store i64 1, i64* %p\n"));
+        assert!(ir.contains("icmp uge i64 %t"));
+        assert!(ir.contains(", 101\n"));
+        assert!(ir.contains("call i32 @getchar()"));
+        assert!(ir.contains("call void @output(i8 %t"));
+        assert_eq!(ir.matches("icmp ne i8").count(), 2);
+    }
+}
@@ -0,0 +1,74 @@
+/*
+ *  Ahead of time backends: turn an optimized program into C, JavaScript, LLVM IR, a WebAssembly
+ *  module or Brainfuck again.
+ */
+
+pub mod bf;
+pub mod c;
+pub mod js;
+#[cfg(feature = "llvm")]
+pub mod llvm;
+pub mod wasm;
+
+use crate::ir::{self, Ir, Step};
+
+/// Loops nest as deep as the program does: past this depth the lines of the C and JavaScript backends
+/// are not indented any further, so the output grows with the program and not with its depth squared.
+const MAX_INDENT: usize = 16;
+
+/// Indents a line `depth` loops deep.
+fn indent(out: &mut String, depth: usize) {
+    for _ in 0..depth.min(MAX_INDENT) {
+        out.push_str("    ");
+    }
+}
+
+/// Largest distance from the pointer any node of `block` reads or writes, used to size tape margins.
+pub fn max_offset(block: &[Ir]) -> usize {
+    ir::walk(block)
+        .filter_map(|step| match step {
+            Step::Node(node) => Some(node),
+            Step::End { .. } => None,
+        })
+        .map(|node| match *node {
+            Ir::Fill { offset, len, .. } => offset
+                .unsigned_abs()
+                .max((offset + len as isize - 1).unsigned_abs()),
+            ref node => node.offset().map_or(0, isize::unsigned_abs),
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+
+    use crate::{
+        emit::{bf, c, js, wasm},
+        lexer, parser,
+    };
+
+    /// Loops nest as deep as the program does: writing them can't take stack for every one, and the
+    /// lines of a deep loop aren't indented as deep as it is.
+    #[test]
+    fn deep_nesting_is_not_recursed_into() {
+        let deep = || {
+            let depth = 100_000;
+            let src = format!(",{}.{}", "[".repeat(depth), "]".repeat(depth));
+            let program = parser::parse(lexer::parse(&src)).unwrap();
+
+            assert_eq!(bf::emit(&program).unwrap(), src + "\n");
+            assert!(c::emit(&program, 100).unwrap().len() < 200 * depth);
+            assert!(js::emit(&program, 100).unwrap().len() < 200 * depth);
+            assert!(!wasm::emit(&program, 100).unwrap().is_empty());
+        };
+
+        thread::Builder::new()
+            .stack_size(256 << 10)
+            .spawn(deep)
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+}
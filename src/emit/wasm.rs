@@ -0,0 +1,367 @@
+/*
+ *  WebAssembly backend.
+ *  Emits a binary module exporting its linear memory as `memory` and a `run` function. The host provides
+ *  `env.output(ch, count)` and `env.input() -> i32`, returning a negative value at the end of input.
+ *  Like the C backend, offsets are covered by margins around the tape; going past its last cell and
+ *  running out of input trap.
+ */
+
+use anyhow::{bail, Result};
+
+use crate::{
+    emit::max_offset,
+    ir::{self, Ir, ProgramIr, Step},
+    parser::Program,
+};
+
+const PAGE_SIZE: usize = 65536;
+
+const OUTPUT_FUNC: u32 = 0;
+const INPUT_FUNC: u32 = 1;
+const RUN_FUNC: u32 = 2;
+
+/// Locals of `run`: the pointer and a scratch value.
+const PTR: u32 = 0;
+const TMP: u32 = 1;
+
+mod op {
+    pub const UNREACHABLE: u8 = 0x00;
+    pub const BLOCK: u8 = 0x02;
+    pub const LOOP: u8 = 0x03;
+    pub const IF: u8 = 0x04;
+    pub const END: u8 = 0x0b;
+    pub const BR_IF: u8 = 0x0d;
+    pub const CALL: u8 = 0x10;
+    pub const LOCAL_GET: u8 = 0x20;
+    pub const LOCAL_SET: u8 = 0x21;
+    pub const LOCAL_TEE: u8 = 0x22;
+    pub const I32_LOAD8_U: u8 = 0x2d;
+    pub const I32_STORE8: u8 = 0x3a;
+    pub const I32_CONST: u8 = 0x41;
+    pub const I32_EQZ: u8 = 0x45;
+    pub const I32_LT_S: u8 = 0x48;
+    pub const I32_GE_U: u8 = 0x4f;
+    pub const I32_ADD: u8 = 0x6a;
+    pub const I32_SUB: u8 = 0x6b;
+    pub const I32_MUL: u8 = 0x6c;
+    pub const PREFIX_FC: u8 = 0xfc;
+    pub const MEMORY_FILL: u32 = 11;
+    pub const EMPTY_TYPE: u8 = 0x40;
+}
+
+const I32: u8 = 0x7f;
+const FUNC_TYPE: u8 = 0x60;
+
+pub fn emit(program: &Program, tape_size: usize) -> Result<Vec<u8>> {
+    let ir = ProgramIr::from_program(program)?;
+    let margin = max_offset(&ir.body);
+    let memory_size = margin + tape_size + margin;
+
+    if memory_size > i32::MAX as usize {
+        bail!("tape of {} cells does not fit in wasm memory", tape_size);
+    }
+
+    let mut body = Function {
+        code: vec![],
+        margin: margin as i32,
+        end: (margin + tape_size) as i32,
+    };
+    body.const_i32(margin as i32);
+    body.local(op::LOCAL_SET, PTR);
+    body.body(&ir.body);
+    body.code.push(op::END);
+
+    let mut module = b"\0asm".to_vec();
+    module.extend_from_slice(&1u32.to_le_bytes());
+
+    // Types: output, input, run.
+    section(&mut module, 1, |s| {
+        uleb(s, 3);
+        s.extend_from_slice(&[FUNC_TYPE, 2, I32, I32, 0]);
+        s.extend_from_slice(&[FUNC_TYPE, 0, 1, I32]);
+        s.extend_from_slice(&[FUNC_TYPE, 0, 0]);
+    });
+
+    section(&mut module, 2, |s| {
+        uleb(s, 2);
+        for (name, ty) in [("output", 0), ("input", 1)] {
+            string(s, "env");
+            string(s, name);
+            s.push(0x00);
+            uleb(s, ty);
+        }
+    });
+
+    section(&mut module, 3, |s| {
+        uleb(s, 1);
+        uleb(s, 2);
+    });
+
+    section(&mut module, 5, |s| {
+        uleb(s, 1);
+        s.push(0x00);
+        uleb(s, memory_size.div_ceil(PAGE_SIZE) as u64);
+    });
+
+    section(&mut module, 7, |s| {
+        uleb(s, 2);
+        string(s, "memory");
+        s.push(0x02);
+        uleb(s, 0);
+        string(s, "run");
+        s.push(0x00);
+        uleb(s, RUN_FUNC as u64);
+    });
+
+    section(&mut module, 10, |s| {
+        uleb(s, 1);
+
+        let mut func = vec![];
+        uleb(&mut func, 1);
+        uleb(&mut func, 2);
+        func.push(I32);
+        func.extend_from_slice(&body.code);
+
+        uleb(s, func.len() as u64);
+        s.extend_from_slice(&func);
+    });
+
+    Ok(module)
+}
+
+struct Function {
+    code: Vec<u8>,
+    /// Address of the first tape cell.
+    margin: i32,
+    /// Address one past the last tape cell.
+    end: i32,
+}
+
+impl Function {
+    /// Emits `body`, going through its loops with `ir::walk`: they nest as deep as the program does.
+    fn body(&mut self, body: &[Ir]) {
+        for step in ir::walk(body) {
+            let node = match step {
+                Step::Node(node) => node,
+                Step::End { offset, .. } => {
+                    self.load(offset);
+                    self.code
+                        .extend_from_slice(&[op::BR_IF, 0, op::END, op::END]);
+                    continue;
+                }
+            };
+
+            match *node {
+                Ir::Add { offset, value, .. } => {
+                    let at = self.address(offset);
+                    self.load(offset);
+                    self.const_i32(value as i32);
+                    self.code.push(op::I32_ADD);
+                    self.store(at);
+                }
+                Ir::Shift { amount, .. } if amount < 0 => {
+                    // p = max(p - amount, margin), `<` stops at the first cell.
+                    self.local(op::LOCAL_GET, PTR);
+                    self.const_i32(-amount as i32);
+                    self.code.push(op::I32_SUB);
+                    self.local(op::LOCAL_TEE, PTR);
+                    self.const_i32(self.margin);
+                    self.code
+                        .extend_from_slice(&[op::I32_LT_S, op::IF, op::EMPTY_TYPE]);
+                    self.const_i32(self.margin);
+                    self.local(op::LOCAL_SET, PTR);
+                    self.code.push(op::END);
+                }
+                Ir::Shift { amount, .. } => {
+                    self.local(op::LOCAL_GET, PTR);
+                    self.const_i32(amount as i32);
+                    self.code.push(op::I32_ADD);
+                    self.local(op::LOCAL_TEE, PTR);
+                    self.const_i32(self.end);
+                    self.code.push(op::I32_GE_U);
+                    self.trap_if();
+                }
+                Ir::Set { offset, value, .. } => {
+                    let at = self.address(offset);
+                    self.const_i32(value as i32);
+                    self.store(at);
+                }
+                Ir::MulAdd { offset, factor, .. } => {
+                    let at = self.address(offset);
+                    self.load(offset);
+                    self.load(0);
+                    self.const_i32(factor as i32);
+                    self.code.extend_from_slice(&[op::I32_MUL, op::I32_ADD]);
+                    self.store(at);
+                }
+                Ir::Input { offset, .. } => {
+                    let at = self.address(offset);
+                    self.call(INPUT_FUNC);
+                    self.local(op::LOCAL_TEE, TMP);
+                    self.const_i32(0);
+                    self.code.push(op::I32_LT_S);
+                    self.trap_if();
+                    self.local(op::LOCAL_GET, TMP);
+                    self.store(at);
+                }
+                Ir::Output { offset, count, .. } => {
+                    self.load(offset);
+                    self.const_i32(count as i32);
+                    self.call(OUTPUT_FUNC);
+                }
+                Ir::Fill {
+                    offset, len, value, ..
+                } => {
+                    self.local(op::LOCAL_GET, PTR);
+                    self.const_i32(offset as i32);
+                    self.code.push(op::I32_ADD);
+                    self.const_i32(value as i32);
+                    self.const_i32(len as i32);
+                    self.code.push(op::PREFIX_FC);
+                    uleb(&mut self.code, op::MEMORY_FILL as u64);
+                    self.code.push(0x00);
+                }
+                Ir::Loop { offset, .. } => {
+                    // block { br_if 0 (!cell); loop { body; br_if 0 (cell) } }, the end when the
+                    // walk gets there.
+                    self.code.extend_from_slice(&[op::BLOCK, op::EMPTY_TYPE]);
+                    self.load(offset);
+                    self.code.push(op::I32_EQZ);
+                    self.code.extend_from_slice(&[op::BR_IF, 0]);
+                    self.code.extend_from_slice(&[op::LOOP, op::EMPTY_TYPE]);
+                }
+            }
+        }
+    }
+
+    /// Pushes the base address for the cell at `offset`, returns the static offset to use with it.
+    fn address(&mut self, offset: isize) -> u32 {
+        self.local(op::LOCAL_GET, PTR);
+
+        // Memory argument offsets are unsigned, cells to the left need the subtraction done here.
+        if offset < 0 {
+            self.const_i32(offset as i32);
+            self.code.push(op::I32_ADD);
+            0
+        } else {
+            offset as u32
+        }
+    }
+
+    fn load(&mut self, offset: isize) {
+        let at = self.address(offset);
+        self.code.push(op::I32_LOAD8_U);
+        self.memarg(at);
+    }
+
+    fn store(&mut self, at: u32) {
+        self.code.push(op::I32_STORE8);
+        self.memarg(at);
+    }
+
+    fn memarg(&mut self, offset: u32) {
+        // Alignment of 1 byte, stored as its log2.
+        self.code.push(0);
+        uleb(&mut self.code, offset as u64);
+    }
+
+    fn trap_if(&mut self) {
+        self.code
+            .extend_from_slice(&[op::IF, op::EMPTY_TYPE, op::UNREACHABLE, op::END]);
+    }
+
+    fn call(&mut self, func: u32) {
+        self.code.push(op::CALL);
+        uleb(&mut self.code, func as u64);
+    }
+
+    fn local(&mut self, instr: u8, local: u32) {
+        self.code.push(instr);
+        uleb(&mut self.code, local as u64);
+    }
+
+    fn const_i32(&mut self, value: i32) {
+        self.code.push(op::I32_CONST);
+        sleb(&mut self.code, value as i64);
+    }
+}
+
+fn section(module: &mut Vec<u8>, id: u8, write: impl FnOnce(&mut Vec<u8>)) {
+    let mut contents = vec![];
+    write(&mut contents);
+
+    module.push(id);
+    uleb(module, contents.len() as u64);
+    module.extend_from_slice(&contents);
+}
+
+fn string(out: &mut Vec<u8>, s: &str) {
+    uleb(out, s.len() as u64);
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn uleb(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+
+        if value == 0 {
+            return out.push(byte);
+        }
+
+        out.push(byte | 0x80);
+    }
+}
+
+fn sleb(out: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+
+        let done = (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0);
+        if done {
+            return out.push(byte);
+        }
+
+        out.push(byte | 0x80);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        emit::wasm::{self, sleb, uleb},
+        vm,
+    };
+
+    #[test]
+    fn leb128() {
+        let encode = |f: fn(&mut Vec<u8>, i64), value| {
+            let mut out = vec![];
+            f(&mut out, value);
+            out
+        };
+        let uleb = |out: &mut Vec<u8>, value: i64| uleb(out, value as u64);
+
+        assert_eq!(encode(uleb, 0), [0x00]);
+        assert_eq!(encode(uleb, 624485), [0xe5, 0x8e, 0x26]);
+        assert_eq!(encode(sleb, 63), [0x3f]);
+        assert_eq!(encode(sleb, 64), [0xc0, 0x00]);
+        assert_eq!(encode(sleb, -1), [0x7f]);
+        assert_eq!(encode(sleb, -123456), [0xc0, 0xbb, 0x78]);
+    }
+
+    #[test]
+    fn emits_module() {
+        let program = vm::compile("+[->+++<]>.>,[-]<<<[>]").unwrap();
+        let module = wasm::emit(&program, 100).unwrap();
+
+        assert_eq!(&module[..8], b"\0asm\x01\0\0\0");
+
+        let has = |needle: &[u8]| module.windows(needle.len()).any(|w| w == needle);
+        assert!(has(b"\x03env\x06output"));
+        assert!(has(b"\x03env\x05input"));
+        assert!(has(b"\x06memory\x02\x00"));
+        assert!(has(b"\x03run\x00\x02"));
+    }
+}
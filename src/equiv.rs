@@ -0,0 +1,299 @@
+/*
+ *  Equivalence of two programs on inputs, for `bf equiv`: both are run on each input, unoptimized on
+ *  the VM, and what they write, how they end and the tape they leave are compared. The first input
+ *  they differ on is reported with how.
+ *
+ *  A program running more than `max_steps` commands is stopped. Only the output written until then
+ *  is compared: the programs differ when one of them wrote a byte the other wrote something else in
+ *  its place, otherwise the input is left. Inputs come from files, or are generated from a seed: the
+ *  empty input, lines of printable characters and bytes of any value.
+ */
+
+use std::{fmt, sync::Arc};
+
+use anyhow::{bail, Result};
+
+use crate::{
+    fuzz::Rng,
+    parser::Program,
+    vm::{Observer, Vm},
+};
+
+#[derive(Debug, Clone, Copy)]
+pub struct Options {
+    pub max_steps: u64,
+    pub tape_size: usize,
+    /// Compare what they write and how they end only.
+    pub output_only: bool,
+}
+
+/// How a program ended on an input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Ended,
+    Failed(String),
+    TooLong,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Outcome::Ended => write!(f, "ended"),
+            Outcome::Failed(err) => write!(f, "failed: {}", err),
+            Outcome::TooLong => write!(f, "ran past the step limit"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Run {
+    pub outcome: Outcome,
+    pub output: Vec<u8>,
+    /// The tape, without the zeros at its end.
+    pub memory: Vec<u8>,
+}
+
+/// How two runs differ.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Divergence {
+    Outcome(Outcome, Outcome),
+    /// The byte at `at` of the output, None past its end.
+    Output {
+        at: usize,
+        a: Option<u8>,
+        b: Option<u8>,
+    },
+    Memory {
+        cell: usize,
+        a: u8,
+        b: u8,
+    },
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let byte = |byte: Option<u8>| match byte {
+            Some(byte) => format!("{:?}", byte as char),
+            None => "nothing".to_string(),
+        };
+
+        match self {
+            Divergence::Outcome(a, b) => write!(f, "the first {}, the second {}", a, b),
+            Divergence::Output { at, a, b } => write!(
+                f,
+                "byte {} of the output is {} from the first, {} from the second",
+                at,
+                byte(*a),
+                byte(*b)
+            ),
+            Divergence::Memory { cell, a, b } => write!(
+                f,
+                "cell {} is {} after the first, {} after the second",
+                cell, a, b
+            ),
+        }
+    }
+}
+
+pub fn run(program: &Arc<Program>, input: &[u8], options: &Options) -> Result<Run> {
+    let mut vm = Vm::from_shared(program.clone())?;
+    vm.set_tape_size(options.tape_size)?;
+    let mut limit = Limit {
+        max_steps: options.max_steps,
+        steps: 0,
+    };
+
+    let mut output = vec![];
+    let outcome = match vm.run_observed(&mut &input[..], &mut output, &mut limit) {
+        _ if limit.steps > options.max_steps => Outcome::TooLong,
+        Ok(()) => Outcome::Ended,
+        Err(err) => Outcome::Failed(err.to_string()),
+    };
+    let used = vm
+        .mem()
+        .iter()
+        .rposition(|&cell| cell != 0)
+        .map_or(0, |i| i + 1);
+
+    Ok(Run {
+        outcome,
+        output,
+        memory: vm.mem()[..used].to_vec(),
+    })
+}
+
+/// How `a` and `b` differ, None if they don't or one of them ran too long without writing
+/// something else.
+pub fn compare(a: &Run, b: &Run, options: &Options) -> Option<Divergence> {
+    let too_long = a.outcome == Outcome::TooLong || b.outcome == Outcome::TooLong;
+
+    let at = a.output.iter().zip(&b.output).position(|(a, b)| a != b);
+    let at = match at {
+        Some(at) => Some(at),
+        None if too_long || a.output.len() == b.output.len() => None,
+        None => Some(a.output.len().min(b.output.len())),
+    };
+    if let Some(at) = at {
+        return Some(Divergence::Output {
+            at,
+            a: a.output.get(at).copied(),
+            b: b.output.get(at).copied(),
+        });
+    }
+    if too_long {
+        return None;
+    }
+
+    // Programs failing the same way can fail with other messages, such as on other cells.
+    let same = match (&a.outcome, &b.outcome) {
+        (Outcome::Failed(_), Outcome::Failed(_)) => true,
+        (a, b) => a == b,
+    };
+    if !same {
+        return Some(Divergence::Outcome(a.outcome.clone(), b.outcome.clone()));
+    }
+
+    if options.output_only {
+        return None;
+    }
+    let len = a.memory.len().max(b.memory.len());
+    let cell = |memory: &[u8], i: usize| memory.get(i).copied().unwrap_or(0);
+    (0..len)
+        .find(|&i| cell(&a.memory, i) != cell(&b.memory, i))
+        .map(|i| Divergence::Memory {
+            cell: i,
+            a: cell(&a.memory, i),
+            b: cell(&b.memory, i),
+        })
+}
+
+/// `count` inputs of `seed`: the empty one, then lines of printable characters and bytes of any
+/// value in turn, up to 32 bytes long.
+pub fn generate_inputs(seed: u64, count: usize) -> Vec<Vec<u8>> {
+    let mut rng = Rng::new(seed);
+
+    (0..count)
+        .map(|i| {
+            let len = match i {
+                0 => 0,
+                _ => 1 + rng.below(32),
+            };
+            match i % 2 {
+                0 => (0..len).map(|_| rng.next_u64() as u8).collect(),
+                _ => {
+                    let mut line: Vec<u8> = (1..len).map(|_| b' ' + rng.below(95) as u8).collect();
+                    line.push(b'\n');
+                    line
+                }
+            }
+        })
+        .collect()
+}
+
+#[derive(Debug, Default)]
+pub struct Summary {
+    /// Inputs both programs were compared on.
+    pub compared: usize,
+    /// Inputs left, a program running too long.
+    pub left: usize,
+    /// The first input they differ on, with its name, and how.
+    pub divergence: Option<(String, Vec<u8>, Divergence)>,
+}
+
+/// Runs `a` and `b`, unoptimized, on the named inputs until they differ.
+pub fn check(
+    a: Program,
+    b: Program,
+    inputs: &[(String, Vec<u8>)],
+    options: &Options,
+) -> Result<Summary> {
+    let (a, b) = (Arc::new(a), Arc::new(b));
+    let mut summary = Summary::default();
+
+    for (name, input) in inputs {
+        let (run_a, run_b) = (run(&a, input, options)?, run(&b, input, options)?);
+        if let Some(divergence) = compare(&run_a, &run_b, options) {
+            summary.divergence = Some((name.clone(), input.clone(), divergence));
+            break;
+        }
+
+        match run_a.outcome == Outcome::TooLong || run_b.outcome == Outcome::TooLong {
+            true => summary.left += 1,
+            false => summary.compared += 1,
+        }
+    }
+
+    Ok(summary)
+}
+
+/// Stops the run past `max_steps`.
+struct Limit {
+    max_steps: u64,
+    steps: u64,
+}
+
+impl Observer for Limit {
+    fn instruction(&mut self, _: &Vm) -> Result<()> {
+        self.steps += 1;
+        if self.steps > self.max_steps {
+            bail!("ran more than {} steps", self.max_steps);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        equiv::{self, Divergence, Options, Outcome},
+        lexer, parser,
+    };
+
+    const OPTIONS: Options = Options {
+        max_steps: 10_000,
+        tape_size: 100,
+        output_only: false,
+    };
+
+    fn check(a: &str, b: &str) -> Option<Divergence> {
+        let inputs: Vec<_> = equiv::generate_inputs(1, 20)
+            .into_iter()
+            .enumerate()
+            .map(|(i, input)| (i.to_string(), input))
+            .collect();
+        let parse = |src| parser::parse(lexer::parse(src)).unwrap();
+        equiv::check(parse(a), parse(b), &inputs, &OPTIONS)
+            .unwrap()
+            .divergence
+            .map(|(_, _, divergence)| divergence)
+    }
+
+    #[test]
+    fn finds_where_programs_differ() {
+        // The same echo, one with a loop moving the byte to print it.
+        assert_eq!(check(",[.,]", ",[>+<-]>[<+>-]<[.,]"), None);
+        assert_eq!(
+            check("++++++++[>++++++++<-]>+.", "+++++++[>+++++++++<-]>+++."),
+            Some(Divergence::Output {
+                at: 0,
+                a: Some(b'A'),
+                b: Some(b'B')
+            })
+        );
+        assert_eq!(
+            check("+.", "+.>+<"),
+            Some(Divergence::Memory {
+                cell: 1,
+                a: 0,
+                b: 1
+            })
+        );
+        assert!(matches!(
+            check("+.", "+.[>+]"),
+            Some(Divergence::Outcome(Outcome::Ended, Outcome::Failed(_)))
+        ));
+        // Both run forever writing the same.
+        assert_eq!(check("+[.]", "+[.>+<]"), None);
+    }
+}
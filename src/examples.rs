@@ -0,0 +1,64 @@
+/*
+ *  Example programs built into the binary, for `bf examples`.
+ */
+
+pub struct Example {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub src: &'static str,
+}
+
+pub static EXAMPLES: [Example; 5] = [
+    Example {
+        name: "hello",
+        description: "prints Hello World!",
+        src: include_str!("../examples/hello.bf"),
+    },
+    Example {
+        name: "cat",
+        description: "copies a line of input to the output",
+        src: include_str!("../examples/cat.bf"),
+    },
+    Example {
+        name: "rot13",
+        description: "ROT13 of a line of input",
+        src: include_str!("../examples/rot13.bf"),
+    },
+    Example {
+        name: "mandelbrot",
+        description: "draws the Mandelbrot set, takes a few seconds",
+        src: include_str!("../examples/mandelbrot.bf"),
+    },
+    Example {
+        name: "sierpinski",
+        description: "draws a Sierpinski triangle",
+        src: include_str!("../examples/sierpinski.bf"),
+    },
+];
+
+pub fn find(name: &str) -> Option<&'static Example> {
+    EXAMPLES.iter().find(|example| example.name == name)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{examples, vm::Vm};
+
+    #[test]
+    fn examples_run() {
+        let run = |name: &str, input: &[u8]| {
+            let mut output = vec![];
+            Vm::new(examples::find(name).unwrap().src)
+                .unwrap()
+                .run_with(&mut &input[..], &mut output)
+                .unwrap();
+            String::from_utf8(output).unwrap()
+        };
+
+        assert_eq!(run("hello", b""), "Hello World!\n");
+        assert_eq!(run("cat", b"a line\n"), "a line\n");
+        assert_eq!(run("rot13", b"Hello, World!\n"), "Uryyb, Jbeyq!\n");
+        assert_eq!(run("sierpinski", b"").lines().count(), 32);
+        assert!(examples::find("nope").is_none());
+    }
+}
@@ -0,0 +1,102 @@
+/*
+ *  Programs embedded in other documents, for `--extract`: the code blocks of Markdown fenced with
+ *  ```bf or ```brainfuck, and in any text the lines between a line with `bf-begin` and one with
+ *  `bf-end`, such as `<!-- bf-begin -->` or `# bf-end`. Everything else is taken out but the line
+ *  breaks, so the lines and columns of errors are the ones of the document. The blocks make one
+ *  program, in order.
+ */
+
+use anyhow::{bail, Result};
+
+/// Info strings of the fenced blocks holding the program.
+const LANGUAGES: [&str; 2] = ["bf", "brainfuck"];
+const BEGIN: &str = "bf-begin";
+const END: &str = "bf-end";
+
+#[derive(Clone, Copy)]
+enum Block<'a> {
+    /// Opened by this fence, such as "```".
+    Fenced(&'a str),
+    /// Between a `bf-begin` line and a `bf-end` one.
+    Markers,
+}
+
+/// The program in `text`, on the lines it is on. Fails if there is none.
+pub fn extract(text: &str) -> Result<String> {
+    let mut out = String::with_capacity(text.len());
+    let mut found = false;
+    let mut inside = None;
+
+    for line in text.split_inclusive('\n') {
+        let trimmed = line.trim();
+        let keep = match inside {
+            Some(Block::Markers) if trimmed.contains(END) => {
+                inside = None;
+                false
+            }
+            Some(Block::Fenced(fence)) if closes(trimmed, fence) => {
+                inside = None;
+                false
+            }
+            Some(_) => true,
+            None if trimmed.contains(BEGIN) => {
+                (inside, found) = (Some(Block::Markers), true);
+                false
+            }
+            None => {
+                if let Some(fence) = fence_of(trimmed) {
+                    (inside, found) = (Some(Block::Fenced(fence)), true);
+                }
+                false
+            }
+        };
+
+        match keep {
+            true => out.push_str(line),
+            false => out.extend(line.matches('\n')),
+        }
+    }
+
+    if !found {
+        bail!(
+            "found no code block fenced with ```bf or ```brainfuck, nor a line with `{}`",
+            BEGIN
+        );
+    }
+
+    Ok(out)
+}
+
+/// Whether `line` closes the block opened by `fence`, with at least as many backticks or tildes.
+fn closes(line: &str, fence: &str) -> bool {
+    line.starts_with(fence) && line.trim_start_matches(&fence[..1]).is_empty()
+}
+
+/// The fence of a line opening a block of Brainfuck, such as "```" for "```bf".
+fn fence_of(line: &str) -> Option<&str> {
+    let ch = line.chars().next().filter(|&ch| ch == '`' || ch == '~')?;
+    let len = line.len() - line.trim_start_matches(ch).len();
+    if len < 3 {
+        return None;
+    }
+
+    let (fence, info) = line.split_at(len);
+    let language = info.split_whitespace().next()?;
+    LANGUAGES.contains(&language).then_some(fence)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::extract;
+
+    #[test]
+    fn extracts_blocks() {
+        let text = "# Hello, world.\n\nThe program:\n\n```bf\n++[->+<]\n```\n\n````brainfuck\n```\n.\n````\n\n```c\nx--;\n```\n<!-- bf-begin -->\n  >.\n<!-- bf-end -->\nDone.";
+        assert_eq!(
+            extract::extract(text).unwrap(),
+            "\n\n\n\n\n++[->+<]\n\n\n\n```\n.\n\n\n\n\n\n\n  >.\n\n"
+        );
+        assert_eq!(extract::extract("```bf\n+.").unwrap(), "\n+.");
+        assert!(extract::extract("```rust\n+.\n```\n").is_err());
+    }
+}
@@ -0,0 +1,152 @@
+/*
+ *  File commands, after Brainfuck++: `%` opens the file named by the cells from the pointer up to a
+ *  zero cell, or closes the one open, `^` writes the current cell to it and `!` reads a byte of it
+ *  into the current cell, 0 at its end. One file is open at a time.
+ *
+ *  Programs only get to the files of a directory: a name is a relative path with no `..` in it, and
+ *  where it leads after symlinks has to be in the directory. Files are made if missing and opened at
+ *  their start for reading and writing, unless the directory is read-only, which only opens files that
+ *  exist for reading.
+ */
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Read, Write},
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Result};
+
+#[derive(Debug)]
+pub struct FileIo {
+    /// The directory, canonical.
+    dir: PathBuf,
+    read_only: bool,
+    open: Option<File>,
+}
+
+impl FileIo {
+    pub fn new(dir: impl AsRef<Path>, read_only: bool) -> Result<Self> {
+        let dir = dir.as_ref();
+        let canonical = dir
+            .canonicalize()
+            .map_err(|err| anyhow!("file directory {}: {}", dir.display(), err))?;
+        if !canonical.is_dir() {
+            bail!("{} is not a directory", dir.display());
+        }
+
+        Ok(Self {
+            dir: canonical,
+            read_only,
+            open: None,
+        })
+    }
+
+    /// `%`: closes the file open, or opens the one named `name`.
+    pub fn toggle(&mut self, name: &[u8]) -> Result<()> {
+        if self.open.take().is_some() {
+            return Ok(());
+        }
+
+        let path = self.resolve(name)?;
+        let file = match self.read_only {
+            true => File::open(&path),
+            false => OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path),
+        };
+        self.open = Some(file.map_err(|err| anyhow!("can't open {}: {}", path.display(), err))?);
+
+        Ok(())
+    }
+
+    /// `^`: writes `byte` to the file open.
+    pub fn write(&mut self, byte: u8) -> Result<()> {
+        if self.read_only {
+            bail!("files are read-only");
+        }
+
+        Ok(self.file()?.write_all(&[byte])?)
+    }
+
+    /// `!`: the next byte of the file open, 0 at its end.
+    pub fn read(&mut self) -> Result<u8> {
+        let mut byte = [0];
+        match self.file()?.read(&mut byte)? {
+            0 => Ok(0),
+            _ => Ok(byte[0]),
+        }
+    }
+
+    fn file(&mut self) -> Result<&mut File> {
+        self.open.as_mut().ok_or_else(|| anyhow!("no file is open"))
+    }
+
+    /// The path of the file `name` in the directory.
+    fn resolve(&self, name: &[u8]) -> Result<PathBuf> {
+        let name = std::str::from_utf8(name).map_err(|_| anyhow!("file name is not UTF-8"))?;
+        let relative = Path::new(name);
+        let in_dir = !name.is_empty()
+            && relative
+                .components()
+                .all(|part| matches!(part, Component::Normal(_)));
+        if !in_dir {
+            bail!("`{}` is not the name of a file in the file directory", name);
+        }
+
+        // Symlinks can lead out of the directory, a file to be made is checked by its parent.
+        let path = self.dir.join(relative);
+        let real = match fs::canonicalize(&path) {
+            Ok(real) => real,
+            Err(_) => path
+                .parent()
+                .and_then(|parent| parent.canonicalize().ok())
+                .ok_or_else(|| anyhow!("`{}` is not in the file directory", name))?,
+        };
+        if !real.starts_with(&self.dir) {
+            bail!("`{}` is not in the file directory", name);
+        }
+
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{env, fs, process};
+
+    use crate::fileio::FileIo;
+
+    #[test]
+    fn keeps_to_its_directory() {
+        let dir = env::temp_dir().join(format!("bf-fileio-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut files = FileIo::new(&dir, false).unwrap();
+        assert!(files.write(1).is_err());
+        files.toggle(b"out.txt").unwrap();
+        files.write(b'h').unwrap();
+        files.write(b'i').unwrap();
+        files.toggle(b"").unwrap();
+        assert_eq!(fs::read(dir.join("out.txt")).unwrap(), b"hi");
+
+        files.toggle(b"out.txt").unwrap();
+        assert_eq!(files.read().unwrap(), b'h');
+        assert_eq!(files.read().unwrap(), b'i');
+        assert_eq!(files.read().unwrap(), 0);
+        files.toggle(b"").unwrap();
+
+        for name in ["../out.txt", "/etc/passwd", "", "sub/../out.txt"] {
+            assert!(files.toggle(name.as_bytes()).is_err(), "{}", name);
+        }
+
+        let mut files = FileIo::new(&dir, true).unwrap();
+        assert!(files.toggle(b"new.txt").is_err());
+        files.toggle(b"out.txt").unwrap();
+        assert!(files.write(1).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,260 @@
+/*
+ *  Source formatter.
+ *  Lays loops out by nesting depth: a loop holding other loops or comments gets its brackets on lines
+ *  of their own with the body indented, other loops stay inline (`[->+<]`). Comments go on lines of
+ *  their own, long runs of commands are wrapped. Formatting formatted source changes nothing.
+ */
+
+use std::mem;
+
+use anyhow::Result;
+
+use crate::{lexer, parser};
+
+const INDENT: &str = "    ";
+
+/// Lines are never wrapped shorter than this, however deep the nesting.
+const MIN_WIDTH: usize = 20;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Options {
+    /// Longest line, indentation included.
+    pub width: usize,
+    pub keep_comments: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            width: 80,
+            keep_comments: true,
+        }
+    }
+}
+
+enum Item {
+    Code(String),
+    Comment(String),
+    Loop(Vec<Item>),
+}
+
+/// Loops nest as deep as the source does: their bodies are taken apart one after the other instead
+/// of each one being dropped from inside the one around it.
+impl Drop for Item {
+    fn drop(&mut self) {
+        let Item::Loop(body) = self else {
+            return;
+        };
+
+        let mut items = mem::take(body);
+        while let Some(mut item) = items.pop() {
+            if let Item::Loop(body) = &mut item {
+                items.append(body);
+            }
+        }
+    }
+}
+
+pub fn format(src: &str, options: &Options) -> Result<String> {
+    // Only formats what would run.
+    parser::parse(lexer::parse(src))?;
+
+    // The `#!` line stays first, as it is.
+    let (shebang, src) = src.split_at(lexer::shebang_len(src.as_bytes()));
+    let items = items(&mut src.chars().peekable(), options);
+
+    let mut out = String::new();
+    if !shebang.is_empty() {
+        out.push_str(shebang);
+        out.push('\n');
+    }
+
+    let mut line = String::new();
+    write_block(&mut out, &mut line, &items, options);
+    flush(&mut out, &mut line, 0, options);
+
+    Ok(out)
+}
+
+fn is_command(ch: char) -> bool {
+    "+-<>[].,".contains(ch)
+}
+
+fn items(
+    chars: &mut std::iter::Peekable<impl Iterator<Item = char>>,
+    options: &Options,
+) -> Vec<Item> {
+    // The items of every loop open, the whole source first. The source parsed, brackets match.
+    let mut stack = vec![vec![]];
+
+    while let Some(ch) = chars.next() {
+        let items = stack.last_mut().unwrap();
+
+        match ch {
+            '[' => stack.push(vec![]),
+            ']' => {
+                let body = stack.pop().unwrap();
+                stack.last_mut().unwrap().push(Item::Loop(body));
+            }
+            ch if is_command(ch) => match items.last_mut() {
+                Some(Item::Code(code)) => code.push(ch),
+                _ => items.push(Item::Code(ch.to_string())),
+            },
+            ch => {
+                let mut text = ch.to_string();
+                while let Some(&ch) = chars.peek().filter(|ch| !is_command(**ch)) {
+                    text.push(ch);
+                    chars.next();
+                }
+
+                // One comment per line, blank lines and surrounding space go.
+                if options.keep_comments {
+                    let lines = text.lines().map(str::trim).filter(|line| !line.is_empty());
+                    items.extend(lines.map(|line| Item::Comment(line.to_string())));
+                }
+            }
+        }
+    }
+
+    stack.pop().unwrap()
+}
+
+fn is_flat(items: &[Item]) -> bool {
+    items.iter().all(|item| matches!(item, Item::Code(_)))
+}
+
+fn write_block(out: &mut String, line: &mut String, items: &[Item], options: &Options) {
+    // The items left in every loop gone into, the whole source first: the depth of a block is its
+    // place on the stack.
+    let mut stack = vec![items.iter()];
+
+    while !stack.is_empty() {
+        let depth = stack.len() - 1;
+        let Some(item) = stack[depth].next() else {
+            stack.pop();
+            if depth > 0 {
+                flush(out, line, depth, options);
+                line.push(']');
+                flush(out, line, depth - 1, options);
+            }
+            continue;
+        };
+
+        match item {
+            Item::Code(code) => line.push_str(code),
+            Item::Comment(text) => {
+                flush(out, line, depth, options);
+                out.push_str(&INDENT.repeat(depth));
+                out.push_str(text);
+                out.push('\n');
+            }
+            Item::Loop(body) if is_flat(body) => {
+                line.push('[');
+                for item in body {
+                    if let Item::Code(code) = item {
+                        line.push_str(code);
+                    }
+                }
+                line.push(']');
+            }
+            Item::Loop(body) => {
+                flush(out, line, depth, options);
+                line.push('[');
+                flush(out, line, depth, options);
+
+                stack.push(body.iter());
+            }
+        }
+    }
+}
+
+/// Writes out the pending commands at `depth`, wrapped to the width, if there are any.
+fn flush(out: &mut String, line: &mut String, depth: usize, options: &Options) {
+    let indent = INDENT.repeat(depth);
+    let width = options.width.saturating_sub(indent.len()).max(MIN_WIDTH);
+
+    // Commands are ASCII, splitting by bytes is fine.
+    for chunk in line.as_bytes().chunks(width) {
+        out.push_str(&indent);
+        out.push_str(std::str::from_utf8(chunk).unwrap());
+        out.push('\n');
+    }
+
+    line.clear();
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+
+    use crate::fmt::{self, Options};
+
+    #[test]
+    fn indents_by_nesting() {
+        let options = Options {
+            keep_comments: false,
+            ..Options::default()
+        };
+        let src = "hello ++++[>++[>+<-] comment\n<-]>>.";
+        let expected = "++++
+[
+    >++[>+<-]<-
+]
+>>.
+";
+
+        assert_eq!(fmt::format(src, &options).unwrap(), expected);
+        assert_eq!(fmt::format(expected, &options).unwrap(), expected);
+        assert!(fmt::format("[[]", &options).is_err());
+
+        let script = "#!/usr/bin/env bf\n+.\n";
+        assert_eq!(fmt::format(script, &options).unwrap(), script);
+    }
+
+    #[test]
+    fn keeps_comments_and_wraps() {
+        let options = Options {
+            width: 24,
+            ..Options::default()
+        };
+        let src = "  set up\n\n ++ [ move it\n  >+<-] print:. ";
+        let expected = "set up
+++
+[
+    move it
+    >+<-
+]
+print:
+.
+";
+
+        assert_eq!(fmt::format(src, &options).unwrap(), expected);
+        assert_eq!(fmt::format(expected, &options).unwrap(), expected);
+
+        let long = "+".repeat(50);
+        let wrapped = fmt::format(&long, &options).unwrap();
+        assert_eq!(
+            wrapped.lines().map(str::len).collect::<Vec<_>>(),
+            [24, 24, 2]
+        );
+    }
+
+    #[test]
+    fn formats_deep_nesting() {
+        // Every line is indented as deep as it is, which keeps the depth down, but there is far too
+        // little stack here to take some for every loop.
+        let depth = 2_000;
+        let src = format!("+{}-{}", "[".repeat(depth), "]".repeat(depth));
+        let format = move || fmt::format(&src, &Options::default()).unwrap();
+
+        let formatted = thread::Builder::new()
+            .stack_size(64 << 10)
+            .spawn(format)
+            .unwrap()
+            .join()
+            .unwrap();
+        let lines: Vec<_> = formatted.lines().map(str::trim).collect();
+        assert_eq!(lines.len(), 2 * depth);
+        assert_eq!(lines[depth - 1..depth + 2], ["[", "[-]", "]"]);
+    }
+}
@@ -0,0 +1,156 @@
+/*
+ *  Tape frames, for `--frames DIR`: the tape every N instructions, a file per frame, to make an
+ *  animation of the memory of a run.
+ *
+ *  A frame is the first cells of the tape, as a grayscale PGM image with `WIDTH` cells to a row or as
+ *  CSV with a row per cell. `frames.csv` lists the frames with the instructions run before each and
+ *  the pc and the pointer, which is where a drifting pointer shows.
+ */
+
+use std::{
+    fmt::Write as _,
+    fs,
+    io::{BufWriter, Write},
+    path::PathBuf,
+};
+
+use anyhow::Result;
+
+use crate::vm::{Observer, Vm};
+
+/// Cells in a row of an image.
+pub const WIDTH: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameFormat {
+    Pgm,
+    Csv,
+}
+
+pub struct Frames {
+    dir: PathBuf,
+    format: FrameFormat,
+    every: u64,
+    cells: usize,
+    /// Instructions run so far.
+    instructions: u64,
+    frame: usize,
+    index: BufWriter<fs::File>,
+}
+
+impl Frames {
+    /// Writes the first `cells` cells every `every` instructions, from the first one on, to `dir`,
+    /// made if needed.
+    pub fn new(
+        dir: impl Into<PathBuf>,
+        format: FrameFormat,
+        every: u64,
+        cells: usize,
+    ) -> Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let mut index = BufWriter::new(fs::File::create(dir.join("frames.csv"))?);
+        writeln!(index, "frame,instructions,pc,ptr")?;
+
+        Ok(Self {
+            dir,
+            format,
+            every: every.max(1),
+            cells,
+            instructions: 0,
+            frame: 0,
+            index,
+        })
+    }
+
+    fn write_frame(&mut self, vm: &Vm) -> Result<()> {
+        let mem = vm.mem();
+        let cells = &mem[..self.cells.min(mem.len())];
+
+        let (extension, data) = match self.format {
+            FrameFormat::Pgm => ("pgm", pgm(cells)),
+            FrameFormat::Csv => ("csv", csv(cells)),
+        };
+        let name = format!("frame-{:06}.{}", self.frame, extension);
+        fs::write(self.dir.join(name), data)?;
+
+        writeln!(
+            self.index,
+            "{},{},{},{}",
+            self.frame,
+            self.instructions,
+            vm.pc(),
+            vm.mem_ptr()
+        )?;
+        self.frame += 1;
+
+        Ok(())
+    }
+}
+
+impl Observer for Frames {
+    fn instruction(&mut self, vm: &Vm) -> Result<()> {
+        if self.instructions.is_multiple_of(self.every) {
+            self.write_frame(vm)?;
+        }
+        self.instructions += 1;
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        Ok(self.index.flush()?)
+    }
+}
+
+/// A binary PGM image of `cells`, `WIDTH` to a row, the last row filled up with zeros.
+pub fn pgm(cells: &[u8]) -> Vec<u8> {
+    let height = cells.len().div_ceil(WIDTH).max(1);
+    let mut image = format!("P5\n{} {}\n255\n", WIDTH, height).into_bytes();
+    image.extend_from_slice(cells);
+    image.resize(image.len() + WIDTH * height - cells.len(), 0);
+
+    image
+}
+
+fn csv(cells: &[u8]) -> Vec<u8> {
+    let mut out = "cell,value\n".to_string();
+    for (cell, value) in cells.iter().enumerate() {
+        writeln!(out, "{},{}", cell, value).unwrap();
+    }
+
+    out.into_bytes()
+}
+
+#[cfg(test)]
+mod test {
+    use std::{env, fs, process};
+
+    use crate::{
+        frames::{pgm, FrameFormat, Frames},
+        lexer, parser,
+        vm::Vm,
+    };
+
+    #[test]
+    fn writes_frames() {
+        let image = pgm(&[1, 2, 3]);
+        assert!(image.starts_with(b"P5\n64 1\n255\n\x01\x02\x03\x00"));
+        assert_eq!(image.len(), 12 + 64);
+
+        let dir = env::temp_dir().join(format!("bf-frames-test-{}", process::id()));
+        let mut frames = Frames::new(&dir, FrameFormat::Csv, 2, 3).unwrap();
+        Vm::from_program(parser::parse(lexer::parse("+>++>+++")).unwrap())
+            .unwrap()
+            .run_observed(&mut &b""[..], &mut vec![], &mut frames)
+            .unwrap();
+
+        let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(
+            read("frames.csv"),
+            "frame,instructions,pc,ptr\n0,0,0,0\n1,2,2,1\n2,4,4,2\n"
+        );
+        assert_eq!(read("frame-000002.csv"), "cell,value\n0,1\n1,2\n2,0\n");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,180 @@
+/*
+ *  Frontends: what reads a program in one of the languages bf knows into Brainfuck tokens, each
+ *  with where it is in the program as written.
+ *
+ *  Brainfuck and the dialects adding commands to it are the lexer with an `Extension`. The others
+ *  are read into Brainfuck first, with every command where the program has it (see `lexer::layout`),
+ *  so the rest of the pipeline and its errors work the same for all of them.
+ *
+ *  `FRONTENDS` has one of each, by the name `bf run --dialect` knows it by.
+ */
+
+use anyhow::Result;
+
+use crate::{
+    lexer::{self, Extension, Options},
+    ook,
+    parser::TokenList,
+    spoon,
+};
+
+pub trait Frontend: Sync {
+    /// What `--dialect` calls it.
+    fn name(&self) -> &'static str;
+
+    /// The tokens of `src`, with their locations in it.
+    fn tokenize(&self, src: &[u8]) -> Result<TokenList>;
+
+    /// `src` as Brainfuck, for the languages that aren't Brainfuck with more commands.
+    fn to_brainfuck(&self, _src: &[u8]) -> Result<Option<String>> {
+        Ok(None)
+    }
+}
+
+pub static FRONTENDS: [&dyn Frontend; 9] = [
+    &Extension::None,
+    &Extension::Pbrain,
+    &Extension::Extended1,
+    &Extension::Brainfork,
+    &Extension::MultiTape,
+    &Ook {
+        name: "ook",
+        word: ook::OOK,
+    },
+    &Ook {
+        name: "blub",
+        word: ook::BLUB,
+    },
+    &Spoon,
+    &Brainloller,
+];
+
+/// The frontend called `name`.
+pub fn find(name: &str) -> Option<&'static dyn Frontend> {
+    FRONTENDS
+        .iter()
+        .find(|frontend| frontend.name() == name)
+        .copied()
+}
+
+impl Frontend for Extension {
+    fn name(&self) -> &'static str {
+        match self {
+            Extension::None => "brainfuck",
+            Extension::Pbrain => "pbrain",
+            Extension::Extended1 => "extended1",
+            Extension::Brainfork => "brainfork",
+            Extension::MultiTape => "multi-tape",
+        }
+    }
+
+    fn tokenize(&self, src: &[u8]) -> Result<TokenList> {
+        Ok(lexer::parse_with(
+            std::str::from_utf8(src)?,
+            Options::with_extension(*self),
+        ))
+    }
+}
+
+/// Ook! or one of its variants, `word` standing for `Ook`.
+pub struct Ook {
+    pub name: &'static str,
+    pub word: &'static str,
+}
+
+impl Frontend for Ook {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn tokenize(&self, src: &[u8]) -> Result<TokenList> {
+        ook::parse_with(std::str::from_utf8(src)?, self.word)
+    }
+
+    fn to_brainfuck(&self, src: &[u8]) -> Result<Option<String>> {
+        ook::to_brainfuck_with(std::str::from_utf8(src)?, self.word).map(Some)
+    }
+}
+
+pub struct Spoon;
+
+impl Frontend for Spoon {
+    fn name(&self) -> &'static str {
+        "spoon"
+    }
+
+    fn tokenize(&self, src: &[u8]) -> Result<TokenList> {
+        spoon::parse(std::str::from_utf8(src)?)
+    }
+
+    fn to_brainfuck(&self, src: &[u8]) -> Result<Option<String>> {
+        spoon::to_brainfuck(std::str::from_utf8(src)?).map(Some)
+    }
+}
+
+/// PNG images, the locations of their tokens are in their Brainfuck.
+pub struct Brainloller;
+
+impl Frontend for Brainloller {
+    fn name(&self) -> &'static str {
+        "brainloller"
+    }
+
+    fn tokenize(&self, src: &[u8]) -> Result<TokenList> {
+        let bf = self.to_brainfuck(src)?.unwrap_or_default();
+
+        Ok(lexer::parse(&bf))
+    }
+
+    #[cfg(feature = "brainloller")]
+    fn to_brainfuck(&self, src: &[u8]) -> Result<Option<String>> {
+        use crate::brainloller::{read_png, to_brainfuck};
+
+        Ok(Some(to_brainfuck(&read_png(src)?)))
+    }
+
+    #[cfg(not(feature = "brainloller"))]
+    fn to_brainfuck(&self, _src: &[u8]) -> Result<Option<String>> {
+        anyhow::bail!("bf was built without the brainloller feature")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        frontend::{find, FRONTENDS},
+        lexer::{Token::*, TokenLoc},
+    };
+
+    #[test]
+    fn reads_every_dialect() {
+        let at = |col, line| TokenLoc::from_col_line(col, line);
+        for (name, src, token) in [
+            ("brainfuck", "a +", Plus),
+            ("pbrain", "a :", Colon),
+            ("multi-tape", "a }", NextTape),
+            ("ook", "a Ook. Ook.", Plus),
+            ("blub", "a Blub! Blub.", Dot),
+            ("spoon", "a 1", Plus),
+        ] {
+            let frontend = find(name).unwrap();
+            assert_eq!(frontend.name(), name);
+            assert_eq!(
+                frontend.tokenize(src.as_bytes()).unwrap(),
+                [(token, at(3, 1))],
+                "{}",
+                name
+            );
+        }
+
+        let translated = find("ook").unwrap().to_brainfuck(b"Ook. Ook.").unwrap();
+        assert_eq!(translated.as_deref(), Some("+        "));
+        assert_eq!(find("brainfuck").unwrap().to_brainfuck(b"+").unwrap(), None);
+        assert!(find("spoon").unwrap().tokenize(b"0").is_err());
+
+        let mut names: Vec<_> = FRONTENDS.iter().map(|frontend| frontend.name()).collect();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), FRONTENDS.len());
+    }
+}
@@ -0,0 +1,393 @@
+/*
+ *  Fuzzing, for `bf fuzz`: random programs with balanced brackets are run on every backend built in
+ *  and what they do is compared with the VM running them unoptimized.
+ *
+ *  The VM runs a program first, one instruction at a time under a limit on their number. A program
+ *  running past it is left there. The backends have no such limit: the VM runs the program optimized
+ *  under it next, it has to end there too or they would never. One that ran to the end has to write
+ *  the same output on every backend, the pointer stopped at the left edge or not: programs start on
+ *  the first cell, where the optimizer has to leave the moves `<` stops as they are. One that failed,
+ *  such as off the end of the tape, is only run for panics, the optimized program can fail somewhere
+ *  else.
+ *
+ *  Programs are made of the commands with a bias for the loops the optimizer and the engines have
+ *  forms of their own for, such as `[-]`, `[->+<]` and `[<]`. The same seed makes the same programs.
+ */
+
+use std::{
+    any::Any,
+    fmt, io,
+    panic::{self, AssertUnwindSafe},
+};
+
+use anyhow::{bail, Result};
+
+use crate::{
+    backend, lexer, parser,
+    vm::{self, Observer, Vm},
+};
+
+/// Loops the generator writes whole.
+const IDIOMS: [&str; 8] = [
+    "[-]",
+    "[+]",
+    "[->+<]",
+    "[->>+++<<]",
+    "[-<+>]",
+    "[<]",
+    "[>]",
+    "[>>]",
+];
+/// How deep loops go at most.
+const MAX_DEPTH: usize = 4;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Options {
+    pub seed: u64,
+    /// Programs to run.
+    pub runs: usize,
+    /// Commands in a program at most.
+    pub max_len: usize,
+    /// Instructions the VM runs of a program before leaving it.
+    pub max_steps: u64,
+    pub tape_size: usize,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            runs: 1000,
+            max_len: 64,
+            max_steps: 100_000,
+            tape_size: 64,
+        }
+    }
+}
+
+/// splitmix64, as the VM's `?`.
+#[derive(Debug, Clone)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number below `n`, which isn't 0.
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+}
+
+/// A program of at most `max_len` commands, its brackets balanced.
+pub fn generate(rng: &mut Rng, max_len: usize) -> String {
+    let len = rng.below(max_len + 1);
+    let mut src = String::with_capacity(len);
+    let mut open = 0;
+
+    // Room is kept for the `]` of the loops open.
+    while src.len() + open < len {
+        let room = len - src.len() - open;
+        match rng.below(16) {
+            0..=3 => src.push(['+', '-'][rng.below(2)]),
+            4..=7 => src.push(['<', '>'][rng.below(2)]),
+            8 => src.push(['.', ','][rng.below(2)]),
+            9 | 10 => {
+                let idiom = IDIOMS[rng.below(IDIOMS.len())];
+                if idiom.len() <= room {
+                    src.push_str(idiom);
+                }
+            }
+            11 | 12 if open < MAX_DEPTH && room >= 2 => {
+                src.push('[');
+                open += 1;
+            }
+            13 if open > 0 => {
+                src.push(']');
+                open -= 1;
+            }
+            _ => {}
+        }
+    }
+    src.extend(std::iter::repeat_n(']', open));
+
+    src
+}
+
+/// What went wrong with a program on a backend.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Finding {
+    Panic(String),
+    /// It didn't compile or load, such as the verifier failing the optimized program.
+    Compile(String),
+    /// It failed where the VM ran it to the end.
+    Failed(String),
+    /// It ran past the limit on the VM optimized, where it ended unoptimized.
+    TooLong(u64),
+    Diverged {
+        expected: Vec<u8>,
+        got: Vec<u8>,
+    },
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Finding::Panic(message) => write!(f, "panicked: {}", message),
+            Finding::Compile(err) => write!(f, "didn't compile: {}", err),
+            Finding::Failed(err) => write!(f, "failed: {}", err),
+            Finding::TooLong(steps) => write!(f, "ran more than {} steps", steps),
+            Finding::Diverged { expected, got } => write!(
+                f,
+                "wrote {:?}, expected {:?}",
+                String::from_utf8_lossy(got),
+                String::from_utf8_lossy(expected)
+            ),
+        }
+    }
+}
+
+/// How the VM ran a program unoptimized.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Baseline {
+    /// To the end, writing this.
+    Clean(Vec<u8>),
+    Failed,
+    /// It ran past the limit.
+    TooLong,
+}
+
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub programs: usize,
+    /// Programs that ran clean, compared on every backend.
+    pub compared: usize,
+    /// Programs left after the VM ran them too long.
+    pub left: usize,
+    /// The programs with findings, the findings with the backend they are on.
+    pub failures: Vec<(String, Vec<(&'static str, Finding)>)>,
+}
+
+/// Runs `options.runs` programs, `found` is told of each program with findings as they come.
+pub fn run(options: &Options, mut found: impl FnMut(&str, &[(&'static str, Finding)])) -> Summary {
+    let mut rng = Rng::new(options.seed);
+    let mut summary = Summary::default();
+
+    // Panics are findings, not messages on standard error.
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+
+    for _ in 0..options.runs {
+        let src = generate(&mut rng, options.max_len);
+        let input: Vec<u8> = (0..options.max_len).map(|_| rng.next_u64() as u8).collect();
+
+        let (baseline, findings) = check(&src, &input, options);
+        summary.programs += 1;
+        match baseline {
+            Baseline::Clean(_) => summary.compared += 1,
+            Baseline::TooLong => summary.left += 1,
+            Baseline::Failed => {}
+        }
+        if !findings.is_empty() {
+            found(&src, &findings);
+            summary.failures.push((src, findings));
+        }
+    }
+
+    panic::set_hook(hook);
+
+    summary
+}
+
+/// Runs `src` on the VM unoptimized, then on every backend built in if it ended or failed.
+pub fn check(
+    src: &str,
+    input: &[u8],
+    options: &Options,
+) -> (Baseline, Vec<(&'static str, Finding)>) {
+    let baseline = match catch(|| baseline(src, input, options)) {
+        Ok(baseline) => baseline,
+        Err(finding) => return (Baseline::Failed, vec![("vm unoptimized", finding)]),
+    };
+    if baseline == Baseline::TooLong {
+        return (baseline, vec![]);
+    }
+    match catch(|| ends_optimized(src, input, options)) {
+        Ok(true) => {}
+        Ok(false) if baseline == Baseline::Failed => return (baseline, vec![]),
+        Ok(false) => return (baseline, vec![("vm", Finding::TooLong(options.max_steps))]),
+        Err(finding) => return (baseline, vec![("vm", finding)]),
+    }
+
+    let mut findings = vec![];
+    for name in backend::BACKENDS {
+        if !backend::is_available(name) {
+            continue;
+        }
+
+        let result = catch(|| {
+            let mut vm = Vm::from_program(vec![])?;
+            vm.set_tape_size(options.tape_size)?;
+            let mut backend = backend::with_vm(name, vm)?;
+            if let Err(err) = backend.compile(src) {
+                return Ok(Some(Finding::Compile(err.to_string())));
+            }
+
+            let mut output = vec![];
+            let result = backend.run(&mut &input[..], &mut output);
+            Ok(match (&baseline, result) {
+                (Baseline::Clean(_), Err(err)) => Some(Finding::Failed(err.to_string())),
+                (Baseline::Clean(expected), Ok(())) if *expected != output => {
+                    Some(Finding::Diverged {
+                        expected: expected.clone(),
+                        got: output,
+                    })
+                }
+                _ => None,
+            })
+        });
+        match result {
+            Ok(Some(finding)) | Err(finding) => findings.push((name, finding)),
+            Ok(None) => {}
+        }
+    }
+
+    (baseline, findings)
+}
+
+fn baseline(src: &str, input: &[u8], options: &Options) -> Result<Baseline> {
+    let mut vm = Vm::from_program(parser::parse(lexer::parse(src))?)?;
+    vm.set_tape_size(options.tape_size)?;
+    let mut limit = Limit {
+        max_steps: options.max_steps,
+        steps: 0,
+    };
+
+    let mut output = vec![];
+    let result = vm.run_observed(&mut &input[..], &mut output, &mut limit);
+
+    Ok(match result {
+        _ if limit.steps > options.max_steps => Baseline::TooLong,
+        Ok(()) => Baseline::Clean(output),
+        Err(_) => Baseline::Failed,
+    })
+}
+
+/// Whether the optimized program ends on the VM under the limit.
+fn ends_optimized(src: &str, input: &[u8], options: &Options) -> Result<bool> {
+    let mut vm = Vm::from_program(vm::compile(src)?)?;
+    vm.set_tape_size(options.tape_size)?;
+    let mut limit = Limit {
+        max_steps: options.max_steps,
+        steps: 0,
+    };
+
+    // Where it fails is up to the backends.
+    let _ = vm.run_observed(&mut &input[..], &mut io::sink(), &mut limit);
+
+    Ok(limit.steps <= options.max_steps)
+}
+
+/// Stops the run past `max_steps`.
+struct Limit {
+    max_steps: u64,
+    steps: u64,
+}
+
+impl Observer for Limit {
+    fn instruction(&mut self, _: &Vm) -> Result<()> {
+        self.steps += 1;
+        if self.steps > self.max_steps {
+            bail!("ran more than {} steps", self.max_steps);
+        }
+
+        Ok(())
+    }
+}
+
+/// `f`, a panic in it made a finding.
+fn catch<T>(f: impl FnOnce() -> Result<T>) -> std::result::Result<T, Finding> {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(value)) => Ok(value),
+        Ok(Err(err)) => Err(Finding::Compile(err.to_string())),
+        Err(payload) => Err(Finding::Panic(panic_message(&*payload))),
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => payload
+            .downcast_ref::<String>()
+            .cloned()
+            .unwrap_or_else(|| "a panic without a message".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        fuzz::{self, Baseline, Options, Rng},
+        lexer, parser,
+    };
+
+    #[test]
+    fn generates_programs_that_parse() {
+        let mut rng = Rng::new(7);
+        for _ in 0..500 {
+            let src = fuzz::generate(&mut rng, 40);
+            assert!(src.len() <= 40, "{}", src);
+            assert!(parser::parse(lexer::parse(&src)).is_ok(), "{}", src);
+        }
+
+        let programs = |seed| {
+            let mut rng = Rng::new(seed);
+            (0..10)
+                .map(|_| fuzz::generate(&mut rng, 40))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(programs(1), programs(1));
+        assert_ne!(programs(1), programs(2));
+    }
+
+    #[test]
+    fn compares_the_backends() {
+        let options = Options::default();
+        let (baseline, findings) = fuzz::check("++[->+++<]>.", b"", &options);
+        assert_eq!(baseline, Baseline::Clean(vec![6]));
+        assert_eq!(findings, []);
+
+        assert_eq!(fuzz::check("+[]", b"", &options).0, Baseline::TooLong);
+        // `<` stops at the first cell, on every backend.
+        for (src, output) in [
+            ("<+.", 1),
+            ("+<>.", 0),
+            ("+[<+>-].", 0),
+            ("+<[-]>[-]>[-]>.", 0),
+        ] {
+            let (baseline, findings) = fuzz::check(src, b"", &options);
+            assert_eq!(baseline, Baseline::Clean(vec![output]), "{}", src);
+            assert_eq!(findings, [], "{}", src);
+        }
+        assert_eq!(fuzz::check("+[>+]", b"", &options).0, Baseline::Failed);
+
+        let summary = fuzz::run(
+            &Options {
+                runs: 200,
+                ..options
+            },
+            |src, findings| panic!("{}: {:?}", src, findings),
+        );
+        assert_eq!(summary.programs, 200);
+        assert!(summary.compared > 0);
+    }
+}
@@ -0,0 +1,108 @@
+/*
+ *  Brainfuck programs printing a text, for `bf generate`.
+ *
+ *  A loop first sets some cells to multiples of a factor, near the bytes of the text:
+ *  `++++++++++[>+++++++>++++++++++<<-]` leaves 70 and 100 in cells 1 and 2. Each byte is then printed
+ *  from the cell it takes the fewest commands to get to and change into it, and the cell keeps the
+ *  byte for the next ones. Every factor of `FACTORS` is tried and no loop at all, the shortest
+ *  program is kept.
+ */
+
+/// Factors the cells are set to multiples of.
+const FACTORS: std::ops::RangeInclusive<usize> = 4..=20;
+
+/// A program printing `text`.
+pub fn generate(text: &[u8]) -> String {
+    FACTORS
+        .map(|factor| with_factor(text, Some(factor)))
+        .chain([with_factor(text, None)])
+        .min_by_key(|program| program.len())
+        .expect("there are factors")
+}
+
+/// The program printing `text` from cells set to multiples of `factor`, from cell 0 alone for None.
+fn with_factor(text: &[u8], factor: Option<usize>) -> String {
+    let mut out = String::new();
+    // Cell 0 is the counter of the loop, 0 after it.
+    let mut cells = vec![0u8];
+
+    if let Some(factor) = factor {
+        // The multiples, in the order their bytes first come.
+        let mut multiples = vec![];
+        for &byte in text {
+            let multiple = (byte as usize + factor / 2) / factor;
+            if multiple > 0 && !multiples.contains(&multiple) {
+                multiples.push(multiple);
+            }
+        }
+
+        if !multiples.is_empty() {
+            out.push_str(&"+".repeat(factor));
+            out.push('[');
+            for &multiple in &multiples {
+                out.push('>');
+                out.push_str(&"+".repeat(multiple));
+                cells.push((multiple * factor) as u8);
+            }
+            out.push_str(&"<".repeat(multiples.len()));
+            out.push_str("-]");
+        }
+    }
+
+    let mut ptr = 0usize;
+    for &byte in text {
+        let cell = (0..cells.len())
+            .min_by_key(|&cell| ptr.abs_diff(cell) + change(cells[cell], byte).1)
+            .expect("there is cell 0");
+
+        let shift = if cell < ptr { '<' } else { '>' };
+        out.extend(std::iter::repeat_n(shift, ptr.abs_diff(cell)));
+        let (command, count) = change(cells[cell], byte);
+        out.extend(std::iter::repeat_n(command, count));
+        out.push('.');
+
+        ptr = cell;
+        cells[cell] = byte;
+    }
+
+    out
+}
+
+/// The command turning `from` into `to` and how many of it, wrapping around.
+fn change(from: u8, to: u8) -> (char, usize) {
+    let up = to.wrapping_sub(from) as usize;
+    match up <= 128 {
+        true => ('+', up),
+        false => ('-', 256 - up),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{generate, vm::Vm};
+
+    fn run(src: &str) -> Vec<u8> {
+        let mut vm = Vm::from_program(crate::vm::compile(src).unwrap()).unwrap();
+        let mut output = vec![];
+        vm.run_with(&mut &b""[..], &mut output).unwrap();
+        output
+    }
+
+    #[test]
+    fn prints_the_text() {
+        for text in [
+            &b"Hello, world!\n"[..],
+            b"",
+            b"a",
+            b"\0\xff\x80\x7f\0",
+            b"The quick brown fox jumps over the lazy dog.",
+        ] {
+            let src = generate::generate(text);
+            assert_eq!(run(&src), text, "{}", src);
+        }
+
+        // Shorter than adding up to each byte from the last.
+        let src = generate::generate(b"Hello, world!\n");
+        assert!(src.len() < 150, "{}", src);
+    }
+}
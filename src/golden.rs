@@ -0,0 +1,136 @@
+/*
+ *  Golden tests, for `bf test`: every `foo.bf` in a directory is run with `foo.in` as its input (none
+ *  if there is no such file) and its output compared with `foo.out`. Programs without a `foo.out` are
+ *  skipped.
+ */
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+
+use crate::vm::Vm;
+
+/// Differing lines shown by `diff`, the rest are only counted.
+const MAX_DIFF_LINES: usize = 5;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Pass,
+    /// Why, the diff of the outputs or the error of the program.
+    Fail(String),
+    Skip,
+}
+
+#[derive(Debug)]
+pub struct Case {
+    pub path: PathBuf,
+    pub outcome: Outcome,
+}
+
+/// Runs the tests in `dir`, in file name order.
+pub fn run_dir(dir: &Path) -> Result<Vec<Case>> {
+    let mut paths = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "bf") {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    paths
+        .into_iter()
+        .map(|path| {
+            let outcome = run(&path)?;
+            Ok(Case { path, outcome })
+        })
+        .collect()
+}
+
+fn run(path: &Path) -> Result<Outcome> {
+    let Ok(expected) = fs::read(path.with_extension("out")) else {
+        return Ok(Outcome::Skip);
+    };
+    let input = fs::read(path.with_extension("in")).unwrap_or_default();
+
+    let mut output = vec![];
+    let result = Vm::new(&fs::read_to_string(path)?)
+        .and_then(|mut vm| vm.run_with(&mut input.as_slice(), &mut output));
+
+    Ok(match result {
+        Err(err) => Outcome::Fail(format!("error: {}", err)),
+        Ok(()) if output == expected => Outcome::Pass,
+        Ok(()) => Outcome::Fail(diff(&expected, &output)),
+    })
+}
+
+/// The lines where `actual` differs from `expected`, line breaks included so a missing one shows.
+pub fn diff(expected: &[u8], actual: &[u8]) -> String {
+    let (expected, actual) = (
+        String::from_utf8_lossy(expected),
+        String::from_utf8_lossy(actual),
+    );
+    let expected: Vec<_> = expected.split_inclusive('\n').collect();
+    let actual: Vec<_> = actual.split_inclusive('\n').collect();
+
+    let differing: Vec<_> = (0..expected.len().max(actual.len()))
+        .filter(|&i| expected.get(i) != actual.get(i))
+        .collect();
+
+    let show =
+        |line: Option<&&str>| line.map_or("nothing".to_string(), |line| format!("{:?}", line));
+
+    let mut out = String::new();
+    for &i in differing.iter().take(MAX_DIFF_LINES) {
+        out += &format!("line {}\n", i + 1);
+        out += &format!("  expected {}\n", show(expected.get(i)));
+        out += &format!("  actual   {}\n", show(actual.get(i)));
+    }
+    if differing.len() > MAX_DIFF_LINES {
+        out += &format!("and {} more lines\n", differing.len() - MAX_DIFF_LINES);
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use std::{env, fs, process};
+
+    use crate::golden::{self, Outcome};
+
+    #[test]
+    fn runs_golden_tests() {
+        let dir = env::temp_dir().join(format!("bf-golden-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let hello = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+        fs::write(dir.join("a.bf"), hello).unwrap();
+        fs::write(dir.join("a.out"), "Hello World!\n").unwrap();
+        fs::write(dir.join("b.bf"), ",.,.").unwrap();
+        fs::write(dir.join("b.in"), "xy").unwrap();
+        fs::write(dir.join("b.out"), "xz").unwrap();
+        fs::write(dir.join("c.bf"), "+[").unwrap();
+        fs::write(dir.join("c.out"), "").unwrap();
+        fs::write(dir.join("d.bf"), "+.").unwrap();
+
+        let outcomes: Vec<_> = golden::run_dir(&dir)
+            .unwrap()
+            .into_iter()
+            .map(|case| case.outcome)
+            .collect();
+
+        assert_eq!(outcomes[0], Outcome::Pass);
+        assert_eq!(
+            outcomes[1],
+            Outcome::Fail("line 1\n  expected \"xz\"\n  actual   \"xy\"\n".to_string())
+        );
+        assert!(matches!(&outcomes[2], Outcome::Fail(why) if why.starts_with("error: unclosed")));
+        assert_eq!(outcomes[3], Outcome::Skip);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,187 @@
+/*
+ *  Tape heatmap, for `--heatmap`: how many times each cell was read and written during a run.
+ *  Counted per instruction of the compiled program, so a run of `+` is one write and a fused loop
+ *  counts the cells it touches once per time it runs.
+ */
+
+use std::fmt::Write;
+
+use anyhow::Result;
+
+use crate::{
+    opcodes::OpCodeType,
+    vm::{Observer, Vm},
+};
+
+/// Rows of the text summary at most, cells get grouped to fit.
+const ROWS: usize = 32;
+const BAR_WIDTH: usize = 40;
+
+#[derive(Default)]
+pub struct Heatmap {
+    reads: Vec<u64>,
+    writes: Vec<u64>,
+}
+
+impl Heatmap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cells up to the last one used.
+    fn used(&self) -> usize {
+        let used = |counts: &[u64]| {
+            counts
+                .iter()
+                .rposition(|&count| count > 0)
+                .map_or(0, |i| i + 1)
+        };
+        used(&self.reads).max(used(&self.writes))
+    }
+
+    /// A row per group of cells with their reads and writes, and a bar of both.
+    pub fn text(&self) -> String {
+        let used = self.used();
+        let group = used.div_ceil(ROWS).max(1);
+
+        let rows: Vec<(usize, u64, u64)> = (0..used)
+            .step_by(group)
+            .map(|start| {
+                let end = (start + group).min(used);
+                let sum = |counts: &[u64]| counts[start..end].iter().sum();
+                (start, sum(&self.reads), sum(&self.writes))
+            })
+            .collect();
+        let max = rows
+            .iter()
+            .map(|&(_, reads, writes)| reads + writes)
+            .max()
+            .unwrap_or(0)
+            .max(1);
+
+        let mut out = String::new();
+        writeln!(out, "{:<13} {:>14} {:>14}", "cells", "reads", "writes").unwrap();
+        for (start, reads, writes) in rows {
+            let cells = match group {
+                1 => start.to_string(),
+                _ => format!("{}-{}", start, (start + group).min(used) - 1),
+            };
+            // Any use at all gets a mark, so a cold cell is told apart from an unused one.
+            let bar = match reads + writes {
+                0 => 0,
+                uses => ((uses * BAR_WIDTH as u64 / max) as usize).max(1),
+            };
+            writeln!(
+                out,
+                "{:<13} {:>14} {:>14}  {}",
+                cells,
+                reads,
+                writes,
+                "#".repeat(bar)
+            )
+            .unwrap();
+        }
+
+        out
+    }
+
+    /// `cell,reads,writes` for every cell up to the last one used.
+    pub fn csv(&self) -> String {
+        let mut out = "cell,reads,writes\n".to_string();
+        for cell in 0..self.used() {
+            writeln!(out, "{},{},{}", cell, self.reads[cell], self.writes[cell]).unwrap();
+        }
+
+        out
+    }
+
+    fn read(&mut self, cell: usize) {
+        self.reads[cell] += 1;
+    }
+
+    fn write(&mut self, cell: usize) {
+        self.writes[cell] += 1;
+    }
+}
+
+impl Observer for Heatmap {
+    fn instruction(&mut self, vm: &Vm) -> Result<()> {
+        use OpCodeType::*;
+
+        if self.reads.is_empty() {
+            self.reads = vec![0; vm.mem().len()];
+            self.writes = vec![0; vm.mem().len()];
+        }
+
+        let op = vm.program()[vm.pc()];
+        let ptr = vm.mem_ptr();
+        // Out of the tape, the instruction is about to fail.
+        let Some(cell) = ptr
+            .checked_add_signed(op.offset as isize)
+            .filter(|&cell| cell < vm.mem().len())
+        else {
+            return Ok(());
+        };
+
+        match op.ty {
+            Add | Sub => {
+                self.read(cell);
+                self.write(cell);
+            }
+            JmpZero | JmpNotZero | PrintChar | ClearLoop | MulLoop | ProcStart | Call | Store
+            | WriteFile | CopyToTape => self.read(cell),
+            InputChar | Set | Retrieve | Random | ReadFile => self.write(cell),
+            BitsLeft | BitsRight | Not | Xor | And | Or | Fork => {
+                self.read(cell);
+                self.write(cell);
+            }
+            MulAdd => {
+                self.read(ptr);
+                self.read(cell);
+                self.write(cell);
+            }
+            FillRange | ClearRange => {
+                let len = match op.ty {
+                    FillRange => op.data >> 8,
+                    _ => op.data,
+                };
+                for cell in cell..(cell + len).min(vm.mem().len()) {
+                    self.write(cell);
+                }
+            }
+            ShiftLeft | ShiftRight | ProcEnd | End | Dump | PrevTape | NextTape => {}
+            OpenFile => {
+                // The name, up to its zero cell.
+                let mem = vm.mem();
+                let end = mem[cell..]
+                    .iter()
+                    .position(|&ch| ch == 0)
+                    .map_or(mem.len(), |len| cell + len + 1);
+                (cell..end.min(mem.len())).for_each(|cell| self.read(cell));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{heatmap::Heatmap, vm::Vm};
+
+    #[test]
+    fn counts_cells() {
+        let mut heatmap = Heatmap::new();
+        Vm::new("+>>,.<<[-]")
+            .unwrap()
+            .run_observed(&mut &b"x"[..], &mut vec![], &mut heatmap)
+            .unwrap();
+
+        assert_eq!(heatmap.csv(), "cell,reads,writes\n0,1,2\n1,0,0\n2,1,1\n");
+
+        let text = heatmap.text();
+        assert_eq!(text.lines().count(), 4);
+        assert!(text.lines().nth(1).unwrap().ends_with(&"#".repeat(40)));
+        assert!(text.lines().nth(2).unwrap().ends_with(" 0  "));
+    }
+}
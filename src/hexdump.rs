@@ -0,0 +1,72 @@
+/*
+ *  Hex and ASCII dump of tape cells, for `--dump-mem`. Laid out like `hexdump -C`.
+ */
+
+use anyhow::{anyhow, Result};
+
+const ROW: usize = 16;
+
+/// Dumps `cells`, which start at cell `start` of the tape.
+pub fn hexdump(cells: &[u8], start: usize) -> String {
+    let mut out = String::new();
+
+    for (i, row) in cells.chunks(ROW).enumerate() {
+        out += &format!("{:08x} ", start + i * ROW);
+
+        for col in 0..ROW {
+            if col == ROW / 2 {
+                out.push(' ');
+            }
+            match row.get(col) {
+                Some(cell) => out += &format!(" {:02x}", cell),
+                None => out += "   ",
+            }
+        }
+
+        let ascii: String = row
+            .iter()
+            .map(|&cell| match cell {
+                0x20..=0x7e => cell as char,
+                _ => '.',
+            })
+            .collect();
+        out += &format!("  |{}|\n", ascii);
+    }
+
+    out
+}
+
+/// The cells of a dump `hexdump` wrote, read back. The rows are taken to follow each other.
+pub fn parse(dump: &str) -> Result<Vec<u8>> {
+    let mut cells = vec![];
+
+    for line in dump.lines() {
+        // The offset, the bytes, then the ASCII column from `  |` on.
+        let (row, _) = line.split_once("  |").unwrap_or((line, ""));
+        for byte in row.split_whitespace().skip(1) {
+            cells.push(
+                u8::from_str_radix(byte, 16).map_err(|_| anyhow!("bad hex dump row `{}`", line))?,
+            );
+        }
+    }
+
+    Ok(cells)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::hexdump::{hexdump, parse};
+
+    #[test]
+    fn dumps_rows() {
+        let cells = b"Hello, World!\n\x00\x01\xff";
+        let expected = "\
+00000010  48 65 6c 6c 6f 2c 20 57  6f 72 6c 64 21 0a 00 01  |Hello, World!...|
+00000020  ff                                                |.|
+";
+
+        assert_eq!(hexdump(cells, 16), expected);
+        assert_eq!(hexdump(&[], 0), "");
+        assert_eq!(parse(expected).unwrap(), cells);
+    }
+}
@@ -0,0 +1,171 @@
+/*
+ *  Execution history for the debugger's reverse commands. A snapshot of the machine is kept every
+ *  `INTERVAL` steps; going back restores the one before the target and runs forward to it again.
+ *
+ *  Running forward again has to do what the first run did, so every byte of input the program read is
+ *  kept and read back, and output that was already written is not written twice.
+ */
+
+use std::io::{self, Read, Write};
+
+use anyhow::Result;
+
+use crate::vm::{Snapshot, Vm};
+
+/// Steps between snapshots. A snapshot is a copy of the tape, so this trades memory for the
+/// instructions run again when going back.
+const INTERVAL: u64 = 10_000;
+
+struct Checkpoint {
+    vm: Snapshot,
+    input_pos: usize,
+    output_pos: usize,
+}
+
+#[derive(Default)]
+pub struct History {
+    /// Checkpoint `k` is at step `k * INTERVAL`.
+    checkpoints: Vec<Checkpoint>,
+    /// Everything the program has read.
+    input: Vec<u8>,
+    /// Furthest the output got, bytes before it are not written again.
+    output_written: usize,
+    step: u64,
+    input_pos: usize,
+    output_pos: usize,
+}
+
+impl History {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Instructions run since the start.
+    pub fn step(&self) -> u64 {
+        self.step
+    }
+
+    /// `Vm::step`, recording what is needed to come back here.
+    pub fn step_vm<R: Read, W: Write>(
+        &mut self,
+        vm: &mut Vm,
+        input: &mut R,
+        output: &mut W,
+    ) -> Result<bool> {
+        if self.step == self.checkpoints.len() as u64 * INTERVAL {
+            self.checkpoints.push(Checkpoint {
+                vm: vm.snapshot(),
+                input_pos: self.input_pos,
+                output_pos: self.output_pos,
+            });
+        }
+
+        let mut input = ReplayInput {
+            log: &mut self.input,
+            pos: &mut self.input_pos,
+            inner: input,
+        };
+        let mut output = ReplayOutput {
+            written: &mut self.output_written,
+            pos: &mut self.output_pos,
+            inner: output,
+        };
+
+        let stepped = vm.step(&mut input, &mut output)?;
+        if stepped {
+            self.step += 1;
+        }
+
+        Ok(stepped)
+    }
+
+    /// Takes `vm` back to step `target`, at most the current one.
+    pub fn goto(&mut self, vm: &mut Vm, target: u64) -> Result<()> {
+        if target == self.step {
+            return Ok(());
+        }
+
+        let checkpoint = &self.checkpoints[(target / INTERVAL) as usize];
+        vm.restore(&checkpoint.vm);
+        self.step = target / INTERVAL * INTERVAL;
+        self.input_pos = checkpoint.input_pos;
+        self.output_pos = checkpoint.output_pos;
+
+        // Everything up to the current step has been run before, its input is in the log.
+        while self.step < target {
+            self.step_vm(vm, &mut io::empty(), &mut io::sink())?;
+        }
+
+        Ok(())
+    }
+
+    /// The last step before the current one where `stop` is true of the machine, `vm` is left at the
+    /// current step.
+    pub fn find_back(&mut self, vm: &mut Vm, stop: impl Fn(&Vm) -> bool) -> Result<Option<u64>> {
+        let current = self.step;
+
+        for k in (0..current.div_ceil(INTERVAL)).rev() {
+            let end = ((k + 1) * INTERVAL).min(current);
+            self.goto(vm, k * INTERVAL)?;
+
+            let mut found = None;
+            while self.step < end {
+                if stop(vm) {
+                    found = Some(self.step);
+                }
+                self.step_vm(vm, &mut io::empty(), &mut io::sink())?;
+            }
+            if found.is_some() {
+                self.goto(vm, current)?;
+                return Ok(found);
+            }
+        }
+
+        self.goto(vm, current)?;
+        Ok(None)
+    }
+}
+
+/// Reads from the log while running what ran before, then from the program's input, logging it.
+struct ReplayInput<'a, R> {
+    log: &'a mut Vec<u8>,
+    pos: &'a mut usize,
+    inner: &'a mut R,
+}
+
+impl<R: Read> Read for ReplayInput<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if *self.pos == self.log.len() {
+            let read = self.inner.read(buf)?;
+            self.log.extend_from_slice(&buf[..read]);
+        }
+
+        let read = (&self.log[*self.pos..]).read(buf)?;
+        *self.pos += read;
+
+        Ok(read)
+    }
+}
+
+/// Drops the output that was written before.
+struct ReplayOutput<'a, W> {
+    written: &'a mut usize,
+    pos: &'a mut usize,
+    inner: &'a mut W,
+}
+
+impl<W: Write> Write for ReplayOutput<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let skip = (*self.written - *self.pos).min(buf.len());
+        self.inner.write_all(&buf[skip..])?;
+
+        *self.pos += buf.len();
+        *self.written = (*self.written).max(*self.pos);
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,602 @@
+/*
+ *  Structured (loop tree) representation of a program, used by the optimizer.
+ *  The VM still runs the flat, jump-patched `Program`; the IR only lives between parsing and execution.
+ *
+ *  Every node touching memory carries an `offset` relative to the memory pointer. Straight from the
+ *  parser all offsets are 0, the balanced loop pass is what turns pointer movement into offsets.
+ */
+
+use std::{fmt::Write, mem, slice};
+
+use anyhow::{bail, Result};
+
+use crate::{
+    opcodes::{OpCode, OpCodeType},
+    parser::{Program, Span},
+    srcmap::SourceMap,
+};
+
+/// Every node has the `span` of the source it was made from, when known: from the first to the last
+/// command of it, from the `[` to the `]` for a loop.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Ir {
+    /// Wrapping add. Subtraction is stored as its two's complement.
+    Add {
+        offset: isize,
+        value: u8,
+        span: Option<Span>,
+    },
+    /// Moves the pointer, negative amounts move left.
+    Shift { amount: isize, span: Option<Span> },
+    Set {
+        offset: isize,
+        value: u8,
+        span: Option<Span>,
+    },
+    /// mem[ptr + offset] += mem[ptr] * factor, what a fused multiplication loop is made of.
+    MulAdd {
+        offset: isize,
+        factor: u8,
+        span: Option<Span>,
+    },
+    Input {
+        offset: isize,
+        count: usize,
+        span: Option<Span>,
+    },
+    Output {
+        offset: isize,
+        count: usize,
+        span: Option<Span>,
+    },
+    /// Sets `len` cells starting at mem[ptr + offset] to `value`.
+    Fill {
+        offset: isize,
+        len: usize,
+        value: u8,
+        span: Option<Span>,
+    },
+    /// Runs `body` while mem[ptr + offset] is not zero.
+    Loop {
+        offset: isize,
+        body: Vec<Ir>,
+        span: Option<Span>,
+    },
+}
+
+impl Ir {
+    pub fn add(value: u8) -> Self {
+        Self::Add {
+            offset: 0,
+            value,
+            span: None,
+        }
+    }
+
+    pub fn shift(amount: isize) -> Self {
+        Self::Shift { amount, span: None }
+    }
+
+    pub fn set(value: u8) -> Self {
+        Self::Set {
+            offset: 0,
+            value,
+            span: None,
+        }
+    }
+
+    pub fn input(count: usize) -> Self {
+        Self::Input {
+            offset: 0,
+            count,
+            span: None,
+        }
+    }
+
+    pub fn output(count: usize) -> Self {
+        Self::Output {
+            offset: 0,
+            count,
+            span: None,
+        }
+    }
+
+    pub fn new_loop(body: Vec<Ir>) -> Self {
+        Self::Loop {
+            offset: 0,
+            body,
+            span: None,
+        }
+    }
+
+    /// Offset of the cell this node reads or writes, `None` for `Shift`.
+    pub fn offset(&self) -> Option<isize> {
+        match *self {
+            Ir::Add { offset, .. }
+            | Ir::Set { offset, .. }
+            | Ir::MulAdd { offset, .. }
+            | Ir::Input { offset, .. }
+            | Ir::Output { offset, .. }
+            | Ir::Fill { offset, .. }
+            | Ir::Loop { offset, .. } => Some(offset),
+            Ir::Shift { .. } => None,
+        }
+    }
+
+    pub fn span(&self) -> Option<Span> {
+        match *self {
+            Ir::Add { span, .. }
+            | Ir::Shift { span, .. }
+            | Ir::Set { span, .. }
+            | Ir::MulAdd { span, .. }
+            | Ir::Input { span, .. }
+            | Ir::Output { span, .. }
+            | Ir::Fill { span, .. }
+            | Ir::Loop { span, .. } => span,
+        }
+    }
+}
+
+/// The span from the start of `first` to the end of `last`, for a node made from both, `last` coming
+/// after `first` in the source.
+pub fn join(first: Option<Span>, last: Option<Span>) -> Option<Span> {
+    match (first, last) {
+        (Some((start, _)), Some((_, end))) => Some((start, end)),
+        (span, None) | (None, span) => span,
+    }
+}
+
+/// Loops nest as deep as the program does: their bodies are taken apart one after the other
+/// instead of each one being dropped from inside the one around it.
+impl Drop for Ir {
+    fn drop(&mut self) {
+        let Ir::Loop { body, .. } = self else {
+            return;
+        };
+
+        let mut nodes = mem::take(body);
+        while let Some(mut node) = nodes.pop() {
+            if let Ir::Loop { body, .. } = &mut node {
+                nodes.append(body);
+            }
+        }
+    }
+}
+
+/// Where `walk` is in the tree.
+#[derive(Debug, Clone, Copy)]
+pub enum Step<'a> {
+    /// A node, a loop comes before its body.
+    Node(&'a Ir),
+    /// The end of the body of the loop with this offset and span.
+    End { offset: isize, span: Option<Span> },
+}
+
+/// Goes through `block` and the bodies of its loops in program order with a stack of its own, loops
+/// nest as deep as the program does.
+pub fn walk(block: &[Ir]) -> Walk<'_> {
+    Walk {
+        stack: vec![(block.iter(), None)],
+    }
+}
+
+pub struct Walk<'a> {
+    /// The nodes left in each block gone into, with the loop it is the body of.
+    stack: Vec<(slice::Iter<'a, Ir>, Option<&'a Ir>)>,
+}
+
+impl<'a> Iterator for Walk<'a> {
+    type Item = Step<'a>;
+
+    fn next(&mut self) -> Option<Step<'a>> {
+        let (nodes, _) = self.stack.last_mut()?;
+
+        match nodes.next() {
+            Some(node) => {
+                if let Ir::Loop { body, .. } = node {
+                    self.stack.push((body.iter(), Some(node)));
+                }
+                Some(Step::Node(node))
+            }
+            None => match self.stack.pop()? {
+                (_, Some(&Ir::Loop { offset, span, .. })) => Some(Step::End { offset, span }),
+                _ => None,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct ProgramIr {
+    pub body: Vec<Ir>,
+}
+
+impl ProgramIr {
+    pub fn new(body: Vec<Ir>) -> Self {
+        Self { body }
+    }
+
+    pub fn from_program(program: &Program) -> Result<Self> {
+        Self::from_program_with_spans(program, &[])
+    }
+
+    /// Same as `from_program`, with the nodes keeping their place in the source. `spans` come from
+    /// `parser::spans` and can be empty.
+    pub fn from_program_with_spans(program: &Program, spans: &[Span]) -> Result<Self> {
+        // Each open loop keeps its offset, its pc and the nodes collected so far.
+        let mut stack: Vec<(isize, usize, Vec<Ir>)> = vec![(0, 0, vec![])];
+
+        for (pc, op) in program.iter().enumerate() {
+            let offset = op.offset as isize;
+            let span = spans.get(pc).copied();
+            let node = match op.ty {
+                OpCodeType::Add => Ir::Add {
+                    offset,
+                    value: (op.data % 256) as u8,
+                    span,
+                },
+                OpCodeType::Sub => Ir::Add {
+                    offset,
+                    value: 0u8.wrapping_sub((op.data % 256) as u8),
+                    span,
+                },
+                OpCodeType::ShiftLeft => Ir::Shift {
+                    amount: -(op.data as isize),
+                    span,
+                },
+                OpCodeType::ShiftRight => Ir::Shift {
+                    amount: op.data as isize,
+                    span,
+                },
+                OpCodeType::Set => Ir::Set {
+                    offset,
+                    value: op.data as u8,
+                    span,
+                },
+                OpCodeType::MulAdd => Ir::MulAdd {
+                    offset,
+                    factor: op.data as u8,
+                    span,
+                },
+                OpCodeType::InputChar => Ir::Input {
+                    offset,
+                    count: op.data,
+                    span,
+                },
+                OpCodeType::PrintChar => Ir::Output {
+                    offset,
+                    count: op.data,
+                    span,
+                },
+                OpCodeType::FillRange => Ir::Fill {
+                    offset,
+                    len: op.data >> 8,
+                    value: op.data as u8,
+                    span,
+                },
+                OpCodeType::ClearRange => Ir::Fill {
+                    offset,
+                    len: op.data,
+                    value: 0,
+                    span,
+                },
+                OpCodeType::JmpZero | OpCodeType::ClearLoop | OpCodeType::MulLoop => {
+                    stack.push((offset, pc, vec![]));
+                    continue;
+                }
+                OpCodeType::ProcStart
+                | OpCodeType::ProcEnd
+                | OpCodeType::Call
+                | OpCodeType::End
+                | OpCodeType::Store
+                | OpCodeType::Retrieve
+                | OpCodeType::BitsLeft
+                | OpCodeType::BitsRight
+                | OpCodeType::Not
+                | OpCodeType::Xor
+                | OpCodeType::And
+                | OpCodeType::Or
+                | OpCodeType::Fork
+                | OpCodeType::PrevTape
+                | OpCodeType::NextTape
+                | OpCodeType::CopyToTape
+                | OpCodeType::Random
+                | OpCodeType::Dump
+                | OpCodeType::OpenFile
+                | OpCodeType::WriteFile
+                | OpCodeType::ReadFile => bail!(
+                    "{} is only run by the VM, not optimized or compiled",
+                    op.ty.mnemonic()
+                ),
+                OpCodeType::JmpNotZero => {
+                    if stack.len() < 2 {
+                        bail!("unmatched JmpNotZero at pc={}", pc);
+                    }
+
+                    let (offset, start, body) = stack.pop().unwrap();
+                    let span = match (spans.get(start), span) {
+                        (Some(&(start, _)), Some((_, end))) => Some((start, end)),
+                        _ => None,
+                    };
+                    Ir::Loop { offset, body, span }
+                }
+            };
+
+            stack.last_mut().unwrap().2.push(node);
+        }
+
+        if stack.len() != 1 {
+            bail!("program has {} unclosed JmpZero", stack.len() - 1);
+        }
+
+        Ok(Self::new(stack.pop().unwrap().2))
+    }
+
+    /// Nodes in the tree, loops count as one plus their body.
+    pub fn node_count(&self) -> usize {
+        walk(&self.body)
+            .filter(|step| matches!(step, Step::Node(_)))
+            .count()
+    }
+
+    pub fn to_program(&self) -> Program {
+        self.to_program_with_map().0
+    }
+
+    /// Same as `to_program`, with where every opcode comes from: the start of the span of its node.
+    pub fn to_program_with_map(&self) -> (Program, SourceMap) {
+        let mut program = Program::new();
+        let mut map = SourceMap::new();
+
+        lower(&self.body, &mut program, &mut map);
+
+        (program, map)
+    }
+}
+
+/// Prints the loop tree with where every node comes from.
+pub fn dump(body: &[Ir]) -> String {
+    let mut out = String::new();
+    let mut depth = 0;
+    for step in walk(body) {
+        let Step::Node(node) = step else {
+            depth -= 1;
+            continue;
+        };
+
+        let indent = "    ".repeat(depth);
+        writeln!(
+            out,
+            "{:<16}{}{}",
+            span_text(node.span()),
+            indent,
+            node_text(node)
+        )
+        .unwrap();
+
+        if let Ir::Loop { .. } = node {
+            depth += 1;
+        }
+    }
+
+    out
+}
+
+fn node_text(node: &Ir) -> String {
+    match *node {
+        Ir::Add { offset, value, .. } if value > 128 => {
+            format!("sub {}{}", 256 - value as usize, at(offset))
+        }
+        Ir::Add { offset, value, .. } => format!("add {}{}", value, at(offset)),
+        Ir::Shift { amount, .. } if amount < 0 => format!("left {}", -amount),
+        Ir::Shift { amount, .. } => format!("right {}", amount),
+        Ir::Set { offset, value, .. } => format!("set {}{}", value, at(offset)),
+        Ir::MulAdd { offset, factor, .. } => format!("muladd {}{}", factor, at(offset)),
+        Ir::Input { offset, count, .. } => format!("input {}{}", count, at(offset)),
+        Ir::Output { offset, count, .. } => format!("output {}{}", count, at(offset)),
+        Ir::Fill {
+            offset, len, value, ..
+        } => format!("fill {} {}{}", len, value, at(offset)),
+        Ir::Loop { offset, .. } => format!("loop{}", at(offset)),
+    }
+}
+
+fn at(offset: isize) -> String {
+    match offset {
+        0 => String::new(),
+        offset => format!(" @{:+}", offset),
+    }
+}
+
+fn span_text(span: Option<Span>) -> String {
+    match span {
+        Some((start, end)) if start == end => start.to_string(),
+        Some((start, end)) => format!("{}-{}", start, end),
+        None => "?".to_string(),
+    }
+}
+
+fn lower(block: &[Ir], program: &mut Program, map: &mut SourceMap) {
+    // Where the `JmpZero` of each open loop is.
+    let mut starts = vec![];
+    let op = |ty, data, offset: isize| OpCode::with_offset(ty, data, offset as i32);
+
+    for step in walk(block) {
+        // Where the opcode the step lowers to comes from, the `]` for the end of a loop.
+        let loc = match step {
+            Step::Node(&Ir::Loop { offset, span, .. }) => {
+                starts.push(program.len());
+                program.push(op(OpCodeType::JmpZero, usize::MAX, offset));
+                span.map(|(start, _)| start)
+            }
+            Step::Node(node) => {
+                lower_node(node, program);
+                node.span().map(|(start, _)| start)
+            }
+            Step::End { offset, span } => {
+                let start = starts.pop().unwrap();
+                let end = program.len();
+                program.push(op(OpCodeType::JmpNotZero, start, offset));
+                program[start].data = end;
+                span.map(|(_, end)| end)
+            }
+        };
+
+        while map.len() < program.len() {
+            map.push(loc);
+        }
+    }
+}
+
+fn lower_node(node: &Ir, program: &mut Program) {
+    let op = |ty, data, offset: isize| OpCode::with_offset(ty, data, offset as i32);
+
+    match *node {
+        Ir::Add { value: 0, .. } | Ir::Shift { amount: 0, .. } => {}
+        // Keep the bytecode readable: `-` stays a Sub instead of Add 255.
+        Ir::Add { offset, value, .. } if value > 128 => {
+            program.push(op(OpCodeType::Sub, 256 - value as usize, offset))
+        }
+        Ir::Add { offset, value, .. } => program.push(op(OpCodeType::Add, value as usize, offset)),
+        Ir::Shift { amount, .. } if amount < 0 => {
+            program.push(OpCode::new(OpCodeType::ShiftLeft, amount.unsigned_abs()))
+        }
+        Ir::Shift { amount, .. } => {
+            program.push(OpCode::new(OpCodeType::ShiftRight, amount as usize))
+        }
+        Ir::Set { offset, value, .. } => program.push(op(OpCodeType::Set, value as usize, offset)),
+        Ir::MulAdd { offset, factor, .. } => {
+            program.push(op(OpCodeType::MulAdd, factor as usize, offset))
+        }
+        Ir::Input { offset, count, .. } => program.push(op(OpCodeType::InputChar, count, offset)),
+        Ir::Output { offset, count, .. } => program.push(op(OpCodeType::PrintChar, count, offset)),
+        Ir::Fill {
+            offset,
+            len,
+            value: 0,
+            ..
+        } => program.push(op(OpCodeType::ClearRange, len, offset)),
+        Ir::Fill {
+            offset, len, value, ..
+        } => program.push(op(OpCodeType::FillRange, len << 8 | value as usize, offset)),
+        Ir::Loop { .. } => unreachable!("loops are lowered as the walk goes in and out of them"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+
+    use crate::{
+        ir::{self, Ir, ProgramIr},
+        lexer,
+        opcodes::{OpCode, OpCodeType::*},
+        optimizer::PassRegistry,
+        parser,
+    };
+
+    #[test]
+    fn from_program_builds_loop_tree() {
+        let program = parser::parse(lexer::parse("++>[-<[.]]")).unwrap();
+        let ir = ProgramIr::from_program(&program).unwrap();
+
+        let expected = vec![
+            Ir::add(2),
+            Ir::shift(1),
+            Ir::new_loop(vec![
+                Ir::add(255),
+                Ir::shift(-1),
+                Ir::new_loop(vec![Ir::output(1)]),
+            ]),
+        ];
+
+        assert_eq!(ir.body, expected);
+    }
+
+    #[test]
+    fn dumps_tree_with_spans() {
+        let tokens = lexer::parse("++>[-<\n[.]]");
+        let spans = parser::spans(&tokens);
+        let ir =
+            ProgramIr::from_program_with_spans(&parser::parse(tokens).unwrap(), &spans).unwrap();
+
+        let expected = "1:1-1:2         add 2
+1:3             right 1
+1:4-2:4         loop
+1:5                 sub 1
+1:6                 left 1
+2:1-2:3             loop
+2:2                     output 1
+";
+        assert_eq!(ir::dump(&ir.body), expected);
+    }
+
+    #[test]
+    fn round_trip_keeps_program() {
+        let program = parser::parse(lexer::parse("+++>>>>[[[--]]]<,.")).unwrap();
+        let ir = ProgramIr::from_program(&program).unwrap();
+
+        assert_eq!(ir.to_program(), program);
+    }
+
+    #[test]
+    fn round_trip_keeps_offsets() {
+        let program = vec![
+            OpCode::with_offset(JmpZero, 5, -1),
+            OpCode::with_offset(Sub, 1, 2),
+            OpCode::with_offset(PrintChar, 1, -1),
+            OpCode::with_offset(ClearRange, 4, 1),
+            OpCode::with_offset(FillRange, 3 << 8 | 42, -2),
+            OpCode::with_offset(JmpNotZero, 0, -1),
+        ];
+        let ir = ProgramIr::from_program(&program).unwrap();
+
+        assert_eq!(ir.to_program(), program);
+    }
+
+    #[test]
+    fn unbalanced_program_is_rejected() {
+        let program = vec![OpCode::new(JmpZero, 1), OpCode::new(Add, 1)];
+
+        assert!(ProgramIr::from_program(&program).is_err());
+        assert!(ProgramIr::from_program(&[OpCode::new(JmpNotZero, 0)].to_vec()).is_err());
+    }
+
+    /// Loops nest as deep as the program does: going through the tree, optimizing, lowering and
+    /// dropping it can't take stack for every loop, there is far too little of it here.
+    #[test]
+    fn deep_nesting_is_not_recursed_into() {
+        let nested = |depth: usize| {
+            let src = format!("+{}.{}", "[".repeat(depth), "]".repeat(depth));
+            let tokens = lexer::parse(&src);
+            let spans = parser::spans(&tokens);
+            (parser::parse(tokens).unwrap(), spans)
+        };
+
+        let deep = move || {
+            let depth = 100_000;
+            let (program, spans) = nested(depth);
+
+            let ir = ProgramIr::from_program_with_spans(&program, &spans).unwrap();
+            assert_eq!(ir.node_count(), depth + 2);
+            assert_eq!(ir.to_program(), program);
+
+            let (optimized, map) = PassRegistry::default()
+                .optimize_with_map(&program, &spans)
+                .unwrap();
+            assert_eq!((optimized.len(), map.len()), (program.len(), program.len()));
+
+            // Every line of the dump is indented as deep as it is, keep it small.
+            let (program, spans) = nested(1_000);
+            let ir = ProgramIr::from_program_with_spans(&program, &spans).unwrap();
+            assert_eq!(ir::dump(&ir.body).lines().count(), 1_002);
+        };
+
+        thread::Builder::new()
+            .stack_size(256 << 10)
+            .spawn(deep)
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+}
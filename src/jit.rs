@@ -0,0 +1,445 @@
+/*
+ *  Cranelift JIT backend.
+ *  The whole program becomes one native function working directly on the VM memory. I/O goes through
+ *  callbacks into Rust. Whenever the native code cannot go on (going off the end of the tape, input
+ *  errors) it stops in front of that instruction and returns its pc, the interpreter takes over from
+ *  there and reports the error exactly like it would have.
+ */
+
+use std::io::{Read, Write};
+
+use anyhow::{anyhow, bail, Result};
+use cranelift_codegen::{
+    ir::{condcodes::IntCC, types, AbiParam, Block, InstBuilder, MemFlags, Signature, Value},
+    settings::{self, Configurable},
+    Context,
+};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, FuncId, Linkage, Module};
+
+use crate::{
+    native_io::{self, IoContext},
+    opcodes::{OpCode, OpCodeType},
+    parser::Program,
+};
+
+type EntryFn = unsafe extern "C" fn(*mut IoContext, *mut u8, usize, usize, *mut usize) -> usize;
+
+pub struct JitProgram {
+    module: Option<JITModule>,
+    entry: EntryFn,
+}
+
+impl JitProgram {
+    pub fn compile(program: &Program) -> Result<Self> {
+        let mut flags = settings::builder();
+        flags.set("opt_level", "speed")?;
+
+        let isa = cranelift_native::builder()
+            .map_err(|msg| anyhow!("cannot build JIT for this host: {}", msg))?
+            .finish(settings::Flags::new(flags))?;
+
+        let mut builder = JITBuilder::with_isa(isa, default_libcall_names());
+        builder.symbol("bf_jit_output", native_io::output as *const u8);
+        builder.symbol("bf_jit_input", native_io::input as *const u8);
+
+        let mut module = JITModule::new(builder);
+        let entry = Translator::new(&mut module)?.translate(program)?;
+
+        module.finalize_definitions()?;
+
+        // SAFETY: the function was declared with exactly the signature of `EntryFn`.
+        let entry = unsafe {
+            std::mem::transmute::<*const u8, EntryFn>(module.get_finalized_function(entry))
+        };
+
+        Ok(Self {
+            module: Some(module),
+            entry,
+        })
+    }
+
+    /// Runs the program from its start. Returns the pc the interpreter has to continue at, which is the
+    /// length of the program when it ran to the end.
+    pub fn run(
+        &self,
+        mem: &mut [u8],
+        mem_ptr: &mut usize,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+    ) -> usize {
+        assert!(*mem_ptr < mem.len());
+
+        let mut ctx = IoContext { input, output };
+
+        // SAFETY:
+        //      every access to mem[ptr + offset] is checked against mem.len() first,
+        //      and the pointer never leaves 0..mem.len().
+        unsafe { (self.entry)(&mut ctx, mem.as_mut_ptr(), mem.len(), *mem_ptr, mem_ptr) }
+    }
+}
+
+impl Drop for JitProgram {
+    fn drop(&mut self) {
+        if let Some(module) = self.module.take() {
+            // SAFETY: `entry` can't be called anymore once self is dropped.
+            unsafe { module.free_memory() };
+        }
+    }
+}
+
+struct Translator<'a> {
+    module: &'a mut JITModule,
+    input_fn: FuncId,
+    output_fn: FuncId,
+}
+
+/// Values every instruction needs, living for the whole function.
+struct Frame {
+    ctx: Value,
+    mem: Value,
+    len: Value,
+    ptr: Variable,
+    exit: Block,
+}
+
+impl<'a> Translator<'a> {
+    fn new(module: &'a mut JITModule) -> Result<Self> {
+        let output_fn = module.declare_function(
+            "bf_jit_output",
+            Linkage::Import,
+            &signature(module, 3, false),
+        )?;
+        let input_fn = module.declare_function(
+            "bf_jit_input",
+            Linkage::Import,
+            &signature(module, 2, true),
+        )?;
+
+        Ok(Self {
+            module,
+            input_fn,
+            output_fn,
+        })
+    }
+
+    fn translate(mut self, program: &Program) -> Result<FuncId> {
+        let sig = signature(self.module, 5, true);
+        let func_id = self
+            .module
+            .declare_function("bf_main", Linkage::Local, &sig)?;
+
+        let mut ctx = Context::new();
+        ctx.func.signature = sig;
+
+        let mut func_ctx = FunctionBuilderContext::new();
+        let mut builder = FunctionBuilder::new(&mut ctx.func, &mut func_ctx);
+
+        let entry = builder.create_block();
+        builder.append_block_params_for_function_params(entry);
+        builder.switch_to_block(entry);
+        builder.seal_block(entry);
+
+        let params = builder.block_params(entry).to_vec();
+        let ptr = Variable::from_u32(0);
+        builder.declare_var(ptr, types::I64);
+        builder.def_var(ptr, params[3]);
+
+        // exit(pc, ptr): stores the pointer and returns pc to the caller.
+        let exit = builder.create_block();
+        builder.append_block_param(exit, types::I64);
+        builder.append_block_param(exit, types::I64);
+
+        let frame = Frame {
+            ctx: params[0],
+            mem: params[1],
+            len: params[2],
+            ptr,
+            exit,
+        };
+
+        let mut loops = vec![];
+
+        for (pc, &op) in program.iter().enumerate() {
+            self.translate_op(&mut builder, &frame, &mut loops, pc, op)?;
+        }
+
+        if !loops.is_empty() {
+            return Err(anyhow!("program has {} unclosed loops", loops.len()));
+        }
+
+        let pc = builder.ins().iconst(types::I64, program.len() as i64);
+        let ptr = builder.use_var(frame.ptr);
+        builder.ins().jump(exit, &[pc, ptr]);
+
+        builder.switch_to_block(exit);
+        builder.seal_block(exit);
+        let (pc, ptr) = (builder.block_params(exit)[0], builder.block_params(exit)[1]);
+        builder.ins().store(MemFlags::trusted(), ptr, params[4], 0);
+        builder.ins().return_(&[pc]);
+
+        builder.finalize();
+
+        self.module.define_function(func_id, &mut ctx)?;
+
+        Ok(func_id)
+    }
+
+    fn translate_op(
+        &mut self,
+        builder: &mut FunctionBuilder,
+        frame: &Frame,
+        loops: &mut Vec<(Block, Block)>,
+        pc: usize,
+        op: OpCode,
+    ) -> Result<()> {
+        let OpCode { ty, data, offset } = op;
+
+        match ty {
+            OpCodeType::Add | OpCodeType::Sub | OpCodeType::Set => {
+                let addr = cell_addr(builder, frame, pc, offset as i64);
+
+                let value = match ty {
+                    OpCodeType::Set => builder.ins().iconst(types::I8, data as i64),
+                    _ => {
+                        let cell = builder.ins().load(types::I8, MemFlags::trusted(), addr, 0);
+                        let amount = match ty {
+                            OpCodeType::Add => data as u8,
+                            _ => (data as u8).wrapping_neg(),
+                        };
+                        builder.ins().iadd_imm(cell, amount as i64)
+                    }
+                };
+
+                builder.ins().store(MemFlags::trusted(), value, addr, 0);
+            }
+            OpCodeType::MulAdd => {
+                let src = cell_addr(builder, frame, pc, 0);
+                let value = builder.ins().load(types::I8, MemFlags::trusted(), src, 0);
+
+                // Nothing happens (not even the bounds check) when the loop would not have run.
+                let fuse = builder.create_block();
+                let done = builder.create_block();
+                builder.ins().brif(value, fuse, &[], done, &[]);
+                builder.switch_to_block(fuse);
+                builder.seal_block(fuse);
+
+                let addr = cell_addr(builder, frame, pc, offset as i64);
+                let cell = builder.ins().load(types::I8, MemFlags::trusted(), addr, 0);
+                let product = builder.ins().imul_imm(value, data as i64);
+                let sum = builder.ins().iadd(cell, product);
+                builder.ins().store(MemFlags::trusted(), sum, addr, 0);
+                builder.ins().jump(done, &[]);
+
+                builder.switch_to_block(done);
+                builder.seal_block(done);
+            }
+            OpCodeType::FillRange | OpCodeType::ClearRange => {
+                let (len, value) = match ty {
+                    OpCodeType::FillRange => (data >> 8, data as u8),
+                    _ => (data, 0),
+                };
+
+                if len > 0 {
+                    cell_addr(builder, frame, pc, offset as i64 + len as i64 - 1);
+                    let addr = cell_addr(builder, frame, pc, offset as i64);
+
+                    let value = builder.ins().iconst(types::I8, value as i64);
+                    let len = builder.ins().iconst(types::I64, len as i64);
+                    builder.call_memset(self.module.target_config(), addr, value, len);
+                }
+            }
+            OpCodeType::ShiftRight => {
+                let ptr = builder.use_var(frame.ptr);
+                let moved = builder.ins().iadd_imm(ptr, data as i64);
+                let out = builder
+                    .ins()
+                    .icmp(IntCC::UnsignedGreaterThanOrEqual, moved, frame.len);
+                exit_if(builder, frame, pc, out);
+
+                builder.def_var(frame.ptr, moved);
+            }
+            OpCodeType::ShiftLeft => {
+                // Same clamping at cell 0 as the interpreter.
+                let ptr = builder.use_var(frame.ptr);
+                let moved = builder.ins().iadd_imm(ptr, (data as i64).wrapping_neg());
+                let under = builder
+                    .ins()
+                    .icmp_imm(IntCC::UnsignedLessThan, ptr, data as i64);
+                let zero = builder.ins().iconst(types::I64, 0);
+                let moved = builder.ins().select(under, zero, moved);
+
+                builder.def_var(frame.ptr, moved);
+            }
+            OpCodeType::ProcStart
+            | OpCodeType::ProcEnd
+            | OpCodeType::Call
+            | OpCodeType::End
+            | OpCodeType::Store
+            | OpCodeType::Retrieve
+            | OpCodeType::BitsLeft
+            | OpCodeType::BitsRight
+            | OpCodeType::Not
+            | OpCodeType::Xor
+            | OpCodeType::And
+            | OpCodeType::Or
+            | OpCodeType::Fork
+            | OpCodeType::PrevTape
+            | OpCodeType::NextTape
+            | OpCodeType::CopyToTape
+            | OpCodeType::Random
+            | OpCodeType::Dump
+            | OpCodeType::OpenFile
+            | OpCodeType::WriteFile
+            | OpCodeType::ReadFile => bail!("{} is only run by the VM", op.ty.mnemonic()),
+            // Quickened loops still have their body, running them as plain loops is fine.
+            OpCodeType::JmpZero | OpCodeType::ClearLoop | OpCodeType::MulLoop => {
+                let addr = cell_addr(builder, frame, pc, offset as i64);
+                let cell = builder.ins().load(types::I8, MemFlags::trusted(), addr, 0);
+
+                let body = builder.create_block();
+                let after = builder.create_block();
+                builder.ins().brif(cell, body, &[], after, &[]);
+
+                builder.switch_to_block(body);
+                loops.push((body, after));
+            }
+            OpCodeType::JmpNotZero => {
+                let (body, after) = loops
+                    .pop()
+                    .ok_or_else(|| anyhow!("unmatched JmpNotZero at pc={}", pc))?;
+
+                let addr = cell_addr(builder, frame, pc, offset as i64);
+                let cell = builder.ins().load(types::I8, MemFlags::trusted(), addr, 0);
+                builder.ins().brif(cell, body, &[], after, &[]);
+
+                builder.seal_block(body);
+                builder.switch_to_block(after);
+                builder.seal_block(after);
+            }
+            OpCodeType::PrintChar => {
+                let addr = cell_addr(builder, frame, pc, offset as i64);
+                let cell = builder.ins().load(types::I8, MemFlags::trusted(), addr, 0);
+                let ch = builder.ins().uextend(types::I64, cell);
+                let count = builder.ins().iconst(types::I64, data as i64);
+
+                let output = self
+                    .module
+                    .declare_func_in_func(self.output_fn, builder.func);
+                builder.ins().call(output, &[frame.ctx, ch, count]);
+            }
+            OpCodeType::InputChar => {
+                let addr = cell_addr(builder, frame, pc, offset as i64);
+
+                let input = self
+                    .module
+                    .declare_func_in_func(self.input_fn, builder.func);
+                let call = builder.ins().call(input, &[frame.ctx, addr]);
+                let ok = builder.inst_results(call)[0];
+                let failed = builder.ins().icmp_imm(IntCC::Equal, ok, 0);
+                exit_if(builder, frame, pc, failed);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// All parameters and the return value are 64 bit integers (pointers included).
+fn signature(module: &JITModule, params: usize, returns: bool) -> Signature {
+    let mut sig = module.make_signature();
+    sig.params
+        .extend((0..params).map(|_| AbiParam::new(types::I64)));
+    if returns {
+        sig.returns.push(AbiParam::new(types::I64));
+    }
+
+    sig
+}
+
+/// Leaves the native code in front of the instruction at `pc` when `cond` is true.
+fn exit_if(builder: &mut FunctionBuilder, frame: &Frame, pc: usize, cond: Value) {
+    let pc = builder.ins().iconst(types::I64, pc as i64);
+    let ptr = builder.use_var(frame.ptr);
+
+    let next = builder.create_block();
+    builder.ins().brif(cond, frame.exit, &[pc, ptr], next, &[]);
+    builder.switch_to_block(next);
+    builder.seal_block(next);
+}
+
+/// Address of mem[ptr + offset], leaving the native code if it is outside of memory.
+fn cell_addr(builder: &mut FunctionBuilder, frame: &Frame, pc: usize, offset: i64) -> Value {
+    let ptr = builder.use_var(frame.ptr);
+
+    let idx = if offset == 0 {
+        ptr
+    } else {
+        let idx = builder.ins().iadd_imm(ptr, offset);
+        let out = builder
+            .ins()
+            .icmp(IntCC::UnsignedGreaterThanOrEqual, idx, frame.len);
+        exit_if(builder, frame, pc, out);
+        idx
+    };
+
+    builder.ins().iadd(frame.mem, idx)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::empty;
+
+    use crate::{jit::JitProgram, lexer, parser, vm};
+
+    fn run(program: &parser::Program, mem: &mut [u8], input: &[u8]) -> (usize, usize, Vec<u8>) {
+        let jit = JitProgram::compile(program).unwrap();
+        let mut mem_ptr = 0;
+        let mut output = vec![];
+
+        let pc = jit.run(mem, &mut mem_ptr, &mut &input[..], &mut output);
+
+        (pc, mem_ptr, output)
+    }
+
+    #[test]
+    fn runs_optimized_program() {
+        let program = vm::compile("++++++++[>++++++++<-]>+.,[->+<]>.<<<+").unwrap();
+        let mut mem = vec![0; 8];
+
+        let (pc, mem_ptr, output) = run(&program, &mut mem, b"\x02");
+
+        assert_eq!(pc, program.len());
+        assert_eq!(mem_ptr, 0);
+        assert_eq!(output, b"A\x02");
+        assert_eq!(mem, [1, 0, 2, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn stops_where_the_interpreter_has_to_take_over() {
+        let program = parser::parse(lexer::parse("+[>+]")).unwrap();
+        let mut mem = vec![0; 4];
+
+        // The `>` on the last cell.
+        assert_eq!(run(&program, &mut mem, b"").0, 2);
+        assert_eq!(mem, [1, 1, 1, 1]);
+
+        let program = parser::parse(lexer::parse("+,.")).unwrap();
+        let (pc, _, output) = run(&program, &mut mem, b"");
+
+        assert_eq!(pc, 1);
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn jit_matches_interpreter() {
+        let src = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+
+        let mut vm = vm::Vm::new(src).unwrap();
+        let mut output = vec![];
+        vm.run_jit(&mut empty(), &mut output).unwrap();
+
+        assert_eq!(output, b"Hello World!\n");
+    }
+}
@@ -0,0 +1,381 @@
+/*
+ *  Just enough JSON for the debug adapter, the language server and `--stats-json`: a value type, a
+ *  parser and writing values back out. Numbers are f64 like in JavaScript, objects keep their keys
+ *  in order.
+ */
+
+use std::fmt;
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::diagnostic::json_string;
+
+/// How deep arrays and objects can nest: each one is parsed on the stack of the one around it, and
+/// requests to the servers come from anyone.
+const MAX_DEPTH: usize = 128;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut parser = Parser {
+            bytes: text.as_bytes(),
+            pos: 0,
+            depth: 0,
+        };
+
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        if parser.pos != parser.bytes.len() {
+            bail!("trailing characters at byte {}", parser.pos);
+        }
+
+        Ok(value)
+    }
+
+    pub fn object<'a>(fields: impl IntoIterator<Item = (&'a str, Json)>) -> Self {
+        Json::Object(
+            fields
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect(),
+        )
+    }
+
+    /// The field `key` of an object, `Null` if there is none or this is not an object.
+    pub fn get(&self, key: &str) -> &Json {
+        match self {
+            Json::Object(fields) => fields
+                .iter()
+                .find(|(name, _)| name == key)
+                .map_or(&Json::Null, |(_, value)| value),
+            _ => &Json::Null,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Json::Bool(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    pub fn as_usize(&self) -> Option<usize> {
+        match *self {
+            Json::Number(n) if n >= 0.0 && n.fract() == 0.0 => Some(n as usize),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> &[Json] {
+        match self {
+            Json::Array(items) => items,
+            _ => &[],
+        }
+    }
+}
+
+impl From<bool> for Json {
+    fn from(b: bool) -> Self {
+        Json::Bool(b)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(n: usize) -> Self {
+        Json::Number(n as f64)
+    }
+}
+
+impl From<f64> for Json {
+    fn from(n: f64) -> Self {
+        Json::Number(n)
+    }
+}
+
+impl From<&str> for Json {
+    fn from(s: &str) -> Self {
+        Json::String(s.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(s: String) -> Self {
+        Json::String(s)
+    }
+}
+
+impl From<Vec<Json>> for Json {
+    fn from(items: Vec<Json>) -> Self {
+        Json::Array(items)
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) if n.fract() == 0.0 && n.abs() < 1e15 => write!(f, "{}", *n as i64),
+            Json::Number(n) => write!(f, "{}", n),
+            Json::String(s) => write!(f, "{}", json_string(s)),
+            Json::Array(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(fields) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}:{}", json_string(key), value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    /// Arrays and objects open.
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn value(&mut self) -> Result<Json> {
+        self.skip_whitespace();
+
+        match self.peek() {
+            Some(b'{' | b'[') if self.depth == MAX_DEPTH => {
+                bail!("nested deeper than {} at byte {}", MAX_DEPTH, self.pos)
+            }
+            Some(ch @ (b'{' | b'[')) => {
+                self.depth += 1;
+                let value = match ch {
+                    b'{' => self.object(),
+                    _ => self.array(),
+                };
+                self.depth -= 1;
+                value
+            }
+            Some(b'"') => Ok(Json::String(self.string()?)),
+            Some(b't') => self.keyword("true", Json::Bool(true)),
+            Some(b'f') => self.keyword("false", Json::Bool(false)),
+            Some(b'n') => self.keyword("null", Json::Null),
+            Some(b'-' | b'0'..=b'9') => self.number(),
+            Some(ch) => bail!("unexpected {:?} at byte {}", ch as char, self.pos),
+            None => bail!("unexpected end of JSON"),
+        }
+    }
+
+    fn object(&mut self) -> Result<Json> {
+        self.expect(b'{')?;
+        let mut fields = vec![];
+
+        self.skip_whitespace();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(Json::Object(fields));
+        }
+
+        loop {
+            self.skip_whitespace();
+            let key = self.string()?;
+            self.skip_whitespace();
+            self.expect(b':')?;
+            fields.push((key, self.value()?));
+
+            self.skip_whitespace();
+            match self.next() {
+                Some(b',') => {}
+                Some(b'}') => return Ok(Json::Object(fields)),
+                _ => bail!("expected `,` or `}}` at byte {}", self.pos),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Json> {
+        self.expect(b'[')?;
+        let mut items = vec![];
+
+        self.skip_whitespace();
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return Ok(Json::Array(items));
+        }
+
+        loop {
+            items.push(self.value()?);
+
+            self.skip_whitespace();
+            match self.next() {
+                Some(b',') => {}
+                Some(b']') => return Ok(Json::Array(items)),
+                _ => bail!("expected `,` or `]` at byte {}", self.pos),
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String> {
+        self.expect(b'"')?;
+        let mut out = String::new();
+
+        loop {
+            // Everything but `"` and `\` is copied as it is, the input is valid UTF-8 already.
+            let start = self.pos;
+            while !matches!(self.peek(), Some(b'"' | b'\\') | None) {
+                self.pos += 1;
+            }
+            out.push_str(std::str::from_utf8(&self.bytes[start..self.pos])?);
+
+            match self.next() {
+                Some(b'"') => return Ok(out),
+                Some(b'\\') => {}
+                _ => bail!("unterminated string"),
+            }
+
+            let ch = match self.next() {
+                Some(b'"') => '"',
+                Some(b'\\') => '\\',
+                Some(b'/') => '/',
+                Some(b'b') => '\u{8}',
+                Some(b'f') => '\u{c}',
+                Some(b'n') => '\n',
+                Some(b'r') => '\r',
+                Some(b't') => '\t',
+                Some(b'u') => {
+                    let unit = self.hex4()?;
+                    // A surrogate pair is two escapes, a lone half becomes U+FFFD.
+                    let code = if (0xd800..0xdc00).contains(&unit)
+                        && self.bytes[self.pos..].starts_with(b"\\u")
+                    {
+                        self.pos += 2;
+                        let low = self.hex4()?;
+                        0x10000 + ((unit - 0xd800) << 10) + (low.wrapping_sub(0xdc00) & 0x3ff)
+                    } else {
+                        unit
+                    };
+                    char::from_u32(code).unwrap_or('\u{fffd}')
+                }
+                _ => bail!("bad escape at byte {}", self.pos),
+            };
+            out.push(ch);
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32> {
+        let digits = self
+            .bytes
+            .get(self.pos..self.pos + 4)
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+            .ok_or_else(|| anyhow!("bad \\u escape at byte {}", self.pos))?;
+        self.pos += 4;
+
+        Ok(digits)
+    }
+
+    fn number(&mut self) -> Result<Json> {
+        let start = self.pos;
+        while matches!(
+            self.peek(),
+            Some(b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9')
+        ) {
+            self.pos += 1;
+        }
+
+        let text = std::str::from_utf8(&self.bytes[start..self.pos])?;
+        text.parse()
+            .map(Json::Number)
+            .map_err(|_| anyhow!("bad number {:?}", text))
+    }
+
+    fn keyword(&mut self, word: &str, value: Json) -> Result<Json> {
+        if !self.bytes[self.pos..].starts_with(word.as_bytes()) {
+            bail!("unexpected characters at byte {}", self.pos);
+        }
+        self.pos += word.len();
+
+        Ok(value)
+    }
+
+    fn expect(&mut self, ch: u8) -> Result<()> {
+        match self.next() {
+            Some(next) if next == ch => Ok(()),
+            _ => bail!("expected `{}` at byte {}", ch as char, self.pos),
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let ch = self.peek()?;
+        self.pos += 1;
+        Some(ch)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::json::Json;
+
+    #[test]
+    fn parses_and_writes_values() {
+        let text = r#" {"seq": 1, "args": {"lines": [3, -1.5e1], "ok": true,
+            "name": "a \"b\"\n\u00e9\ud83d\ude00", "none": null}, "empty": {}} "#;
+        let value = Json::parse(text).unwrap();
+
+        assert_eq!(value.get("seq").as_usize(), Some(1));
+        assert_eq!(value.get("args").get("lines").as_array().len(), 2);
+        assert_eq!(value.get("args").get("ok").as_bool(), Some(true));
+        assert_eq!(value.get("args").get("name").as_str(), Some("a \"b\"\né😀"));
+        assert_eq!(value.get("missing"), &Json::Null);
+        assert_eq!(
+            value.to_string(),
+            r#"{"seq":1,"args":{"lines":[3,-15],"ok":true,"name":"a \"b\"\né😀","none":null},"empty":{}}"#
+        );
+
+        assert!(Json::parse("{\"a\": 1,}").is_err());
+        assert!(Json::parse("[1] 2").is_err());
+        assert!(Json::parse("\"open").is_err());
+
+        let nested = |depth| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+        assert!(Json::parse(&nested(128)).is_ok());
+        assert_eq!(
+            Json::parse(&nested(1_000_000)).unwrap_err().to_string(),
+            "nested deeper than 128 at byte 128"
+        );
+    }
+}
@@ -1,5 +1,18 @@
 use crate::parser::{TokenData, TokenList};
-use std::fmt::{self, Display};
+use std::{
+    fmt::{self, Display},
+    io::{self, ErrorKind, Read},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Columns a tab takes up to, see `set_tab_width`.
+static TAB_WIDTH: AtomicUsize = AtomicUsize::new(1);
+
+/// Makes a tab go on to the next column after a multiple of `width`, the way an editor showing tabs
+/// that wide counts columns. One for the whole process, a tab is a single column until set.
+pub fn set_tab_width(width: usize) {
+    TAB_WIDTH.store(width.max(1), Ordering::Relaxed);
+}
 
 pub fn parse(src: &str) -> TokenList {
     let lexer = Lexer::new(src);
@@ -7,8 +20,100 @@ pub fn parse(src: &str) -> TokenList {
     lexer.parse()
 }
 
+/// Same as `parse` for pbrain, Brainfuck with procedures: `(` and `)` around the body of the procedure
+/// numbered by the current cell, `:` calls it.
+pub fn parse_pbrain(src: &str) -> TokenList {
+    parse_with(src, Options::with_extension(Extension::Pbrain))
+}
+
+/// Same as `parse` for Extended Brainfuck Type I, which adds a storage cell and bitwise operations.
+/// An `@` outside of any loop ends the program, what follows it is left out.
+pub fn parse_extended1(src: &str) -> TokenList {
+    parse_with(src, Options::with_extension(Extension::Extended1))
+}
+
+/// Same as `parse` for Brainfork, which adds `Y` to fork the running thread.
+pub fn parse_brainfork(src: &str) -> TokenList {
+    parse_with(src, Options::with_extension(Extension::Brainfork))
+}
+
+/// Same as `parse` for the multi-tape dialect: `{` and `}` switch to the previous and the next tape,
+/// `=` copies the current cell to the next one.
+pub fn parse_multitape(src: &str) -> TokenList {
+    parse_with(src, Options::with_extension(Extension::MultiTape))
+}
+
+/// Same as `parse` with the commands `options` adds.
+pub fn parse_with(src: &str, options: Options) -> TokenList {
+    let mut lexer = Lexer::new(src);
+    lexer.options = options;
+    let mut tokens = lexer.parse();
+
+    if options.extension == Extension::Extended1 {
+        let mut depth = 0;
+        let end = tokens
+            .iter()
+            .position(|&(token, _)| ends_extended1(&mut depth, token));
+        if let Some(end) = end {
+            tokens.truncate(end + 1);
+        }
+    }
+
+    tokens
+}
+
+/// Whether `token` is the `@` ending an Extended Brainfuck program, `depth` being the loops open
+/// before it.
+fn ends_extended1(depth: &mut usize, token: Token) -> bool {
+    match token {
+        Token::LBracket => *depth += 1,
+        Token::RBracket => *depth = depth.saturating_sub(1),
+        _ => {}
+    }
+
+    token == Token::At && *depth == 0
+}
+
+/// Commands on top of the eight of Brainfuck, the ones of a dialect.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Extension {
+    #[default]
+    None,
+    Pbrain,
+    Extended1,
+    Brainfork,
+    MultiTape,
+}
+
+/// What the lexer reads on top of Brainfuck: the commands of a dialect, and commands any dialect
+/// can have.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Options {
+    pub extension: Extension,
+    /// `?`, which sets the cell to a random byte.
+    pub random: bool,
+    /// `#`, which prints the pointer and the first cells to standard error.
+    pub dump: bool,
+    /// `%`, `^` and `!` of Brainfuck++, which open, write and read files.
+    pub files: bool,
+}
+
+impl Options {
+    pub fn with_extension(extension: Extension) -> Self {
+        Self {
+            extension,
+            ..Self::default()
+        }
+    }
+}
+
 /*
    Brainf*ck tokens: +-<>[],.
+   pbrain adds ():
+   Extended Brainfuck Type I adds @$!{}~^&|
+   Brainfork adds Y
+   Multi-tape adds {}=
+   Any of them can have ?, for random numbers, # to print the tape and the %^! of files
 */
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
 pub enum Token {
@@ -20,9 +125,62 @@ pub enum Token {
     RBracket,
     Comma,
     Dot,
+    LParen,
+    RParen,
+    Colon,
+    At,
+    Dollar,
+    Bang,
+    LBrace,
+    RBrace,
+    Tilde,
+    Caret,
+    Ampersand,
+    Pipe,
+    Fork,
+    PrevTape,
+    NextTape,
+    CopyToTape,
+    Question,
+    Hash,
+    OpenFile,
+    WriteFile,
+    ReadFile,
 }
 
 impl Token {
+    pub const ALL: [Token; 29] = [
+        Token::Plus,
+        Token::Minus,
+        Token::Less,
+        Token::Greater,
+        Token::LBracket,
+        Token::RBracket,
+        Token::Comma,
+        Token::Dot,
+        Token::LParen,
+        Token::RParen,
+        Token::Colon,
+        Token::At,
+        Token::Dollar,
+        Token::Bang,
+        Token::LBrace,
+        Token::RBrace,
+        Token::Tilde,
+        Token::Caret,
+        Token::Ampersand,
+        Token::Pipe,
+        Token::Fork,
+        Token::PrevTape,
+        Token::NextTape,
+        Token::CopyToTape,
+        Token::Question,
+        Token::Hash,
+        Token::OpenFile,
+        Token::WriteFile,
+        Token::ReadFile,
+    ];
+
     pub fn from_u8(ch: u8) -> Option<Self> {
         Some(match ch {
             b'+' => Token::Plus,
@@ -37,20 +195,197 @@ impl Token {
         })
     }
 
+    /// Same as `from_u8`, with the tokens of pbrain.
+    pub fn from_u8_pbrain(ch: u8) -> Option<Self> {
+        Some(match ch {
+            b'(' => Token::LParen,
+            b')' => Token::RParen,
+            b':' => Token::Colon,
+            _ => return Self::from_u8(ch),
+        })
+    }
+
+    /// Same as `from_u8`, with the tokens of Extended Brainfuck Type I.
+    pub fn from_u8_extended1(ch: u8) -> Option<Self> {
+        Some(match ch {
+            b'@' => Token::At,
+            b'$' => Token::Dollar,
+            b'!' => Token::Bang,
+            b'{' => Token::LBrace,
+            b'}' => Token::RBrace,
+            b'~' => Token::Tilde,
+            b'^' => Token::Caret,
+            b'&' => Token::Ampersand,
+            b'|' => Token::Pipe,
+            _ => return Self::from_u8(ch),
+        })
+    }
+
+    /// Same as `from_u8`, with the `Y` of Brainfork.
+    pub fn from_u8_brainfork(ch: u8) -> Option<Self> {
+        match ch {
+            b'Y' => Some(Token::Fork),
+            _ => Self::from_u8(ch),
+        }
+    }
+
+    /// Same as `from_u8`, with the tape commands of the multi-tape dialect.
+    pub fn from_u8_multitape(ch: u8) -> Option<Self> {
+        Some(match ch {
+            b'{' => Token::PrevTape,
+            b'}' => Token::NextTape,
+            b'=' => Token::CopyToTape,
+            _ => return Self::from_u8(ch),
+        })
+    }
+
+    pub fn to_char(self) -> char {
+        match self {
+            Token::Plus => '+',
+            Token::Minus => '-',
+            Token::Less => '<',
+            Token::Greater => '>',
+            Token::LBracket => '[',
+            Token::RBracket => ']',
+            Token::Comma => ',',
+            Token::Dot => '.',
+            Token::LParen => '(',
+            Token::RParen => ')',
+            Token::Colon => ':',
+            Token::At => '@',
+            Token::Dollar => '$',
+            Token::Bang => '!',
+            Token::LBrace => '{',
+            Token::RBrace => '}',
+            Token::Tilde => '~',
+            Token::Caret => '^',
+            Token::Ampersand => '&',
+            Token::Pipe => '|',
+            Token::Fork => 'Y',
+            Token::PrevTape => '{',
+            Token::NextTape => '}',
+            Token::CopyToTape => '=',
+            Token::Question => '?',
+            Token::Hash => '#',
+            Token::OpenFile => '%',
+            Token::WriteFile => '^',
+            Token::ReadFile => '!',
+        }
+    }
+
+    /// The command `ch` is easily mistaken for, these are comments like any other character.
+    pub fn lookalike(ch: char) -> Option<Self> {
+        Some(match ch {
+            '＋' | '﹢' | '➕' => Token::Plus,
+            '−' | '–' | '—' | '‐' | '‑' | '－' | '﹣' => Token::Minus,
+            '‹' | '＜' | '﹤' | '〈' | '⟨' => Token::Less,
+            '›' | '＞' | '﹥' | '〉' | '⟩' => Token::Greater,
+            '［' => Token::LBracket,
+            '］' => Token::RBracket,
+            '，' | '、' | '‚' => Token::Comma,
+            '．' | '。' | '․' => Token::Dot,
+            _ => return None,
+        })
+    }
+
     pub fn is_loop_token(&self) -> bool {
         matches!(self, Self::LBracket | Self::RBracket)
     }
 
+    /// `(`, `)` and `:` of pbrain.
+    pub fn is_procedure_token(&self) -> bool {
+        matches!(self, Self::LParen | Self::RParen | Self::Colon)
+    }
+
+    /// The commands repeats of which are a single opcode, the ones of Brainfuck but for loops.
     pub fn is_groupable(&self) -> bool {
-        !self.is_loop_token()
+        matches!(
+            self,
+            Self::Plus | Self::Minus | Self::Less | Self::Greater | Self::Comma | Self::Dot
+        )
     }
 }
 
+/// Characters in `src` looking like commands, with where they are. See `Token::lookalike`.
+pub fn lookalikes(src: &str) -> Vec<(char, Token, TokenLoc)> {
+    let mut loc = TokenLoc::new();
+    let mut found = vec![];
+
+    for ch in src.chars() {
+        let mut buf = [0; 4];
+        let bytes = ch.encode_utf8(&mut buf).as_bytes();
+
+        // Located at its first byte.
+        loc.update_location(bytes[0]);
+        if let Some(tok) = Token::lookalike(ch) {
+            found.push((ch, tok, loc));
+        }
+        bytes[1..].iter().for_each(|&b| loc.update_location(b));
+    }
+
+    found
+}
+
+/// Length of the `#!` line `src` starts with, without its line break. Scripts run by `#!/usr/bin/env bf`
+/// would otherwise get the `.` and `-` of the interpreter path as commands.
+pub fn shebang_len(src: &[u8]) -> usize {
+    if !src.starts_with(b"#!") {
+        return 0;
+    }
+
+    src.iter().position(|&ch| ch == b'\n').unwrap_or(src.len())
+}
+
+/// Brainfuck laid out like `src`, written in another language: every command in place of the
+/// character at the byte offset it has in `src`, the line breaks and tabs where they are and a space
+/// for every other character. The Brainfuck lexer finds the commands at the same locations as in
+/// `src`.
+pub fn layout(src: &str, commands: impl IntoIterator<Item = (Token, usize)>) -> String {
+    // Where the character at each byte of `src` is in `out`.
+    let mut at = vec![0; src.len()];
+    let mut out: Vec<u8> = Vec::with_capacity(src.len());
+    for (i, ch) in src.char_indices() {
+        at[i] = out.len();
+        out.push(match ch {
+            '\n' | '\t' => ch as u8,
+            _ => b' ',
+        });
+    }
+    for (token, offset) in commands {
+        out[at[offset]] = token.to_char() as u8;
+    }
+
+    String::from_utf8(out).expect("spaces, line breaks and commands are ASCII")
+}
+
+/// The column of every byte of `line`, as `TokenLoc` counts them: the bytes of a character are in its
+/// column, a tab is in the last one it takes up.
+pub fn columns(line: &str) -> Vec<usize> {
+    let mut loc = TokenLoc::new();
+
+    line.bytes()
+        .map(|ch| {
+            loc.update_location(ch);
+            loc.col().max(1)
+        })
+        .collect()
+}
+
+/// Where column `col` of `line` starts, in bytes: the character in it or the tab taking it up, the
+/// length of the line past its end.
+pub fn col_offset(line: &str, col: usize) -> usize {
+    columns(line)
+        .iter()
+        .position(|&at| at >= col)
+        .unwrap_or(line.len())
+}
+
 #[derive(Debug)]
 pub struct Lexer<'a> {
     src: &'a [u8],
     pos: usize,
     loc: TokenLoc,
+    options: Options,
 }
 
 impl<'a> Lexer<'a> {
@@ -59,6 +394,7 @@ impl<'a> Lexer<'a> {
             src,
             pos: 0,
             loc: TokenLoc::new(),
+            options: Options::default(),
         }
     }
 
@@ -70,11 +406,35 @@ impl<'a> Lexer<'a> {
         self.loc
     }
 
-    pub fn parse(mut self) -> TokenList {
+    pub fn parse(self) -> TokenList {
+        self.tokens().collect()
+    }
+
+    /// Same as `parse`, the locations kept apart from the tokens and only if `locs`.
+    pub fn into_tokens(self, locs: bool) -> Tokens {
+        let mut tokens = Tokens {
+            tokens: Vec::with_capacity(self.src.len()),
+            locs: locs.then(Vec::new),
+        };
+        for (token, loc) in self.tokens() {
+            tokens.push(token, loc);
+        }
+        tokens.tokens.shrink_to_fit();
+        if let Some(locs) = &mut tokens.locs {
+            locs.shrink_to_fit();
+        }
+
+        tokens
+    }
+
+    /// Same as `parse`, a token at a time.
+    pub fn tokens(mut self) -> impl Iterator<Item = TokenData> + 'a {
+        let shebang = shebang_len(self.src);
+
         self.src
             .iter()
-            .filter_map(|&ch| self.get_token_with_location(ch))
-            .collect()
+            .enumerate()
+            .filter_map(move |(i, &ch)| self.get_token_with_location(ch).filter(|_| i >= shebang))
     }
 
     fn inc_pos(&mut self) {
@@ -85,7 +445,193 @@ impl<'a> Lexer<'a> {
         self.inc_pos();
         self.loc.update_location(ch);
 
-        Token::from_u8(ch).map(|tok| (tok, self.get_location()))
+        let token = match self.options.extension {
+            _ if ch == b'?' && self.options.random => Some(Token::Question),
+            _ if ch == b'#' && self.options.dump => Some(Token::Hash),
+            _ if ch == b'%' && self.options.files => Some(Token::OpenFile),
+            _ if ch == b'^' && self.options.files => Some(Token::WriteFile),
+            _ if ch == b'!' && self.options.files => Some(Token::ReadFile),
+            Extension::None => Token::from_u8(ch),
+            Extension::Pbrain => Token::from_u8_pbrain(ch),
+            Extension::Extended1 => Token::from_u8_extended1(ch),
+            Extension::Brainfork => Token::from_u8_brainfork(ch),
+            Extension::MultiTape => Token::from_u8_multitape(ch),
+        };
+
+        token.map(|tok| (tok, self.get_location()))
+    }
+}
+
+/// A `TokenList` as two: the tokens, a byte each, and where they are, which can be left out. A
+/// program of many tokens takes much less memory this way, and none for the locations when its
+/// errors don't need them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Tokens {
+    tokens: Vec<Token>,
+    /// The location of each token, None when left out.
+    locs: Option<Vec<TokenLoc>>,
+}
+
+impl Tokens {
+    fn push(&mut self, token: Token, loc: TokenLoc) {
+        self.tokens.push(token);
+        if let Some(locs) = &mut self.locs {
+            locs.push(loc);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    pub fn tokens(&self) -> &[Token] {
+        &self.tokens
+    }
+
+    pub fn locs(&self) -> Option<&[TokenLoc]> {
+        self.locs.as_deref()
+    }
+
+    /// The same tokens without their locations.
+    pub fn without_locs(mut self) -> Self {
+        self.locs = None;
+        self
+    }
+
+    /// The tokens with their locations, all at the start of the program when left out.
+    pub fn iter(&self) -> impl ExactSizeIterator<Item = TokenData> + '_ {
+        self.tokens.iter().enumerate().map(|(i, &token)| {
+            let loc = self.locs.as_ref().map_or(TokenLoc::new(), |locs| locs[i]);
+            (token, loc)
+        })
+    }
+}
+
+/// Bytes `StreamLexer` reads at a time.
+const CHUNK_SIZE: usize = 64 << 10;
+
+/// Same as `parse_with` for a program read from `reader` a chunk at a time, for programs too big to
+/// read into memory first. Gives the tokens one by one, see `parser::parse_stream`.
+pub struct StreamLexer<R> {
+    reader: R,
+    buf: Box<[u8]>,
+    /// Bytes of `buf` read, and the next one to lex.
+    len: usize,
+    pos: usize,
+    lexer: Lexer<'static>,
+    started: bool,
+    /// Whether the `#!` line is being skipped, see `shebang_len`.
+    in_shebang: bool,
+    /// Loops open, for the `@` ending Extended Brainfuck programs.
+    depth: usize,
+    ended: bool,
+}
+
+impl<R: Read> StreamLexer<R> {
+    pub fn new(reader: R, options: Options) -> Self {
+        let mut lexer = Lexer::from_bytes(&[]);
+        lexer.options = options;
+
+        Self {
+            reader,
+            buf: vec![0; CHUNK_SIZE].into_boxed_slice(),
+            len: 0,
+            pos: 0,
+            lexer,
+            started: false,
+            in_shebang: false,
+            depth: 0,
+            ended: false,
+        }
+    }
+
+    /// Reads on into `buf`, false at the end of the program.
+    fn fill(&mut self) -> io::Result<bool> {
+        if self.pos == self.len {
+            (self.pos, self.len) = (0, 0);
+        }
+
+        loop {
+            match self.reader.read(&mut self.buf[self.len..]) {
+                Ok(read) => {
+                    self.len += read;
+                    return Ok(read > 0);
+                }
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// The next token in the bytes read so far, None once lexing on needs another read. For running a
+    /// program as it arrives, see `parser::IncrementalParser`.
+    pub fn next_buffered(&mut self) -> Option<TokenData> {
+        match self.started {
+            true => self.lex_buffered(),
+            false => None,
+        }
+    }
+
+    fn lex_buffered(&mut self) -> Option<TokenData> {
+        while !self.ended && self.pos < self.len {
+            let ch = self.buf[self.pos];
+            self.pos += 1;
+
+            let token = self.lexer.get_token_with_location(ch);
+            if self.in_shebang {
+                self.in_shebang = ch != b'\n';
+                continue;
+            }
+            let Some((token, loc)) = token else {
+                continue;
+            };
+            if self.lexer.options.extension == Extension::Extended1 {
+                self.ended = ends_extended1(&mut self.depth, token);
+            }
+
+            return Some((token, loc));
+        }
+
+        None
+    }
+
+    /// Reads the first two bytes, which tell if the program starts with a `#!` line.
+    fn start(&mut self) -> io::Result<()> {
+        self.started = true;
+        while self.len < 2 && self.fill()? {}
+        self.in_shebang = self.buf[..self.len].starts_with(b"#!");
+
+        Ok(())
+    }
+}
+
+impl<R: Read> Iterator for StreamLexer<R> {
+    type Item = io::Result<TokenData>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if !self.started {
+            if let Err(err) = self.start() {
+                return Some(Err(err));
+            }
+        }
+
+        loop {
+            if let Some(token) = self.lex_buffered() {
+                return Some(Ok(token));
+            }
+            if self.ended {
+                return None;
+            }
+            match self.fill() {
+                Ok(true) => {}
+                Ok(false) => return None,
+                Err(err) => return Some(Err(err)),
+            }
+        }
     }
 }
 
@@ -100,9 +646,18 @@ impl TokenLoc {
         Self { col: 0, line: 1 }
     }
 
+    /// Moves past the byte `ch` of the source. Columns count characters, the bytes after the first
+    /// of one stay in its column, and a tab goes on to the next multiple of the tab width.
     pub fn update_location(&mut self, ch: u8) {
+        self.advance(ch, TAB_WIDTH.load(Ordering::Relaxed));
+    }
+
+    fn advance(&mut self, ch: u8, tab_width: usize) {
         match ch {
             b'\n' => self.inc_line(),
+            b'\t' => self.col = (self.col / tab_width + 1) * tab_width,
+            // UTF-8 continuation bytes.
+            0x80..=0xbf => {}
             _ => self.inc_col(),
         }
     }
@@ -115,7 +670,6 @@ impl TokenLoc {
         self.col
     }
 
-    #[cfg(test)]
     pub fn from_col_line(col: usize, line: usize) -> Self {
         Self { line, col }
     }
@@ -173,4 +727,123 @@ mod test {
 
         assert_eq!(tokens, expected);
     }
+
+    #[test]
+    fn finds_lookalikes() {
+        let found = super::lookalikes("+−\n［a］");
+
+        let expected = vec![
+            ('−', Minus, TokenLoc::from_col_line(2, 1)),
+            ('［', LBracket, TokenLoc::from_col_line(1, 2)),
+            ('］', RBracket, TokenLoc::from_col_line(3, 2)),
+        ];
+
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn counts_characters_and_tab_stops() {
+        // The comment is 2 characters in 5 bytes.
+        let tokens = super::parse("é☃+\n\t-");
+        assert_eq!(tokens[0], (Plus, TokenLoc::from_col_line(3, 1)));
+        assert_eq!(tokens[1], (Minus, TokenLoc::from_col_line(2, 2)));
+        assert_eq!(super::columns("é+"), [1, 1, 2]);
+        assert_eq!(super::col_offset("é+", 2), 2);
+
+        let mut loc = TokenLoc::new();
+        for &ch in b"ab\t" {
+            loc.advance(ch, 4);
+        }
+        assert_eq!(loc.col(), 4);
+        loc.advance(b'\t', 4);
+        assert_eq!(loc.col(), 8);
+    }
+
+    #[test]
+    fn skips_shebang() {
+        let tokens = super::parse("#!/usr/local/bin/bf-2.0\n+.");
+
+        let expected = vec![
+            (Plus, TokenLoc::from_col_line(1, 2)),
+            (Dot, TokenLoc::from_col_line(2, 2)),
+        ];
+
+        assert_eq!(tokens, expected);
+        assert_eq!(super::parse("+#!.").len(), 2);
+    }
+
+    #[test]
+    fn reads_optional_commands_with_any_dialect() {
+        let options = Options {
+            extension: Extension::Pbrain,
+            random: true,
+            ..Options::default()
+        };
+        let tokens: Vec<_> = super::parse_with("?(?)", options)
+            .into_iter()
+            .map(|(token, _)| token)
+            .collect();
+
+        assert_eq!(tokens, [Question, LParen, Question, RParen]);
+        assert!(super::parse("?").is_empty());
+
+        // Not the `#` of a shebang.
+        let options = Options {
+            dump: true,
+            ..Options::default()
+        };
+        let tokens = super::parse_with("#!/usr/bin/bf\n+#", options);
+        assert_eq!(tokens.last(), Some(&(Hash, TokenLoc::from_col_line(2, 2))));
+        assert_eq!(tokens.len(), 2);
+    }
+
+    /// Gives a byte at a time, so every byte is at the end of a chunk.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl io::Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let Some((&first, rest)) = self.0.split_first() else {
+                return Ok(0);
+            };
+            buf[0] = first;
+            self.0 = rest;
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn streams_the_same_tokens() {
+        let dump = Options {
+            dump: true,
+            ..Options::default()
+        };
+        for (src, options) in [
+            ("#!/usr/bin/env bf\n+[é\t-]#", dump),
+            ("#+", dump),
+            ("+[@]-@data[", Options::with_extension(Extension::Extended1)),
+            ("", Options::default()),
+        ] {
+            let streamed: io::Result<TokenList> =
+                StreamLexer::new(Trickle(src.as_bytes()), options).collect();
+            assert_eq!(
+                streamed.unwrap(),
+                super::parse_with(src, options),
+                "{}",
+                src
+            );
+        }
+    }
+
+    #[test]
+    fn keeps_locations_apart() {
+        let src = "#!/usr/bin/env bf\n+a[\t-]";
+        let tokens = Lexer::new(src).into_tokens(true);
+        assert_eq!(tokens.iter().collect::<TokenList>(), super::parse(src));
+        assert_eq!(tokens.tokens(), [Plus, LBracket, Minus, RBracket]);
+
+        let tokens = tokens.without_locs();
+        assert_eq!(tokens.locs(), None);
+        assert_eq!(tokens, Lexer::new(src).into_tokens(false));
+        assert!(tokens.iter().all(|(_, loc)| loc == TokenLoc::new()));
+    }
 }
@@ -0,0 +1,80 @@
+#![cfg_attr(feature = "tail-call", feature(explicit_tail_calls))]
+#![cfg_attr(feature = "tail-call", allow(incomplete_features))]
+
+pub mod asm;
+pub mod backend;
+pub mod batch;
+pub mod blocks;
+pub mod brainfork;
+pub mod brainloller;
+pub mod bundle;
+pub mod bytecode;
+pub mod cache;
+#[cfg(feature = "jit-x64")]
+pub mod codegen;
+pub mod compat;
+pub mod config;
+pub mod coverage;
+pub mod crashdump;
+#[cfg(unix)]
+pub mod daemon;
+pub mod dap;
+pub mod debugger;
+pub mod detect;
+pub mod diagnostic;
+pub mod emit;
+pub mod equiv;
+pub mod examples;
+pub mod extract;
+pub mod fileio;
+pub mod fmt;
+pub mod frames;
+pub mod frontend;
+pub mod fuzz;
+pub mod generate;
+pub mod golden;
+pub mod heatmap;
+pub mod hexdump;
+pub mod history;
+pub mod ir;
+#[cfg(feature = "jit")]
+pub mod jit;
+pub mod json;
+pub mod lexer;
+pub mod lint;
+pub mod log;
+pub mod lsp;
+pub mod minify;
+#[cfg(any(feature = "jit", feature = "jit-x64"))]
+mod native_io;
+pub mod obfuscate;
+pub mod ook;
+pub mod opcodes;
+pub mod optimizer;
+pub mod parser;
+pub mod pgo;
+pub mod pragma;
+pub mod preprocess;
+pub mod profiler;
+pub mod project;
+pub mod quicken;
+pub mod reference;
+pub mod repl;
+pub mod serve;
+pub mod source;
+pub mod spoon;
+pub mod srcmap;
+pub mod stats;
+#[cfg(feature = "tail-call")]
+pub mod tailcall;
+pub mod tbs;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod threaded;
+#[cfg(feature = "tiered")]
+pub mod tiered;
+pub mod trace;
+#[cfg(feature = "tui")]
+pub mod tui;
+pub mod vm;
+pub mod writer;
@@ -0,0 +1,421 @@
+/*
+ *  Linter, for `bf lint`: mistakes a program can be seen to have without running it.
+ *
+ *  The program is followed from the start with what is known of the tape: every cell is 0 and the
+ *  pointer on the first one, until input, loops and moves the linter can't follow make cells and
+ *  the pointer unknown. After a loop the cell it tests is 0. A loop whose body moves the pointer
+ *  back where it was only makes the cells it writes unknown, any other makes everything unknown.
+ *  Bodies are looked at once, knowing nothing of the cells they start on.
+ */
+
+use std::{collections::HashMap, fmt, mem};
+
+use anyhow::Result;
+
+use crate::{
+    lexer::{self, Token, TokenLoc},
+    parser::{self, TokenData},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rule {
+    /// A loop on a cell known to be 0, which never runs.
+    DeadLoop,
+    /// A loop that can't end once it runs: it doesn't move, read or print and what it adds to the
+    /// cell it tests never makes it 0.
+    InfiniteLoop,
+    /// A `<` on the first cell.
+    LeftEdge,
+    /// A loop without inner loops changing the cell it tests, like a counter, and moving the
+    /// pointer somewhere else each time around, which is how a missing `<` or `>` looks.
+    UnbalancedLoop,
+}
+
+impl Rule {
+    pub fn name(self) -> &'static str {
+        match self {
+            Rule::DeadLoop => "dead-loop",
+            Rule::InfiniteLoop => "infinite-loop",
+            Rule::LeftEdge => "left-edge",
+            Rule::UnbalancedLoop => "unbalanced-loop",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    pub rule: Rule,
+    pub loc: TokenLoc,
+    pub message: String,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {} [{}]", self.loc, self.message, self.rule.name())
+    }
+}
+
+/// The warnings of `src`, in the order of the source. Fails if it doesn't parse.
+pub fn lint(src: &str) -> Result<Vec<Warning>> {
+    let tokens = lexer::parse(src);
+    parser::parse(tokens.clone())?;
+
+    let mut warnings = vec![];
+    let state = State {
+        ptr: Some(0),
+        cells: HashMap::new(),
+        fresh: true,
+        current: Some(0),
+    };
+    walk(&tree(tokens), state, &mut warnings);
+    warnings.sort_by_key(|warning| (warning.loc.line(), warning.loc.col()));
+
+    Ok(warnings)
+}
+
+enum Node {
+    Op(Token, TokenLoc),
+    /// A loop, with what its body does.
+    Loop(TokenLoc, Vec<Node>, Effect),
+}
+
+/// Loops nest as deep as the program does: their bodies are taken apart one after the other instead
+/// of each one being dropped from inside the one around it.
+impl Drop for Node {
+    fn drop(&mut self) {
+        let Node::Loop(_, body, _) = self else {
+            return;
+        };
+
+        let mut nodes = mem::take(body);
+        while let Some(mut node) = nodes.pop() {
+            if let Node::Loop(_, body, _) = &mut node {
+                nodes.append(body);
+            }
+        }
+    }
+}
+
+/// The nodes of a program that parsed.
+fn tree(tokens: impl IntoIterator<Item = TokenData>) -> Vec<Node> {
+    // The `[` of every loop open with the nodes of its body so far, the whole program first.
+    let mut stack = vec![(None, vec![])];
+
+    for (token, loc) in tokens {
+        match token {
+            Token::LBracket => stack.push((Some(loc), vec![])),
+            Token::RBracket => {
+                let (Some(loc), body) = stack.pop().unwrap() else {
+                    unreachable!("the program parsed");
+                };
+                let effect = effect_of(&body);
+                stack
+                    .last_mut()
+                    .unwrap()
+                    .1
+                    .push(Node::Loop(loc, body, effect));
+            }
+            token => stack.last_mut().unwrap().1.push(Node::Op(token, loc)),
+        }
+    }
+
+    stack.pop().unwrap().1
+}
+
+/// What is known of the tape. None is a cell or pointer not known.
+struct State {
+    ptr: Option<usize>,
+    /// Cells written, when the pointer is known.
+    cells: HashMap<usize, Option<u8>>,
+    /// The cells not in `cells` are 0.
+    fresh: bool,
+    /// The current cell, when the pointer isn't known.
+    current: Option<u8>,
+}
+
+impl State {
+    /// Nothing known but the current cell.
+    fn unknown(current: Option<u8>) -> Self {
+        Self {
+            ptr: None,
+            cells: HashMap::new(),
+            fresh: false,
+            current,
+        }
+    }
+
+    fn get(&self) -> Option<u8> {
+        match self.ptr {
+            Some(ptr) => match self.cells.get(&ptr) {
+                Some(&value) => value,
+                None => self.fresh.then_some(0),
+            },
+            None => self.current,
+        }
+    }
+
+    fn set(&mut self, value: Option<u8>) {
+        match self.ptr {
+            Some(ptr) => {
+                self.cells.insert(ptr, value);
+            }
+            None => self.current = value,
+        }
+    }
+
+    fn shift(&mut self, amount: isize) {
+        match self.ptr {
+            Some(ptr) => self.ptr = Some(ptr.saturating_add_signed(amount)),
+            None => self.current = None,
+        }
+    }
+}
+
+/// What a loop body does each time around.
+struct Effect {
+    /// Where it leaves the pointer, None when inner loops move it.
+    shift: Option<isize>,
+    /// The cells it writes, from the pointer.
+    writes: Vec<isize>,
+    /// What it adds to the cell it tests, when it has no inner loops.
+    change: Option<u8>,
+    io: bool,
+}
+
+/// The effect of `body`, from the ones of its loops.
+fn effect_of(body: &[Node]) -> Effect {
+    let mut effect = Effect {
+        shift: Some(0),
+        writes: vec![],
+        change: Some(0),
+        io: false,
+    };
+    let mut at = 0;
+    let mut flat = true;
+
+    for node in body {
+        let write = |effect: &mut Effect, at: isize| {
+            if !effect.writes.contains(&at) {
+                effect.writes.push(at);
+            }
+        };
+        match node {
+            Node::Op(Token::Plus | Token::Minus, _) => {
+                write(&mut effect, at);
+                if at == 0 {
+                    let add = match node {
+                        Node::Op(Token::Plus, _) => 1,
+                        _ => 255,
+                    };
+                    effect.change = effect.change.map(|change: u8| change.wrapping_add(add));
+                }
+            }
+            Node::Op(Token::Less, _) => at -= 1,
+            Node::Op(Token::Greater, _) => at += 1,
+            Node::Op(Token::Comma, _) => {
+                write(&mut effect, at);
+                effect.io = true;
+            }
+            Node::Op(_, _) => effect.io = true,
+            Node::Loop(_, _, inner) => {
+                flat = false;
+                match inner.shift {
+                    Some(0) => {
+                        for &offset in &inner.writes {
+                            write(&mut effect, at + offset);
+                        }
+                    }
+                    _ => effect.shift = None,
+                }
+                effect.io |= inner.io;
+            }
+        }
+    }
+
+    if !flat {
+        effect.change = None;
+    }
+    effect.shift = effect.shift.map(|_| at);
+
+    effect
+}
+
+fn walk(nodes: &[Node], state: State, warnings: &mut Vec<Warning>) {
+    // Every body gone into, with what is known in it. What is known after a loop doesn't depend on
+    // going through its body, which is gone through once whatever follows it is known.
+    let mut stack = vec![(nodes.iter(), state)];
+
+    while let Some((nodes, state)) = stack.last_mut() {
+        let Some(node) = nodes.next() else {
+            stack.pop();
+            continue;
+        };
+
+        match *node {
+            Node::Op(Token::Plus, _) => state.set(state.get().map(|value| value.wrapping_add(1))),
+            Node::Op(Token::Minus, _) => state.set(state.get().map(|value| value.wrapping_sub(1))),
+            Node::Op(Token::Less, loc) => {
+                if state.ptr == Some(0) {
+                    warnings.push(Warning {
+                        rule: Rule::LeftEdge,
+                        loc,
+                        message: "the pointer is on the first cell, `<` goes off the tape".into(),
+                    });
+                }
+                state.shift(-1);
+            }
+            Node::Op(Token::Greater, _) => state.shift(1),
+            Node::Op(Token::Comma, _) => state.set(None),
+            Node::Op(_, _) => {}
+            Node::Loop(loc, ref body, ref effect) => {
+                let entry = state.get();
+                if entry == Some(0) {
+                    warnings.push(Warning {
+                        rule: Rule::DeadLoop,
+                        loc,
+                        message: "the cell is 0 here, the loop never runs".into(),
+                    });
+                    continue;
+                }
+
+                check_loop(loc, entry, effect, warnings);
+
+                let inner = match effect.shift {
+                    Some(0) => State {
+                        ptr: state.ptr,
+                        ..State::unknown(None)
+                    },
+                    _ => State::unknown(None),
+                };
+
+                match (effect.shift, state.ptr) {
+                    (Some(0), Some(ptr)) => {
+                        for &offset in &effect.writes {
+                            if let Some(cell) = ptr.checked_add_signed(offset) {
+                                state.cells.insert(cell, None);
+                            }
+                        }
+                    }
+                    (Some(0), None) => {}
+                    _ => *state = State::unknown(None),
+                }
+                state.set(Some(0));
+
+                stack.push((body.iter(), inner));
+            }
+        }
+    }
+}
+
+/// The warnings of a loop that can run, entered with `entry` in the cell it tests.
+fn check_loop(loc: TokenLoc, entry: Option<u8>, effect: &Effect, warnings: &mut Vec<Warning>) {
+    let Some(change) = effect.change else {
+        return;
+    };
+
+    match effect.shift {
+        Some(0) if !effect.io => {
+            let message = match (change, entry) {
+                (0, Some(_)) => "the loop doesn't change the cell it tests, it never ends".into(),
+                (0, None) => {
+                    "the loop doesn't change the cell it tests, it never ends once it runs".into()
+                }
+                // Adding `change` again and again only reaches the multiples of its lowest bit.
+                (change, Some(entry)) if entry % (1 << change.trailing_zeros()) != 0 => {
+                    let step = match change > 128 {
+                        true => format!("takes {} from it", 256 - change as usize),
+                        false => format!("adds {} to it", change),
+                    };
+                    format!(
+                        "the cell is {} here and the loop {}, it never gets to 0",
+                        entry, step
+                    )
+                }
+                _ => return,
+            };
+            warnings.push(Warning {
+                rule: Rule::InfiniteLoop,
+                loc,
+                message,
+            });
+        }
+        Some(shift) if shift != 0 && change != 0 => warnings.push(Warning {
+            rule: Rule::UnbalancedLoop,
+            loc,
+            message: format!(
+                "the loop changes the cell it tests but moves the pointer {} {} each time around",
+                match shift.unsigned_abs() {
+                    1 => "a cell".to_string(),
+                    cells => format!("{} cells", cells),
+                },
+                if shift < 0 { "left" } else { "right" }
+            ),
+        }),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+
+    use crate::lint::{self, Rule};
+
+    fn rules(src: &str) -> Vec<(Rule, String)> {
+        lint::lint(src)
+            .unwrap()
+            .into_iter()
+            .map(|warning| (warning.rule, warning.loc.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn finds_mistakes() {
+        assert_eq!(
+            rules("[-]+[-][>]\n>,[<+>-]<<"),
+            [
+                (Rule::DeadLoop, "1:1".to_string()),
+                (Rule::DeadLoop, "1:8".to_string()),
+                (Rule::LeftEdge, "2:10".to_string()),
+            ]
+        );
+        assert_eq!(
+            rules("+[]+++[--]+,[>]+[.]"),
+            [
+                (Rule::InfiniteLoop, "1:2".to_string()),
+                (Rule::InfiniteLoop, "1:7".to_string()),
+            ]
+        );
+        assert_eq!(
+            rules(",[->+<<]"),
+            [(Rule::UnbalancedLoop, "1:2".to_string())]
+        );
+        // The pointer isn't known after `[>]`, nor the cell after a loop on another one.
+        assert_eq!(rules("+[>]<<<+>[<]>[-]"), []);
+        assert!(lint::lint("[").is_err());
+    }
+
+    #[test]
+    fn examples_are_clean() {
+        for example in &crate::examples::EXAMPLES {
+            let warnings = lint::lint(example.src).unwrap();
+            assert_eq!(warnings, [], "{}", example.name);
+        }
+    }
+
+    #[test]
+    fn lints_deep_nesting() {
+        // Far too little stack to take some for every loop.
+        let depth = 100_000;
+        let src = format!("+{}-{}", "[".repeat(depth), "]".repeat(depth));
+        let lint = move || lint::lint(&src).unwrap();
+
+        let warnings = thread::Builder::new()
+            .stack_size(256 << 10)
+            .spawn(lint)
+            .unwrap()
+            .join()
+            .unwrap();
+        assert_eq!(warnings, []);
+    }
+}
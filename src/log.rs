@@ -0,0 +1,156 @@
+/*
+ *  Logging to standard error for `-q`, `-v` and `-vv`.
+ *  One level for the whole process, set by the CLI before anything runs. `info!` reports what the
+ *  toolchain did (stage timings, program size), `debug!` also how (each optimizer pass, the cache).
+ *  The stage timings can also be kept whatever the level, for `--stats-json`.
+ *
+ *  With the tracing feature, programs embedding the library get the stage timings as events of the
+ *  tracing crate instead, `bf::stage` at info level in a `compile` span. The interpreter's run loop
+ *  is in a `run` span, with a `bf::loop` event at debug level each time it reaches a loop.
+ */
+
+use std::{
+    mem,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// Errors only.
+    Quiet,
+    /// Errors and notes.
+    Normal,
+    Info,
+    Debug,
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Normal as u8);
+/// The stage timings kept, None when they aren't.
+static STAGES: Mutex<Option<Vec<(String, Duration)>>> = Mutex::new(None);
+
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+/// Level for `-q` and the number of `-v`.
+pub fn level_from_flags(quiet: bool, verbose: u8) -> Level {
+    match (quiet, verbose) {
+        (true, _) => Level::Quiet,
+        (false, 0) => Level::Normal,
+        (false, 1) => Level::Info,
+        (false, _) => Level::Debug,
+    }
+}
+
+/// One line of a stage's timing: its name, how long it took and what it made.
+pub fn stage(name: &str, elapsed: Duration, made: &str) {
+    crate::info!("{:<16} {:>10}  {}", name, format!("{:.3?}", elapsed), made);
+    #[cfg(feature = "tracing")]
+    tracing::info!(target: "bf::stage", stage = name, ?elapsed, made, "stage");
+
+    let mut stages = STAGES.lock().unwrap_or_else(|err| err.into_inner());
+    if let Some(stages) = stages.as_mut() {
+        stages.push((name.to_string(), elapsed));
+    }
+}
+
+/// Keeps the timings of the stages from now on, for `take_stages`.
+pub fn keep_stages() {
+    let mut stages = STAGES.lock().unwrap_or_else(|err| err.into_inner());
+    stages.get_or_insert_with(Vec::new);
+}
+
+/// The stage timings kept since `keep_stages` or the last call, in order.
+pub fn take_stages() -> Vec<(String, Duration)> {
+    let mut stages = STAGES.lock().unwrap_or_else(|err| err.into_inner());
+    stages.as_mut().map(mem::take).unwrap_or_default()
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Info) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Debug) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod test {
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+    };
+
+    use tracing::{span, Event, Metadata, Subscriber};
+
+    use crate::vm::{self, Vm};
+
+    /// The names of the spans and the targets of the events, in order.
+    #[derive(Clone, Default)]
+    struct Seen(Arc<Mutex<Vec<String>>>);
+
+    impl Subscriber for Seen {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &span::Attributes) -> span::Id {
+            let mut seen = self.0.lock().unwrap();
+            seen.push(span.metadata().name().to_string());
+            span::Id::from_u64(seen.len() as u64)
+        }
+
+        fn record(&self, _: &span::Id, _: &span::Record) {}
+
+        fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+
+        fn event(&self, event: &Event) {
+            let target = event.metadata().target().to_string();
+            self.0.lock().unwrap().push(target);
+        }
+
+        fn enter(&self, _: &span::Id) {}
+
+        fn exit(&self, _: &span::Id) {}
+    }
+
+    #[test]
+    fn traces_compiling_and_running() {
+        let seen = Seen::default();
+        tracing::subscriber::with_default(seen.clone(), || {
+            let mut vm = Vm::from_program(vm::compile("++[>+<-]>[<]").unwrap()).unwrap();
+            vm.run_with(&mut io::empty(), &mut io::sink()).unwrap();
+        });
+
+        assert_eq!(
+            *seen.0.lock().unwrap(),
+            [
+                "compile",
+                "bf::stage",
+                "bf::stage",
+                "bf::stage",
+                "run",
+                "bf::loop",
+                "bf::loop"
+            ]
+        );
+    }
+}
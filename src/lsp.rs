@@ -0,0 +1,469 @@
+/*
+ *  Language Server Protocol server, for `bf lsp`: editors show the unmatched brackets of a Brainfuck
+ *  program as it is typed, go from a bracket to the one matching it, list the loops at the top level
+ *  of the program and show what a command compiles to on hover. Messages are JSON-RPC, framed like
+ *  the debug adapter's (see `dap`).
+ *
+ *  Documents are sent whole on every change. The protocol counts columns in UTF-16 code units from 0,
+ *  the lexer in characters from 1, `Document` goes from one to the other.
+ */
+
+use std::{
+    collections::HashMap,
+    io::{BufRead, Write},
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    asm, dap,
+    json::Json,
+    lexer::{self, Token, TokenLoc},
+    parser::{Parser, TokenList},
+};
+
+const METHOD_NOT_FOUND: f64 = -32601.0;
+const INTERNAL_ERROR: f64 = -32603.0;
+/// Full documents on every change.
+const SYNC_FULL: usize = 1;
+/// The protocol has no kind for a block of code, loops are listed as functions.
+const SYMBOL_FUNCTION: usize = 12;
+const SEVERITY_ERROR: usize = 1;
+
+pub fn serve(input: &mut dyn BufRead, output: &mut dyn Write) -> Result<()> {
+    let mut server = Server {
+        out: output,
+        documents: HashMap::new(),
+    };
+
+    while let Some(message) = dap::read_message(input)? {
+        let message = Json::parse(&message)?;
+        if !server.message(&message)? {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// A loop of a document, from its `[` to its `]`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Loop {
+    start: TokenLoc,
+    end: TokenLoc,
+    /// Loops it is in.
+    depth: usize,
+}
+
+struct Document {
+    text: String,
+    tokens: TokenList,
+    loops: Vec<Loop>,
+    /// The brackets matching none, in order.
+    unmatched: Vec<(Token, TokenLoc)>,
+}
+
+impl Document {
+    fn new(text: String) -> Self {
+        let tokens = lexer::parse(&text);
+        let (mut loops, mut unmatched) = (vec![], vec![]);
+        let mut open = vec![];
+
+        for &(token, loc) in &tokens {
+            match token {
+                Token::LBracket => open.push(loc),
+                Token::RBracket => match open.pop() {
+                    Some(start) => loops.push(Loop {
+                        start,
+                        end: loc,
+                        depth: open.len(),
+                    }),
+                    None => unmatched.push((token, loc)),
+                },
+                _ => {}
+            }
+        }
+        unmatched.extend(open.into_iter().map(|loc| (Token::LBracket, loc)));
+        unmatched.sort_by_key(|&(_, loc)| (loc.line(), loc.col()));
+        loops.sort_by_key(|l| (l.start.line(), l.start.col()));
+
+        Self {
+            text,
+            tokens,
+            loops,
+            unmatched,
+        }
+    }
+
+    fn line(&self, loc: TokenLoc) -> &str {
+        self.text
+            .split('\n')
+            .nth(loc.line() - 1)
+            .unwrap_or_default()
+    }
+
+    fn position(&self, loc: TokenLoc) -> Json {
+        let character: usize = self
+            .line(loc)
+            .chars()
+            .take(loc.col().saturating_sub(1))
+            .map(char::len_utf16)
+            .sum();
+
+        Json::object([
+            ("line", (loc.line() - 1).into()),
+            ("character", character.into()),
+        ])
+    }
+
+    /// From the command at `start` to the one at `end`, both in it.
+    fn range(&self, start: TokenLoc, end: TokenLoc) -> Json {
+        let after = TokenLoc::from_col_line(end.col() + 1, end.line());
+
+        Json::object([
+            ("start", self.position(start)),
+            ("end", self.position(after)),
+        ])
+    }
+
+    /// The character at a position of the protocol.
+    fn loc_at(&self, position: &Json) -> Option<TokenLoc> {
+        let line = position.get("line").as_usize()? + 1;
+        let character = position.get("character").as_usize()?;
+
+        let mut units = 0;
+        let col = self
+            .line(TokenLoc::from_col_line(1, line))
+            .chars()
+            .take_while(|ch| {
+                units += ch.len_utf16();
+                units <= character
+            })
+            .count();
+
+        Some(TokenLoc::from_col_line(col + 1, line))
+    }
+
+    fn diagnostics(&self) -> Vec<Json> {
+        self.unmatched
+            .iter()
+            .map(|&(token, loc)| {
+                let message = match token {
+                    Token::LBracket => "unclosed delimiter '['",
+                    _ => "unexpected closing delimiter ']'",
+                };
+                Json::object([
+                    ("range", self.range(loc, loc)),
+                    ("severity", SEVERITY_ERROR.into()),
+                    ("source", "bf".into()),
+                    ("message", message.into()),
+                ])
+            })
+            .collect()
+    }
+}
+
+struct Server<'a> {
+    out: &'a mut dyn Write,
+    /// By URI.
+    documents: HashMap<String, Document>,
+}
+
+impl Server<'_> {
+    /// Handles `message`, returns false once the editor is done.
+    fn message(&mut self, message: &Json) -> Result<bool> {
+        let method = message.get("method").as_str().unwrap_or_default();
+        let params = message.get("params");
+        let id = message.get("id");
+
+        let result = match method {
+            "initialize" => Ok(Json::object([(
+                "capabilities",
+                Json::object([
+                    ("textDocumentSync", SYNC_FULL.into()),
+                    ("hoverProvider", true.into()),
+                    ("definitionProvider", true.into()),
+                    ("documentSymbolProvider", true.into()),
+                ]),
+            )])),
+            "shutdown" => Ok(Json::Null),
+            "exit" => return Ok(false),
+            "textDocument/didOpen" => {
+                let document = params.get("textDocument");
+                let text = document.get("text").as_str().unwrap_or_default();
+                self.open(document, text)?;
+                return Ok(true);
+            }
+            "textDocument/didChange" => {
+                let changes = params.get("contentChanges").as_array();
+                let text = changes
+                    .last()
+                    .and_then(|change| change.get("text").as_str());
+                self.open(params.get("textDocument"), text.unwrap_or_default())?;
+                return Ok(true);
+            }
+            "textDocument/didClose" => {
+                let uri = params.get("textDocument").get("uri").as_str();
+                let uri = uri.unwrap_or_default().to_string();
+                self.documents.remove(&uri);
+                self.publish(&uri, vec![])?;
+                return Ok(true);
+            }
+            "textDocument/hover" => self.hover(params),
+            "textDocument/definition" => self.definition(params),
+            "textDocument/documentSymbol" => self.symbols(params),
+            // Notifications it doesn't know are left alone.
+            _ if id == &Json::Null => return Ok(true),
+            _ => {
+                let error = Json::object([
+                    ("code", Json::Number(METHOD_NOT_FOUND)),
+                    ("message", format!("`{}` is not supported", method).into()),
+                ]);
+                self.respond(id, ("error", error))?;
+                return Ok(true);
+            }
+        };
+
+        let response = match result {
+            Ok(result) => ("result", result),
+            Err(err) => (
+                "error",
+                Json::object([
+                    ("code", Json::Number(INTERNAL_ERROR)),
+                    ("message", err.to_string().into()),
+                ]),
+            ),
+        };
+        self.respond(id, response)?;
+
+        Ok(true)
+    }
+
+    fn open(&mut self, document: &Json, text: &str) -> Result<()> {
+        let uri = document
+            .get("uri")
+            .as_str()
+            .ok_or_else(|| anyhow!("a document without a `uri`"))?;
+        let document = Document::new(text.to_string());
+        let diagnostics = document.diagnostics();
+        self.documents.insert(uri.to_string(), document);
+
+        self.publish(uri, diagnostics)
+    }
+
+    /// The document of a request and the character it is about.
+    fn at(&self, params: &Json) -> Result<(&Document, Option<TokenLoc>)> {
+        let uri = params.get("textDocument").get("uri").as_str();
+        let document = uri
+            .and_then(|uri| self.documents.get(uri))
+            .ok_or_else(|| anyhow!("the document is not open"))?;
+
+        Ok((document, document.loc_at(params.get("position"))))
+    }
+
+    /// The opcode the command under the cursor compiles to, unoptimized. Nothing while the program
+    /// doesn't parse.
+    fn hover(&self, params: &Json) -> Result<Json> {
+        let (document, Some(loc)) = self.at(params)? else {
+            return Ok(Json::Null);
+        };
+        let Ok((program, spans)) = Parser::new(document.tokens.clone()).parse_with_spans() else {
+            return Ok(Json::Null);
+        };
+        let key = |loc: TokenLoc| (loc.line(), loc.col());
+        let Some(pc) = spans
+            .iter()
+            .position(|&(start, end)| (key(start)..=key(end)).contains(&key(loc)))
+        else {
+            return Ok(Json::Null);
+        };
+
+        let (start, end) = spans[pc];
+        Ok(Json::object([
+            (
+                "contents",
+                Json::object([
+                    ("kind", "plaintext".into()),
+                    (
+                        "value",
+                        format!("{}: {}", pc, asm::instruction(&program[pc])).into(),
+                    ),
+                ]),
+            ),
+            ("range", document.range(start, end)),
+        ]))
+    }
+
+    /// The bracket matching the one under the cursor.
+    fn definition(&self, params: &Json) -> Result<Json> {
+        let (document, Some(loc)) = self.at(params)? else {
+            return Ok(Json::Null);
+        };
+        let other = document.loops.iter().find_map(|l| {
+            if loc == l.start {
+                Some(l.end)
+            } else if loc == l.end {
+                Some(l.start)
+            } else {
+                None
+            }
+        });
+
+        Ok(match other {
+            Some(other) => Json::object([
+                ("uri", params.get("textDocument").get("uri").clone()),
+                ("range", document.range(other, other)),
+            ]),
+            None => Json::Null,
+        })
+    }
+
+    /// The loops at the top level, by where they start.
+    fn symbols(&self, params: &Json) -> Result<Json> {
+        let (document, _) = self.at(params)?;
+        let symbols = document
+            .loops
+            .iter()
+            .filter(|l| l.depth == 0)
+            .map(|l| {
+                Json::object([
+                    ("name", format!("loop at {}", l.start).into()),
+                    ("kind", SYMBOL_FUNCTION.into()),
+                    ("range", document.range(l.start, l.end)),
+                    ("selectionRange", document.range(l.start, l.start)),
+                ])
+            })
+            .collect::<Vec<_>>();
+
+        Ok(symbols.into())
+    }
+
+    fn publish(&mut self, uri: &str, diagnostics: Vec<Json>) -> Result<()> {
+        self.send(vec![
+            ("method", "textDocument/publishDiagnostics".into()),
+            (
+                "params",
+                Json::object([("uri", uri.into()), ("diagnostics", diagnostics.into())]),
+            ),
+        ])
+    }
+
+    /// The response to the request `id`, with its result or error.
+    fn respond(&mut self, id: &Json, response: (&str, Json)) -> Result<()> {
+        self.send(vec![("id", id.clone()), response])
+    }
+
+    fn send(&mut self, fields: Vec<(&str, Json)>) -> Result<()> {
+        let message = Json::object([("jsonrpc", "2.0".into())].into_iter().chain(fields));
+
+        let body = message.to_string();
+        write!(self.out, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+        Ok(self.out.flush()?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{json::Json, lsp};
+
+    #[test]
+    fn serves_a_document() {
+        let uri = r#"{"uri":"file:///a.bf"}"#;
+        let at = |line, character| {
+            format!(
+                r#""textDocument":{},"position":{{"line":{},"character":{}}}"#,
+                uri, line, character
+            )
+        };
+        let requests = [
+            r#""id":1,"method":"initialize","params":{}"#.to_string(),
+            format!(
+                r#""method":"textDocument/didOpen","params":{{"textDocument":{{"uri":"file:///a.bf","text":{}}}}}"#,
+                Json::from("+[>+<-]]\né [ [-]")
+            ),
+            format!(
+                r#""id":2,"method":"textDocument/definition","params":{{{}}}"#,
+                at(0, 6)
+            ),
+            format!(
+                r#""id":3,"method":"textDocument/definition","params":{{{}}}"#,
+                at(1, 4)
+            ),
+            format!(
+                r#""id":4,"method":"textDocument/documentSymbol","params":{{"textDocument":{}}}"#,
+                uri
+            ),
+            format!(
+                r#""method":"textDocument/didChange","params":{{"textDocument":{},"contentChanges":[{{"text":"+[>+<-]\né [-]"}}]}}"#,
+                uri
+            ),
+            format!(
+                r#""id":5,"method":"textDocument/hover","params":{{{}}}"#,
+                at(1, 3)
+            ),
+            r#""id":6,"method":"textDocument/formatting","params":{}"#.to_string(),
+            r#""id":7,"method":"shutdown""#.to_string(),
+            r#""method":"exit""#.to_string(),
+        ];
+        let input: String = requests
+            .iter()
+            .map(|request| {
+                let request = format!(r#"{{"jsonrpc":"2.0",{}}}"#, request);
+                format!("Content-Length: {}\r\n\r\n{}", request.len(), request)
+            })
+            .collect();
+
+        let mut output = vec![];
+        lsp::serve(&mut input.as_bytes(), &mut output).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        let messages: Vec<Json> = output
+            .split("Content-Length: ")
+            .skip(1)
+            .map(|message| Json::parse(message.split_once("\r\n\r\n").unwrap().1).unwrap())
+            .collect();
+        let result = |id: usize| {
+            messages
+                .iter()
+                .find(|message| message.get("id").as_usize() == Some(id))
+                .unwrap()
+        };
+
+        let diagnostics = messages[1].get("params").get("diagnostics").as_array();
+        let ranges: Vec<_> = diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic.get("range").get("start").to_string())
+            .collect();
+        assert_eq!(
+            ranges,
+            [r#"{"line":0,"character":7}"#, r#"{"line":1,"character":2}"#]
+        );
+
+        assert_eq!(
+            result(2).get("result").get("range").to_string(),
+            r#"{"start":{"line":0,"character":1},"end":{"line":0,"character":2}}"#
+        );
+        let start = result(3).get("result").get("range").get("start");
+        assert_eq!(start.get("character").as_usize(), Some(6));
+
+        // The inner loop of the second line is not at the top level.
+        let symbols = result(4).get("result").as_array();
+        let names: Vec<_> = symbols
+            .iter()
+            .map(|symbol| symbol.get("name").as_str())
+            .collect();
+        assert_eq!(names, [Some("loop at 1:2")]);
+
+        // Fixed, the errors are gone.
+        let diagnostics = messages[5].get("params").get("diagnostics");
+        assert_eq!(diagnostics, &Json::Array(vec![]));
+        let hover = result(5).get("result");
+        assert_eq!(
+            hover.get("contents").get("value").as_str(),
+            Some("8: SUB 1")
+        );
+
+        assert!(result(6).get("error").get("message").as_str().is_some());
+        assert_eq!(result(7).get("result"), &Json::Null);
+    }
+}
@@ -1,36 +1,3362 @@
-use std::fs;
+use std::{
+    env,
+    ffi::OsString,
+    fs,
+    io::{self, BufRead, IsTerminal, Read, Write},
+    net::TcpListener,
+    ops::Range,
+    path::{Path, PathBuf},
+    process, thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-use crate::vm::{Vm, DEFAULT_VM_MEM_SIZE};
-use clap::Parser;
+#[cfg(unix)]
+use bf::daemon;
+use bf::{
+    asm, backend, batch,
+    brainfork::Scheduler,
+    bundle, bytecode,
+    cache::Cache,
+    compat, config,
+    coverage::Coverage,
+    crashdump::{CrashDump, PendingOutput},
+    dap,
+    debugger::Debugger,
+    detect,
+    diagnostic::{json_string, Diagnostic, Severity},
+    emit, equiv, examples, extract,
+    fileio::FileIo,
+    fmt,
+    frames::{FrameFormat, Frames},
+    frontend::{self, Frontend},
+    fuzz,
+    golden::{self, Outcome},
+    heatmap::Heatmap,
+    hexdump,
+    ir::{self, ProgramIr},
+    json::Json,
+    lexer::{self, Extension, StreamLexer, Token},
+    lint, log, lsp,
+    opcodes::OpCodeType,
+    optimizer::PassRegistry,
+    parser::{self, IncrementalParser, ParseError, Program, TokenList},
+    pgo::{self, Profile},
+    pragma,
+    preprocess::{self, Expanded},
+    profiler::{LoopProfiler, Profiler},
+    project,
+    repl::{LineReader, Prompt, Repl, Typed},
+    serve,
+    source::Source,
+    srcmap::SourceMap,
+    stats,
+    tbs::Substitution,
+    trace::{Trace, TraceFormat},
+    vm::{self, MemoryError, Observer, Vm},
+    writer::WriterThread,
+};
+use clap::{ArgEnum, CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
 
-mod lexer;
-mod opcodes;
-mod parser;
-mod vm;
+#[derive(Debug, Clone, Copy, ArgEnum)]
+enum Backend {
+    Interpreter,
+    /// Cranelift
+    Jit,
+    /// Built-in x86-64 code generator
+    JitX64,
+    /// LLVM's lli, with full optimization
+    Llvm,
+}
+
+#[derive(Debug, Clone, Copy, ArgEnum)]
+enum Engine {
+    /// One `match` on the opcode type per instruction
+    Match,
+    /// Opcodes resolved to handler functions up front
+    Threaded,
+    /// Straight runs of opcodes made into blocks, each checking the tape once
+    Blocks,
+    /// Handlers tail calling each other, needs the tail-call feature
+    TailCall,
+    /// Slow tree walking interpreter of the unoptimized program, for checking the others
+    Reference,
+}
+
+#[derive(Debug, Clone, Copy, ArgEnum)]
+enum Emit {
+    C,
+    /// ES module exporting `run(input: Uint8Array): Uint8Array`
+    Js,
+    Wasm,
+    /// Textual LLVM IR, needs the llvm feature
+    Llvm,
+    /// Object file built with LLVM, needs the llvm feature
+    Obj,
+    /// Binary bytecode (.bfc) for `bf exec`
+    Bytecode,
+    /// The optimized program as plain Brainfuck, for other interpreters
+    Bf,
+}
+
+impl Emit {
+    /// Guesses the format from the output file extension, C if there is nothing to go by.
+    fn from_path(path: Option<&str>) -> Self {
+        match path.and_then(|path| Path::new(path).extension()?.to_str()) {
+            Some("js" | "mjs") => Emit::Js,
+            Some("wasm") => Emit::Wasm,
+            Some("ll") => Emit::Llvm,
+            Some("o") => Emit::Obj,
+            Some("bfc") => Emit::Bytecode,
+            Some("bf" | "b") => Emit::Bf,
+            _ => Emit::C,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum)]
+enum Dialect {
+    Brainfuck,
+    /// Commands as pairs of `Ook.`, `Ook?` and `Ook!`
+    Ook,
+    /// Brainfuck with procedures: `(` and `)` around the one numbered by the current cell, `:` calls
+    /// it. Runs unoptimized on the VM
+    Pbrain,
+    /// Extended Brainfuck Type I: `@` ends the program, `$` and `!` store the cell and get it back,
+    /// `{` `}` shift its bits, `~` `^` `&` `|` are bitwise operations with what was stored. Runs
+    /// unoptimized on the VM
+    Extended1,
+    /// Brainfuck with `Y`, which forks the running thread. Threads take turns on the VM, unoptimized
+    Brainfork,
+    /// PNG images with a command for each pixel color, needs the brainloller feature
+    Brainloller,
+    /// Commands as codes of `0` and `1`
+    Spoon,
+    /// Ook! with `Blub` for `Ook`
+    Blub,
+    /// Brainfuck with several tapes, see --tapes: `{` and `}` switch to the previous and the next
+    /// one, `=` copies the current cell to the next. Runs unoptimized on the VM
+    MultiTape,
+    /// Compiled by `bf compile`, run on the VM like `bf exec` does
+    Bytecode,
+}
+
+impl Dialect {
+    /// The dialect of the file at `path` holding `bytes`, see `bf::detect`.
+    fn detect(path: Option<&str>, bytes: &[u8]) -> Self {
+        match detect::detect(path, bytes) {
+            detect::Format::Brainfuck => Dialect::Brainfuck,
+            detect::Format::Ook => Dialect::Ook,
+            detect::Format::Blub => Dialect::Blub,
+            detect::Format::Spoon => Dialect::Spoon,
+            detect::Format::Brainloller => Dialect::Brainloller,
+            detect::Format::Bytecode => Dialect::Bytecode,
+        }
+    }
+
+    /// What reads programs in the dialect, None for bytecode.
+    fn frontend(self) -> Option<&'static dyn Frontend> {
+        frontend::find(self.to_possible_value()?.get_name())
+    }
+}
+
+/// A program file, as read by `RunArgs::read_program`.
+struct Input {
+    /// What errors call it, None for --eval and standard input when no file is given, the locations
+    /// of which are left as they are.
+    name: Option<String>,
+    path: Option<String>,
+    bytes: Vec<u8>,
+}
+
+/// Interpreters to behave like, see `bf::compat`.
+#[derive(Debug, Clone, Copy, ArgEnum)]
+enum Compat {
+    /// 30000 cells, `,` leaves the cell as it is at the end of input
+    Classic,
+    /// A tape growing from 30000 cells, `,` leaves the cell as it is at the end of input
+    Portable,
+    /// A tape growing from 64K cells, `,` reads 0 at the end of input
+    #[clap(name = "busybeaver")]
+    BusyBeaver,
+    /// `,` reads 0 at the end of input
+    Eof0,
+    /// `,` reads 255 at the end of input
+    #[clap(name = "eofneg1")]
+    EofNeg1,
+}
+
+#[derive(Debug, Clone, Copy, ArgEnum)]
+enum Eof {
+    /// Fail
+    Error,
+    /// Leave the cell as it is
+    Unchanged,
+    Zero,
+    /// 255, -1 as a signed byte
+    #[clap(name = "neg1")]
+    NegOne,
+}
+
+impl From<Eof> for vm::Eof {
+    fn from(eof: Eof) -> Self {
+        match eof {
+            Eof::Error => vm::Eof::Error,
+            Eof::Unchanged => vm::Eof::Unchanged,
+            Eof::Zero => vm::Eof::Zero,
+            Eof::NegOne => vm::Eof::NegOne,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, ArgEnum)]
+enum TapeEdge {
+    /// Fail
+    Error,
+    /// Make the tape longer. The program runs unoptimized
+    Grow,
+}
+
+#[derive(Debug, Clone, Copy, ArgEnum)]
+enum Format {
+    Text,
+    Json,
+}
+
+#[cfg(unix)]
+#[derive(Debug, Subcommand)]
+enum ClientCommand {
+    /// Run a program on the daemon, reading all of its input from standard input before it runs
+    Run {
+        file: String,
+
+        /// Read the input from this file instead
+        #[clap(short, long)]
+        input: Option<String>,
+
+        /// Cells on the tape, such as 30000, 64K or 1M
+        #[clap(long, default_value = "30000", value_parser = parse_tape_size)]
+        tape_size: usize,
+
+        /// What `,` does at the end of the input
+        #[clap(long, arg_enum, default_value = "error")]
+        eof: Eof,
+    },
+    /// Stop the daemon once the runs it is doing end
+    Stop,
+}
+
+#[derive(Debug, Subcommand)]
+enum ExamplesCommand {
+    /// List the examples
+    List,
+    /// Run an example, reading its input from standard input
+    Run { name: String },
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Run a program, same as `bf <FILE>`
+    #[clap(args_override_self = true)]
+    Run(Box<RunArgs>),
+    /// Compile a program to C, JavaScript, WebAssembly, LLVM, bytecode or optimized Brainfuck
+    Compile {
+        file: String,
+
+        /// Output format, guessed from the output file extension if not given
+        #[clap(long, arg_enum)]
+        emit: Option<Emit>,
+
+        /// Where to write the output, standard output if not given
+        #[clap(short, long)]
+        output: Option<String>,
+
+        /// Cells on the tape of the compiled program, such as 30000, 64K or 1M
+        #[clap(long, default_value = "30000", value_parser = parse_tape_size)]
+        tape_size: usize,
+
+        /// How to print errors, json prints an object per error with where it is
+        #[clap(long, arg_enum, default_value_t = Format::Text)]
+        format: Format,
+
+        /// Keep where every opcode comes from in the source, for bytecode output
+        #[clap(short = 'g', long)]
+        debug_info: bool,
+    },
+    /// Run a bytecode file made by `bf compile`
+    Exec { file: String },
+    /// Print the bytecode of a program or .bfc file as assembly
+    Disasm { file: String },
+    /// Assemble a program from `bf disasm` output, then run it or write it as bytecode
+    Asm {
+        file: String,
+
+        /// Write the bytecode (.bfc) here instead of running the program
+        #[clap(short, long)]
+        output: Option<String>,
+    },
+    /// Build a standalone executable running the program
+    Build {
+        file: String,
+
+        /// Where to write the executable, the file name without its extension if not given
+        #[clap(short, long)]
+        output: Option<String>,
+    },
+    /// Print a program with only its commands
+    Minify {
+        /// Standard input if not given or `-`
+        file: Option<String>,
+
+        /// Also sum runs of commands and drop or shorten loops, for the smallest program doing the same
+        #[clap(short, long)]
+        shrink: bool,
+    },
+    /// Print a Brainfuck program printing a text, using loops setting cells near its bytes
+    Generate {
+        /// The text, standard input if not given
+        #[clap(long)]
+        text: Option<String>,
+    },
+    /// Print a program doing the same, scrambled with commands cancelling each other, loops never
+    /// entered and comments
+    Obfuscate {
+        /// Standard input if not given or `-`
+        file: Option<String>,
+
+        /// The same seed scrambles a program the same way
+        #[clap(long, default_value_t = 0)]
+        seed: u64,
+    },
+    /// Print statistics of a program without running it: commands, loops, nesting and tape used
+    Stats {
+        /// Standard input if not given or `-`
+        file: Option<String>,
+    },
+    /// Check that programs parse, without running them. Exits with 2 if any does not, -q prints nothing
+    Check {
+        /// The programs of bf.toml if not given
+        files: Vec<String>,
+
+        #[clap(long, arg_enum, default_value_t = Format::Text)]
+        format: Format,
+    },
+    /// Warn of mistakes in programs without running them: loops that never run or never end, `<` on
+    /// the first cell and loops counting down a cell while moving away from it. Exits with 1 if there
+    /// are warnings, with 2 if a program does not parse
+    Lint {
+        #[clap(required = true)]
+        files: Vec<String>,
+
+        #[clap(long, arg_enum, default_value_t = Format::Text)]
+        format: Format,
+    },
+    /// Run two programs on the same inputs and report the first one they write, end or leave the
+    /// tape differently on. Exits with 1 if they differ
+    Equiv {
+        a: String,
+        b: String,
+
+        /// Run them on every file in this directory instead of generated inputs
+        #[clap(long, value_name = "DIR")]
+        inputs: Option<String>,
+
+        /// Inputs to generate
+        #[clap(long, default_value_t = 100, conflicts_with = "inputs")]
+        runs: usize,
+
+        /// The same seed generates the same inputs
+        #[clap(long, default_value_t = 0, conflicts_with = "inputs")]
+        seed: u64,
+
+        /// Commands a program runs on an input before it is stopped, only the output written until
+        /// then is compared
+        #[clap(long, default_value_t = 10_000_000)]
+        max_steps: u64,
+
+        /// Cells on the tape, such as 30000, 64K or 1M
+        #[clap(long, default_value = "30000", value_parser = parse_tape_size)]
+        tape_size: usize,
+
+        /// Only compare what they write and how they end, not the tape
+        #[clap(long)]
+        output_only: bool,
+    },
+    /// Run golden tests: every foo.bf in the directory with foo.in as input, its output checked against
+    /// foo.out. Exits with 1 if any fails
+    Test {
+        /// The tests of bf.toml if not given
+        dir: Option<String>,
+    },
+    /// Run many programs at once and print a table of how they did: the programs of manifests (see
+    /// the `batch` module) or files, with foo.in as input and foo.out as the output expected like
+    /// `bf test`. Exits with 1 if any fails or stops at a limit
+    RunBatch {
+        /// Manifests, ending in .toml, and programs
+        #[clap(required = true)]
+        files: Vec<String>,
+
+        /// Programs run at once, as many as there are CPUs if not given
+        #[clap(short, long)]
+        jobs: Option<usize>,
+
+        /// Instructions a program can run, for the programs not setting theirs
+        #[clap(long)]
+        max_steps: Option<u64>,
+
+        /// Milliseconds a program can run for, for the programs not setting theirs
+        #[clap(long, value_name = "MS")]
+        timeout_ms: Option<u64>,
+
+        /// Bytes a program can write, for the programs not setting theirs
+        #[clap(long)]
+        max_output: Option<usize>,
+
+        /// Cells on the tape, for the programs not setting theirs
+        #[clap(long, default_value = "30000", value_parser = parse_tape_size)]
+        tape_size: usize,
+
+        /// Write the output of every program to a file of the same name ending in .out in this
+        /// directory
+        #[clap(long, value_name = "DIR")]
+        output_dir: Option<String>,
+    },
+    /// Run random programs on every backend built in and report panics, programs that don't compile
+    /// and output other than the unoptimized VM's. Exits with 1 if anything was found
+    Fuzz {
+        /// The same seed makes the same programs
+        #[clap(long, default_value_t = 0)]
+        seed: u64,
+
+        /// Programs to run
+        #[clap(long, default_value_t = 1000)]
+        runs: usize,
+
+        /// Commands in a program at most
+        #[clap(long, default_value_t = 64)]
+        max_len: usize,
+
+        /// Instructions a program runs on the VM before it is left alone
+        #[clap(long, default_value_t = 100_000)]
+        max_steps: u64,
+
+        /// Cells on the tape, small for the programs to reach its edges
+        #[clap(long, default_value = "64", value_parser = parse_tape_size)]
+        tape_size: usize,
+    },
+    /// Format a program: indented by loop nesting, comments on their own lines, long lines wrapped
+    Fmt {
+        /// Standard input if not given or `-`
+        file: Option<String>,
+
+        /// Write the result back to the file instead of printing it
+        #[clap(short, long, requires = "file")]
+        write: bool,
+
+        /// Longest line, indentation included
+        #[clap(long, default_value_t = 80)]
+        width: usize,
+
+        /// Leave out everything that is not a command
+        #[clap(long)]
+        strip_comments: bool,
+    },
+    /// Debug a program at a prompt, with breakpoints and stepping. `help` lists the commands
+    Debug {
+        #[clap(required_unless_present = "core")]
+        file: Option<String>,
+
+        /// Open a crash dump written by --crash-dump instead, to look at where the program failed
+        #[clap(long, value_name = "FILE", conflicts_with_all = &["file", "input", "tui"])]
+        core: Option<String>,
+
+        /// Read the program's input from this file instead of the terminal
+        #[clap(long)]
+        input: Option<String>,
+
+        /// Full-screen interface, needs the tui feature. The program's input is empty without --input
+        #[clap(long)]
+        tui: bool,
+    },
+    /// Print a script completing the subcommands and flags of bf in a shell, to be loaded by it such
+    /// as `bf completions bash > ~/.local/share/bash-completion/completions/bf`
+    Completions {
+        #[clap(arg_enum)]
+        shell: Shell,
+    },
+    /// Serve the Debug Adapter Protocol on standard input and output, for debugging in an editor
+    Dap,
+    /// Serve the Language Server Protocol on standard input and output, for unmatched brackets,
+    /// bracket matching, the loops of a program and what its commands compile to in an editor
+    Lsp,
+    /// Keep programs compiled in the background, for `bf client run` to run them again without
+    /// compiling them, see the `daemon` module
+    #[cfg(unix)]
+    Daemon {
+        /// Socket to listen on, bf.sock in $XDG_RUNTIME_DIR if not given
+        #[clap(long)]
+        socket: Option<String>,
+
+        /// Requests handled at once, as many as there are CPUs if not given
+        #[clap(short, long)]
+        jobs: Option<usize>,
+
+        /// Programs kept compiled
+        #[clap(long, default_value = "64")]
+        max_programs: usize,
+
+        /// How deep the loops of a program can nest
+        #[clap(long, default_value = "100000")]
+        max_depth: usize,
+
+        /// Bytes of a request, the program and its input
+        #[clap(long, default_value = "67108864")]
+        max_request: usize,
+
+        /// Cells a request can ask for on the tape
+        #[clap(long, default_value = "16M", value_parser = parse_tape_size)]
+        max_tape_size: usize,
+
+        /// Instructions a program can run, as many as it takes if not given
+        #[clap(long)]
+        max_steps: Option<u64>,
+
+        /// Milliseconds a program can run for
+        #[clap(long, value_name = "MS", default_value = "60000")]
+        timeout_ms: u64,
+
+        /// Bytes a program can write
+        #[clap(long, default_value = "67108864")]
+        max_output: usize,
+    },
+    /// Talk to `bf daemon`
+    #[cfg(unix)]
+    Client {
+        /// Socket the daemon listens on, bf.sock in $XDG_RUNTIME_DIR if not given
+        #[clap(long, global = true)]
+        socket: Option<String>,
+
+        #[clap(subcommand)]
+        command: ClientCommand,
+    },
+    /// Serve an HTTP API running programs, for playgrounds: `POST /run` with
+    /// `{"program": "...", "input": "..."}` answers what the program wrote and how it ran, see the
+    /// `serve` module. Every run is held to the limits below
+    Serve {
+        /// Address to listen on
+        #[clap(long, default_value = "127.0.0.1:8080")]
+        addr: String,
+
+        /// Requests handled at once, as many as there are CPUs if not given
+        #[clap(short, long)]
+        jobs: Option<usize>,
+
+        /// Instructions a program can run
+        #[clap(long, default_value = "100000000")]
+        max_steps: u64,
+
+        /// Milliseconds a program can run for
+        #[clap(long, value_name = "MS", default_value = "5000")]
+        timeout_ms: u64,
+
+        /// Bytes a program can write
+        #[clap(long, default_value = "65536")]
+        max_output: usize,
+
+        /// Cells on the tape
+        #[clap(long, default_value = "30000", value_parser = parse_tape_size)]
+        tape_size: usize,
+
+        /// Bytes of a request, the program and its input
+        #[clap(long, default_value = "1048576")]
+        max_body: usize,
+
+        /// How deep the loops of a program can nest
+        #[clap(long, default_value = "1000")]
+        max_depth: usize,
+    },
+    /// Run lines of Brainfuck as they are typed, on a tape kept between them. `:quit` to leave. Line
+    /// editing and history need the readline feature
+    Repl {
+        /// Run this program first, the prompt starts with the tape it leaves
+        file: Option<String>,
+
+        /// Cells on the tape, such as 30000, 64K or 1M
+        #[clap(long, default_value = "30000", value_parser = parse_tape_size)]
+        tape_size: usize,
+    },
+    /// Example programs built into bf
+    Examples {
+        #[clap(subcommand)]
+        command: ExamplesCommand,
+    },
+    /// Time programs on every backend built in
+    Bench {
+        /// Programs to time, the built-in examples not reading input if none are given. Input for the
+        /// programs is read from standard input, once
+        files: Vec<String>,
+
+        /// How many times to run each program on each backend, the best time counts
+        #[clap(short = 'n', long, default_value_t = 3)]
+        runs: usize,
+
+        /// Only time these backends
+        #[clap(long = "backend", use_value_delimiter = true)]
+        backends: Vec<String>,
+
+        /// Also time the programs without optimizing them
+        #[clap(long)]
+        unoptimized: bool,
+    },
+}
+
+/// Size from which a Brainfuck file is lexed as it is read, see `RunArgs::streamed_file`.
+const STREAM_SIZE: u64 = 16 << 20;
+
+const EXIT_SYNTAX: i32 = 2;
+const EXIT_MEMORY: i32 = 3;
+const EXIT_IO: i32 = 5;
+const EXIT_USAGE: i32 = 64;
+
+const AFTER_HELP: &str = "EXIT STATUS:
+    0     Success
+    1     Any other error, such as failing golden tests
+    2     Syntax error, such as an unclosed `[` (or a file `bf check` found one in)
+    3     The program went off the tape
+    5     Reading or writing failed, running out of input included
+    64    Bad command line
+
+ENVIRONMENT:
+    BF_TAPE_SIZE, BF_EOF, BF_TAPE_EDGE, BF_COMPAT, BF_DIALECT, BF_BACKEND, BF_ENGINE
+          Defaults of the flags of the same name, over the ones of bf.toml
+    BF_CELL_SIZE
+          Cells are 8 bits, anything else is an error
+    BF_CONFIG
+          User config, a [run] table of flags like in bf.toml, instead of ~/.config/bf/config.toml
+
+Directives at the top of a program, such as `;; bf: tape=64K eof=zero`, go over these and under the
+flags given.";
+
+#[derive(Debug, Parser)]
+#[clap(
+    author,
+    version,
+    about,
+    args_conflicts_with_subcommands = true,
+    args_override_self = true,
+    after_help = AFTER_HELP
+)]
+pub struct Args {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    #[clap(flatten)]
+    run: RunArgs,
+
+    /// Report what bf did before running the program: -v for stage timings and sizes, -vv also each
+    /// optimizer pass and the cache
+    #[clap(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Print errors only
+    #[clap(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Columns a tab takes up to in the locations of errors, the tab width of your editor. Other
+    /// characters are a column each
+    #[clap(long, global = true, value_name = "N", default_value_t = 1)]
+    tab_width: usize,
+}
+
+#[derive(Debug, clap::Args)]
+struct RunArgs {
+    /// Program to run, `-` (or nothing, when piped) for standard input. Several files are run as one
+    /// program, concatenated in order. A Brainfuck program piped in runs as it arrives, each part
+    /// once no loop is open in it. In a project, with a bf.toml, nothing runs its first program and
+    /// the name of one of its programs that program
+    files: Vec<String>,
+
+    /// Run this program instead of a file
+    #[clap(short, long, conflicts_with = "files")]
+    eval: Option<String>,
+
+    /// Language the program is written in, by default from the file extension (`.bf`, `.b`, `.ook`,
+    /// `.blub`, `.spoon`, `.png` for Brainloller, `.bfc` for bytecode) or else from what it holds
+    #[clap(long, arg_enum)]
+    dialect: Option<Dialect>,
+
+    /// Read the program as a Trivial Brainfuck Substitution, with the words for the commands in this
+    /// file, a line like `+ moo` for each
+    #[clap(long, value_name = "FILE", conflicts_with = "dialect")]
+    substitution: Option<String>,
+
+    /// Word for a command in the program, such as `+=moo`, over the one in --substitution. Commands
+    /// with no word are their own character
+    #[clap(long, value_name = "CMD=WORD", conflicts_with = "dialect")]
+    substitute: Vec<String>,
+
+    /// Carry out `@include "FILE"` and `@def NAME CODE` lines before reading the program, expanding
+    /// `@NAME` and `@NAME*N` to the code of the macro. Errors point at the files as written
+    #[clap(long, conflicts_with_all = &["substitution", "substitute"])]
+    preprocess: bool,
+
+    /// Run the program embedded in a document: its code blocks fenced with ```bf or ```brainfuck, or
+    /// the lines between a line with `bf-begin` and one with `bf-end`. Errors point at the lines of
+    /// the document. Markdown files, ending in .md, are always read this way
+    #[clap(long)]
+    extract: bool,
+
+    /// Read `?` as a command setting the current cell to a random byte. The program runs unoptimized
+    /// on the VM
+    #[clap(long)]
+    random: bool,
+
+    /// Seed of the random numbers of `?`, the same seed gives the same run. Taken from the clock if
+    /// not given
+    #[clap(long, value_name = "N", requires = "random")]
+    seed: Option<u64>,
+
+    /// Read `#` as a command printing the pointer and the first cells of the tape to standard error,
+    /// 16 if not given. The program runs unoptimized on the VM
+    #[clap(long, value_name = "CELLS", require_equals = true)]
+    hash_dump: Option<Option<usize>>,
+
+    /// Read `%`, `^` and `!` as the file commands of Brainfuck++, which open, write and read files,
+    /// only the ones in this directory. The program runs unoptimized on the VM
+    #[clap(long, value_name = "DIR")]
+    file_io: Option<String>,
+
+    /// Only let the file commands read files that exist
+    #[clap(long, requires = "file-io")]
+    file_io_read_only: bool,
+
+    /// Tapes of a multi-tape program, each the size of --tape-size with a pointer of its own
+    #[clap(long, value_name = "N", default_value_t = 2)]
+    tapes: usize,
+
+    /// Give the program this text as input instead of standard input
+    #[clap(short, long)]
+    input: Option<String>,
+
+    /// Write every byte of input the program reads to this file, to run it again the same way with
+    /// --replay-input
+    #[clap(long, value_name = "FILE")]
+    record_input: Option<String>,
+
+    /// Give the program the contents of this file as input, such as one written by --record-input
+    #[clap(long, value_name = "FILE", conflicts_with = "input")]
+    replay_input: Option<String>,
+
+    /// Cells on the tape, such as 30000, 64K or 1M. 30000 unless --compat sets another
+    #[clap(long, value_parser = parse_tape_size)]
+    tape_size: Option<usize>,
+
+    /// Behave like other interpreters: set the tape size, what `,` does at the end of input and what
+    /// going past the last cell does in one go. The options setting one of them go over it. Cells are
+    /// 8 bits in every profile
+    #[clap(long, arg_enum, value_name = "PROFILE")]
+    compat: Option<Compat>,
+
+    /// What `,` does at the end of input
+    #[clap(long, arg_enum)]
+    eof: Option<Eof>,
+
+    /// What moving past the last cell does, moving left of the first one stays on it
+    #[clap(long, arg_enum)]
+    tape_edge: Option<TapeEdge>,
+
+    /// Run the program once to collect loop counts, then re-compile with that profile and run it again
+    #[clap(long)]
+    pgo: bool,
+
+    /// How to run the program, JIT backends need the feature of the same name
+    #[clap(long, arg_enum, default_value_t = Backend::Interpreter)]
+    backend: Backend,
+
+    /// How the interpreter dispatches opcodes
+    #[clap(long, arg_enum, default_value_t = Engine::Match)]
+    engine: Engine,
+
+    /// Print the tokens and where they are instead of running the program
+    #[clap(long)]
+    dump_tokens: bool,
+
+    /// Print the loop tree of the program, unoptimized, instead of running it
+    #[clap(long)]
+    dump_ast: bool,
+
+    /// Print the compiled bytecode instead of running it
+    #[clap(long)]
+    dump_bytecode: bool,
+
+    /// After the program ends or fails, print the tape as hex and ASCII to standard error. Up to the
+    /// last cell used if no range is given
+    #[clap(long, value_name = "START..END", require_equals = true, value_parser = parse_cell_range)]
+    dump_mem: Option<Option<Range<usize>>>,
+
+    /// When the program fails, write a file to this directory with the error, the pc, the tape, the
+    /// last instructions run and the output not yet flushed, found by running it again on the VM.
+    /// `bf debug --core` opens it
+    #[clap(long, value_name = "DIR", conflicts_with_all = &["trace", "profile", "profile-loops", "heatmap", "heatmap-csv", "coverage", "coverage-lcov", "frames", "verify", "verify-tape", "pgo"])]
+    crash_dump: Option<String>,
+
+    /// Write the output from a thread of its own, so the program goes on running while a slow
+    /// terminal or pipe takes it
+    #[clap(long, conflicts_with_all = &["verify", "verify-tape"])]
+    output_thread: bool,
+
+    /// Print the run time to standard error, with instructions executed and the highest pointer on the VM
+    #[clap(long)]
+    time: bool,
+
+    /// Write statistics of the run to this file as JSON: the time each stage took, the bytes read
+    /// and written and, on the VM, the instructions executed by type, the times each loop went
+    /// around and the highest pointer
+    #[clap(long, value_name = "FILE", conflicts_with_all = &["trace", "profile", "profile-loops", "heatmap", "heatmap-csv", "coverage", "coverage-lcov", "frames", "verify", "verify-tape"])]
+    stats_json: Option<String>,
+
+    /// Keep the tape in this file between runs: the cells it holds are put on the tape before the
+    /// program runs, and the tape is written back after it ends or fails, up to the last cell that
+    /// isn't zero. The pointer starts at the first cell. A run that is killed leaves the file as it was.
+    /// Bytecode from `bf compile` is optimized for a tape of zeros, run the source instead
+    #[clap(long, value_name = "FILE", conflicts_with_all = &["trace", "profile", "profile-loops", "heatmap", "heatmap-csv", "coverage", "coverage-lcov", "frames", "verify", "verify-tape", "pgo"])]
+    persist_tape: Option<String>,
+
+    /// Log every instruction run, with the pointer and the cell, to standard error. Only the first N
+    /// if given. Runs on the VM
+    #[clap(long, value_name = "N", require_equals = true)]
+    trace: Option<Option<u64>>,
+
+    /// Write the trace to this file instead
+    #[clap(long, requires = "trace")]
+    trace_file: Option<String>,
+
+    /// Write the trace as 9-byte records: the pc and the pointer as little-endian u32, then the cell
+    #[clap(long, requires = "trace")]
+    trace_binary: bool,
+
+    /// Count the instructions run and time them, then print a table per opcode and per pc to standard
+    /// error. Runs on the VM, a lot slower than a normal run
+    #[clap(long, conflicts_with = "trace")]
+    profile: bool,
+
+    /// Print the N loops that took longest, 10 by default, with where they are in the source, how many
+    /// times they were entered and iterated. Runs the program unoptimized on the VM
+    #[clap(long, value_name = "N", require_equals = true, conflicts_with_all = &["trace", "profile"])]
+    profile_loops: Option<Option<usize>>,
+
+    /// Count the reads and writes of every cell and print them per group of cells, with a bar, to
+    /// standard error. Runs on the VM
+    #[clap(long, conflicts_with_all = &["trace", "profile", "profile-loops"])]
+    heatmap: bool,
+
+    /// Write the counts of --heatmap as CSV to this file, a row per cell up to the last one used
+    #[clap(long, value_name = "FILE", conflicts_with_all = &["trace", "profile", "profile-loops"])]
+    heatmap_csv: Option<String>,
+
+    /// Print the source to standard error with how many times each line ran, marking the commands
+    /// that never did. Runs the program unoptimized on the VM
+    #[clap(long, conflicts_with_all = &["trace", "profile", "profile-loops", "heatmap", "heatmap-csv"])]
+    coverage: bool,
+
+    /// Write the line counts of --coverage to this file in the lcov format
+    #[clap(long, value_name = "FILE", conflicts_with_all = &["trace", "profile", "profile-loops", "heatmap", "heatmap-csv"])]
+    coverage_lcov: Option<String>,
+
+    /// Write the tape to this directory every --frame-every instructions, as grayscale PGM images of
+    /// 64 cells to a row, with frames.csv listing the pc and the pointer of each frame. For making an
+    /// animation of the run. Runs on the VM
+    #[clap(long, value_name = "DIR", conflicts_with_all = &["trace", "profile", "profile-loops", "heatmap", "heatmap-csv", "coverage", "coverage-lcov"])]
+    frames: Option<String>,
+
+    /// Instructions between two frames of --frames
+    #[clap(long, value_name = "N", default_value_t = 1000)]
+    frame_every: u64,
+
+    /// Cells in a frame of --frames, from the first one
+    #[clap(long, value_name = "N", default_value_t = 1024)]
+    frame_cells: usize,
+
+    /// Write the frames of --frames as CSV, a row per cell, instead of images
+    #[clap(long, requires = "frames")]
+    frames_csv: bool,
+
+    /// Run the program on the reference interpreter too, with the same input, and fail if it behaved
+    /// differently. The output is only written once both agree
+    #[clap(long)]
+    verify: bool,
+
+    /// Also compare the tapes at the end, implies --verify
+    #[clap(long)]
+    verify_tape: bool,
+
+    /// How much to optimize the program: 0 runs it as written, 1 only clears loops and adds up runs
+    /// of commands, 2 runs every pass
+    #[clap(long, default_value = "2", value_parser = parse_opt_level)]
+    opt_level: u8,
+
+    /// Compile the program again instead of using the cache (in $BF_CACHE_DIR, or ~/.cache/bf)
+    #[clap(long)]
+    no_cache: bool,
+
+    /// Keep no record of where in the source each command is, which huge programs take much memory
+    /// for. Errors while running then don't point at the program
+    #[clap(long)]
+    no_debug_info: bool,
+
+    /// Let the VM rewrite loops into faster forms the first time they run
+    #[clap(long)]
+    quicken: bool,
+
+    /// Check the pointer against the tape on every access to a cell, instead of only when it moves.
+    /// Slower, on the interpreter only
+    #[clap(long)]
+    strict_bounds: bool,
+
+    /// How to print errors, json prints an object per error with where it is
+    #[clap(long, arg_enum, default_value_t = Format::Text)]
+    format: Format,
+
+    /// Compile hot loops to native code while interpreting
+    #[cfg(feature = "tiered")]
+    #[clap(long)]
+    tiered: bool,
+
+    /// How many times a loop has to run before it gets compiled
+    #[cfg(feature = "tiered")]
+    #[clap(long, default_value_t = bf::tiered::DEFAULT_JIT_THRESHOLD)]
+    jit_threshold: u32,
+}
+
+impl RunArgs {
+    /// The program, from `--eval`, the files or standard input. Reading it from standard input leaves
+    /// nothing there for the program, `--input` can give it some.
+    fn read_program(&self) -> anyhow::Result<Vec<Input>> {
+        if let Some(src) = &self.eval {
+            if self.dialect == Some(Dialect::Brainloller) {
+                anyhow::bail!("Brainloller programs are images, give the file");
+            }
+            let input = Input {
+                name: None,
+                path: None,
+                bytes: src.clone().into_bytes(),
+            };
+            return Ok(vec![self.extracted(input)?]);
+        }
+
+        if self.files.is_empty() {
+            if io::stdin().is_terminal() {
+                anyhow::bail!("no program given, pass a file, `-` for standard input or --eval");
+            }
+            let input = Input {
+                name: None,
+                path: None,
+                bytes: read_bytes(&mut io::stdin().lock())?,
+            };
+            return Ok(vec![self.extracted(input)?]);
+        }
+
+        self.files
+            .iter()
+            .map(|path| {
+                let input = match path.as_str() {
+                    "-" => Input {
+                        name: Some("<stdin>".to_string()),
+                        path: None,
+                        bytes: read_bytes(&mut io::stdin().lock())?,
+                    },
+                    path => Input {
+                        name: Some(path.to_string()),
+                        path: Some(path.to_string()),
+                        bytes: fs::read(path)?,
+                    },
+                };
+                self.extracted(input)
+            })
+            .collect()
+    }
+
+    /// `input` with only the program embedded in it, with --extract or for Markdown files.
+    fn extracted(&self, mut input: Input) -> anyhow::Result<Input> {
+        let markdown = input.path.as_deref().is_some_and(is_markdown);
+        if self.extract || markdown {
+            let text = into_text(input.bytes)?;
+            let name = input.name.as_deref().unwrap_or("<stdin>");
+            input.bytes = extract::extract(&text)
+                .map_err(|err| anyhow::anyhow!("{}: {}", name, err))?
+                .into_bytes();
+        }
+
+        Ok(input)
+    }
+
+    /// The dialect of `input`, --dialect or else the one it looks like.
+    fn dialect_of(&self, input: &Input) -> Dialect {
+        self.dialect
+            .unwrap_or_else(|| Dialect::detect(input.path.as_deref(), &input.bytes))
+    }
+
+    /// The program of a bytecode file, if that is what was given. It can't be joined to other files.
+    fn bytecode(&self, inputs: &[Input]) -> anyhow::Result<Option<(Program, Option<SourceMap>)>> {
+        match inputs {
+            [input] if self.dialect_of(input) == Dialect::Bytecode => {
+                bytecode::decode_with_map(&input.bytes).map(Some)
+            }
+            _ if inputs
+                .iter()
+                .any(|input| self.dialect_of(input) == Dialect::Bytecode) =>
+            {
+                anyhow::bail!("bytecode files run on their own, not with other files")
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// The program read by `read_program`, as source.
+    fn source(&self, inputs: Vec<Input>) -> anyhow::Result<Source> {
+        let substitution = self.substitution()?;
+        // The program as Brainfuck, if it is written in another language.
+        let translate = |dialect: Dialect, src: &[u8]| -> anyhow::Result<Option<String>> {
+            if let Some(substitution) = &substitution {
+                return substitution
+                    .to_brainfuck(std::str::from_utf8(src)?)
+                    .map(Some);
+            }
+            match dialect.frontend() {
+                Some(frontend) => frontend.to_brainfuck(src),
+                None => Ok(None),
+            }
+        };
+        // Preprocessing is textual, for Brainfuck and the dialects adding commands to it.
+        let expand = |dialect: Dialect, name: &str, text: &str| -> anyhow::Result<Expanded> {
+            if let dialect @ (Dialect::Ook | Dialect::Blub | Dialect::Spoon) = dialect {
+                anyhow::bail!("--preprocess doesn't read {:?} programs", dialect);
+            }
+            preprocess::expand(name, text)
+        };
+
+        let mut source = Source::default();
+        for input in inputs {
+            let dialect = self.dialect_of(&input);
+            let brainloller = dialect == Dialect::Brainloller;
+
+            let Some(name) = input.name else {
+                if brainloller {
+                    let bf = translate(dialect, &input.bytes)?.unwrap_or_default();
+                    return Ok(Source::unnamed(bf));
+                }
+                let src = into_text(input.bytes)?;
+                if self.preprocess {
+                    let name = if self.eval.is_some() {
+                        "<eval>"
+                    } else {
+                        "<stdin>"
+                    };
+                    source.push_expanded(name, expand(dialect, name, &src)?);
+                    return Ok(source);
+                }
+                return Ok(match translate(dialect, src.as_bytes())? {
+                    Some(bf) => Source::unnamed_translated(src, bf),
+                    None => Source::unnamed(src),
+                });
+            };
+
+            if brainloller {
+                let bf = translate(dialect, &input.bytes)
+                    .map_err(|err| anyhow::anyhow!("{}: {}", name, err))?;
+                source.push(&name, &bf.unwrap_or_default());
+                continue;
+            }
+            let text = into_text(input.bytes)?;
+            if self.preprocess {
+                source.push_expanded(&name, expand(dialect, &name, &text)?);
+                continue;
+            }
+            match translate(dialect, text.as_bytes())
+                .map_err(|err| anyhow::anyhow!("{}: {}", name, err))?
+            {
+                Some(bf) => source.push_translated(&name, &text, &bf),
+                None => source.push(&name, &text),
+            }
+        }
+
+        Ok(source)
+    }
+
+    /// The tokens of `src`, in the dialect of the program and with `?` for --random.
+    fn lex(&self, src: &str) -> TokenList {
+        let extension = match self.dialect {
+            Some(Dialect::Pbrain) => Extension::Pbrain,
+            Some(Dialect::Extended1) => Extension::Extended1,
+            Some(Dialect::Brainfork) => Extension::Brainfork,
+            Some(Dialect::MultiTape) => Extension::MultiTape,
+            _ => Extension::None,
+        };
+
+        lexer::parse_with(
+            src,
+            lexer::Options {
+                extension,
+                random: self.random,
+                dump: self.hash_dump.is_some(),
+                files: self.file_io.is_some(),
+            },
+        )
+    }
+
+    /// `vm::compile_with_map`, unoptimized for the commands the optimizer does not know.
+    fn compile(&self, src: &str) -> anyhow::Result<(Program, SourceMap)> {
+        match (self.vm_only(), self.no_debug_info) {
+            (true, false) => vm::compile_tokens_with_map(self.lex(src)),
+            (true, true) => Ok((parser::parse(self.lex(src))?, SourceMap::new())),
+            (false, false) => vm::compile_with_map_passes(src, &self.passes()),
+            (false, true) => Ok((
+                vm::compile_with_passes(src, &self.passes())?,
+                SourceMap::new(),
+            )),
+        }
+    }
+
+    /// The passes the program is optimized with. The cells of a tape loaded by --persist-tape
+    /// aren't all zero at the start, like the ones of a part of a piped program.
+    fn passes(&self) -> PassRegistry {
+        let mut passes = PassRegistry::with_level(self.opt_level);
+        if self.persist_tape.is_some() {
+            passes.remove("dead-loops");
+        }
+
+        passes
+    }
+
+    /// Whether the program has commands only the VM runs, from its dialect or the options adding
+    /// some, or runs on a tape only the VM grows.
+    fn vm_only(&self) -> bool {
+        self.settings().tape_edge == vm::TapeEdge::Grow
+            || self.random
+            || self.hash_dump.is_some()
+            || self.file_io.is_some()
+            || matches!(
+                self.dialect,
+                Some(
+                    Dialect::Pbrain | Dialect::Extended1 | Dialect::Brainfork | Dialect::MultiTape
+                )
+            )
+    }
+
+    /// The file to lex without reading it into memory, see `compile_streamed`: a Brainfuck file of
+    /// `STREAM_SIZE` bytes or more, only run on the interpreter. Programs that big are generated,
+    /// these runs are plain ones.
+    fn streamed_file(&self) -> Option<&str> {
+        let [path] = &self.files[..] else {
+            return None;
+        };
+        let dialect = self.dialect.or_else(|| match detect::from_extension(path) {
+            Some(detect::Format::Brainfuck) => Some(Dialect::Brainfuck),
+            _ => None,
+        });
+        let interpreted = matches!(
+            self.backend_name(),
+            "vm" | "threaded" | "blocks" | "tail-call"
+        );
+
+        let streams = path != "-"
+            && !is_markdown(path)
+            && dialect == Some(Dialect::Brainfuck)
+            && self.plain_run()
+            && interpreted
+            && fs::metadata(path).is_ok_and(|meta| meta.len() >= STREAM_SIZE);
+        streams.then_some(path.as_str())
+    }
+
+    /// Whether the program piped in is run as it arrives, see `run_piped`: a plain run of it on the
+    /// VM, standard input being a pipe. The program reads --input or --replay-input, or nothing.
+    fn piped_program(&self) -> bool {
+        #[cfg(feature = "tiered")]
+        if self.tiered {
+            return false;
+        }
+        let from_stdin = match &self.files[..] {
+            [] => self.eval.is_none(),
+            [path] => path == "-",
+            _ => false,
+        };
+
+        from_stdin
+            && matches!(self.dialect, None | Some(Dialect::Brainfuck))
+            && self.plain_run()
+            && self.backend_name() == "vm"
+            && !(self.quicken
+                || self.time
+                || self.stats_json.is_some()
+                || self.persist_tape.is_some()
+                || self.dump_mem.is_some())
+            && stdin_is_pipe()
+    }
+
+    /// Whether the program is Brainfuck as written, only run on a tape the optimized program runs on.
+    fn plain_run(&self) -> bool {
+        let plain = self.substitution.is_none()
+            && self.substitute.is_empty()
+            && !self.preprocess
+            && !self.extract;
+        let only_run = !(self.dump_tokens
+            || self.dump_ast
+            || self.dump_bytecode
+            || self.pgo
+            || self.crash_dump.is_some()
+            || self.trace.is_some()
+            || self.profile
+            || self.profile_loops.is_some()
+            || self.heatmap
+            || self.heatmap_csv.is_some()
+            || self.coverage
+            || self.coverage_lcov.is_some()
+            || self.frames.is_some()
+            || self.verify
+            || self.verify_tape);
+
+        plain && only_run && !self.vm_only()
+    }
+
+    /// The tape and the end of input of --compat, with --tape-size, --eof and --tape-edge over it.
+    fn settings(&self) -> compat::Settings {
+        let profile = match self.compat {
+            None => compat::DEFAULT,
+            Some(Compat::Classic) => compat::CLASSIC,
+            Some(Compat::Portable) => compat::PORTABLE,
+            Some(Compat::BusyBeaver) => compat::BUSY_BEAVER,
+            Some(Compat::Eof0) => compat::EOF_ZERO,
+            Some(Compat::EofNeg1) => compat::EOF_NEG_ONE,
+        };
+        let eof = self.eof.map(vm::Eof::from);
+        let tape_edge = self.tape_edge.map(|edge| match edge {
+            TapeEdge::Error => vm::TapeEdge::Error,
+            TapeEdge::Grow => vm::TapeEdge::Grow,
+        });
+
+        compat::Settings {
+            tape_size: self.tape_size.unwrap_or(profile.tape_size),
+            eof: eof.unwrap_or(profile.eof),
+            tape_edge: tape_edge.unwrap_or(profile.tape_edge),
+        }
+    }
+
+    /// The seed of --seed, or one from the clock.
+    fn seed(&self) -> u64 {
+        self.seed.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_nanos() as u64)
+        })
+    }
+
+    /// The words of --substitution and --substitute, if the program is written with other words.
+    fn substitution(&self) -> anyhow::Result<Option<Substitution>> {
+        let mut substitution = match &self.substitution {
+            Some(path) => Substitution::read(&fs::read_to_string(path)?)
+                .map_err(|err| anyhow::anyhow!("{}: {}", path, err))?,
+            None if self.substitute.is_empty() => return Ok(None),
+            None => Substitution::new(),
+        };
+        for spec in &self.substitute {
+            substitution.set(spec)?;
+        }
+
+        Ok(Some(substitution))
+    }
+
+    fn input(&self) -> anyhow::Result<Box<dyn Read>> {
+        let input: Box<dyn Read> = match (&self.input, &self.replay_input) {
+            (Some(text), _) => Box::new(io::Cursor::new(text.clone().into_bytes())),
+            (_, Some(path)) => Box::new(io::Cursor::new(fs::read(path)?)),
+            (None, None) => Box::new(io::stdin().lock()),
+        };
+
+        Ok(match &self.record_input {
+            Some(path) => Box::new(RecordInput {
+                inner: input,
+                out: fs::File::create(path)?,
+            }),
+            None => input,
+        })
+    }
+
+    /// Standard output, written by a thread of its own with --output-thread.
+    fn output(&self) -> Box<dyn Write> {
+        match self.output_thread {
+            true => Box::new(WriterThread::spawn(io::stdout())),
+            false => Box::new(io::stdout().lock()),
+        }
+    }
+
+    /// Name of the `backend` module's backend for the chosen backend and engine.
+    fn backend_name(&self) -> &'static str {
+        match (self.backend, self.engine) {
+            (Backend::Jit, _) => "jit",
+            (Backend::JitX64, _) => "jit-x64",
+            (_, Engine::Match) => "vm",
+            (_, Engine::Threaded) => "threaded",
+            (_, Engine::Blocks) => "blocks",
+            (_, Engine::TailCall) => "tail-call",
+            (_, Engine::Reference) => "reference",
+        }
+    }
+}
+
+/// Writes what is read through it to `out` as it goes, for `--record-input` and `--crash-dump`. The
+/// program reads a byte at a time, so that is what it consumed, even when killed halfway.
+struct RecordInput<R: Read, W: Write> {
+    inner: R,
+    out: W,
+}
+
+impl<R: Read, W: Write> Read for RecordInput<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.out.write_all(&buf[..read])?;
+
+        Ok(read)
+    }
+}
+
+/// A number of cells, optionally in K (1024 cells) or M (1024K).
+fn parse_tape_size(s: &str) -> anyhow::Result<usize> {
+    let (number, unit) = match s.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&s[..i], 1 << 10),
+        Some((i, 'm' | 'M')) => (&s[..i], 1 << 20),
+        _ => (s, 1),
+    };
+
+    number
+        .parse::<usize>()
+        .ok()
+        .and_then(|number| number.checked_mul(unit))
+        .filter(|&size| size > 0)
+        .ok_or_else(|| anyhow::anyhow!("expected a number of cells, such as 30000, 64K or 1M"))
+}
+
+/// A level of `--opt-level`, 0 to 2.
+fn parse_opt_level(s: &str) -> anyhow::Result<u8> {
+    s.parse::<u8>()
+        .ok()
+        .filter(|&level| level <= 2)
+        .ok_or_else(|| anyhow::anyhow!("expected an optimization level, 0, 1 or 2"))
+}
+
+/// `start..end`, either can be left out.
+fn parse_cell_range(s: &str) -> anyhow::Result<Range<usize>> {
+    let Some((start, end)) = s.split_once("..") else {
+        anyhow::bail!("expected a range of cells, such as 0..64, 100.. or ..32");
+    };
+
+    let start = match start {
+        "" => 0,
+        start => start.parse()?,
+    };
+    let end = match end {
+        "" => usize::MAX,
+        end => end.parse()?,
+    };
+
+    Ok(start..end)
+}
+
+fn read_source(reader: &mut dyn Read) -> anyhow::Result<String> {
+    let mut src = String::new();
+    reader.read_to_string(&mut src)?;
+
+    Ok(src)
+}
+
+/// Whether standard input is a pipe, a program on it can still be being written.
+#[cfg(unix)]
+fn stdin_is_pipe() -> bool {
+    use std::os::{fd::AsFd, unix::fs::FileTypeExt};
+
+    io::stdin()
+        .as_fd()
+        .try_clone_to_owned()
+        .and_then(|fd| fs::File::from(fd).metadata())
+        .is_ok_and(|meta| meta.file_type().is_fifo())
+}
+
+#[cfg(not(unix))]
+fn stdin_is_pipe() -> bool {
+    !io::stdin().is_terminal()
+}
+
+fn read_bytes(reader: &mut dyn Read) -> anyhow::Result<Vec<u8>> {
+    let mut bytes = vec![];
+    reader.read_to_end(&mut bytes)?;
+
+    Ok(bytes)
+}
+
+/// Whether `path` is a Markdown file, which --extract is implied for.
+fn is_markdown(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .is_some_and(|ext| ext == "md" || ext == "markdown")
+}
+
+/// `bytes` as text, failing like reading a file that isn't UTF-8 does.
+fn into_text(bytes: Vec<u8>) -> anyhow::Result<String> {
+    Ok(String::from_utf8(bytes).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?)
+}
+
+fn run_file(args: &RunArgs) -> anyhow::Result<()> {
+    if args.stats_json.is_some() {
+        log::keep_stages();
+    }
+    if let Some(path) = args.streamed_file() {
+        let source = Source::streamed(path);
+        return run_streamed(args, path).map_err(|err| source.locate_error(err));
+    }
+
+    if args.piped_program() {
+        return run_piped(args);
+    }
+
+    run_inputs(args, args.read_program()?)
+}
+
+fn run_inputs(args: &RunArgs, inputs: Vec<Input>) -> anyhow::Result<()> {
+    if let Some(program) = args.bytecode(&inputs)? {
+        return run_bytecode(args, program);
+    }
+    let source = args.source(inputs)?;
+
+    run_source(args, &source).map_err(|err| source.locate_error(err))
+}
+
+/// Runs the program piped in as it arrives, see `RunArgs::piped_program`. Whenever all of it read so
+/// far is lexed and no loop is open, what came since the last part is optimized and run after it,
+/// then its output written before waiting on more. Programs that don't look like Brainfuck are read
+/// whole and run like the others.
+fn run_piped(args: &RunArgs) -> anyhow::Result<()> {
+    let name = (!args.files.is_empty()).then_some("<stdin>");
+    let mut stdin = io::stdin().lock();
+    if args.dialect.is_none() && detect::sniff(stdin.fill_buf()?) != detect::Format::Brainfuck {
+        let input = Input {
+            name: name.map(str::to_string),
+            path: None,
+            bytes: read_bytes(&mut stdin)?,
+        };
+        drop(stdin);
+        return run_inputs(args, vec![input]);
+    }
+
+    let mut text = vec![];
+    let result = run_incremental(
+        args,
+        RecordInput {
+            inner: stdin,
+            out: &mut text,
+        },
+    );
+
+    let text = String::from_utf8_lossy(&text).into_owned();
+    let source = match name {
+        Some(name) => {
+            let mut source = Source::default();
+            source.push(name, &text);
+            source
+        }
+        None => Source::unnamed(text),
+    };
+    result.map_err(|err| source.locate_error(err))
+}
+
+fn run_incremental<R: Read>(args: &RunArgs, program: R) -> anyhow::Result<()> {
+    let mut vm = Vm::from_program(vec![])?;
+    args.settings().apply(&mut vm)?;
+    if args.strict_bounds {
+        vm.enable_strict_bounds();
+    }
+    // Standard input is the program.
+    let input = &mut match (&args.input, &args.replay_input) {
+        (None, None) => Box::new(io::empty()),
+        _ => args.input()?,
+    };
+    let output = &mut args.output();
+
+    // The cells aren't all zero at the start of a part.
+    let mut passes = args.passes();
+    passes.remove("dead-loops");
+    let mut map = SourceMap::new();
+    let mut run = |vm: &mut Vm, (program, spans): (Program, Vec<parser::Span>)| {
+        let (program, part_map) = passes.optimize_with_map(&program, &spans)?;
+        (0..part_map.len()).for_each(|pc| map.push(part_map.get(pc)));
+        vm.append(program)?;
+
+        let result = vm.run_with(input, output);
+        output.flush()?;
+        result.map_err(|err| match args.no_debug_info {
+            true => err,
+            false => vm::locate_error(err, vm.program(), &map, vm.pc()),
+        })
+    };
+
+    let mut lexer = StreamLexer::new(program, lexer::Options::default());
+    let mut parser = IncrementalParser::new();
+    loop {
+        let token = match lexer.next_buffered() {
+            Some(token) => token,
+            None => {
+                if !parser.is_open() {
+                    run(&mut vm, parser.take()?)?;
+                }
+                match lexer.next() {
+                    Some(token) => token?,
+                    None => break,
+                }
+            }
+        };
+        parser.push(token);
+    }
+
+    run(&mut vm, parser.finish()?)
+}
+
+/// Runs the Brainfuck file at `path` without reading it into memory first, see `RunArgs::streamed_file`.
+fn run_streamed(args: &RunArgs, path: &str) -> anyhow::Result<()> {
+    let program = compile_streamed(path, &args.passes())?.0;
+
+    let mut vm = Vm::from_program(vec![])?;
+    args.settings().apply(&mut vm)?;
+    if args.quicken {
+        vm.enable_quickening();
+    }
+    if args.strict_bounds {
+        vm.enable_strict_bounds();
+    }
+    #[cfg(feature = "tiered")]
+    if args.tiered {
+        vm.enable_tiering(args.jit_threshold);
+    }
+
+    let name = args.backend_name();
+    let mut backend = backend::with_vm(name, vm)?;
+    backend.load(program)?;
+
+    let (input, output) = (&mut args.input()?, &mut args.output());
+    let result =
+        run_loaded(args, &mut *backend, name, input, output).and_then(|()| Ok(output.flush()?));
+
+    if let Some(range) = &args.dump_mem {
+        output.flush()?;
+        eprint!("{}", dump_mem(backend.tape(), backend.ptr(), range.clone()));
+    }
+
+    // Compiled again to find where it failed, as `run_source` does.
+    result.map_err(|err| match backend.pc() {
+        Some(pc) if !args.no_debug_info => match compile_streamed(path, &args.passes()) {
+            Ok((program, map)) => vm::locate_error(err, &program, &map, pc),
+            Err(_) => err,
+        },
+        _ => err,
+    })
+}
+
+/// The Brainfuck program in the file at `path`, lexed from a map of the file with the mmap feature or
+/// else as it is read.
+fn compile_streamed(path: &str, passes: &PassRegistry) -> anyhow::Result<(Program, SourceMap)> {
+    let file = fs::File::open(path)?;
+
+    // SAFETY: the map is only read while compiling. A file changed meanwhile makes for a program
+    // mixing the old and the new, the same as reading it as it is written would.
+    #[cfg(feature = "mmap")]
+    if let Ok(map) = unsafe { memmap2::Mmap::map(&file) } {
+        return vm::compile_bytes(&map, passes);
+    }
+
+    vm::compile_reader(file, passes)
+}
+
+/// Runs a bytecode file like `bf exec`, with the input and the tape settings of `args`.
+fn run_bytecode(
+    args: &RunArgs,
+    (program, map): (Program, Option<SourceMap>),
+) -> anyhow::Result<()> {
+    let mut vm = Vm::from_program(program)?;
+    args.settings().apply(&mut vm)?;
+    if args.strict_bounds {
+        vm.enable_strict_bounds();
+    }
+    if let Some(path) = &args.persist_tape {
+        vm.load_tape(&read_tape(path)?)?;
+    }
+    let output = &mut args.output();
+    let result = vm
+        .run_with(&mut args.input()?, output)
+        .and_then(|()| Ok(output.flush()?));
+    let result = match &args.persist_tape {
+        Some(path) => persist(result, path, vm.mem()),
+        None => result,
+    };
+
+    match map {
+        Some(map) => result.map_err(|err| vm::locate_error(err, vm.program(), &map, vm.pc())),
+        None => result,
+    }
+}
+
+fn run_source(args: &RunArgs, source: &Source) -> anyhow::Result<()> {
+    let content = &source.text;
+    let vm_only = args.vm_only();
+    if vm_only && (args.pgo || args.verify || args.verify_tape || args.crash_dump.is_some()) {
+        anyhow::bail!("--pgo, --verify and --crash-dump can't be used with --random, --hash-dump, --file-io or pbrain, Extended Brainfuck, Brainfork or multi-tape programs");
+    }
+    let settings = args.settings();
+    if settings.needs_vm() {
+        if args.pgo || args.verify || args.verify_tape || args.crash_dump.is_some() {
+            anyhow::bail!("--pgo, --verify and --crash-dump can't be used with --compat, --eof or --tape-edge");
+        }
+        if !matches!(
+            args.backend_name(),
+            "vm" | "threaded" | "blocks" | "tail-call"
+        ) {
+            anyhow::bail!("--compat, --eof and --tape-edge need the interpreter, without the reference engine");
+        }
+    }
+    if args.strict_bounds
+        && !matches!(
+            args.backend_name(),
+            "vm" | "threaded" | "blocks" | "tail-call"
+        )
+    {
+        anyhow::bail!("--strict-bounds needs the interpreter, without the reference engine");
+    }
+    #[cfg(feature = "tiered")]
+    let tiered = args.tiered;
+    #[cfg(not(feature = "tiered"))]
+    let tiered = false;
+    if settings.tape_edge == vm::TapeEdge::Grow && (args.quicken || tiered) {
+        anyhow::bail!("--quicken and --tiered can't be used with a growing tape");
+    }
+    if args.file_io.is_some() && args.dialect == Some(Dialect::Extended1) {
+        anyhow::bail!(
+            "--file-io can't be used with Extended Brainfuck, its `^` and `!` are other commands"
+        );
+    }
+
+    if args.dump_tokens {
+        return dump_tokens(content, args.lex(content));
+    }
+
+    if args.dump_ast {
+        let tokens = args.lex(content);
+        let spans = parser::spans(&tokens);
+        let ir = ProgramIr::from_program_with_spans(&parser::parse(tokens)?, &spans)?;
+
+        return Ok(io::stdout().write_all(ir::dump(&ir.body).as_bytes())?);
+    }
+
+    if args.dump_bytecode {
+        let listing = asm::listing(&args.compile(content)?.0);
+        return Ok(io::stdout().write_all(listing.as_bytes())?);
+    }
+
+    if args.pgo {
+        return run_pgo(content, &mut args.input()?, settings.tape_size);
+    }
+
+    // lli runs the program on its own, with this process' stdin and stdout.
+    #[cfg(feature = "llvm")]
+    if let Backend::Llvm = args.backend {
+        if args.input.is_some() {
+            anyhow::bail!("--input can't be used with --backend=llvm");
+        }
+        if args.verify || args.verify_tape {
+            anyhow::bail!("--verify can't be used with --backend=llvm");
+        }
+
+        let program = args.compile(content)?.0;
+        return emit::llvm::run(&emit::llvm::emit(&program, settings.tape_size)?);
+    }
+
+    let mut vm = Vm::from_program(vec![])?;
+    settings.apply(&mut vm)?;
+    vm.set_seed(args.seed());
+    if let Some(Some(cells)) = args.hash_dump {
+        vm.set_dump_cells(cells);
+    }
+    if let Some(dir) = &args.file_io {
+        vm.enable_file_io(FileIo::new(dir, args.file_io_read_only)?);
+    }
+    if args.dialect == Some(Dialect::MultiTape) {
+        if args.tapes == 0 {
+            anyhow::bail!("--tapes needs at least one tape");
+        }
+        if args.persist_tape.is_some() {
+            anyhow::bail!(
+                "--persist-tape keeps a single tape, it can't be used with multi-tape programs"
+            );
+        }
+        vm.set_tapes(args.tapes);
+    }
+
+    if args.quicken {
+        vm.enable_quickening();
+    }
+    if args.strict_bounds {
+        vm.enable_strict_bounds();
+    }
+
+    #[cfg(feature = "tiered")]
+    if args.tiered {
+        vm.enable_tiering(args.jit_threshold);
+    }
+
+    if args.dialect == Some(Dialect::Brainfork) {
+        return run_forked(args, vm, args.compile(content)?);
+    }
+
+    if let Some(limit) = args.trace {
+        let out: Box<dyn Write> = match &args.trace_file {
+            Some(path) => Box::new(io::BufWriter::new(fs::File::create(path)?)),
+            None => Box::new(io::stderr().lock()),
+        };
+        let format = match args.trace_binary {
+            true => TraceFormat::Binary,
+            false => TraceFormat::Text,
+        };
+
+        let compiled = args.compile(content)?;
+        return run_observed(args, vm, compiled, &mut Trace::new(out, format, limit));
+    }
+
+    if args.profile {
+        let (program, map) = args.compile(content)?;
+        let mut profiler = Profiler::new();
+        let result = run_observed(args, vm, (program.clone(), map), &mut profiler);
+        eprint!("{}", profiler.report(&program));
+
+        return result;
+    }
+
+    if args.heatmap || args.heatmap_csv.is_some() {
+        let mut heatmap = Heatmap::new();
+        let result = run_observed(args, vm, args.compile(content)?, &mut heatmap);
+
+        if args.heatmap {
+            eprint!("{}", heatmap.text());
+        }
+        if let Some(path) = &args.heatmap_csv {
+            fs::write(path, heatmap.csv())?;
+        }
+
+        return result;
+    }
+
+    if args.coverage || args.coverage_lcov.is_some() {
+        let tokens = args.lex(content);
+        let spans = parser::spans(&tokens);
+
+        let mut coverage = Coverage::new();
+        let program = parser::parse(tokens)?;
+        let map = SourceMap::from_spans(&spans);
+        let result = run_observed(args, vm, (program, map), &mut coverage);
+
+        if args.coverage {
+            eprint!("{}", coverage.annotate(content, &spans));
+        }
+        if let Some(path) = &args.coverage_lcov {
+            fs::write(path, coverage.lcov(source, &spans))?;
+        }
+
+        return result;
+    }
+
+    if let Some(dir) = &args.frames {
+        let format = match args.frames_csv {
+            true => FrameFormat::Csv,
+            false => FrameFormat::Pgm,
+        };
+
+        let mut frames = Frames::new(dir, format, args.frame_every, args.frame_cells)?;
+        return run_observed(args, vm, args.compile(content)?, &mut frames);
+    }
+
+    if let Some(top) = args.profile_loops {
+        let tokens = args.lex(content);
+        let spans = parser::spans(&tokens);
+        let program = parser::parse(tokens)?;
+
+        let mut profiler = LoopProfiler::new(&program);
+        let map = SourceMap::from_spans(&spans);
+        let result = run_observed(args, vm, (program, map), &mut profiler);
+        eprint!("{}", profiler.report(&spans, top.unwrap_or(10)));
+
+        return result;
+    }
+
+    let name = args.backend_name();
+    bf::debug!("backend {}", name);
+    let mut backend = backend::with_vm(name, vm)?;
+
+    match Cache::default_dir() {
+        _ if vm_only => backend.load(args.compile(content)?.0)?,
+        // The reference interpreter runs the program unoptimized, it never goes through the cache.
+        _ if name == "reference" => backend.compile(content)?,
+        Some(dir) if !args.no_cache => {
+            backend.load(Cache::new(dir).compile(content, &args.passes())?)?
+        }
+        _ => backend.load(vm::compile_with_passes(content, &args.passes())?)?,
+    }
+
+    if args.verify || args.verify_tape {
+        // Both runs must see the same input, read up front when the program needs it.
+        let mut input = vec![];
+        if lexer::parse(content)
+            .iter()
+            .any(|&(token, _)| token == Token::Comma)
+        {
+            args.input()?.read_to_end(&mut input)?;
+        }
+
+        let output = &mut io::stdout().lock();
+        return backend::verify(
+            name,
+            &mut *backend,
+            content,
+            &input,
+            output,
+            args.verify_tape,
+        );
+    }
+
+    let mut read = vec![];
+    let mut input: Box<dyn Read + '_> = args.input()?;
+    if args.crash_dump.is_some() {
+        input = Box::new(RecordInput {
+            inner: input,
+            out: &mut read,
+        });
+    }
+    let output = &mut PendingOutput::new(args.output());
+    let result = run_loaded(args, &mut *backend, name, &mut input, output)
+        .and_then(|()| Ok(output.flush()?));
+    drop(input);
+
+    if let Some(range) = &args.dump_mem {
+        output.flush()?;
+        eprint!("{}", dump_mem(backend.tape(), backend.ptr(), range.clone()));
+    }
+
+    if let (Err(err), Some(dir)) = (&result, &args.crash_dump) {
+        let dump = CrashDump::capture(source, settings.tape_size, &read, output.pending(), err)?;
+        let path = dump.write(Path::new(dir))?;
+        eprintln!("note: crash dump written to {}", path.display());
+    }
+
+    // Only compiled again when it failed, the program loaded may come from the cache. The reference
+    // interpreter ran it unoptimized.
+    let compile = || match name {
+        "reference" if !args.no_debug_info => vm::compile_tokens_with_map(lexer::parse(content)),
+        _ => args.compile(content),
+    };
+    result.map_err(|err| match backend.pc() {
+        Some(pc) => match compile() {
+            Ok((program, map)) => vm::locate_error(err, &program, &map, pc),
+            Err(_) => err,
+        },
+        None => err,
+    })
+}
+
+/// Runs `program` on `vm` one instruction at a time, for `--trace` and the profiles. `map` locates
+/// the instruction the run fails at.
+fn run_observed(
+    args: &RunArgs,
+    mut vm: Vm,
+    (program, map): (Program, SourceMap),
+    observer: &mut dyn Observer,
+) -> anyhow::Result<()> {
+    if args.backend_name() != "vm" || args.verify || args.verify_tape {
+        anyhow::bail!("--trace and the profiles run on the VM, they can't be used with another backend or --verify");
+    }
+    vm.load(program)?;
 
-#[derive(Debug, Parser)]
-#[clap(author, version, about)]
-pub struct Args {
-    file: String,
+    let (input, output) = (&mut args.input()?, &mut io::stdout().lock());
+    let result = vm.run_observed(input, output, observer);
+    output.flush()?;
+
+    if let Some(range) = &args.dump_mem {
+        eprint!("{}", dump_mem(vm.mem(), vm.mem_ptr(), range.clone()));
+    }
+
+    result.map_err(|err| vm::locate_error(err, vm.program(), &map, vm.pc()))
+}
+
+/// Runs a Brainfork program, its threads taking turns on the VM, see `brainfork`.
+fn run_forked(
+    args: &RunArgs,
+    mut vm: Vm,
+    (program, map): (Program, SourceMap),
+) -> anyhow::Result<()> {
+    let single_thread = args.trace.is_some()
+        || args.profile
+        || args.profile_loops.is_some()
+        || args.heatmap
+        || args.heatmap_csv.is_some()
+        || args.coverage
+        || args.coverage_lcov.is_some()
+        || args.frames.is_some()
+        || args.dump_mem.is_some()
+        || args.time
+        || args.stats_json.is_some()
+        || args.persist_tape.is_some();
+    if args.backend_name() != "vm" || single_thread {
+        anyhow::bail!("Brainfork programs run on the VM, without --trace, the profiles, --frames, --dump-mem, --time, --stats-json or --persist-tape");
+    }
+    vm.load(program)?;
+
+    let mut scheduler = Scheduler::new(vm);
+    let (input, output) = (&mut args.input()?, &mut io::stdout().lock());
+    let result = scheduler.run(input, output);
+    output.flush()?;
+
+    result.map_err(|err| match scheduler.current() {
+        Some(vm) => vm::locate_error(err, vm.program(), &map, vm.pc()),
+        None => err,
+    })
+}
+
+/// Runs the program loaded in `backend` on the tape of --persist-tape, written back however the run
+/// ends.
+fn run_loaded(
+    args: &RunArgs,
+    backend: &mut dyn backend::ExecutionBackend,
+    name: &str,
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+) -> anyhow::Result<()> {
+    let Some(path) = &args.persist_tape else {
+        return run_counted(args, backend, name, input, output);
+    };
+
+    backend.load_tape(&read_tape(path)?)?;
+    let result = run_counted(args, backend, name, input, output);
+    persist(result, path, backend.tape())
+}
+
+/// Runs the program loaded in `backend`, for --time and --stats-json with the statistics of the
+/// run.
+fn run_counted(
+    args: &RunArgs,
+    backend: &mut dyn backend::ExecutionBackend,
+    name: &str,
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+) -> anyhow::Result<()> {
+    if !args.time && args.stats_json.is_none() {
+        return backend.run(input, output);
+    }
+
+    let mut input = Counted {
+        inner: input,
+        bytes: 0,
+    };
+    let mut output = Counted {
+        inner: output,
+        bytes: 0,
+    };
+    let start = Instant::now();
+    let stats = backend.run_stats(&mut input, &mut output);
+    let elapsed = start.elapsed();
+    output.flush()?;
+
+    if let Some(path) = &args.stats_json {
+        let json = Json::object([
+            ("backend", name.into()),
+            ("ok", stats.is_ok().into()),
+            (
+                "error",
+                stats
+                    .as_ref()
+                    .err()
+                    .map_or(Json::Null, |err| err.to_string().into()),
+            ),
+            ("stages", stages_json(elapsed)),
+            ("input_bytes", Json::Number(input.bytes as f64)),
+            ("output_bytes", Json::Number(output.bytes as f64)),
+            (
+                "run",
+                run_stats_json(stats.as_ref().ok().and_then(Option::as_ref)),
+            ),
+        ]);
+        fs::write(path, format!("{}\n", json))?;
+    }
+
+    if args.time {
+        print_time(elapsed, &stats, name);
+    }
+
+    stats.map(|_| ())
+}
+
+/// The cells of a tape file, none if there is no such file yet.
+fn read_tape(path: &str) -> anyhow::Result<Vec<u8>> {
+    match fs::read(path) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(vec![]),
+        cells => Ok(cells.map_err(|err| anyhow::anyhow!("{}: {}", path, err))?),
+    }
+}
+
+/// Writes `tape` to `path` after a run ending with `result`, up to its last cell that isn't zero. The
+/// file is replaced at once, a failure halfway leaves the one before.
+fn persist(result: anyhow::Result<()>, path: &str, tape: &[u8]) -> anyhow::Result<()> {
+    let used = tape
+        .iter()
+        .rposition(|&cell| cell != 0)
+        .map_or(0, |i| i + 1);
+    let written = (|| -> io::Result<()> {
+        let path = Path::new(path);
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+
+        let mut file = fs::File::create(&temp)?;
+        file.write_all(&tape[..used])?;
+        file.sync_all()?;
+        fs::rename(&temp, path)
+    })()
+    .map_err(|err| anyhow::anyhow!("couldn't write the tape to {}: {}", path, err));
+
+    // The error of the program goes first, the one writing the tape is only printed then.
+    match (result, written) {
+        (Err(err), Err(written)) => {
+            eprintln!("error: {}", written);
+            Err(err)
+        }
+        (result, written) => result.and(written),
+    }
+}
+
+/// The stage timings kept, then the run taking `elapsed`.
+fn stages_json(elapsed: Duration) -> Json {
+    let stage = |name: &str, elapsed: Duration| {
+        Json::object([
+            ("name", name.into()),
+            ("seconds", elapsed.as_secs_f64().into()),
+        ])
+    };
+
+    let mut stages: Vec<Json> = log::take_stages()
+        .iter()
+        .map(|(name, elapsed)| stage(name, *elapsed))
+        .collect();
+    stages.push(stage("run", elapsed));
+
+    stages.into()
+}
+
+/// What the backend counted of the run, null if it doesn't count.
+fn run_stats_json(stats: Option<&vm::Stats>) -> Json {
+    let Some(stats) = stats else {
+        return Json::Null;
+    };
+
+    let by_type = stats
+        .by_type
+        .iter()
+        .map(|&(ty, count)| (ty.mnemonic(), Json::Number(count as f64)));
+    let loops = stats.loops.iter().map(|count| {
+        Json::object([
+            ("pc", count.pc.into()),
+            ("reached", Json::Number(count.reached as f64)),
+            ("iterations", Json::Number(count.iterations as f64)),
+        ])
+    });
+
+    Json::object([
+        ("instructions", Json::Number(stats.instructions as f64)),
+        ("by_type", Json::object(by_type)),
+        ("loops", loops.collect::<Vec<_>>().into()),
+        ("peak_pointer", stats.peak_ptr.into()),
+    ])
+}
+
+/// Counts the bytes read or written through it.
+struct Counted<T> {
+    inner: T,
+    bytes: u64,
+}
+
+impl<T: Read> Read for Counted<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.bytes += read as u64;
+        Ok(read)
+    }
+}
+
+impl<T: Write> Write for Counted<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.bytes += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Prints the run time and what the backend counted.
+fn print_time(elapsed: Duration, stats: &anyhow::Result<Option<vm::Stats>>, name: &str) {
+    eprintln!("time          {:.3?}", elapsed);
+    match stats {
+        Ok(Some(stats)) => {
+            let per_second = stats.instructions as f64 / elapsed.as_secs_f64();
+            eprintln!(
+                "instructions  {} ({:.1}M/s)",
+                stats.instructions,
+                per_second / 1e6
+            );
+            eprintln!("peak pointer  {}", stats.peak_ptr);
+        }
+        Ok(None) => eprintln!("instructions  not counted by the {} backend", name),
+        Err(_) => {}
+    }
+}
+
+/// `range` defaults to the cells from the first to the last one not 0, or to the pointer if that is
+/// further.
+fn dump_mem(tape: &[u8], ptr: usize, range: Option<Range<usize>>) -> String {
+    let range = range.unwrap_or_else(|| {
+        let used = tape
+            .iter()
+            .rposition(|&cell| cell != 0)
+            .map_or(0, |i| i + 1);
+        0..used.max(ptr + 1)
+    });
+    let end = range.end.min(tape.len());
+    let start = range.start.min(end);
+
+    format!(
+        "pointer at cell {}\n{}",
+        ptr,
+        hexdump::hexdump(&tape[start..end], start)
+    )
+}
+
+/// `tokens` are the ones of `src`.
+fn dump_tokens(src: &str, tokens: TokenList) -> anyhow::Result<()> {
+    let mut out = io::stdout().lock();
+    for (token, loc) in tokens {
+        writeln!(
+            out,
+            "{:<8} {}  {:?}",
+            loc.to_string(),
+            token.to_char(),
+            token
+        )?;
+    }
+
+    if !log::enabled(log::Level::Normal) {
+        return Ok(());
+    }
+
+    if src.starts_with('\u{feff}') {
+        eprintln!(
+            "note: the file starts with a byte order mark, columns on line 1 count its 3 bytes"
+        );
+    }
+
+    for (ch, token, loc) in lexer::lookalikes(src) {
+        eprintln!(
+            "note: {} {:?} (U+{:04X}) looks like `{}` but is a comment",
+            loc,
+            ch,
+            ch as u32,
+            token.to_char()
+        );
+    }
+
+    Ok(())
+}
 
-    #[clap(default_value_t = DEFAULT_VM_MEM_SIZE)]
+fn compile_file(
+    path: &str,
+    emit: Option<Emit>,
+    output: Option<&str>,
     tape_size: usize,
+    debug_info: bool,
+) -> anyhow::Result<()> {
+    let mut source = Source::default();
+    source.push(path, &fs::read_to_string(path)?);
+    let (program, map) =
+        vm::compile_with_map(&source.text).map_err(|err| source.locate_error(err))?;
+
+    let bytes = match emit.unwrap_or_else(|| Emit::from_path(output)) {
+        Emit::C => emit::c::emit(&program, tape_size)?.into_bytes(),
+        Emit::Js => emit::js::emit(&program, tape_size)?.into_bytes(),
+        Emit::Wasm => emit::wasm::emit(&program, tape_size)?,
+        Emit::Bytecode => bytecode::encode_with_map(&program, debug_info.then_some(&map)),
+        Emit::Bf => emit::bf::emit(&program)?.into_bytes(),
+        #[cfg(feature = "llvm")]
+        Emit::Llvm => emit::llvm::emit(&program, tape_size)?.into_bytes(),
+        #[cfg(feature = "llvm")]
+        Emit::Obj => {
+            let Some(output) = output else {
+                anyhow::bail!("object files need an output path, pass -o");
+            };
+
+            let ir = emit::llvm::emit(&program, tape_size)?;
+            return emit::llvm::compile_object(&ir, Path::new(output));
+        }
+        #[cfg(not(feature = "llvm"))]
+        Emit::Llvm | Emit::Obj => anyhow::bail!("bf was built without the llvm feature"),
+    };
+
+    match output {
+        Some(path) => fs::write(path, bytes)?,
+        None => io::stdout().write_all(&bytes)?,
+    }
+
+    Ok(())
+}
+
+fn exec_file(path: &str) -> anyhow::Result<()> {
+    let (program, map) = bytecode::decode_with_map(&fs::read(path)?)?;
+
+    let mut vm = Vm::from_program(program)?;
+    let result = vm.run_with(&mut io::stdin().lock(), &mut io::stdout().lock());
+
+    // Files compiled with -g know where in the source the run failed, not which source.
+    match map {
+        Some(map) => result.map_err(|err| vm::locate_error(err, vm.program(), &map, vm.pc())),
+        None => result,
+    }
+}
+
+fn disasm_file(path: &str) -> anyhow::Result<()> {
+    let bytes = fs::read(path)?;
+
+    let (program, map) = if bytes.starts_with(bytecode::MAGIC) {
+        bytecode::decode_with_map(&bytes)?
+    } else {
+        (vm::compile(std::str::from_utf8(&bytes)?)?, None)
+    };
+
+    let mut listing = asm::disassemble(&program);
+    // Files compiled with -g say where each opcode comes from, as a comment so it still assembles.
+    if let Some(map) = map {
+        listing = listing
+            .lines()
+            .enumerate()
+            .map(|(pc, line)| match map.get(pc) {
+                Some(loc) => format!("{:<24}; {}\n", line, loc),
+                None => format!("{}\n", line),
+            })
+            .collect();
+    }
+
+    io::stdout().write_all(listing.as_bytes())?;
+
+    Ok(())
+}
+
+fn asm_file(path: &str, output: Option<&str>) -> anyhow::Result<()> {
+    let program = asm::assemble(&fs::read_to_string(path)?)?;
+
+    if let Some(output) = output {
+        return Ok(fs::write(output, bytecode::encode(&program))?);
+    }
+
+    let mut vm = Vm::from_program(program)?;
+    vm.run_with(&mut io::stdin().lock(), &mut io::stdout().lock())
+}
+
+fn build_file(path: &str, output: Option<&str>) -> anyhow::Result<()> {
+    let program = vm::compile(&fs::read_to_string(path)?)?;
+
+    let output = match output {
+        Some(output) => Path::new(output).to_owned(),
+        None => Path::new(path).with_extension(env::consts::EXE_EXTENSION),
+    };
+
+    if output == Path::new(path) {
+        anyhow::bail!("refusing to overwrite {}, pass -o", path);
+    }
+
+    bundle::write_executable(&env::current_exe()?, &program, &output)
+}
+
+/// Returns whether every file parsed.
+fn check_files(paths: &[String], quiet: bool, format: Format) -> anyhow::Result<bool> {
+    let check = |path: &str| -> anyhow::Result<()> {
+        parser::parse(lexer::parse(&fs::read_to_string(path)?))?;
+        Ok(())
+    };
+
+    let results: Vec<_> = paths.iter().map(|path| (path, check(path))).collect();
+    let ok = results.iter().all(|(_, result)| result.is_ok());
+
+    if quiet {
+        return Ok(ok);
+    }
+
+    match format {
+        Format::Text => {
+            for (path, result) in &results {
+                match result {
+                    Ok(()) => println!("{}: ok", path),
+                    Err(err) => eprintln!("{}: error: {}", path, err),
+                }
+            }
+        }
+        Format::Json => {
+            let entries: Vec<_> = results
+                .iter()
+                .map(|(path, result)| match result {
+                    Ok(()) => format!("{{\"file\":{},\"ok\":true}}", json_string(path)),
+                    Err(err) => {
+                        let mut diagnostic = Diagnostic::from_error(err);
+                        diagnostic.file.get_or_insert_with(|| path.to_string());
+
+                        format!(
+                            "{{\"file\":{},\"ok\":false,\"diagnostics\":[{}]}}",
+                            json_string(path),
+                            diagnostic.to_json()
+                        )
+                    }
+                })
+                .collect();
+            println!("[{}]", entries.join(","));
+        }
+    }
+
+    Ok(ok)
+}
+
+/// Returns the exit code: 0 without warnings, 1 with some, `EXIT_SYNTAX` if a program didn't parse.
+fn lint_files(paths: &[String], format: Format) -> anyhow::Result<i32> {
+    let mut diagnostics = vec![];
+    let mut code = 0;
+
+    for path in paths {
+        let src = fs::read_to_string(path)?;
+        match lint::lint(&src) {
+            Ok(warnings) => {
+                for warning in &warnings {
+                    let snippet = src.split('\n').nth(warning.loc.line() - 1);
+                    diagnostics.push(Diagnostic::from_warning(warning, Some(path), snippet));
+                }
+                if !warnings.is_empty() {
+                    code = code.max(1);
+                }
+            }
+            Err(err) => {
+                let mut source = Source::default();
+                source.push(path, &src);
+                diagnostics.push(Diagnostic::from_error(&source.locate_error(err)));
+                code = EXIT_SYNTAX;
+            }
+        }
+    }
+
+    match format {
+        Format::Text => {
+            let color = io::stdout().is_terminal() && env::var_os("NO_COLOR").is_none();
+            for diagnostic in &diagnostics {
+                println!("{}", diagnostic.render(color));
+            }
+            let warnings = diagnostics
+                .iter()
+                .filter(|diagnostic| diagnostic.severity == Severity::Warning)
+                .count();
+            if warnings > 0 {
+                println!(
+                    "{} warning{}",
+                    warnings,
+                    if warnings == 1 { "" } else { "s" }
+                );
+            }
+        }
+        Format::Json => {
+            let entries: Vec<_> = diagnostics.iter().map(Diagnostic::to_json).collect();
+            println!("[{}]", entries.join(","));
+        }
+    }
+
+    Ok(code)
+}
+
+/// Returns whether the programs did the same on every input.
+fn equiv_files(
+    a: &str,
+    b: &str,
+    dir: Option<&str>,
+    runs: usize,
+    seed: u64,
+    options: &equiv::Options,
+) -> anyhow::Result<bool> {
+    let inputs: Vec<(String, Vec<u8>)> = match dir {
+        Some(dir) => {
+            let mut paths = fs::read_dir(dir)?
+                .map(|entry| Ok(entry?.path()))
+                .collect::<io::Result<Vec<_>>>()?;
+            paths.retain(|path| path.is_file());
+            paths.sort();
+            paths
+                .into_iter()
+                .map(|path| Ok((path.display().to_string(), fs::read(&path)?)))
+                .collect::<io::Result<_>>()?
+        }
+        None => equiv::generate_inputs(seed, runs)
+            .into_iter()
+            .enumerate()
+            .map(|(i, input)| (format!("#{}", i), input))
+            .collect(),
+    };
+
+    let parse = |path: &str| {
+        let mut source = Source::default();
+        source.push(path, &fs::read_to_string(path)?);
+        parser::parse(lexer::parse(&source.text)).map_err(|err| source.locate_error(err))
+    };
+    let summary = equiv::check(parse(a)?, parse(b)?, &inputs, options)?;
+
+    if let Some((name, input, divergence)) = &summary.divergence {
+        println!(
+            "differ on input {} {:?}: {}",
+            name,
+            String::from_utf8_lossy(&input[..input.len().min(64)]),
+            divergence
+        );
+        return Ok(false);
+    }
+
+    print!("the same on {} inputs", summary.compared);
+    if summary.left > 0 {
+        print!(", {} left at the step limit", summary.left);
+    }
+    println!();
+
+    Ok(true)
+}
+
+/// Returns whether every test passed.
+fn test_dir(dir: &Path) -> anyhow::Result<bool> {
+    let cases = golden::run_dir(dir)?;
+    let (mut passed, mut failed, mut skipped) = (0, 0, 0);
+
+    for case in &cases {
+        let name = case.path.file_name().unwrap_or_default().to_string_lossy();
+        match &case.outcome {
+            Outcome::Pass => {
+                passed += 1;
+                println!("PASS {}", name);
+            }
+            Outcome::Fail(why) => {
+                failed += 1;
+                println!("FAIL {}", name);
+                for line in why.lines() {
+                    println!("    {}", line);
+                }
+            }
+            Outcome::Skip => {
+                skipped += 1;
+                println!("SKIP {} (no .out file)", name);
+            }
+        }
+    }
+
+    println!("{} passed, {} failed, {} skipped", passed, failed, skipped);
+
+    Ok(failed == 0)
+}
+
+/// Whether every program passed or ran.
+/// Prints the programs with findings as they are found, then how many programs ran. False if there
+/// were findings.
+fn run_fuzz(options: &fuzz::Options) -> bool {
+    let summary = fuzz::run(options, |src, findings| {
+        println!("FOUND {}", src);
+        for (backend, finding) in findings {
+            println!("    {}: {}", backend, finding);
+        }
+    });
+
+    println!(
+        "{} programs, {} compared on every backend, {} left after running too long, {} with findings",
+        summary.programs,
+        summary.compared,
+        summary.left,
+        summary.failures.len()
+    );
+
+    summary.failures.is_empty()
+}
+
+fn serve_http(addr: &str, options: &serve::Options) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    eprintln!(
+        "listening on http://{}, {} requests at once",
+        listener.local_addr()?,
+        options.threads
+    );
+
+    serve::serve(&listener, options)
+}
+
+#[cfg(unix)]
+fn run_daemon(socket: Option<&str>, options: &daemon::Options) -> anyhow::Result<()> {
+    let path = socket.map_or_else(daemon::default_socket, PathBuf::from);
+    let listener = daemon::listen(&path)?;
+    eprintln!(
+        "listening on {}, {} requests at once",
+        path.display(),
+        options.threads
+    );
+
+    daemon::serve(&listener, &path, options)
+}
+
+#[cfg(unix)]
+fn client(socket: Option<&str>, command: &ClientCommand) -> anyhow::Result<()> {
+    let socket = socket.map_or_else(daemon::default_socket, PathBuf::from);
+    let ClientCommand::Run {
+        file,
+        input,
+        tape_size,
+        eof,
+    } = command
+    else {
+        return daemon::stop(&socket);
+    };
+
+    let mut bytes = vec![];
+    match input {
+        Some(path) => bytes = fs::read(path)?,
+        None => _ = io::stdin().read_to_end(&mut bytes)?,
+    }
+    let request = daemon::Request {
+        program: fs::read_to_string(file)?,
+        input: bytes,
+        tape_size: *tape_size,
+        eof: (*eof).into(),
+    };
+    let reply = daemon::send(&socket, &request)?;
+
+    let stdout = &mut io::stdout().lock();
+    stdout.write_all(&reply.output)?;
+    stdout.flush()?;
+    match reply.error {
+        Some(failed) => Err(failed.into()),
+        None => Ok(()),
+    }
+}
+
+fn run_batch(
+    files: &[String],
+    jobs: Option<usize>,
+    limits: batch::Limits,
+    output_dir: Option<&str>,
+) -> anyhow::Result<bool> {
+    let mut programs = vec![];
+    for file in files {
+        let path = Path::new(file);
+        match path.extension().is_some_and(|ext| ext == "toml") {
+            true => programs.extend(batch::read_manifest(path, limits)?),
+            false => programs.push(batch::Job::from_file(path, limits)),
+        }
+    }
+
+    let threads = jobs
+        .or_else(|| thread::available_parallelism().ok().map(Into::into))
+        .unwrap_or(1);
+    let reports = batch::run(&programs, threads);
+
+    if let Some(dir) = output_dir {
+        fs::create_dir_all(dir)?;
+        for report in &reports {
+            let name = report.file.file_name().unwrap_or_default();
+            fs::write(
+                Path::new(dir).join(name).with_extension("out"),
+                &report.output,
+            )?;
+        }
+    }
+
+    let names: Vec<_> = reports
+        .iter()
+        .map(|report| report.file.display().to_string())
+        .collect();
+    let width = names.iter().map(String::len).max().unwrap_or(0).max(7);
+    println!(
+        "{:<width$} {:<12} {:>14} {:>10}",
+        "program",
+        "result",
+        "instructions",
+        "time",
+        width = width
+    );
+    for (name, report) in names.iter().zip(&reports) {
+        println!(
+            "{:<width$} {:<12} {:>14} {:>10}",
+            name,
+            report.outcome.to_string(),
+            report.steps,
+            format!("{:.3?}", report.time),
+            width = width
+        );
+    }
+
+    let (mut passed, mut ran, mut failed, mut limited) = (0, 0, 0, 0);
+    for (name, report) in names.iter().zip(&reports) {
+        match &report.outcome {
+            batch::Outcome::Pass => passed += 1,
+            batch::Outcome::Ran => ran += 1,
+            batch::Outcome::Fail(why) => {
+                failed += 1;
+                println!("\nFAIL {}", name);
+                for line in why.lines() {
+                    println!("    {}", line);
+                }
+            }
+            batch::Outcome::Limit(_) => limited += 1,
+        }
+    }
+    println!(
+        "\n{} passed, {} ran, {} failed, {} stopped at a limit",
+        passed, ran, failed, limited
+    );
+
+    Ok(failed + limited == 0)
+}
+
+fn fmt_file(path: Option<&str>, write: bool, options: &fmt::Options) -> anyhow::Result<()> {
+    let src = match path {
+        None | Some("-") => read_source(&mut io::stdin().lock())?,
+        Some(path) => fs::read_to_string(path)?,
+    };
+
+    let formatted = fmt::format(&src, options)?;
+
+    match path {
+        Some(path) if write => {
+            if formatted != src {
+                fs::write(path, formatted)?;
+            }
+            Ok(())
+        }
+        _ => Ok(io::stdout().write_all(formatted.as_bytes())?),
+    }
+}
+
+fn minify_file(path: Option<&str>, shrink: bool) -> anyhow::Result<()> {
+    let src = match path {
+        None | Some("-") => read_source(&mut io::stdin().lock())?,
+        Some(path) => fs::read_to_string(path)?,
+    };
+
+    println!("{}", bf::minify::minify(&src, shrink)?);
+
+    Ok(())
+}
+
+fn obfuscate_file(path: Option<&str>, seed: u64) -> anyhow::Result<()> {
+    let src = match path {
+        None | Some("-") => read_source(&mut io::stdin().lock())?,
+        Some(path) => fs::read_to_string(path)?,
+    };
+
+    print!("{}", bf::obfuscate::obfuscate(&src, seed)?);
+
+    Ok(())
+}
+
+fn generate(text: Option<&str>) -> anyhow::Result<()> {
+    let text = match text {
+        Some(text) => text.as_bytes().to_vec(),
+        None => {
+            let mut text = vec![];
+            io::stdin().lock().read_to_end(&mut text)?;
+            text
+        }
+    };
+
+    println!("{}", bf::generate::generate(&text));
+
+    Ok(())
+}
+
+fn stats_file(path: Option<&str>) -> anyhow::Result<()> {
+    let src = match path {
+        None | Some("-") => read_source(&mut io::stdin().lock())?,
+        Some(path) => fs::read_to_string(path)?,
+    };
+
+    let stats = stats::analyze(&src)?;
+    let commands: usize = stats.counts.iter().map(|(_, count)| count).sum();
+
+    println!("commands     {}", commands);
+    for (token, count) in stats.counts {
+        println!("  {}          {}", token.to_char(), count);
+    }
+    println!("loops        {}", stats.loops);
+    println!("max nesting  {}", stats.max_depth);
+    if let Some((token, len)) = stats.longest_run {
+        println!("longest run  {} `{}`", len, token.to_char());
+    }
+    match stats.tape_exact {
+        true => println!("tape         {} cells", stats.tape),
+        false => println!(
+            "tape         at least {} cells, a loop moves the pointer by data",
+            stats.tape
+        ),
+    }
+
+    Ok(())
+}
+
+fn debug_file(path: &str, input_path: Option<&str>, tui: bool) -> anyhow::Result<()> {
+    let mut debugger = Debugger::new(&fs::read_to_string(path)?)?;
+
+    if tui {
+        #[cfg(feature = "tui")]
+        return bf::tui::run(
+            debugger,
+            &input_path.map(fs::read).transpose()?.unwrap_or_default(),
+        );
+        #[cfg(not(feature = "tui"))]
+        anyhow::bail!("bf was built without the tui feature");
+    }
+
+    prompt(&mut debugger, input_path.map(fs::read).transpose()?)
+}
+
+/// `bf debug --core`, a prompt on the state of a crash dump.
+fn debug_core(path: &str) -> anyhow::Result<()> {
+    let dump = CrashDump::parse(&fs::read_to_string(path)?)?;
+    let mut debugger = Debugger::from_core(&dump)?;
+
+    println!("error: {}", dump.error());
+    // Nothing runs, the program gets no input.
+    prompt(&mut debugger, Some(vec![]))
+}
+
+/// Reads the debugger's commands from standard input until `quit` or the end.
+fn prompt(debugger: &mut Debugger, file_input: Option<Vec<u8>>) -> anyhow::Result<()> {
+    // Without an input file the program reads from the terminal too, between the commands.
+    let stdin = io::stdin();
+    let mut commands = stdin.lock();
+    let mut file_input = file_input.as_deref();
+
+    let (output, out) = (&mut io::stdout(), &mut io::stdout());
+    writeln!(out, "{}", debugger.location())?;
+
+    loop {
+        write!(out, "(bf) ")?;
+        out.flush()?;
+
+        let mut line = String::new();
+        if commands.read_line(&mut line)? == 0 {
+            writeln!(out)?;
+            return Ok(());
+        }
+
+        let go_on = match &mut file_input {
+            Some(input) => debugger.command(&line, input, output, out)?,
+            None => debugger.command(&line, &mut commands, output, out)?,
+        };
+        if !go_on {
+            return Ok(());
+        }
+    }
+}
+
+fn repl(path: Option<&str>, tape_size: usize) -> anyhow::Result<()> {
+    let mut repl = Repl::new(tape_size)?;
+    let mut lines = LineReader::new()?;
+    let out = &mut io::stdout();
+    let mut prompt = Prompt::Ready;
+
+    if let Some(path) = path {
+        let output = &mut PendingOutput::new(io::stdout());
+        // A program that fails still leaves a tape to look at.
+        if let Err(err) = repl.run(&fs::read_to_string(path)?, lines.input(), output) {
+            writeln!(out, "error: {}", err)?;
+        }
+        if !output.pending().is_empty() {
+            writeln!(out)?;
+        }
+    }
+
+    while prompt != Prompt::Quit {
+        let text = match prompt {
+            // As wide as the first prompt, with the loops still open.
+            Prompt::More(open) => format!("..{} ", open),
+            _ => "bf> ".to_string(),
+        };
+        let line = match lines.read(&text)? {
+            Typed::Line(line) => line,
+            Typed::Interrupt => {
+                repl.cancel();
+                prompt = Prompt::Ready;
+                continue;
+            }
+            Typed::End => {
+                writeln!(out)?;
+                break;
+            }
+        };
+
+        let output = &mut PendingOutput::new(io::stdout());
+        prompt = repl.line(&line, lines.input(), output, out)?;
+        // The prompt goes on a line of its own.
+        if !output.pending().is_empty() {
+            writeln!(out)?;
+        }
+    }
+
+    lines.save()
+}
+
+fn bench(
+    paths: &[String],
+    runs: usize,
+    backends: &[String],
+    unoptimized: bool,
+) -> anyhow::Result<()> {
+    let reads_input = |src: &str| -> anyhow::Result<bool> {
+        Ok(vm::compile(src)?
+            .iter()
+            .any(|op| op.ty == OpCodeType::InputChar))
+    };
+
+    let mut programs = vec![];
+    if paths.is_empty() {
+        for example in &examples::EXAMPLES {
+            if !reads_input(example.src)? {
+                programs.push((example.name.to_string(), example.src.to_string()));
+            }
+        }
+    }
+    for path in paths {
+        programs.push((path.clone(), fs::read_to_string(path)?));
+    }
+
+    for name in backends {
+        if !backend::BACKENDS.contains(&name.as_str()) {
+            anyhow::bail!(
+                "unknown backend `{}`, expected one of {:?}",
+                name,
+                backend::BACKENDS
+            );
+        }
+        if !backend::is_available(name) {
+            anyhow::bail!("the {} backend was not built in", name);
+        }
+    }
+    let backends: Vec<_> = backend::BACKENDS
+        .into_iter()
+        .filter(|name| backend::is_available(name))
+        .filter(|name| backends.is_empty() || backends.iter().any(|b| b == name))
+        .collect();
+
+    // Every run gets the same input, read up front.
+    let mut input = vec![];
+    for (_, src) in &programs {
+        if reads_input(src)? {
+            io::stdin().read_to_end(&mut input)?;
+            break;
+        }
+    }
+
+    let width = programs
+        .iter()
+        .map(|(name, _)| name.len())
+        .max()
+        .unwrap_or(0);
+    println!(
+        "{:<width$} {:<10} {:<4} {:>10} {:>14} {:>8}",
+        "program",
+        "backend",
+        "opt",
+        "best",
+        "instructions",
+        "speedup",
+        width = width.max(7)
+    );
+
+    for (name, src) in &programs {
+        let mut baseline = None;
+
+        for optimized in [true, false].into_iter().take(1 + unoptimized as usize) {
+            let program = match optimized {
+                true => vm::compile(src)?,
+                false => parser::parse(lexer::parse(src))?,
+            };
+
+            // Counted once on the VM, the other backends run the same bytecode.
+            let instructions = Vm::from_program(program.clone())?
+                .run_stats(&mut input.as_slice(), &mut io::sink())?
+                .instructions;
+
+            for &backend_name in &backends {
+                let mut backend = backend::by_name(backend_name)?;
+                backend.load(program.clone())?;
+
+                let mut best = Duration::MAX;
+                for _ in 0..runs.max(1) {
+                    backend.prepare();
+
+                    let start = Instant::now();
+                    backend.run(&mut input.as_slice(), &mut io::sink())?;
+                    best = best.min(start.elapsed());
+                }
+
+                let baseline = *baseline.get_or_insert(best);
+                println!(
+                    "{:<width$} {:<10} {:<4} {:>10} {:>14} {:>7.2}x",
+                    name,
+                    backend_name,
+                    if optimized { "on" } else { "off" },
+                    format!("{:.3?}", best),
+                    instructions,
+                    baseline.as_secs_f64() / best.as_secs_f64(),
+                    width = width.max(7)
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn examples(command: &ExamplesCommand) -> anyhow::Result<()> {
+    match command {
+        ExamplesCommand::List => {
+            for example in &examples::EXAMPLES {
+                println!("{:<12} {}", example.name, example.description);
+            }
+            Ok(())
+        }
+        ExamplesCommand::Run { name } => {
+            let Some(example) = examples::find(name) else {
+                anyhow::bail!("no example called `{}`, see `bf examples list`", name);
+            };
+
+            let mut vm = Vm::new(example.src)?;
+            vm.run_with(&mut io::stdin().lock(), &mut io::stdout().lock())
+        }
+    }
+}
+
+/// Runs the program bundled into this executable by `bf build`, if there is one.
+fn run_embedded() -> anyhow::Result<bool> {
+    let Some(program) = bundle::embedded_program(&env::current_exe()?)? else {
+        return Ok(false);
+    };
+
+    let mut vm = Vm::from_program(program)?;
+    vm.run_with(&mut io::stdin().lock(), &mut io::stdout().lock())?;
+
+    Ok(true)
+}
+
+fn run_pgo(src: &str, input: &mut dyn Read, tape_size: usize) -> anyhow::Result<()> {
+    let program = vm::compile(src)?;
+
+    // Both runs must see the same input, so it is read up front (only when the program needs it).
+    let input = {
+        let mut all = vec![];
+        if program.iter().any(|op| op.ty == OpCodeType::InputChar) {
+            input.read_to_end(&mut all)?;
+        }
+        all
+    };
+
+    let profile = Profile::record(&program, &mut input.as_slice(), &mut io::sink())?;
+    let program = pgo::optimize(&program, &profile)?;
+
+    let mut vm = Vm::from_program(program)?;
+    vm.set_tape_size(tape_size)?;
+
+    vm.run_with(&mut input.as_slice(), &mut io::stdout().lock())
+}
+
+fn exit_code(err: &anyhow::Error) -> i32 {
+    if err.is::<ParseError>() {
+        EXIT_SYNTAX
+    } else if err.is::<MemoryError>() {
+        EXIT_MEMORY
+    } else if err.is::<io::Error>() {
+        EXIT_IO
+    } else {
+        #[cfg(unix)]
+        if let Some(failed) = err.downcast_ref::<daemon::Failed>() {
+            return match failed.kind {
+                daemon::Kind::Syntax => EXIT_SYNTAX,
+                daemon::Kind::Memory => EXIT_MEMORY,
+                daemon::Kind::Io => EXIT_IO,
+                daemon::Kind::Other => 1,
+            };
+        }
+        1
+    }
+}
+
+/// The arguments of `argv`, exiting on usage errors.
+fn parse_args(argv: Vec<OsString>) -> Args {
+    Args::try_parse_from(argv).unwrap_or_else(|err| match err.use_stderr() {
+        true => {
+            let _ = err.print();
+            process::exit(EXIT_USAGE);
+        }
+        false => err.exit(),
+    })
+}
+
+/// `bf run` with the defaults of `config` and the directives of the program, before the flags of
+/// `argv` which `args` was parsed from, and in `project`: no file given is its first program and a
+/// name that isn't a file the program of that name.
+fn default_args(
+    args: &mut Args,
+    mut argv: Vec<OsString>,
+    project: Option<&project::Project>,
+) -> anyhow::Result<()> {
+    let at = match &args.command {
+        None => 1,
+        Some(Command::Run(_)) => 2 + argv[1..].iter().position(|arg| arg == "run").unwrap_or(0),
+        Some(_) => return Ok(()),
+    };
+
+    project_program(args, project)?;
+    let mut directives = vec![];
+    for file in &run_args(args).files {
+        if file != "-" && Path::new(file).is_file() {
+            directives.extend(pragma::read(Path::new(file))?);
+        }
+    }
+
+    let settings = config::settings(project, directives)?;
+    if settings.is_empty() {
+        return Ok(());
+    }
+    let command = Args::command();
+    let run = command
+        .find_subcommand("run")
+        .expect("there is a run command");
+    let mut flags = vec![];
+    for setting in settings {
+        let flag = &setting.flag;
+        if !run.get_arguments().any(|arg| arg.get_long() == Some(flag)) {
+            anyhow::bail!("{}: --{} isn't a flag of `bf run`", setting.from, flag);
+        }
+        flags.push(match setting.value {
+            Some(value) => OsString::from(format!("--{}={}", flag, value)),
+            None => OsString::from(format!("--{}", flag)),
+        });
+    }
+    argv.splice(at..at, flags);
+    *args = parse_args(argv);
+
+    project_program(args, project)
 }
 
-fn run_file(path: &str) -> anyhow::Result<()> {
-    let content = fs::read_to_string(&path)?;
+/// The arguments of `bf run`, given with the command or without it.
+fn run_args(args: &mut Args) -> &mut RunArgs {
+    match &mut args.command {
+        Some(Command::Run(run)) => run,
+        _ => &mut args.run,
+    }
+}
+
+/// The files of `bf run` in `project`: its first program if none is given, and the program of a
+/// name that isn't a file.
+fn project_program(args: &mut Args, project: Option<&project::Project>) -> anyhow::Result<()> {
+    let Some(project) = project else {
+        return Ok(());
+    };
+    let run = run_args(args);
+    if run.files.is_empty() && run.eval.is_none() {
+        let program = project
+            .program(None)
+            .ok_or_else(|| anyhow::anyhow!("{} lists no programs to run", project::FILE_NAME))?;
+        run.files.push(program.file.display().to_string());
+    }
+    for file in &mut run.files {
+        if let Some(program) = project
+            .program(Some(file))
+            .filter(|_| !Path::new(file).exists())
+        {
+            *file = program.file.display().to_string();
+        }
+    }
 
-    let mut vm = Vm::new(&content)?;
+    Ok(())
+}
 
-    Ok(vm.run()?)
+/// The files given, or the programs of `project` if there are none.
+fn project_files(
+    files: &[String],
+    project: Option<&project::Project>,
+) -> anyhow::Result<Vec<String>> {
+    match (files, project) {
+        ([], Some(project)) => Ok(project
+            .programs
+            .iter()
+            .map(|program| program.file.display().to_string())
+            .collect()),
+        ([], None) => anyhow::bail!("no files given and no {} here", project::FILE_NAME),
+        (files, _) => Ok(files.to_vec()),
+    }
 }
 
 fn main() {
-    let args = Args::parse();
+    match run_embedded() {
+        Ok(false) => {}
+        Ok(true) => return,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            process::exit(exit_code(&err));
+        }
+    }
 
-    let result = run_file(&args.file);
+    let argv: Vec<_> = env::args_os().collect();
+    let mut args = parse_args(argv.clone());
+    let project = project::find().and_then(|project| {
+        default_args(&mut args, argv, project.as_ref())?;
+        Ok(project)
+    });
+    let project = project.unwrap_or_else(|err| {
+        eprintln!("error: {}", err);
+        process::exit(exit_code(&err));
+    });
+    log::set_level(log::level_from_flags(args.quiet, args.verbose));
+    lexer::set_tab_width(args.tab_width);
+
+    let format = match &args.command {
+        Some(Command::Run(run)) => run.format,
+        Some(
+            Command::Compile { format, .. }
+            | Command::Check { format, .. }
+            | Command::Lint { format, .. },
+        ) => *format,
+        None => args.run.format,
+        _ => Format::Text,
+    };
+
+    let result = match &args.command {
+        Some(Command::Run(run)) => run_file(run),
+        Some(Command::Compile {
+            file,
+            emit,
+            output,
+            tape_size,
+            debug_info,
+            ..
+        }) => compile_file(file, *emit, output.as_deref(), *tape_size, *debug_info),
+        Some(Command::Exec { file }) => exec_file(file),
+        Some(Command::Disasm { file }) => disasm_file(file),
+        Some(Command::Asm { file, output }) => asm_file(file, output.as_deref()),
+        Some(Command::Build { file, output }) => build_file(file, output.as_deref()),
+        Some(Command::Check { files, format }) => project_files(files, project.as_ref())
+            .and_then(|files| check_files(&files, args.quiet, *format))
+            .map(|ok| {
+                if !ok {
+                    process::exit(EXIT_SYNTAX);
+                }
+            }),
+        Some(Command::Lint { files, format }) => lint_files(files, *format).map(|code| {
+            if code != 0 {
+                process::exit(code);
+            }
+        }),
+        Some(Command::Equiv {
+            a,
+            b,
+            inputs,
+            runs,
+            seed,
+            max_steps,
+            tape_size,
+            output_only,
+        }) => {
+            let options = equiv::Options {
+                max_steps: *max_steps,
+                tape_size: *tape_size,
+                output_only: *output_only,
+            };
+            equiv_files(a, b, inputs.as_deref(), *runs, *seed, &options).map(|same| {
+                if !same {
+                    process::exit(1);
+                }
+            })
+        }
+        Some(Command::Test { dir }) => match (
+            dir,
+            project.as_ref().and_then(|project| project.tests.as_ref()),
+        ) {
+            (Some(dir), _) => test_dir(Path::new(dir)),
+            (None, Some(dir)) => test_dir(dir),
+            (None, None) => Err(anyhow::anyhow!(
+                "no directory given and no tests in {} here",
+                project::FILE_NAME
+            )),
+        }
+        .map(|ok| {
+            if !ok {
+                process::exit(1);
+            }
+        }),
+        Some(Command::RunBatch {
+            files,
+            jobs,
+            max_steps,
+            timeout_ms,
+            max_output,
+            tape_size,
+            output_dir,
+        }) => {
+            let limits = batch::Limits {
+                max_steps: *max_steps,
+                timeout: timeout_ms.map(Duration::from_millis),
+                max_output: *max_output,
+                tape_size: *tape_size,
+            };
+            run_batch(files, *jobs, limits, output_dir.as_deref()).map(|ok| {
+                if !ok {
+                    process::exit(1);
+                }
+            })
+        }
+        Some(Command::Fuzz {
+            seed,
+            runs,
+            max_len,
+            max_steps,
+            tape_size,
+        }) => {
+            let options = fuzz::Options {
+                seed: *seed,
+                runs: *runs,
+                max_len: *max_len,
+                max_steps: *max_steps,
+                tape_size: *tape_size,
+            };
+            if !run_fuzz(&options) {
+                process::exit(1);
+            }
+            Ok(())
+        }
+        Some(Command::Fmt {
+            file,
+            write,
+            width,
+            strip_comments,
+        }) => {
+            let options = fmt::Options {
+                width: *width,
+                keep_comments: !strip_comments,
+            };
+            fmt_file(file.as_deref(), *write, &options)
+        }
+        Some(Command::Minify { file, shrink }) => minify_file(file.as_deref(), *shrink),
+        Some(Command::Generate { text }) => generate(text.as_deref()),
+        Some(Command::Obfuscate { file, seed }) => obfuscate_file(file.as_deref(), *seed),
+        Some(Command::Stats { file }) => stats_file(file.as_deref()),
+        Some(Command::Debug {
+            file,
+            core,
+            input,
+            tui,
+        }) => match (core, file) {
+            (Some(core), _) => debug_core(core),
+            (None, Some(file)) => debug_file(file, input.as_deref(), *tui),
+            (None, None) => unreachable!("clap asks for a file without --core"),
+        },
+        Some(Command::Completions { shell }) => {
+            // clap_complete panics when it fails to write, such as to a closed pipe.
+            let mut script = vec![];
+            clap_complete::generate(*shell, &mut Args::command(), "bf", &mut script);
+            io::stdout().write_all(&script).map_err(Into::into)
+        }
+        Some(Command::Dap) => dap::serve(&mut io::stdin().lock(), &mut io::stdout().lock()),
+        Some(Command::Lsp) => {
+            // Editors count a tab as one character.
+            lexer::set_tab_width(1);
+            lsp::serve(&mut io::stdin().lock(), &mut io::stdout().lock())
+        }
+        Some(Command::Serve {
+            addr,
+            jobs,
+            max_steps,
+            timeout_ms,
+            max_output,
+            tape_size,
+            max_body,
+            max_depth,
+        }) => {
+            let options = serve::Options {
+                limits: batch::Limits {
+                    max_steps: Some(*max_steps),
+                    timeout: Some(Duration::from_millis(*timeout_ms)),
+                    max_output: Some(*max_output),
+                    tape_size: *tape_size,
+                },
+                threads: jobs
+                    .or_else(|| thread::available_parallelism().ok().map(Into::into))
+                    .unwrap_or(1),
+                max_body: *max_body,
+                max_depth: *max_depth,
+            };
+            serve_http(addr, &options)
+        }
+        #[cfg(unix)]
+        Some(Command::Daemon {
+            socket,
+            jobs,
+            max_programs,
+            max_depth,
+            max_request,
+            max_tape_size,
+            max_steps,
+            timeout_ms,
+            max_output,
+        }) => {
+            let options = daemon::Options {
+                threads: jobs
+                    .or_else(|| thread::available_parallelism().ok().map(Into::into))
+                    .unwrap_or(1),
+                max_programs: *max_programs,
+                max_depth: *max_depth,
+                max_request: *max_request,
+                limits: batch::Limits {
+                    max_steps: *max_steps,
+                    timeout: Some(Duration::from_millis(*timeout_ms)),
+                    max_output: Some(*max_output),
+                    tape_size: *max_tape_size,
+                },
+            };
+            run_daemon(socket.as_deref(), &options)
+        }
+        #[cfg(unix)]
+        Some(Command::Client { socket, command }) => client(socket.as_deref(), command),
+        Some(Command::Repl { file, tape_size }) => repl(file.as_deref(), *tape_size),
+        Some(Command::Examples { command }) => examples(command),
+        Some(Command::Bench {
+            files,
+            runs,
+            backends,
+            unoptimized,
+        }) => bench(files, *runs, backends, *unoptimized),
+        None => run_file(&args.run),
+    };
 
     if let Err(err) = result {
-        eprintln!("error: {}", err);
+        match format {
+            Format::Text => {
+                let color = io::stderr().is_terminal() && env::var_os("NO_COLOR").is_none();
+                eprint!("{}", Diagnostic::from_error(&err).render(color));
+            }
+            Format::Json => eprintln!("{}", Diagnostic::from_error(&err).to_json()),
+        }
+        process::exit(exit_code(&err));
     }
 }
@@ -0,0 +1,268 @@
+/*
+ *  Minifier: the smallest source doing the same thing.
+ *  Stripping leaves only the commands. Shrinking also rewrites the loop tree: runs of `+-` and `<>` are
+ *  summed into their shortest form, and the value of every cell is followed as far as it is known.
+ *  Loops on a cell known to be 0 are dropped, adds a clear loop overwrites go, a clear of a known value
+ *  becomes the adds taking it to 0 when that is shorter and other clear loops become `[-]`.
+ *  `<` stops at the first cell: moves are only summed, and cells only followed, where the pointer is
+ *  known to be far enough right for that not to happen. Like the optimizer, summed pointer moves only
+ *  behave differently at the right edge of the tape.
+ */
+
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+
+use crate::{
+    ir::{self, Ir, ProgramIr, Step},
+    lexer,
+    optimizer::{self, is_clear_loop},
+    parser,
+};
+
+pub fn minify(src: &str, shrink: bool) -> Result<String> {
+    let program = parser::parse(lexer::parse(src))?;
+
+    if !shrink {
+        let src = &src[lexer::shebang_len(src.as_bytes())..];
+        return Ok(src.chars().filter(|ch| "+-<>[].,".contains(*ch)).collect());
+    }
+
+    let body = ProgramIr::from_program(&program)?.body;
+
+    // Blocks are left innermost first: the last one is the body of the program, which starts with
+    // every cell at 0.
+    let mut blocks = ir::walk(&body)
+        .filter(|step| matches!(step, Step::End { .. }))
+        .count()
+        + 1;
+    let body = optimizer::rebuild(body, &mut |_, _| None, &mut |block, bounds| {
+        blocks -= 1;
+        shrink_block(block, bounds, blocks == 0)
+    });
+
+    let mut out = String::new();
+    for step in ir::walk(&body) {
+        match step {
+            Step::Node(node) => emit_node(&mut out, node),
+            Step::End { .. } => out.push(']'),
+        }
+    }
+
+    Ok(out)
+}
+
+/// What is known of the cells around the pointer, as `shrink_block` goes through a block.
+struct Cells {
+    /// Where the pointer is, from where the block started.
+    at: isize,
+    /// The cells written since, `None` when what they were set to isn't known.
+    written: BTreeMap<isize, Option<u8>>,
+    /// Whether the other cells are still 0, as they are at the start of the program.
+    pristine: bool,
+}
+
+impl Cells {
+    fn new(pristine: bool) -> Self {
+        Self {
+            at: 0,
+            written: BTreeMap::new(),
+            pristine,
+        }
+    }
+
+    /// The value of the cell under the pointer, if known.
+    fn get(&self) -> Option<u8> {
+        match self.written.get(&self.at) {
+            Some(&value) => value,
+            None => self.pristine.then_some(0),
+        }
+    }
+
+    fn set(&mut self, value: Option<u8>) {
+        self.written.insert(self.at, value);
+    }
+
+    /// Forgets everything but the value of the cell under the pointer, after the pointer went
+    /// somewhere not known.
+    fn forget(&mut self, value: Option<u8>) {
+        *self = Self::new(false);
+        self.set(value);
+    }
+}
+
+/// `bounds` are the lowest cells the pointer can be at before each node of `block`, and `pristine`
+/// is true when nothing was written to memory before it, every cell is still 0.
+fn shrink_block(block: Vec<Ir>, bounds: &[usize], pristine: bool) -> Vec<Ir> {
+    let mut result: Vec<Ir> = Vec::with_capacity(block.len());
+    // The lowest the pointer can be before each node of `result`.
+    let mut starts = Vec::with_capacity(block.len());
+    let mut cells = Cells::new(pristine);
+
+    for (node, bound) in block.into_iter().zip(bounds.iter().copied()) {
+        match node {
+            Ir::Add { value, .. } => {
+                match result.last_mut() {
+                    Some(Ir::Add { value: last, .. }) => *last = last.wrapping_add(value),
+                    _ => {
+                        result.push(Ir::add(value));
+                        starts.push(bound);
+                    }
+                }
+                cells.set(cells.get().map(|cell| cell.wrapping_add(value)));
+            }
+            Ir::Shift { amount, .. } => {
+                match (result.last_mut(), starts.last()) {
+                    // Going left then right only adds up if the first cell doesn't stop the pointer.
+                    (Some(Ir::Shift { amount: last, .. }), Some(&start))
+                        if *last >= 0 || amount <= 0 || start >= last.unsigned_abs() =>
+                    {
+                        *last += amount
+                    }
+                    _ => {
+                        result.push(Ir::shift(amount));
+                        starts.push(bound);
+                    }
+                }
+
+                match amount < 0 && bound < amount.unsigned_abs() {
+                    true => cells.forget(None),
+                    false => cells.at += amount,
+                }
+            }
+            Ir::Input { .. } => {
+                result.push(node);
+                starts.push(bound);
+                cells.set(None);
+            }
+            Ir::Output { .. } => {
+                result.push(node);
+                starts.push(bound);
+            }
+            // Never entered.
+            Ir::Loop { .. } if cells.get() == Some(0) => {}
+            Ir::Loop {
+                offset, ref body, ..
+            } if is_clear_loop(offset, body) => {
+                // Whatever was added is cleared right after.
+                if let Some(&Ir::Add { value, .. }) = result.last() {
+                    result.pop();
+                    starts.pop();
+                    cells.set(cells.get().map(|cell| cell.wrapping_sub(value)));
+                }
+
+                match cells.get() {
+                    Some(0) => {}
+                    Some(value) if value.min(value.wrapping_neg()) < 3 => {
+                        result.push(Ir::add(value.wrapping_neg()));
+                        starts.push(bound);
+                    }
+                    _ => {
+                        result.push(Ir::new_loop(vec![Ir::add(255)]));
+                        starts.push(bound);
+                    }
+                }
+                cells.set(Some(0));
+            }
+            Ir::Loop { .. } => {
+                result.push(node);
+                starts.push(bound);
+                cells.forget(Some(0));
+            }
+            _ => unreachable!("the parser makes no other nodes"),
+        }
+
+        if matches!(
+            result.last(),
+            Some(Ir::Add { value: 0, .. } | Ir::Shift { amount: 0, .. })
+        ) {
+            result.pop();
+            starts.pop();
+        }
+    }
+
+    result
+}
+
+/// The source of `node`, only the `[` of a loop.
+fn emit_node(out: &mut String, node: &Ir) {
+    let repeat = |ch: char, count: usize| std::iter::repeat_n(ch, count);
+
+    match *node {
+        Ir::Add { value, .. } if value > 128 => out.extend(repeat('-', 256 - value as usize)),
+        Ir::Add { value, .. } => out.extend(repeat('+', value as usize)),
+        Ir::Shift { amount, .. } if amount < 0 => out.extend(repeat('<', amount.unsigned_abs())),
+        Ir::Shift { amount, .. } => out.extend(repeat('>', amount as usize)),
+        Ir::Input { count, .. } => out.extend(repeat(',', count)),
+        Ir::Output { count, .. } => out.extend(repeat('.', count)),
+        Ir::Loop { .. } => out.push('['),
+        _ => unreachable!("the parser makes no other nodes"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+
+    use crate::{minify::minify, vm::Vm};
+
+    #[test]
+    fn strips_comments() {
+        assert_eq!(minify("add two: ++\n[loop -]", false).unwrap(), "++[-]");
+        assert!(minify("[", false).is_err());
+    }
+
+    #[test]
+    fn shrinks_source() {
+        // The cell is cleared, then every cell is 0 again: nothing shows.
+        assert_eq!(minify(">[.]<+++--[+++]><[-][>]", true).unwrap(), "");
+        assert_eq!(minify(&"+".repeat(250), true).unwrap(), "------");
+        assert_eq!(minify("+[>+<-]>[--].,", true).unwrap(), "+[>+<-]>[--].,");
+        assert_eq!(
+            minify("++--[-]>>><<<+[+]+++[->+<]", true).unwrap(),
+            "+++[->+<]"
+        );
+        assert_eq!(minify(",+[-]+>+<[+].", true).unwrap(), ",[-]+>+<-.");
+    }
+
+    #[test]
+    fn keeps_moves_the_first_cell_stops() {
+        assert_eq!(minify("+<>.", true).unwrap(), "+<>.");
+        assert_eq!(minify("+<[-]>.", true).unwrap(), "+<[-]>.");
+        assert_eq!(minify(">+<>.", true).unwrap(), ">+.");
+    }
+
+    /// Loops nest as deep as the program does, shrinking and writing them can't take stack for
+    /// every one.
+    #[test]
+    fn deep_nesting_is_not_recursed_into() {
+        let deep = || {
+            let depth = 100_000;
+            let src = format!(",{}.{}", "[".repeat(depth), "]".repeat(depth));
+            assert_eq!(minify(&src, true).unwrap(), src);
+        };
+
+        thread::Builder::new()
+            .stack_size(256 << 10)
+            .spawn(deep)
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    #[test]
+    fn shrunk_source_runs_the_same() {
+        let src = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+        let shrunk = minify(src, true).unwrap();
+
+        let run = |src: &str| {
+            let mut output = vec![];
+            Vm::new(src)
+                .unwrap()
+                .run_with(&mut &b""[..], &mut output)
+                .unwrap();
+            output
+        };
+        assert_eq!(run(&shrunk), run(src));
+    }
+}
@@ -0,0 +1,30 @@
+/*
+ *  I/O for generated native code.
+ *  Native code can't use `Read`/`Write` directly, it calls these functions with a pointer to the
+ *  `IoContext` set up by whoever started it.
+ */
+
+use std::io::{Read, Write};
+
+pub struct IoContext<'a> {
+    pub input: &'a mut dyn Read,
+    pub output: &'a mut dyn Write,
+}
+
+/// Writes `ch` `count` times. Errors are ignored, native code has no way to stop on them.
+pub extern "C" fn output(ctx: *mut IoContext, ch: usize, count: usize) {
+    // SAFETY: `ctx` is the context passed to the native code, alive for the whole run.
+    let ctx = unsafe { &mut *ctx };
+
+    for _ in 0..count {
+        let _ = ctx.output.write(&[ch as u8]);
+    }
+}
+
+/// Reads one byte into `cell`, returns 0 when that fails.
+pub extern "C" fn input(ctx: *mut IoContext, cell: *mut u8) -> usize {
+    // SAFETY: `ctx` as above, `cell` was bounds checked by the native code.
+    let (ctx, cell) = unsafe { (&mut *ctx, &mut *cell) };
+
+    ctx.input.read_exact(std::array::from_mut(cell)).is_ok() as usize
+}
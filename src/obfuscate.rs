@@ -0,0 +1,111 @@
+/*
+ *  Obfuscator, for `bf obfuscate`: the program doing the same thing, scrambled. What the optimizer
+ *  takes out is put in: pairs of commands cancelling each other, such as `+-` and `><`, loops where
+ *  the cell is known to be 0, after a loop or at the start, which are never entered, and words of
+ *  comments between the commands. The comments of the program are left out.
+ *
+ *  Like the optimizer's sums of moves, a `><` only behaves differently at the right edge of the tape.
+ *  The same seed scrambles a program the same way.
+ */
+
+use anyhow::Result;
+
+use crate::{
+    fuzz::Rng,
+    lexer::{self, Token},
+    parser,
+};
+
+/// Cancelling pairs.
+const PAIRS: [&str; 3] = ["+-", "-+", "><"];
+/// Commands in the loops never entered.
+const DEAD: [char; 6] = ['+', '-', '<', '>', '.', ','];
+
+pub fn obfuscate(src: &str, seed: u64) -> Result<String> {
+    let tokens = lexer::parse(src);
+    parser::parse(tokens.clone())?;
+
+    let mut rng = Rng::new(seed);
+    let mut out = String::new();
+    // The current cell is 0, at the start and after a loop.
+    let mut zero = true;
+
+    for (token, _) in tokens {
+        match rng.below(8) {
+            0 | 1 => out.push_str(PAIRS[rng.below(PAIRS.len())]),
+            2 if zero => dead_loop(&mut rng, &mut out),
+            3 => noise(&mut rng, &mut out),
+            _ => {}
+        }
+
+        out.push(token.to_char());
+        zero = token == Token::RBracket;
+    }
+    if zero {
+        dead_loop(&mut rng, &mut out);
+    }
+    out.push('\n');
+
+    Ok(out)
+}
+
+/// A loop of random commands, nested once at most.
+fn dead_loop(rng: &mut Rng, out: &mut String) {
+    out.push('[');
+    for _ in 0..1 + rng.below(6) {
+        match rng.below(8) {
+            0 => {
+                out.push('[');
+                out.extend((0..1 + rng.below(4)).map(|_| DEAD[rng.below(DEAD.len())]));
+                out.push(']');
+            }
+            _ => out.push(DEAD[rng.below(DEAD.len())]),
+        }
+    }
+    out.push(']');
+}
+
+/// A word of lowercase letters, on a new line now and then.
+fn noise(rng: &mut Rng, out: &mut String) {
+    out.push(match rng.below(4) {
+        0 => '\n',
+        _ => ' ',
+    });
+    out.extend((0..2 + rng.below(7)).map(|_| (b'a' + rng.below(26) as u8) as char));
+    out.push(' ');
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{examples, obfuscate, vm::Vm};
+
+    fn run(src: &str, input: &[u8]) -> Vec<u8> {
+        let mut vm = Vm::from_program(crate::vm::compile(src).unwrap()).unwrap();
+        let mut output = vec![];
+        vm.run_with(&mut &input[..], &mut output).unwrap();
+        output
+    }
+
+    #[test]
+    fn does_the_same() {
+        for name in ["hello", "rot13", "sierpinski"] {
+            let src = examples::find(name).unwrap().src;
+            for seed in 0..4 {
+                let scrambled = obfuscate::obfuscate(src, seed).unwrap();
+                assert_ne!(scrambled, src);
+                assert_eq!(
+                    run(&scrambled, b"Hello\n"),
+                    run(src, b"Hello\n"),
+                    "{}",
+                    name
+                );
+            }
+        }
+
+        assert_eq!(
+            obfuscate::obfuscate("+[-]>.", 3).unwrap(),
+            obfuscate::obfuscate("+[-]>.", 3).unwrap()
+        );
+        assert!(obfuscate::obfuscate("[", 0).is_err());
+    }
+}
@@ -0,0 +1,148 @@
+/*
+ *  Ook!, Brainfuck for orang-utans: every command is a pair of the words `Ook.`, `Ook?` and `Ook!`.
+ *
+ *      Ook. Ook?  >    Ook? Ook.  <    Ook. Ook.  +    Ook! Ook!  -
+ *      Ook! Ook.  .    Ook. Ook!  ,    Ook! Ook?  [    Ook? Ook!  ]
+ *
+ *  Anything else is a comment, the two words of a pair can be on different lines. A command is at
+ *  the `O` of its first word.
+ *
+ *  The rest of the pipeline only knows Brainfuck: `to_brainfuck` writes every command where its pair
+ *  starts and blanks out the rest, so the Brainfuck lexer finds the commands at the same locations.
+ *  Ook! is a Trivial Brainfuck Substitution but for the words of a pair being apart, see `tbs`.
+ *
+ *  Blub is Ook! with `Blub` for `Ook`.
+ */
+
+use anyhow::{bail, Result};
+
+use crate::{
+    lexer::{self, Token, TokenLoc},
+    parser::TokenList,
+};
+
+/// The word of Ook! programs.
+pub const OOK: &str = "Ook";
+/// The word of Blub programs.
+pub const BLUB: &str = "Blub";
+
+/// The tokens of an Ook! program, with where their pairs start.
+pub fn parse(src: &str) -> Result<TokenList> {
+    parse_with(src, OOK)
+}
+
+/// Same as `parse` for Ook! with `word` for `Ook`, such as `BLUB`.
+pub fn parse_with(src: &str, word: &str) -> Result<TokenList> {
+    Ok(commands(src, word)?
+        .into_iter()
+        .map(|(token, loc, _)| (token, loc))
+        .collect())
+}
+
+/// `src` as Brainfuck, see `lexer::layout`.
+pub fn to_brainfuck(src: &str) -> Result<String> {
+    to_brainfuck_with(src, OOK)
+}
+
+/// Same as `to_brainfuck` for Ook! with `word` for `Ook`, such as `BLUB`.
+pub fn to_brainfuck_with(src: &str, word: &str) -> Result<String> {
+    let commands = commands(src, word)?;
+
+    Ok(lexer::layout(
+        src,
+        commands
+            .into_iter()
+            .map(|(token, _, offset)| (token, offset)),
+    ))
+}
+
+/// The commands with their locations and byte offsets.
+fn commands(src: &str, word: &str) -> Result<Vec<(Token, TokenLoc, usize)>> {
+    let bytes = src.as_bytes();
+    let shebang = lexer::shebang_len(bytes);
+    let mut loc = TokenLoc::from_col_line(0, 1);
+    let mut words = vec![];
+
+    for (i, &ch) in bytes.iter().enumerate() {
+        loc.update_location(ch);
+        if i < shebang || !bytes[i..].starts_with(word.as_bytes()) {
+            continue;
+        }
+        if let Some(&mark @ (b'.' | b'?' | b'!')) = bytes.get(i + word.len()) {
+            words.push((mark, loc, i));
+        }
+    }
+
+    let mut commands = Vec::with_capacity(words.len() / 2);
+    for pair in words.chunks(2) {
+        let (first, loc, offset) = pair[0];
+        let Some(&(second, _, _)) = pair.get(1) else {
+            bail!(
+                "`{}{}` at {} is the first half of a command",
+                word,
+                first as char,
+                loc
+            );
+        };
+
+        let token = match (first, second) {
+            (b'.', b'?') => Token::Greater,
+            (b'?', b'.') => Token::Less,
+            (b'.', b'.') => Token::Plus,
+            (b'!', b'!') => Token::Minus,
+            (b'!', b'.') => Token::Dot,
+            (b'.', b'!') => Token::Comma,
+            (b'!', b'?') => Token::LBracket,
+            (b'?', b'!') => Token::RBracket,
+            _ => bail!("`{0}? {0}?` at {1} is not a command", word, loc),
+        };
+        commands.push((token, loc, offset));
+    }
+
+    Ok(commands)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        lexer::{self, Token::*, TokenLoc},
+        ook,
+    };
+
+    #[test]
+    fn reads_pairs_of_words() {
+        let src = "Ook. Ook. Ook! Ook?\nfoo Ook. Ook?\nOok! Ook! Ook? Ook! Ook!\nOok.";
+        let tokens = ook::parse(src).unwrap();
+
+        assert_eq!(
+            tokens,
+            [
+                (Plus, TokenLoc::from_col_line(1, 1)),
+                (LBracket, TokenLoc::from_col_line(11, 1)),
+                (Greater, TokenLoc::from_col_line(5, 2)),
+                (Minus, TokenLoc::from_col_line(1, 3)),
+                (RBracket, TokenLoc::from_col_line(11, 3)),
+                (Dot, TokenLoc::from_col_line(21, 3)),
+            ]
+        );
+
+        let bf = ook::to_brainfuck(src).unwrap();
+        assert_eq!(bf.lines().count(), 4);
+        assert_eq!(lexer::parse(&bf), tokens);
+
+        assert!(ook::parse("Ook? Ook?")
+            .unwrap_err()
+            .to_string()
+            .contains("at 1:1 is not a command"));
+        assert!(ook::parse("Ook. Ook. Ook!").is_err());
+
+        let blub = ook::to_brainfuck_with("Blub. Blub. Ook. Ook? Blub! Blub.", ook::BLUB).unwrap();
+        assert_eq!(
+            lexer::parse(&blub),
+            [
+                (Plus, TokenLoc::from_col_line(1, 1)),
+                (Dot, TokenLoc::from_col_line(23, 1))
+            ]
+        );
+    }
+}
@@ -1,3 +1,5 @@
+use std::fmt::{self, Display};
+
 use crate::lexer::Token;
 
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
@@ -10,17 +12,171 @@ pub enum OpCodeType {
     JmpNotZero,
     InputChar,
     PrintChar,
+    Set,
+    /// mem[ptr + offset] += mem[ptr] * data
+    MulAdd,
+    /// Sets `data >> 8` cells starting at mem[ptr + offset] to `data & 0xff`.
+    FillRange,
+    /// Sets `data` cells starting at mem[ptr + offset] to 0.
+    ClearRange,
+    /// Quickened `JmpZero` of a loop clearing its cell, see `quicken`.
+    ClearLoop,
+    /// Quickened `JmpZero` of a loop only adding and moving, run in closed form, see `quicken`.
+    MulLoop,
+    /// pbrain `(`: the procedure numbered by the current cell starts at the next pc, then jumps to
+    /// `data`, the pc of its `ProcEnd`.
+    ProcStart,
+    /// pbrain `)`: returns from the procedure, `data` is the pc of its `ProcStart`.
+    ProcEnd,
+    /// pbrain `:`: calls the procedure numbered by the current cell, `data` is 0.
+    Call,
+    /// Extended Type I `@`: ends the program.
+    End,
+    /// Extended Type I `$`: copies the current cell to the storage cell.
+    Store,
+    /// Extended Type I `!`: copies the storage cell to the current cell.
+    Retrieve,
+    /// Extended Type I `{`: shifts the bits of the current cell left by one.
+    BitsLeft,
+    /// Extended Type I `}`: shifts the bits of the current cell right by one.
+    BitsRight,
+    /// Extended Type I `~`, `^`, `&` and `|`: the current cell is its bitwise not, or its xor, and,
+    /// or with the storage cell.
+    Not,
+    Xor,
+    And,
+    Or,
+    /// Brainfork `Y`: forks the thread, see `brainfork`.
+    Fork,
+    /// Multi-tape `{` and `}`: switches to the previous or the next tape, wrapping around, see
+    /// `Vm::set_tapes`.
+    PrevTape,
+    NextTape,
+    /// Multi-tape `=`: copies the current cell to the cell under the pointer of the next tape.
+    CopyToTape,
+    /// `?`: sets the current cell to a random byte.
+    Random,
+    /// `#`: prints the pointer and the first cells of the tape to standard error.
+    Dump,
+    /// `%` of file I/O: opens the file named by the cells from the pointer to a zero cell, or closes
+    /// the one open, see `fileio`.
+    OpenFile,
+    /// `^` of file I/O: writes the current cell to the file open.
+    WriteFile,
+    /// `!` of file I/O: reads a byte of the file open into the current cell.
+    ReadFile,
+}
+
+impl OpCodeType {
+    pub const ALL: [OpCodeType; 35] = [
+        OpCodeType::Add,
+        OpCodeType::Sub,
+        OpCodeType::ShiftLeft,
+        OpCodeType::ShiftRight,
+        OpCodeType::JmpZero,
+        OpCodeType::JmpNotZero,
+        OpCodeType::InputChar,
+        OpCodeType::PrintChar,
+        OpCodeType::Set,
+        OpCodeType::MulAdd,
+        OpCodeType::FillRange,
+        OpCodeType::ClearRange,
+        OpCodeType::ClearLoop,
+        OpCodeType::MulLoop,
+        OpCodeType::ProcStart,
+        OpCodeType::ProcEnd,
+        OpCodeType::Call,
+        OpCodeType::End,
+        OpCodeType::Store,
+        OpCodeType::Retrieve,
+        OpCodeType::BitsLeft,
+        OpCodeType::BitsRight,
+        OpCodeType::Not,
+        OpCodeType::Xor,
+        OpCodeType::And,
+        OpCodeType::Or,
+        OpCodeType::Fork,
+        OpCodeType::PrevTape,
+        OpCodeType::NextTape,
+        OpCodeType::CopyToTape,
+        OpCodeType::Random,
+        OpCodeType::Dump,
+        OpCodeType::OpenFile,
+        OpCodeType::WriteFile,
+        OpCodeType::ReadFile,
+    ];
+
+    /// Name used by the textual assembly format, see `asm`.
+    pub fn mnemonic(self) -> &'static str {
+        match self {
+            OpCodeType::Add => "ADD",
+            OpCodeType::Sub => "SUB",
+            OpCodeType::ShiftLeft => "SHL",
+            OpCodeType::ShiftRight => "SHR",
+            OpCodeType::JmpZero => "JZ",
+            OpCodeType::JmpNotZero => "JNZ",
+            OpCodeType::InputChar => "IN",
+            OpCodeType::PrintChar => "OUT",
+            OpCodeType::Set => "SET",
+            OpCodeType::MulAdd => "MULADD",
+            OpCodeType::FillRange => "FILL",
+            OpCodeType::ClearRange => "CLEAR",
+            OpCodeType::ClearLoop => "JZCLEAR",
+            OpCodeType::MulLoop => "JZMUL",
+            OpCodeType::ProcStart => "PROC",
+            OpCodeType::ProcEnd => "RET",
+            OpCodeType::Call => "CALL",
+            OpCodeType::End => "END",
+            OpCodeType::Store => "STORE",
+            OpCodeType::Retrieve => "LOAD",
+            OpCodeType::BitsLeft => "BSHL",
+            OpCodeType::BitsRight => "BSHR",
+            OpCodeType::Not => "NOT",
+            OpCodeType::Xor => "XOR",
+            OpCodeType::And => "AND",
+            OpCodeType::Or => "OR",
+            OpCodeType::Fork => "FORK",
+            OpCodeType::PrevTape => "TPREV",
+            OpCodeType::NextTape => "TNEXT",
+            OpCodeType::CopyToTape => "TCOPY",
+            OpCodeType::Random => "RAND",
+            OpCodeType::Dump => "DUMP",
+            OpCodeType::OpenFile => "FOPEN",
+            OpCodeType::WriteFile => "FWRITE",
+            OpCodeType::ReadFile => "FREAD",
+        }
+    }
+
+    pub fn from_mnemonic(mnemonic: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|ty| ty.mnemonic().eq_ignore_ascii_case(mnemonic))
+    }
+
+    /// `JmpZero` or one of its quickened forms, `data` is the pc of the matching `JmpNotZero`.
+    pub fn is_loop_start(self) -> bool {
+        matches!(
+            self,
+            OpCodeType::JmpZero | OpCodeType::ClearLoop | OpCodeType::MulLoop
+        )
+    }
 }
 
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
 pub struct OpCode {
     pub ty: OpCodeType,
     pub data: usize,
+    /// Cell offset relative to the memory pointer, only used by offset opcodes.
+    pub offset: i32,
 }
 
 impl OpCode {
     pub fn new(ty: OpCodeType, data: usize) -> Self {
-        Self { ty, data }
+        Self::with_offset(ty, data, 0)
+    }
+
+    pub fn with_offset(ty: OpCodeType, data: usize, offset: i32) -> Self {
+        Self { ty, data, offset }
     }
 
     pub fn from_token(token: Token, data: usize) -> Self {
@@ -33,19 +189,47 @@ impl OpCode {
             Token::RBracket => OpCodeType::JmpNotZero,
             Token::Comma => OpCodeType::InputChar,
             Token::Dot => OpCodeType::PrintChar,
+            Token::LParen => OpCodeType::ProcStart,
+            Token::RParen => OpCodeType::ProcEnd,
+            Token::Colon => OpCodeType::Call,
+            Token::At => OpCodeType::End,
+            Token::Dollar => OpCodeType::Store,
+            Token::Bang => OpCodeType::Retrieve,
+            Token::LBrace => OpCodeType::BitsLeft,
+            Token::RBrace => OpCodeType::BitsRight,
+            Token::Tilde => OpCodeType::Not,
+            Token::Caret => OpCodeType::Xor,
+            Token::Ampersand => OpCodeType::And,
+            Token::Pipe => OpCodeType::Or,
+            Token::Fork => OpCodeType::Fork,
+            Token::PrevTape => OpCodeType::PrevTape,
+            Token::NextTape => OpCodeType::NextTape,
+            Token::CopyToTape => OpCodeType::CopyToTape,
+            Token::Question => OpCodeType::Random,
+            Token::Hash => OpCodeType::Dump,
+            Token::OpenFile => OpCodeType::OpenFile,
+            Token::WriteFile => OpCodeType::WriteFile,
+            Token::ReadFile => OpCodeType::ReadFile,
         };
 
         Self::new(ty, data)
     }
 
     #[inline(always)]
-    pub fn to_tuple(&self) -> (OpCodeType, usize) {
+    pub fn to_tuple(self) -> (OpCodeType, usize) {
         (self.ty, self.data)
     }
+}
 
-    #[allow(dead_code)]
-    pub fn to_string(&self) -> String {
+impl Display for OpCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let op = format!("{:?}", self.ty);
-        format!("{:14} {}\n", op, self.data)
+        write!(f, "{:14} {}", op, self.data)?;
+
+        if self.offset != 0 {
+            write!(f, " @{:+}", self.offset)?;
+        }
+
+        Ok(())
     }
 }
@@ -0,0 +1,1083 @@
+/*
+ *  Optimizer: a list of passes rewriting the loop tree IR.
+ *  Built-in passes go through the same `Pass` trait as user supplied ones.
+ */
+
+use std::{collections::BTreeMap, mem, time::Instant};
+
+use anyhow::Result;
+
+use crate::{
+    debug,
+    ir::{self, Ir, ProgramIr, Step},
+    log::{self, Level},
+    parser::{Program, Span},
+    srcmap::SourceMap,
+};
+
+pub trait Pass {
+    fn name(&self) -> &str;
+
+    fn run(&self, program: ProgramIr) -> ProgramIr;
+}
+
+pub fn optimize(program: Program) -> Result<Program> {
+    PassRegistry::default().optimize(&program)
+}
+
+pub struct PassRegistry {
+    passes: Vec<Box<dyn Pass>>,
+}
+
+impl PassRegistry {
+    /// Registry without any pass, `run` returns the IR unchanged.
+    pub fn new() -> Self {
+        Self { passes: vec![] }
+    }
+
+    pub fn with_default_passes() -> Self {
+        let mut registry = Self::new();
+
+        registry
+            .register(ClearLoops)
+            .register(DeadLoops)
+            .register(Contract)
+            .register(BalancedLoops)
+            .register(FillRanges);
+
+        registry
+    }
+
+    /// The passes of `bf run --opt-level`: none at 0, clearing loops and contracting runs at 1,
+    /// the default ones from 2 up.
+    pub fn with_level(level: u8) -> Self {
+        match level {
+            0 => Self::new(),
+            1 => {
+                let mut registry = Self::new();
+                registry.register(ClearLoops).register(Contract);
+                registry
+            }
+            _ => Self::with_default_passes(),
+        }
+    }
+
+    pub fn register<P: Pass + 'static>(&mut self, pass: P) -> &mut Self {
+        self.passes.push(Box::new(pass));
+        self
+    }
+
+    /// Inserts `pass` right before the pass called `name`, or at the end if there is no such pass.
+    pub fn insert_before<P: Pass + 'static>(&mut self, name: &str, pass: P) -> &mut Self {
+        let idx = self.position(name).unwrap_or(self.passes.len());
+        self.passes.insert(idx, Box::new(pass));
+        self
+    }
+
+    /// Inserts `pass` right after the pass called `name`, or at the end if there is no such pass.
+    pub fn insert_after<P: Pass + 'static>(&mut self, name: &str, pass: P) -> &mut Self {
+        let idx = self.position(name).map_or(self.passes.len(), |idx| idx + 1);
+        self.passes.insert(idx, Box::new(pass));
+        self
+    }
+
+    pub fn remove(&mut self, name: &str) -> bool {
+        if let Some(idx) = self.position(name) {
+            self.passes.remove(idx);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn pass_names(&self) -> impl Iterator<Item = &str> {
+        self.passes.iter().map(|pass| pass.name())
+    }
+
+    pub fn run(&self, program: ProgramIr) -> ProgramIr {
+        self.passes.iter().fold(program, |program, pass| {
+            if !log::enabled(Level::Debug) {
+                return pass.run(program);
+            }
+
+            let (before, start) = (program.node_count(), Instant::now());
+            let program = pass.run(program);
+            debug!(
+                "  {:<14} {:>10}  {} -> {} nodes",
+                pass.name(),
+                format!("{:.3?}", start.elapsed()),
+                before,
+                program.node_count()
+            );
+
+            program
+        })
+    }
+
+    /// Same as `run`, with the names of the passes that changed something, in order.
+    pub fn run_changed(&self, program: ProgramIr) -> (ProgramIr, Vec<&str>) {
+        let mut changed = vec![];
+        let program = self.passes.iter().fold(program, |program, pass| {
+            let before = program.clone();
+            let program = pass.run(program);
+            if program != before {
+                changed.push(pass.name());
+            }
+
+            program
+        });
+
+        (program, changed)
+    }
+
+    pub fn optimize(&self, program: &Program) -> Result<Program> {
+        let ir = ProgramIr::from_program(program)?;
+
+        Ok(self.run(ir).to_program())
+    }
+
+    /// Same as `optimize`, keeping track of where the opcodes come from. `spans` are the ones of
+    /// `program` from `parser::spans`.
+    pub fn optimize_with_map(
+        &self,
+        program: &Program,
+        spans: &[Span],
+    ) -> Result<(Program, SourceMap)> {
+        let ir = ProgramIr::from_program_with_spans(program, spans)?;
+
+        Ok(self.run(ir).to_program_with_map())
+    }
+
+    fn position(&self, name: &str) -> Option<usize> {
+        self.passes.iter().position(|pass| pass.name() == name)
+    }
+}
+
+impl Default for PassRegistry {
+    fn default() -> Self {
+        Self::with_default_passes()
+    }
+}
+
+/// Applies `f` to every block of the tree, innermost blocks first.
+pub fn map_blocks(block: Vec<Ir>, f: &mut impl FnMut(Vec<Ir>) -> Vec<Ir>) -> Vec<Ir> {
+    rebuild(block, &mut |_, _| None, &mut |block, _| f(block))
+}
+
+/// Rebuilds the tree with a stack of its own, loops nest as deep as the program does. `enter` can
+/// replace a loop as a whole by other nodes before its body is gone into, `leave` gets every block
+/// gone into once the loops in it are rebuilt, innermost blocks first, with the lowest cell the
+/// pointer can be at before each of its nodes.
+pub fn rebuild(
+    block: Vec<Ir>,
+    enter: &mut impl FnMut(&Ir, &Bounds) -> Option<Vec<Ir>>,
+    leave: &mut impl FnMut(Vec<Ir>, &[usize]) -> Vec<Ir>,
+) -> Vec<Ir> {
+    let mut bounds = Bounds::new(&block);
+    // Each open block: the loop it is the body of, with the body taken out, the nodes left and the
+    // ones done.
+    let mut stack = vec![(None, block.into_iter(), vec![])];
+
+    loop {
+        let (_, nodes, done) = stack.last_mut().unwrap();
+
+        match nodes.next() {
+            Some(mut node @ Ir::Loop { .. }) => {
+                let replaced = enter(&node, &bounds);
+                bounds.node(&node);
+                match replaced {
+                    Some(nodes) => {
+                        bounds.skip();
+                        done.extend(nodes);
+                    }
+                    None => {
+                        let Ir::Loop { body, .. } = &mut node else {
+                            unreachable!();
+                        };
+                        let body = mem::take(body).into_iter();
+                        stack.push((Some(node), body, vec![]));
+                    }
+                }
+            }
+            Some(node) => {
+                bounds.node(&node);
+                done.push(node);
+            }
+            None => {
+                let (node, _, done) = stack.pop().unwrap();
+                let block = leave(done, &bounds.leave());
+                let Some(mut node) = node else {
+                    return block;
+                };
+
+                if let Ir::Loop { body, .. } = &mut node {
+                    *body = block;
+                }
+                stack.last_mut().unwrap().2.push(node);
+            }
+        }
+    }
+}
+
+/// What a loop does to the pointer, from where it is when the loop starts.
+#[derive(Debug, Clone, Copy)]
+struct Moves {
+    /// The lowest the pointer goes if the loop brings it back to where it started every time around.
+    low: Option<isize>,
+    /// It never moves the pointer left.
+    right_only: bool,
+    /// Loops in its body, at any depth.
+    loops: usize,
+}
+
+/// The lowest cell the pointer can be at, as `rebuild` goes through the tree. `<` stops at the
+/// first cell: moves folded together or turned into offsets only do what they did one by one when
+/// the pointer is known to be far enough right for none of them to stop there.
+pub struct Bounds {
+    /// What each loop of the tree does to the pointer, in program order.
+    loops: Vec<Moves>,
+    /// The loop `node` comes to next.
+    next: usize,
+    /// Each block gone into: the lowest the pointer can be before each of its nodes so far, and now.
+    blocks: Vec<(Vec<usize>, usize)>,
+}
+
+impl Bounds {
+    /// For going through `block` from the start of the program, the pointer at any cell.
+    fn new(block: &[Ir]) -> Self {
+        let mut loops = vec![];
+        // Each open block: its loop in `loops`, where the pointer is from where the block starts if
+        // known, the lowest it went and whether it only went right.
+        let mut open = vec![(usize::MAX, Some(0), 0, true)];
+
+        for step in ir::walk(block) {
+            match step {
+                Step::Node(Ir::Loop { .. }) => {
+                    open.push((loops.len(), Some(0), 0, true));
+                    loops.push(Moves {
+                        low: None,
+                        right_only: false,
+                        loops: 0,
+                    });
+                }
+                Step::Node(&Ir::Shift { amount, .. }) => {
+                    let (_, at, low, right_only) = open.last_mut().unwrap();
+                    *at = at.map(|at| at + amount);
+                    *low = at.map_or(*low, |at| at.min(*low));
+                    *right_only &= amount >= 0;
+                }
+                Step::Node(_) => {}
+                Step::End { .. } => {
+                    let (idx, at, low, right_only) = open.pop().unwrap();
+                    let moves = Moves {
+                        low: (at == Some(0)).then_some(low),
+                        right_only,
+                        loops: loops.len() - idx - 1,
+                    };
+                    loops[idx] = moves;
+
+                    let (_, at, low, outer_right_only) = open.last_mut().unwrap();
+                    *outer_right_only &= right_only;
+                    match (*at, moves.low) {
+                        (Some(at), Some(loop_low)) => *low = (*low).min(at + loop_low),
+                        _ => *at = None,
+                    }
+                }
+            }
+        }
+
+        Self {
+            loops,
+            next: 0,
+            blocks: vec![(vec![], 0)],
+        }
+    }
+
+    /// Whether the loop coming next brings the pointer back to where it started every time around,
+    /// without it going as far left as the first cell could stop it.
+    pub fn balanced(&self) -> bool {
+        let bound = self.blocks.last().unwrap().1;
+        let low = self.loops[self.next].low;
+
+        low.is_some_and(|low| bound as isize + low >= 0)
+    }
+
+    /// Goes past `node`, into the body of a loop.
+    fn node(&mut self, node: &Ir) {
+        let balanced = matches!(node, Ir::Loop { .. }) && self.balanced();
+        let (before, bound) = self.blocks.last_mut().unwrap();
+        before.push(*bound);
+
+        match *node {
+            Ir::Shift { amount, .. } => *bound = bound.saturating_add_signed(amount),
+            Ir::Loop { .. } => {
+                let moves = self.loops[self.next];
+                self.next += 1;
+
+                // Where the pointer is every time around, and after the loop.
+                if !balanced && !moves.right_only {
+                    *bound = 0;
+                }
+                let inside = *bound;
+                self.blocks.push((vec![], inside));
+            }
+            _ => {}
+        }
+    }
+
+    /// Leaves the loop `node` went past without going into its body.
+    fn skip(&mut self) {
+        self.blocks.pop();
+        self.next += self.loops[self.next - 1].loops;
+    }
+
+    /// Leaves the block gone into last, with the lowest the pointer can be before each of its nodes.
+    fn leave(&mut self) -> Vec<usize> {
+        self.blocks.pop().unwrap().0
+    }
+}
+
+/// Merges adjacent nodes of the same kind and drops no-ops.
+pub struct Contract;
+
+impl Pass for Contract {
+    fn name(&self) -> &str {
+        "contract"
+    }
+
+    fn run(&self, program: ProgramIr) -> ProgramIr {
+        ProgramIr::new(rebuild(program.body, &mut |_, _| None, &mut contract_block))
+    }
+}
+
+fn contract_block(block: Vec<Ir>, bounds: &[usize]) -> Vec<Ir> {
+    let mut result: Vec<Ir> = Vec::with_capacity(block.len());
+    // The lowest the pointer can be before each node of `result`.
+    let mut starts = Vec::with_capacity(block.len());
+
+    for (node, bound) in block.into_iter().zip(bounds.iter().copied()) {
+        let same_cell = result.last().and_then(Ir::offset) == node.offset();
+        let span = ir::join(result.last().and_then(Ir::span), node.span());
+
+        let merged = match (result.last(), &node) {
+            (Some(Ir::Add { value: a, .. }), &Ir::Add { offset, value, .. }) if same_cell => {
+                Some(Ir::Add {
+                    offset,
+                    value: a.wrapping_add(value),
+                    span,
+                })
+            }
+            (Some(Ir::Set { value: a, .. }), &Ir::Add { offset, value, .. }) if same_cell => {
+                Some(Ir::Set {
+                    offset,
+                    value: a.wrapping_add(value),
+                    span,
+                })
+            }
+            (Some(Ir::Add { .. } | Ir::Set { .. }), &Ir::Set { offset, value, .. })
+                if same_cell =>
+            {
+                Some(Ir::Set {
+                    offset,
+                    value,
+                    span,
+                })
+            }
+            // Going left then right only adds up if the first cell doesn't stop the pointer.
+            (Some(&Ir::Shift { amount: a, .. }), &Ir::Shift { amount: b, .. })
+                if a >= 0 || b <= 0 || starts.last() >= Some(&a.unsigned_abs()) =>
+            {
+                Some(Ir::Shift {
+                    amount: a + b,
+                    span,
+                })
+            }
+            (Some(Ir::Output { count: a, .. }), &Ir::Output { offset, count, .. }) if same_cell => {
+                Some(Ir::Output {
+                    offset,
+                    count: a + count,
+                    span,
+                })
+            }
+            _ => None,
+        };
+
+        match merged {
+            Some(node) => *result.last_mut().unwrap() = node,
+            None => {
+                result.push(node);
+                starts.push(bound);
+            }
+        }
+
+        if matches!(
+            result.last(),
+            Some(Ir::Add { value: 0, .. } | Ir::Shift { amount: 0, .. })
+        ) {
+            result.pop();
+            starts.pop();
+        }
+    }
+
+    result
+}
+
+/// Rewrites `[-]`, `[+]` and any loop adding an odd constant into `Set(0)`.
+pub struct ClearLoops;
+
+impl Pass for ClearLoops {
+    fn name(&self) -> &str {
+        "clear-loops"
+    }
+
+    fn run(&self, program: ProgramIr) -> ProgramIr {
+        ProgramIr::new(map_blocks(program.body, &mut |block| {
+            block
+                .into_iter()
+                .map(|node| match node {
+                    Ir::Loop {
+                        offset,
+                        ref body,
+                        span,
+                    } if is_clear_loop(offset, body) => Ir::Set {
+                        offset,
+                        value: 0,
+                        span,
+                    },
+                    node => node,
+                })
+                .collect()
+        }))
+    }
+}
+
+/// Whether a loop on the cell at `loop_offset` with `body` only adds an odd constant to it, clearing it
+/// whatever it was.
+pub fn is_clear_loop(loop_offset: isize, body: &[Ir]) -> bool {
+    matches!(body, [Ir::Add { offset, value, .. }] if *offset == loop_offset && value % 2 == 1)
+}
+
+/// Removes loops which can never be entered: at the start of the program every cell is zero,
+/// and right after a loop (or a `Set(0)`) its cell is zero.
+pub struct DeadLoops;
+
+impl Pass for DeadLoops {
+    fn name(&self) -> &str {
+        "dead-loops"
+    }
+
+    fn run(&self, program: ProgramIr) -> ProgramIr {
+        let body = program
+            .body
+            .into_iter()
+            .skip_while(|node| matches!(node, Ir::Loop { .. }))
+            .collect();
+
+        ProgramIr::new(map_blocks(body, &mut remove_dead_loops))
+    }
+}
+
+fn remove_dead_loops(block: Vec<Ir>) -> Vec<Ir> {
+    let mut result: Vec<Ir> = Vec::with_capacity(block.len());
+
+    for node in block {
+        let zero_cell = match result.last() {
+            Some(
+                &Ir::Loop { offset, .. }
+                | &Ir::Set {
+                    offset, value: 0, ..
+                },
+            ) => Some(offset),
+            _ => None,
+        };
+
+        let is_dead = matches!(node, Ir::Loop { offset, .. } if zero_cell == Some(offset));
+
+        if !is_dead {
+            result.push(node);
+        }
+    }
+
+    result
+}
+
+/// Compiles loops which come back to where they started with static cell offsets relative to the
+/// pointer at loop entry, so no `Shift` is executed inside them. Nested loops are rewritten as well,
+/// as long as every one of them is balanced too.
+///
+/// `<` stops at the first cell, which offsets can't do: only loops the pointer is known to be far
+/// enough right for are rewritten, see `Bounds`.
+pub struct BalancedLoops;
+
+impl Pass for BalancedLoops {
+    fn name(&self) -> &str {
+        "balanced-loops"
+    }
+
+    fn run(&self, program: ProgramIr) -> ProgramIr {
+        ProgramIr::new(balance_block(program.body))
+    }
+}
+
+fn balance_block(block: Vec<Ir>) -> Vec<Ir> {
+    rebuild(
+        block,
+        &mut |node, bounds| match *node {
+            Ir::Loop {
+                offset,
+                ref body,
+                span,
+            } if bounds.balanced() => Some(vec![Ir::Loop {
+                offset,
+                body: offset_block(body, offset)?,
+                span,
+            }]),
+            _ => None,
+        },
+        &mut |block, _| block,
+    )
+}
+
+/// Rewrites `block` as if the pointer stayed `start` cells away from where it really is.
+/// Returns `None` if the block does not end at `start`, or cannot be expressed with offsets.
+fn offset_block(block: &[Ir], start: isize) -> Option<Vec<Ir>> {
+    // Each open block: the loop it is the body of, the nodes left, where the block starts and
+    // where it is at, and the nodes rewritten.
+    let mut stack = vec![(None, block.iter(), start, start, vec![])];
+
+    loop {
+        let (_, nodes, _, current, _) = stack.last_mut().unwrap();
+        let current = *current;
+
+        let node = match nodes.next() {
+            Some(&Ir::Shift { amount, .. }) => {
+                stack.last_mut().unwrap().3 += amount;
+                continue;
+            }
+            Some(&Ir::Add {
+                offset,
+                value,
+                span,
+            }) => Ir::Add {
+                offset: current + offset,
+                value,
+                span,
+            },
+            Some(&Ir::Set {
+                offset,
+                value,
+                span,
+            }) => Ir::Set {
+                offset: current + offset,
+                value,
+                span,
+            },
+            // The multiplier of `MulAdd` is always the cell under the real pointer.
+            Some(Ir::MulAdd { .. }) if current != 0 => return None,
+            Some(node @ Ir::MulAdd { .. }) => node.clone(),
+            Some(&Ir::Input {
+                offset,
+                count,
+                span,
+            }) => Ir::Input {
+                offset: current + offset,
+                count,
+                span,
+            },
+            Some(&Ir::Output {
+                offset,
+                count,
+                span,
+            }) => Ir::Output {
+                offset: current + offset,
+                count,
+                span,
+            },
+            Some(&Ir::Fill {
+                offset,
+                len,
+                value,
+                span,
+            }) => Ir::Fill {
+                offset: current + offset,
+                len,
+                value,
+                span,
+            },
+            Some(Ir::Loop { offset, body, span }) => {
+                let node = (current + offset, *span);
+                stack.push((Some(node), body.iter(), current, current, vec![]));
+                continue;
+            }
+            None => {
+                let (node, _, start, current, result) = stack.pop().unwrap();
+                if current != start {
+                    return None;
+                }
+                let Some((offset, span)) = node else {
+                    return Some(result);
+                };
+
+                Ir::Loop {
+                    offset,
+                    body: result,
+                    span,
+                }
+            }
+        };
+
+        stack.last_mut().unwrap().4.push(node);
+    }
+}
+
+/// Shortest run of cells set to the same value which is worth a `Fill`.
+pub const MIN_FILL_LEN: usize = 3;
+
+/// Turns straight line code setting neighbouring cells to the same value, like `[-]>[-]>[-]`, into `Fill`s.
+pub struct FillRanges;
+
+impl Pass for FillRanges {
+    fn name(&self) -> &str {
+        "fill-ranges"
+    }
+
+    fn run(&self, program: ProgramIr) -> ProgramIr {
+        ProgramIr::new(rebuild(program.body, &mut |_, _| None, &mut fill_block))
+    }
+}
+
+fn fill_block(block: Vec<Ir>, bounds: &[usize]) -> Vec<Ir> {
+    let mut result = Vec::with_capacity(block.len());
+    let mut segment = vec![];
+    // The lowest the pointer can be at the start of the segment.
+    let mut start = 0;
+
+    for (node, bound) in block.into_iter().zip(bounds.iter().copied()) {
+        if matches!(node, Ir::Set { .. } | Ir::Shift { .. }) {
+            if segment.is_empty() {
+                start = bound;
+            }
+            segment.push(node);
+            continue;
+        }
+
+        result.extend(fill_segment(mem::take(&mut segment), start));
+        result.push(node);
+    }
+
+    result.extend(fill_segment(segment, start));
+
+    result
+}
+
+/// Rewrites a run of `Set`s and `Shift`s, the pointer at least at `start`. Nothing in it reads memory,
+/// so only the last value written to each cell matters and the order of the writes does not.
+fn fill_segment(segment: Vec<Ir>, start: usize) -> Vec<Ir> {
+    // The last value written to each cell, with the span of the `Set` writing it.
+    let mut cells = BTreeMap::new();
+    let mut shift = 0;
+    let mut low = 0;
+    // The spans of the whole segment and of its `Shift`s.
+    let mut spans = (None, None);
+
+    for node in &segment {
+        spans.0 = ir::join(spans.0, node.span());
+        match *node {
+            Ir::Set {
+                offset,
+                value,
+                span,
+            } => {
+                cells.insert(shift + offset, (value, span));
+            }
+            Ir::Shift { amount, span } => {
+                shift += amount;
+                low = low.min(shift);
+                spans.1 = ir::join(spans.1, span);
+            }
+            _ => unreachable!("only Set and Shift are collected"),
+        }
+    }
+
+    // Where the first cell stops the pointer, the cells aren't where the offsets say.
+    if (start as isize) + low < 0 {
+        return segment;
+    }
+
+    // (first cell, length, value) of every run of neighbouring cells set to the same value.
+    let mut runs: Vec<(isize, usize, u8)> = vec![];
+    for (&cell, &(value, _)) in &cells {
+        match runs.last_mut() {
+            Some((start, len, run_value))
+                if *run_value == value && *start + *len as isize == cell =>
+            {
+                *len += 1
+            }
+            _ => runs.push((cell, 1, value)),
+        }
+    }
+
+    if runs.iter().all(|&(_, len, _)| len < MIN_FILL_LEN) {
+        return segment;
+    }
+
+    let mut result = vec![];
+    for (start, len, value) in runs {
+        if len >= MIN_FILL_LEN {
+            result.push(Ir::Fill {
+                offset: start,
+                len,
+                value,
+                span: spans.0,
+            });
+        } else {
+            result.extend((start..start + len as isize).map(|offset| Ir::Set {
+                offset,
+                value,
+                span: cells[&offset].1,
+            }));
+        }
+    }
+
+    if shift != 0 {
+        result.push(Ir::Shift {
+            amount: shift,
+            span: spans.1,
+        });
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        ir::{Ir, ProgramIr},
+        lexer,
+        optimizer::{Pass, PassRegistry},
+        parser,
+        vm::{self, Vm},
+    };
+
+    fn optimize_src(src: &str) -> ProgramIr {
+        let program = parser::parse(lexer::parse(src)).unwrap();
+        let ir = ProgramIr::from_program(&program).unwrap();
+
+        PassRegistry::default().run(ir)
+    }
+
+    #[test]
+    fn source_map_keeps_node_locations() {
+        let tokens = lexer::parse("+>[-]<\n[->+<].");
+        let spans = parser::spans(&tokens);
+        let program = parser::parse(tokens).unwrap();
+
+        let (optimized, map) = PassRegistry::default()
+            .optimize_with_map(&program, &spans)
+            .unwrap();
+        assert_eq!(map.len(), optimized.len());
+
+        // The clear loop is where its `Set` comes from, the `Shift`s are gone from the balanced loop.
+        let locs: Vec<_> = (0..map.len())
+            .map(|pc| map.get(pc).map(|loc| loc.to_string()))
+            .collect();
+        let at = |loc: &str| Some(loc.to_string());
+        assert_eq!(
+            locs,
+            [
+                at("1:1"),
+                at("1:2"),
+                at("1:3"),
+                at("1:6"),
+                at("2:1"),
+                at("2:2"),
+                at("2:4"),
+                at("2:6"),
+                at("2:7")
+            ]
+        );
+    }
+
+    #[test]
+    fn contract_merges_and_cancels() {
+        let ir = optimize_src("+++--->><+-.. .");
+
+        assert_eq!(ir.body, vec![Ir::shift(1), Ir::output(3)]);
+    }
+
+    #[test]
+    fn contract_keeps_moves_the_first_cell_stops() {
+        // `<` stops at the first cell, `>` then goes to the second one.
+        let ir = optimize_src("+<>.");
+        assert_eq!(
+            ir.body,
+            vec![Ir::add(1), Ir::shift(-1), Ir::shift(1), Ir::output(1)]
+        );
+
+        let ir = optimize_src(">+<>.");
+        assert_eq!(ir.body, vec![Ir::shift(1), Ir::add(1), Ir::output(1)]);
+
+        let mut output = vec![];
+        Vm::new("+<>.")
+            .unwrap()
+            .run_with(&mut &b""[..], &mut output)
+            .unwrap();
+        assert_eq!(output, [0]);
+    }
+
+    #[test]
+    fn balanced_loops_only_where_the_first_cell_cant_stop_them() {
+        for (src, expected) in [("+[<+>-].", 0), ("++[<->-]>.", 0), (">+[<+>-]<.", 1)] {
+            let mut output = vec![];
+            Vm::new(src)
+                .unwrap()
+                .run_with(&mut &b""[..], &mut output)
+                .unwrap();
+            assert_eq!(output, [expected], "{}", src);
+        }
+
+        let ir = optimize_src(">+[<+>-]");
+        assert!(matches!(&ir.body[2], Ir::Loop { body, .. } if !body.contains(&Ir::shift(-1))));
+        let ir = optimize_src("+[<+>-]");
+        assert!(matches!(&ir.body[1], Ir::Loop { body, .. } if body.contains(&Ir::shift(-1))));
+    }
+
+    #[test]
+    fn clear_loops() {
+        let ir = optimize_src("+[-]+++>+[+++]");
+
+        assert_eq!(ir.body, vec![Ir::set(3), Ir::shift(1), Ir::set(0)]);
+    }
+
+    #[test]
+    fn dead_loops() {
+        let ir = optimize_src("[.]+[>][<]");
+
+        assert_eq!(ir.body, vec![Ir::add(1), Ir::new_loop(vec![Ir::shift(1)])]);
+    }
+
+    #[test]
+    fn levels() {
+        let names = |level| {
+            let registry = PassRegistry::with_level(level);
+            registry.pass_names().map(String::from).collect::<Vec<_>>()
+        };
+
+        assert!(names(0).is_empty());
+        assert_eq!(names(1), ["clear-loops", "contract"]);
+        assert_eq!(
+            names(2),
+            PassRegistry::default().pass_names().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn leading_loops_on_a_loaded_tape() {
+        let mut passes = PassRegistry::default();
+        passes.remove("dead-loops");
+        let program = vm::compile_with_passes("[.[-]]+", &passes).unwrap();
+
+        let mut vm = Vm::from_program(program).unwrap();
+        vm.load_tape(b"A").unwrap();
+        let mut output = vec![];
+        vm.run_with(&mut &b""[..], &mut output).unwrap();
+
+        assert_eq!(output, b"A");
+        assert_eq!(vm.mem()[0], 1);
+    }
+
+    #[test]
+    fn fill_ranges() {
+        // The loop goes a cell left of where it starts.
+        let ir = optimize_src(">+[[-]>[-]>[-]>[-]<<<<+>[-]>[-]>[-]<<]");
+
+        let expected = vec![
+            Ir::shift(1),
+            Ir::add(1),
+            Ir::new_loop(vec![
+                Ir::Fill {
+                    offset: 0,
+                    len: 4,
+                    value: 0,
+                    span: None,
+                },
+                Ir::Add {
+                    offset: -1,
+                    value: 1,
+                    span: None,
+                },
+                Ir::Fill {
+                    offset: 0,
+                    len: 3,
+                    value: 0,
+                    span: None,
+                },
+            ]),
+        ];
+
+        assert_eq!(ir.body, expected);
+
+        // `<` stops at the first cell, the cells cleared start there instead of a cell left of it.
+        let ir = optimize_src("+<[-]>[-]>[-]>.");
+        assert!(!ir.body.iter().any(|node| matches!(node, Ir::Fill { .. })));
+
+        let ir = optimize_src("+>[-]>[-]>[-]>+");
+
+        let expected = vec![
+            Ir::add(1),
+            Ir::Fill {
+                offset: 1,
+                len: 3,
+                value: 0,
+                span: None,
+            },
+            Ir::shift(4),
+            Ir::add(1),
+        ];
+
+        assert_eq!(ir.body, expected);
+    }
+
+    #[test]
+    fn short_set_runs_are_kept() {
+        let ir = optimize_src(">+[[-]>[-]<]");
+
+        assert_eq!(
+            ir.body[2],
+            Ir::new_loop(vec![
+                Ir::set(0),
+                Ir::Set {
+                    offset: 1,
+                    value: 0,
+                    span: None,
+                }
+            ])
+        );
+    }
+
+    #[test]
+    fn balanced_loops_use_offsets() {
+        let ir = optimize_src(">[->>+<<<.>]");
+
+        let expected = vec![
+            Ir::shift(1),
+            Ir::new_loop(vec![
+                Ir::add(255),
+                Ir::Add {
+                    offset: 2,
+                    value: 1,
+                    span: None,
+                },
+                Ir::Output {
+                    offset: -1,
+                    count: 1,
+                    span: None,
+                },
+            ]),
+        ];
+
+        assert_eq!(ir.body, expected);
+    }
+
+    #[test]
+    fn nested_balanced_loops() {
+        let ir = optimize_src("+[>[>+<-]<-]");
+
+        let expected = vec![
+            Ir::add(1),
+            Ir::new_loop(vec![
+                Ir::Loop {
+                    offset: 1,
+                    body: vec![
+                        Ir::Add {
+                            offset: 2,
+                            value: 1,
+                            span: None,
+                        },
+                        Ir::Add {
+                            offset: 1,
+                            value: 255,
+                            span: None,
+                        },
+                    ],
+                    span: None,
+                },
+                Ir::add(255),
+            ]),
+        ];
+
+        assert_eq!(ir.body, expected);
+    }
+
+    #[test]
+    fn unbalanced_loops_keep_shifts() {
+        let ir = optimize_src("+[>+[>]<-]");
+
+        let expected = vec![
+            Ir::add(1),
+            Ir::new_loop(vec![
+                Ir::shift(1),
+                Ir::add(1),
+                Ir::new_loop(vec![Ir::shift(1)]),
+                Ir::shift(-1),
+                Ir::add(255),
+            ]),
+        ];
+
+        assert_eq!(ir.body, expected);
+    }
+
+    struct DoubleOutput;
+
+    impl Pass for DoubleOutput {
+        fn name(&self) -> &str {
+            "double-output"
+        }
+
+        fn run(&self, program: ProgramIr) -> ProgramIr {
+            let body = program
+                .body
+                .into_iter()
+                .map(|node| match node {
+                    Ir::Output {
+                        offset,
+                        count,
+                        span,
+                    } => Ir::Output {
+                        offset,
+                        count: count * 2,
+                        span,
+                    },
+                    node => node,
+                })
+                .collect();
+
+            ProgramIr::new(body)
+        }
+    }
+
+    #[test]
+    fn custom_pass_registration() {
+        let mut registry = PassRegistry::default();
+        registry.insert_before("contract", DoubleOutput);
+
+        let names = registry.pass_names().collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                "clear-loops",
+                "dead-loops",
+                "double-output",
+                "contract",
+                "balanced-loops",
+                "fill-ranges"
+            ]
+        );
+
+        let ir = registry.run(ProgramIr::new(vec![Ir::output(1), Ir::output(1)]));
+        assert_eq!(ir.body, vec![Ir::output(4)]);
+
+        assert!(registry.remove("double-output"));
+        assert!(!registry.remove("double-output"));
+    }
+}
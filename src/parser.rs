@@ -1,41 +1,320 @@
 /*
  *  Parser emits bytecodes for the VM.
+ *  The procedures of pbrain, `(` to `)`, nest with loops like loops do, their start jumps past the end.
  */
 
-use anyhow::Result;
+use std::{fmt, io, iter::Peekable, vec};
+
+use anyhow::{bail, Result};
 
 use crate::{
     lexer::{Token, TokenLoc},
-    opcodes::OpCode,
+    opcodes::{OpCode, OpCodeType},
 };
 
 pub type TokenData = (Token, TokenLoc);
 pub type TokenList = Vec<TokenData>;
 pub type Program = Vec<OpCode>;
 
+/// First and last token an opcode was made of.
+pub type Span = (TokenLoc, TokenLoc);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    UnexpectedRBracket,
+    /// `count` loops are still open at the end, the error is at the innermost.
+    UnclosedLBracket {
+        count: usize,
+    },
+    UnexpectedRParen,
+    /// Same as `UnclosedLBracket`, the innermost being a procedure.
+    UnclosedLParen {
+        count: usize,
+    },
+}
+
+/// Error `parse` fails with. `Source::locate_error` fills in `file` for programs read from files, and
+/// `snippet`, the line the error is on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    pub loc: TokenLoc,
+    pub file: Option<String>,
+    pub snippet: Option<String>,
+}
+
+impl ParseError {
+    /// What went wrong, without where.
+    pub fn message(&self) -> String {
+        match self.kind {
+            ParseErrorKind::UnexpectedRBracket => "unexpected closing delimiter ']'".to_string(),
+            ParseErrorKind::UnclosedLBracket { count: 1 } => "unclosed delimiter '['".to_string(),
+            ParseErrorKind::UnclosedLBracket { count } => {
+                format!("unclosed delimiter '[', {} are unclosed", count)
+            }
+            ParseErrorKind::UnexpectedRParen => "unexpected closing delimiter ')'".to_string(),
+            ParseErrorKind::UnclosedLParen { count: 1 } => "unclosed delimiter '('".to_string(),
+            ParseErrorKind::UnclosedLParen { count } => {
+                format!("unclosed delimiter '(', {} are unclosed", count)
+            }
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let at = match &self.file {
+            Some(file) => format!("{}:{}", file, self.loc),
+            None => self.loc.to_string(),
+        };
+
+        match self.kind {
+            ParseErrorKind::UnexpectedRBracket | ParseErrorKind::UnexpectedRParen => {
+                write!(f, "{} at {}", self.message(), at)
+            }
+            ParseErrorKind::UnclosedLBracket { count }
+            | ParseErrorKind::UnclosedLParen { count } => {
+                let open = match self.kind {
+                    ParseErrorKind::UnclosedLParen { .. } => '(',
+                    _ => '[',
+                };
+                write!(f, "unclosed delimiter '{}' at {}.", open, at)?;
+                if count > 1 {
+                    write!(f, " There are {} unclosed delimiters.", count)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
 pub fn parse(token_list: TokenList) -> Result<Program> {
     let parser = Parser::new(token_list);
 
     parser.parse()
 }
 
+/// Same as `parse` for tokens as they are lexed, such as the ones of `lexer::StreamLexer`, without
+/// holding all of them. Gives the spans of the opcodes too, the same as `spans`.
+pub fn parse_stream<I>(tokens: I) -> Result<(Program, Vec<Span>)>
+where
+    I: Iterator<Item = io::Result<TokenData>>,
+{
+    let mut error = None;
+    let tokens = tokens.map_while(|token| token.map_err(|err| error = Some(err)).ok());
+    let result = Parser::from_tokens(tokens).parse_with_spans();
+
+    // A program cut short by the error can fail to parse too, the read error is what went wrong.
+    match error {
+        Some(err) => Err(err.into()),
+        None => result,
+    }
+}
+
+/// `parse` for a program arriving a piece at a time, like one piped in while it is written: tokens
+/// are pushed as they come and `take` parses the ones up to where no loop or procedure is open,
+/// keeping the rest until they close. Each piece starts at pc 0, for `Vm::append`.
+#[derive(Debug, Default)]
+pub struct IncrementalParser {
+    tokens: TokenList,
+    /// Loops and procedures open at the end of `tokens`.
+    depth: usize,
+    /// Tokens up to the end of the last construct at the top level.
+    complete: usize,
+}
+
+impl IncrementalParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, token: TokenData) {
+        self.tokens.push(token);
+        match token.0 {
+            Token::LBracket | Token::LParen => self.depth += 1,
+            // A `]` closing nothing is at the top level, `take` gives the error.
+            Token::RBracket | Token::RParen => self.depth = self.depth.saturating_sub(1),
+            _ => {}
+        }
+        if self.depth == 0 {
+            self.complete = self.tokens.len();
+        }
+    }
+
+    /// Whether a loop or procedure is still open.
+    pub fn is_open(&self) -> bool {
+        self.depth > 0
+    }
+
+    /// The opcodes of the tokens pushed up to where nothing is open, with the spans `spans` gives.
+    pub fn take(&mut self) -> Result<(Program, Vec<Span>)> {
+        let rest = self.tokens.split_off(self.complete);
+        let tokens = std::mem::replace(&mut self.tokens, rest);
+        self.complete = 0;
+
+        Parser::new(tokens).parse_with_spans()
+    }
+
+    /// The opcodes of the tokens left at the end of the program, failing on what is still open.
+    pub fn finish(mut self) -> Result<(Program, Vec<Span>)> {
+        self.complete = self.tokens.len();
+
+        self.take()
+    }
+}
+
+/// Span of every opcode `parse` makes out of `token_list`, runs of a token are one opcode.
+pub fn spans(token_list: &TokenList) -> Vec<Span> {
+    let mut spans: Vec<Span> = vec![];
+    let mut prev = None;
+
+    for &(token, loc) in token_list {
+        match spans.last_mut() {
+            Some(span) if token.is_groupable() && prev == Some(token) => span.1 = loc,
+            _ => spans.push((loc, loc)),
+        }
+        prev = Some(token);
+    }
+
+    spans
+}
+
+/// The opcode at `loc` in the source, or the first one after it. `spans` come from `spans`.
+pub fn pc_at(spans: &[Span], loc: TokenLoc) -> Option<usize> {
+    let key = |loc: TokenLoc| (loc.line(), loc.col());
+
+    spans.iter().position(|&(_, end)| key(end) >= key(loc))
+}
+
+/// The pcs of the loop starts `pc` is in, innermost first. The `]` of a loop is in it.
+pub fn enclosing_loops(program: &Program, pc: usize) -> Vec<usize> {
+    (0..pc.min(program.len()))
+        .rev()
+        .filter(|&start| program[start].ty.is_loop_start() && program[start].data >= pc)
+        .collect()
+}
+
+/// How deep the loops of the Brainfuck source `src` nest, counted on its brackets without lexing
+/// it, to turn programs away before compiling them.
+pub fn nesting(src: &str) -> usize {
+    let (mut depth, mut deepest) = (0usize, 0);
+
+    for byte in src.bytes() {
+        match byte {
+            b'[' => {
+                depth += 1;
+                deepest = deepest.max(depth);
+            }
+            b']' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+
+    deepest
+}
+
+/// Checks that every loop start and `JmpNotZero` point at each other, and every `ProcStart` and
+/// `ProcEnd`, nesting like the parser makes them, for programs not made by the parser.
+pub fn verify_jumps(program: &Program) -> Result<()> {
+    // The pcs of the loops and procedures open, innermost last.
+    let mut open = vec![];
+
+    for (pc, op) in program.iter().enumerate() {
+        let partner = program.get(op.data);
+        let broken = if op.ty.is_loop_start() {
+            open.push(pc);
+            !partner.is_some_and(|end| end.ty == OpCodeType::JmpNotZero && end.data == pc)
+        } else if op.ty == OpCodeType::ProcStart {
+            open.push(pc);
+            !partner.is_some_and(|end| end.ty == OpCodeType::ProcEnd && end.data == pc)
+        } else if op.ty == OpCodeType::JmpNotZero {
+            open.pop() != Some(op.data) || !program[op.data].ty.is_loop_start()
+        } else if op.ty == OpCodeType::ProcEnd {
+            open.pop() != Some(op.data) || program[op.data].ty != OpCodeType::ProcStart
+        } else {
+            false
+        };
+
+        if broken {
+            bail!("broken jump at pc={}", pc);
+        }
+    }
+    if let Some(pc) = open.pop() {
+        bail!("broken jump at pc={}", pc);
+    }
+
+    Ok(())
+}
+
+/// Checks what the VM relies on in a program: the jumps, see `verify_jumps`, and the operands that
+/// are cells.
+pub fn verify(program: &Program) -> Result<()> {
+    for op in program {
+        match op.ty {
+            OpCodeType::Add | OpCodeType::Sub if op.data >= u8::MAX as _ => {
+                bail!(
+                    "Add and Sub instruction must have data less than or equal to u8::MAX, data={}",
+                    op.data
+                )
+            }
+            OpCodeType::Set | OpCodeType::MulAdd if op.data > u8::MAX as _ => {
+                bail!(
+                    "{:?} instruction must have data less than or equal to u8::MAX, data={}",
+                    op.ty,
+                    op.data
+                )
+            }
+            _ => {}
+        }
+    }
+
+    verify_jumps(program)
+}
+
+/// `Program::verified`, for programs made some other way than by the parser.
+pub trait Verify: Sized {
+    /// The program if `verify` finds nothing wrong with it.
+    fn verified(self) -> Result<Self>;
+}
+
+impl Verify for Program {
+    fn verified(self) -> Result<Self> {
+        verify(&self)?;
+
+        Ok(self)
+    }
+}
+
 #[derive(Debug)]
-pub struct Parser {
-    src: TokenList,
-    src_pos: usize,
-    lbracket_locations: Vec<(TokenLoc, usize)>,
+pub struct Parser<I: Iterator<Item = TokenData> = vec::IntoIter<TokenData>> {
+    src: Peekable<I>,
+    /// Where the last token read is.
+    last_loc: TokenLoc,
+    /// The `[` and `(` still open, with where they are and their pc.
+    open_delimiters: Vec<(Token, TokenLoc, usize)>,
     opcode_count: usize,
     program: Program,
 }
 
 impl Parser {
     pub fn new(src: TokenList) -> Self {
+        Self::from_tokens(src.into_iter())
+    }
+}
+
+impl<I: Iterator<Item = TokenData>> Parser<I> {
+    pub fn from_tokens(src: I) -> Self {
+        // As many opcodes as tokens at most, runs of a token making one.
+        let program = Program::with_capacity(src.size_hint().0);
+
         Self {
-            src,
-            src_pos: 0,
-            lbracket_locations: vec![],
+            src: src.peekable(),
+            last_loc: TokenLoc::from_col_line(0, 1),
+            open_delimiters: vec![],
             opcode_count: 0,
-            program: Program::new(),
+            program,
         }
     }
 
@@ -49,33 +328,50 @@ impl Parser {
         Ok(self.program)
     }
 
+    /// Same as `parse`, with the span of every opcode.
+    pub fn parse_with_spans(mut self) -> Result<(Program, Vec<Span>)> {
+        let mut spans = vec![];
+        while let Some((_, start)) = self.peek_token() {
+            let op = self.emit_opcode()?.expect("a token is left");
+            self.program.push(op);
+            spans.push((start, self.last_loc));
+        }
+        self.emit_opcode()?;
+
+        Ok((self.program, spans))
+    }
+
     pub fn next_token(&mut self) -> Option<TokenData> {
-        let token_data = self.src.get(self.src_pos).map(|&data| data);
+        let token_data = self.src.next();
 
-        if token_data.is_some() {
-            self.increase_src_pos();
+        if let Some((_, loc)) = token_data {
+            self.last_loc = loc;
         }
 
         token_data
     }
 
-    pub fn peek_token(&self) -> Option<TokenData> {
-        self.src.get(self.src_pos).map(|&data| data)
+    pub fn peek_token(&mut self) -> Option<TokenData> {
+        self.src.peek().copied()
     }
 
     pub fn emit_opcode(&mut self) -> Result<Option<OpCode>> {
         if let Some((token, location)) = self.next_token() {
             let data = match token {
-                Token::LBracket => self.register_jump_not_zero_data(location),
-                Token::RBracket => self.emit_jump_not_zero_data(location)?,
+                Token::LBracket | Token::LParen => {
+                    self.register_jump_not_zero_data(token, location)
+                }
+                Token::RBracket | Token::RParen => self.emit_jump_not_zero_data(token, location)?,
+                // `:` and the commands of Extended Brainfuck.
+                _ if !token.is_groupable() => 0,
                 _ => self.count_current_token(token),
             };
 
             self.opcode_count += 1;
 
             Ok(Some(OpCode::from_token(token, data)))
-        } else if let Some((last_lbracket_location, _)) = self.lbracket_locations.last() {
-            Err(self.emit_error_no_rbracket(last_lbracket_location))
+        } else if let Some(&(open, location, _)) = self.open_delimiters.last() {
+            Err(self.emit_error_no_rbracket(open, location))
         } else {
             Ok(None)
         }
@@ -88,7 +384,7 @@ impl Parser {
             while let Some((token, _)) = self.peek_token() {
                 if token == current_token {
                     counter += 1;
-                    self.increase_src_pos();
+                    self.next_token();
                 } else {
                     break;
                 }
@@ -98,53 +394,67 @@ impl Parser {
         counter
     }
 
-    pub fn register_jump_not_zero_data(&mut self, location: TokenLoc) -> usize {
-        self.lbracket_locations.push((location, self.opcode_count));
+    /// `open` is `[` or `(`.
+    pub fn register_jump_not_zero_data(&mut self, open: Token, location: TokenLoc) -> usize {
+        self.open_delimiters
+            .push((open, location, self.opcode_count));
 
         usize::MAX
     }
 
-    pub fn emit_jump_not_zero_data(&mut self, location: TokenLoc) -> Result<usize> {
-        if let Some((_, lbracket_idx)) = self.lbracket_locations.pop() {
-            // Update lbracket JmpZero data.
-            self.program[lbracket_idx].data = self.opcode_count;
+    /// `close` is `]` or `)`, which has to close the innermost `[` or `(`.
+    pub fn emit_jump_not_zero_data(&mut self, close: Token, location: TokenLoc) -> Result<usize> {
+        let open = match close {
+            Token::RParen => Token::LParen,
+            _ => Token::LBracket,
+        };
 
-            Ok(lbracket_idx)
-        } else {
-            Err(anyhow::anyhow!(
-                "unexpected closing delimiter ']' at {}",
-                location
-            ))
-        }
-    }
+        match self.open_delimiters.last() {
+            Some(&(token, _, lbracket_idx)) if token == open => {
+                self.open_delimiters.pop();
+                // Update lbracket JmpZero data.
+                self.program[lbracket_idx].data = self.opcode_count;
 
-    pub fn increase_src_pos(&mut self) {
-        self.src_pos += 1;
+                Ok(lbracket_idx)
+            }
+            _ => Err(ParseError {
+                kind: match close {
+                    Token::RParen => ParseErrorKind::UnexpectedRParen,
+                    _ => ParseErrorKind::UnexpectedRBracket,
+                },
+                loc: location,
+                file: None,
+                snippet: None,
+            }
+            .into()),
+        }
     }
 
-    pub fn emit_error_no_rbracket(&self, last_lbracket_location: &TokenLoc) -> anyhow::Error {
-        let remaining_lbrackets = self.lbracket_locations.len();
-
-        let extended_err_msg = if remaining_lbrackets > 1 {
-            format!(" There are {} unclosed delimiters.", remaining_lbrackets)
-        } else {
-            String::new()
-        };
-
-        anyhow::anyhow!(
-            "unclosed delimiter '[' at {}.{}",
-            last_lbracket_location,
-            extended_err_msg
-        )
+    pub fn emit_error_no_rbracket(&self, open: Token, location: TokenLoc) -> anyhow::Error {
+        let count = self.open_delimiters.len();
+
+        ParseError {
+            kind: match open {
+                Token::LParen => ParseErrorKind::UnclosedLParen { count },
+                _ => ParseErrorKind::UnclosedLBracket { count },
+            },
+            loc: location,
+            file: None,
+            snippet: None,
+        }
+        .into()
     }
 }
 
 #[cfg(test)]
 mod test {
+    use std::io;
+
     use crate::{
-        lexer::Lexer,
+        lexer::{self, Lexer, TokenLoc},
         opcodes::{OpCode, OpCodeType::*},
-        parser::Parser,
+        parser::{self, Parser, Verify},
+        vm,
     };
 
     #[test]
@@ -179,6 +489,99 @@ mod test {
         );
     }
 
+    #[test]
+    fn pbrain_procedures() {
+        let program = parser::parse(lexer::parse_pbrain("+([-]):")).unwrap();
+        assert_eq!(program[1], OpCode::new(ProcStart, 5));
+        assert_eq!(program[5], OpCode::new(ProcEnd, 1));
+        assert_eq!(program[6], OpCode::new(Call, 0));
+
+        for src in ["([)]", "(]", "(:", ")"] {
+            assert!(parser::parse(lexer::parse_pbrain(src)).is_err(), "{}", src);
+        }
+
+        let (program, _) = vm::compile_tokens_with_map(lexer::parse_pbrain("+++(.-):>:")).unwrap();
+        let mut output = vec![];
+        vm::Vm::from_program(program)
+            .unwrap()
+            .run_with(&mut &b""[..], &mut output)
+            .unwrap_err();
+        assert_eq!(output, [3]);
+    }
+
+    #[test]
+    fn extended_brainfuck() {
+        // The data after the `@` is left out, the one in the loop ends the program early.
+        let tokens = lexer::parse_extended1("+[>@<-]${{~&.!}|^. @ ]data[");
+        assert_eq!(tokens.len(), 19);
+
+        let (program, _) = vm::compile_tokens_with_map(tokens).unwrap();
+        assert_eq!(program[3], OpCode::new(End, 0));
+        let mut output = vec![];
+        let mut vm = vm::Vm::from_program(program).unwrap();
+        vm.run_with(&mut &b""[..], &mut output).unwrap();
+        assert!(output.is_empty());
+
+        let (program, _) =
+            vm::compile_tokens_with_map(lexer::parse_extended1("+++$>{{~&.!}|^.@")).unwrap();
+        let mut vm = vm::Vm::from_program(program).unwrap();
+        vm.run_threaded(&mut &b""[..], &mut output).unwrap();
+        // !(0 << 2) & 3, then ((3 >> 1) | 3) ^ 3.
+        assert_eq!(output, [3, 0]);
+    }
+
+    #[test]
+    fn multiple_tapes() {
+        // 2 on the first tape, copied to the second, 3 more there, then back to the first.
+        let tokens = lexer::parse_multitape("++=}+++.{.}{{.");
+        let (program, _) = vm::compile_tokens_with_map(tokens).unwrap();
+        assert_eq!(program[1], OpCode::new(CopyToTape, 0));
+
+        let mut vm = vm::Vm::from_program(program).unwrap();
+        vm.set_tapes(3);
+        let mut output = vec![];
+        vm.run_with(&mut &b""[..], &mut output).unwrap();
+        // The last `{` wraps around to the third tape.
+        assert_eq!(output, [5, 2, 0]);
+
+        vm.reset();
+        output.clear();
+        vm.run_threaded(&mut &b""[..], &mut output).unwrap();
+        assert_eq!(output, [5, 2, 0]);
+    }
+
+    #[test]
+    fn seeded_random_numbers() {
+        let tokens = lexer::parse_with(
+            "?>?>?",
+            lexer::Options {
+                random: true,
+                ..Default::default()
+            },
+        );
+        let (program, _) = vm::compile_tokens_with_map(tokens).unwrap();
+        let mut vm = vm::Vm::from_program(program).unwrap();
+        vm.set_seed(7);
+
+        vm.run_with(&mut &b""[..], &mut vec![]).unwrap();
+        let first = vm.mem()[..3].to_vec();
+        vm.reset();
+        vm.run_threaded(&mut &b""[..], &mut vec![]).unwrap();
+        assert_eq!(vm.mem()[..3], first);
+        assert_ne!(first, [first[0]; 3]);
+    }
+
+    #[test]
+    fn pc_at_source_location() {
+        let spans = parser::spans(&Lexer::new("+++\n  [-]\n").parse());
+        let pc_at = |line, col| parser::pc_at(&spans, TokenLoc::from_col_line(col, line));
+
+        assert_eq!(pc_at(1, 2), Some(0));
+        assert_eq!(pc_at(1, 4), Some(1));
+        assert_eq!(pc_at(2, 4), Some(2));
+        assert_eq!(pc_at(3, 1), None);
+    }
+
     #[test]
     fn stacked_opcodes() {
         let token_list = Lexer::new("+++>>>>[[[--]]]").parse();
@@ -203,6 +606,72 @@ mod test {
         assert_eq!(program, opcodes);
     }
 
+    #[test]
+    fn parses_streamed_tokens() {
+        let src = "++[>+\n<-]>.";
+        let tokens = lexer::parse(src);
+        let streamed = tokens.iter().copied().map(Ok);
+        let (program, spans) = parser::parse_stream(streamed).unwrap();
+        assert_eq!(program, parser::parse(tokens.clone()).unwrap());
+        assert_eq!(spans, parser::spans(&tokens));
+
+        // A read error is what failed, not the loop left open.
+        let cut = tokens[..3]
+            .iter()
+            .copied()
+            .map(Ok)
+            .chain([Err(io::ErrorKind::UnexpectedEof.into())]);
+        let err = parser::parse_stream(cut).unwrap_err();
+        assert!(err.is::<io::Error>());
+    }
+
+    #[test]
+    fn verifies_programs_made_elsewhere() {
+        let op = OpCode::new;
+        let program = parser::parse(lexer::parse_pbrain("([-]):")).unwrap();
+        assert_eq!(program.clone().verified().unwrap(), program);
+
+        // Loops pointing at each other but crossed, one left open, a jump off the end.
+        for program in [
+            vec![
+                op(JmpZero, 2),
+                op(JmpZero, 3),
+                op(JmpNotZero, 0),
+                op(JmpNotZero, 1),
+            ],
+            vec![op(JmpZero, 1), op(JmpNotZero, 0), op(JmpZero, 2)],
+            vec![op(JmpNotZero, 9)],
+            vec![op(ProcStart, 1), op(ProcEnd, 1)],
+            vec![op(Add, 255)],
+        ] {
+            assert!(program.clone().verified().is_err(), "{:?}", program);
+            assert!(vm::Vm::from_program(program).is_err());
+        }
+    }
+
+    #[test]
+    fn parses_a_piece_at_a_time() {
+        let mut parser = parser::IncrementalParser::new();
+        let mut pieces = vec![];
+        for token in lexer::parse("+[->+<].[-") {
+            parser.push(token);
+            if !parser.is_open() {
+                pieces.push(parser.take().unwrap().0);
+            }
+        }
+        assert!(parser.is_open());
+        assert_eq!(pieces.iter().map(Vec::len).collect::<Vec<_>>(), [1, 6, 1]);
+        // Each piece starts at pc 0.
+        assert_eq!(pieces[1][0], OpCode::new(JmpZero, 5));
+        assert!(parser.finish().is_err());
+
+        let mut parser = parser::IncrementalParser::new();
+        lexer::parse("+]")
+            .into_iter()
+            .for_each(|token| parser.push(token));
+        assert!(parser.take().is_err());
+    }
+
     #[test]
     fn parse_simple() {}
 
@@ -0,0 +1,387 @@
+/*
+ *  Profile guided optimization.
+ *  A profile holds execution counts for every loop of a compiled program, numbered by the order of
+ *  their `[` in the bytecode. Hot loops get fused into straight line code, cold ones are left alone.
+ *  That is all a profile drives: unrolling a loop or moving cold code out of the way would need
+ *  conditional and plain jumps, and loops are the only control flow the bytecode has.
+ */
+
+use std::{
+    collections::BTreeMap,
+    io::{Read, Write},
+};
+
+use anyhow::{bail, Result};
+
+use crate::{
+    ir::{self, Ir, ProgramIr},
+    opcodes::OpCodeType,
+    optimizer::{self, Pass, PassRegistry},
+    parser::Program,
+    vm::Vm,
+};
+
+pub const DEFAULT_HOT_LOOP_THRESHOLD: u64 = 1_000;
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct LoopCounts {
+    /// How many times the loop's `[` was reached.
+    pub entries: u64,
+    /// How many times the loop body ran to its `]`.
+    pub iterations: u64,
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Profile {
+    pub loops: Vec<LoopCounts>,
+}
+
+impl Profile {
+    /// Builds a profile from per-instruction hit counts as returned by `Vm::run_counted`.
+    pub fn from_hits(program: &Program, hits: &[u64]) -> Self {
+        let mut loops = vec![];
+        let mut open = vec![];
+
+        for (pc, op) in program.iter().enumerate() {
+            let count = hits.get(pc).copied().unwrap_or(0);
+
+            match op.ty {
+                OpCodeType::JmpZero => {
+                    open.push(loops.len());
+                    loops.push(LoopCounts {
+                        entries: count,
+                        iterations: 0,
+                    });
+                }
+                OpCodeType::JmpNotZero => {
+                    if let Some(idx) = open.pop() {
+                        loops[idx].iterations = count;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Self { loops }
+    }
+
+    /// Runs `program` once on the given input and output, counting loop executions.
+    /// A runtime error (e.g. running out of input) only ends the recording early.
+    pub fn record<R: Read, W: Write>(
+        program: &Program,
+        input: &mut R,
+        output: &mut W,
+    ) -> Result<Self> {
+        let mut vm = Vm::from_program(program.clone())?;
+        let mut hits = vec![];
+
+        let _ = vm.run_counted(input, output, &mut hits);
+
+        Ok(Self::from_hits(program, &hits))
+    }
+
+    pub fn is_hot(&self, loop_idx: usize, threshold: u64) -> bool {
+        self.loops
+            .get(loop_idx)
+            .is_some_and(|counts| counts.iterations >= threshold)
+    }
+}
+
+/// Re-compiles `program` using a profile recorded on that same program.
+pub fn optimize(program: &Program, profile: &Profile) -> Result<Program> {
+    optimize_with_threshold(program, profile, DEFAULT_HOT_LOOP_THRESHOLD)
+}
+
+pub fn optimize_with_threshold(
+    program: &Program,
+    profile: &Profile,
+    threshold: u64,
+) -> Result<Program> {
+    let loop_count = program
+        .iter()
+        .filter(|op| op.ty == OpCodeType::JmpZero)
+        .count();
+
+    if loop_count != profile.loops.len() {
+        bail!(
+            "profile does not match the program: {} loops in profile, {} in program",
+            profile.loops.len(),
+            loop_count
+        );
+    }
+
+    // Fusion must run before any pass that could add or remove loops, otherwise loop numbers drift.
+    let mut registry = PassRegistry::new();
+    registry.register(HotLoopFusion::new(profile, threshold));
+
+    let ir = registry.run(ProgramIr::from_program(program)?);
+
+    Ok(PassRegistry::default().run(ir).to_program())
+}
+
+/// Replaces hot multiplication loops, like `[->++>+<<]`, by `MulAdd`s followed by clearing the loop cell.
+pub struct HotLoopFusion {
+    hot: Vec<bool>,
+}
+
+impl HotLoopFusion {
+    pub fn new(profile: &Profile, threshold: u64) -> Self {
+        let hot = (0..profile.loops.len())
+            .map(|idx| profile.is_hot(idx, threshold))
+            .collect();
+
+        Self { hot }
+    }
+}
+
+impl Pass for HotLoopFusion {
+    fn name(&self) -> &str {
+        "hot-loop-fusion"
+    }
+
+    fn run(&self, program: ProgramIr) -> ProgramIr {
+        // Loops are gone into in the order of their `[`, a fused one has none in it.
+        let mut loop_idx = 0;
+
+        let body = optimizer::rebuild(
+            program.body,
+            &mut |node, bounds| {
+                let is_hot = self.hot.get(loop_idx).copied().unwrap_or(false);
+                loop_idx += 1;
+
+                // `MulAdd` multiplies by the cell under the pointer, so only loops on it can be fused,
+                // and only where the first cell can't stop the pointer going left in them.
+                match node {
+                    Ir::Loop {
+                        offset: 0, body, ..
+                    } if is_hot && bounds.balanced() => fuse_loop(body),
+                    _ => None,
+                }
+            },
+            &mut |block, _| block,
+        );
+
+        ProgramIr::new(body)
+    }
+}
+
+/// Returns the straight line equivalent of a loop whose body only adds and moves, comes back to
+/// where it started and changes the loop cell by exactly one per iteration.
+pub fn fuse_loop(body: &[Ir]) -> Option<Vec<Ir>> {
+    let mut shift = 0;
+    // What each cell gets added, with the span of the adds to it.
+    let mut adds = BTreeMap::new();
+
+    for node in body {
+        match *node {
+            Ir::Add {
+                offset,
+                value,
+                span,
+            } => {
+                let (cell, spans): &mut (u8, _) = adds.entry(shift + offset).or_default();
+                *cell = cell.wrapping_add(value);
+                *spans = ir::join(*spans, span);
+            }
+            Ir::Shift { amount, .. } => shift += amount,
+            _ => return None,
+        }
+    }
+
+    // With a step of +1 the loop runs (256 - cell) times, which is the same as negating every factor.
+    let (negate, span) = match adds.remove(&0) {
+        Some((255, span)) if shift == 0 => (false, span),
+        Some((1, span)) if shift == 0 => (true, span),
+        _ => return None,
+    };
+
+    let mut fused = adds
+        .into_iter()
+        .filter(|&(_, (factor, _))| factor != 0)
+        .map(|(offset, (factor, span))| Ir::MulAdd {
+            offset,
+            factor: if negate {
+                factor.wrapping_neg()
+            } else {
+                factor
+            },
+            span,
+        })
+        .collect::<Vec<_>>();
+
+    fused.push(Ir::Set {
+        offset: 0,
+        value: 0,
+        span,
+    });
+
+    Some(fused)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{empty, sink},
+        thread,
+    };
+
+    use crate::{
+        ir::Ir,
+        opcodes::OpCodeType,
+        parser::Program,
+        pgo::{self, fuse_loop, LoopCounts, Profile},
+        vm::{self, Vm},
+    };
+
+    #[test]
+    fn record_counts_loops() {
+        let program = vm::compile("++++[>++[>+<-]<-]").unwrap();
+        let profile = Profile::record(&program, &mut empty(), &mut sink()).unwrap();
+
+        let expected = vec![
+            LoopCounts {
+                entries: 1,
+                iterations: 4,
+            },
+            LoopCounts {
+                entries: 4,
+                iterations: 8,
+            },
+        ];
+
+        assert_eq!(profile.loops, expected);
+    }
+
+    #[test]
+    fn fuse_multiplication_loops() {
+        let body = vec![
+            Ir::add(255),
+            Ir::shift(1),
+            Ir::add(3),
+            Ir::shift(-2),
+            Ir::add(1),
+            Ir::shift(1),
+        ];
+        let expected = vec![
+            Ir::MulAdd {
+                offset: -1,
+                factor: 1,
+                span: None,
+            },
+            Ir::MulAdd {
+                offset: 1,
+                factor: 3,
+                span: None,
+            },
+            Ir::set(0),
+        ];
+
+        assert_eq!(fuse_loop(&body), Some(expected));
+
+        assert_eq!(
+            fuse_loop(&[Ir::add(1), Ir::shift(1), Ir::add(2), Ir::shift(-1)]).unwrap()[0],
+            Ir::MulAdd {
+                offset: 1,
+                factor: 254,
+                span: None,
+            }
+        );
+        assert_eq!(fuse_loop(&[Ir::add(255), Ir::shift(1)]), None);
+        assert_eq!(
+            fuse_loop(&[Ir::add(254), Ir::shift(1), Ir::shift(-1)]),
+            None
+        );
+        assert_eq!(fuse_loop(&[Ir::add(255), Ir::output(1)]), None);
+
+        let balanced = vec![
+            Ir::add(255),
+            Ir::Add {
+                offset: 2,
+                value: 4,
+                span: None,
+            },
+        ];
+        assert_eq!(
+            fuse_loop(&balanced).unwrap()[0],
+            Ir::MulAdd {
+                offset: 2,
+                factor: 4,
+                span: None,
+            }
+        );
+    }
+
+    #[test]
+    fn only_hot_loops_are_fused() {
+        let src = "++++++++[>++++++++<-]>+.>+++[>++<-]>.";
+        let program = vm::compile(src).unwrap();
+        let profile = Profile::record(&program, &mut empty(), &mut sink()).unwrap();
+
+        let optimized = pgo::optimize_with_threshold(&program, &profile, 5).unwrap();
+
+        let loops = |program: &Program| {
+            program
+                .iter()
+                .filter(|op| op.ty == OpCodeType::JmpZero)
+                .count()
+        };
+        assert_eq!(loops(&program), 2);
+        assert_eq!(loops(&optimized), 1);
+
+        let mut output = vec![];
+        Vm::from_program(optimized)
+            .unwrap()
+            .run_with(&mut empty(), &mut output)
+            .unwrap();
+
+        assert_eq!(output, b"A\x06");
+    }
+
+    #[test]
+    fn loops_the_first_cell_stops_are_not_fused() {
+        // `<` stops at the first cell: the loop adds to the cell it started on, once.
+        let program = vm::compile("+[-<+>]<.").unwrap();
+        let profile = Profile::record(&program, &mut empty(), &mut sink()).unwrap();
+        let optimized = pgo::optimize_with_threshold(&program, &profile, 0).unwrap();
+
+        let mut output = vec![];
+        Vm::from_program(optimized)
+            .unwrap()
+            .run_with(&mut empty(), &mut output)
+            .unwrap();
+        assert_eq!(output, [1]);
+    }
+
+    /// Loops nest as deep as the program does, fusing them can't take stack for every one.
+    #[test]
+    fn deep_nesting_is_not_recursed_into() {
+        let deep = || {
+            let depth = 100_000;
+            let src = format!("+{}->+<{}>.", "[".repeat(depth), "]".repeat(depth));
+            let program = vm::compile(&src).unwrap();
+            let profile = Profile::record(&program, &mut empty(), &mut sink()).unwrap();
+            let optimized = pgo::optimize_with_threshold(&program, &profile, 0).unwrap();
+
+            let mut output = vec![];
+            Vm::from_program(optimized)
+                .unwrap()
+                .run_with(&mut empty(), &mut output)
+                .unwrap();
+            assert_eq!(output, [1]);
+        };
+
+        thread::Builder::new()
+            .stack_size(256 << 10)
+            .spawn(deep)
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    #[test]
+    fn mismatched_profile_is_rejected() {
+        let program = vm::compile("+[-]>+[>+<-]").unwrap();
+
+        assert!(pgo::optimize(&program, &Profile::default()).is_err());
+    }
+}
@@ -0,0 +1,155 @@
+/*
+ *  Directives at the top of a program, setting the flags of `bf run` it needs so whoever runs it
+ *  doesn't have to know them:
+ *
+ *      ;; bf: tape=65536 cells=8 eof=zero dialect=extended1
+ *
+ *  They are the lines at the start of the file going `bf:` after any of `;`, `#`, `/`, `%` or `*`,
+ *  with blank lines and a `#!` line among them. The first other line ends them. Keys are `tape` for
+ *  --tape-size, `cells` for the cell size, which can only be 8, `eof`, `edge` for --tape-edge,
+ *  `compat` and `dialect`.
+ *
+ *  Directives are comments to Brainfuck, so they can't have its commands in them: `_` stands for `-`
+ *  in values, as in `dialect=multi_tape`. They go over the user config, the project and the
+ *  environment, flags given on the command line go over them, see `config`.
+ */
+
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    path::Path,
+};
+
+use anyhow::{bail, Result};
+
+use crate::config::Setting;
+
+/// Keys of directives, with the flag of `bf run` each one sets.
+pub const KEYS: [(&str, &str); 6] = [
+    ("tape", "tape-size"),
+    ("cells", "cell-size"),
+    ("eof", "eof"),
+    ("edge", "tape-edge"),
+    ("compat", "compat"),
+    ("dialect", "dialect"),
+];
+
+const COMMANDS: &str = "+-<>[].,";
+
+/// The settings of the directives at the top of the file at `path`, read up to the first line that
+/// isn't one.
+pub fn read(path: &Path) -> Result<Vec<Setting>> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut head = String::new();
+    let mut line = vec![];
+
+    // Files that aren't text, such as Brainloller images, have no directives.
+    while file.read_until(b'\n', &mut line)? > 0 {
+        let text = String::from_utf8_lossy(&line);
+        if !skipped(&text, head.is_empty()) && directive(&text).is_none() {
+            break;
+        }
+        head.push_str(&text);
+        line.clear();
+    }
+
+    parse(&head, &path.display().to_string())
+}
+
+/// The settings of the directives at the top of `text`, the program in the file `name`.
+pub fn parse(text: &str, name: &str) -> Result<Vec<Setting>> {
+    let mut settings = vec![];
+
+    for (i, line) in text.lines().enumerate() {
+        if skipped(line, i == 0) {
+            continue;
+        }
+        let Some(directive) = directive(line) else {
+            break;
+        };
+
+        let from = format!("{}:{}", name, i + 1);
+        if let Some(command) = directive.chars().find(|&ch| COMMANDS.contains(ch)) {
+            bail!(
+                "{}: `{}` is a Brainfuck command, directives can't have it, write `_` for `-`",
+                from,
+                command
+            );
+        }
+        for word in directive.split_whitespace() {
+            let Some((key, value)) = word.split_once('=') else {
+                bail!("{}: expected `key=value`, not `{}`", from, word);
+            };
+            let Some(&(_, flag)) = KEYS.iter().find(|&&(name, _)| name == key) else {
+                let keys: Vec<_> = KEYS.iter().map(|(key, _)| *key).collect();
+                bail!(
+                    "{}: unknown key `{}`, expected one of {}",
+                    from,
+                    key,
+                    keys.join(", ")
+                );
+            };
+
+            settings.push(Setting {
+                from: from.clone(),
+                flag: flag.to_string(),
+                value: Some(value.replace('_', "-")),
+            });
+        }
+    }
+
+    Ok(settings)
+}
+
+/// Whether `line` can be among the directives without being one: blank, or the `#!` line if it is
+/// the first.
+fn skipped(line: &str, first: bool) -> bool {
+    line.trim().is_empty() || (first && line.starts_with("#!"))
+}
+
+/// What follows `bf:` on a directive line.
+fn directive(line: &str) -> Option<&str> {
+    line.trim_start_matches(|ch: char| ";#/%*".contains(ch) || ch.is_whitespace())
+        .strip_prefix("bf:")
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{config::Setting, pragma};
+
+    #[test]
+    fn reads_directives() {
+        let text = "#!/usr/bin/env bf\n;; bf: tape=64K eof=zero\n\n# bf: dialect=multi_tape\n+.\n;; bf: cells=16\n";
+        let setting = |from: &str, flag: &str, value: &str| Setting {
+            from: from.to_string(),
+            flag: flag.to_string(),
+            value: Some(value.to_string()),
+        };
+
+        assert_eq!(
+            pragma::parse(text, "a.bf").unwrap(),
+            [
+                setting("a.bf:2", "tape-size", "64K"),
+                setting("a.bf:2", "eof", "zero"),
+                setting("a.bf:4", "dialect", "multi-tape"),
+            ]
+        );
+        assert!(pragma::parse("+\n;; bf: eof=zero", "a.bf")
+            .unwrap()
+            .is_empty());
+
+        let err = |text| pragma::parse(text, "a.bf").unwrap_err().to_string();
+        assert_eq!(
+            err(";; bf: eof=neg-1"),
+            "a.bf:1: `-` is a Brainfuck command, directives can't have it, write `_` for `-`"
+        );
+        assert_eq!(
+            err(";; bf: tape_size=1"),
+            "a.bf:1: unknown key `tape_size`, expected one of tape, cells, eof, edge, compat, dialect"
+        );
+        assert_eq!(
+            err("// bf: zero"),
+            "a.bf:1: expected `key=value`, not `zero`"
+        );
+    }
+}
@@ -0,0 +1,335 @@
+/*
+ *  Preprocessor, for `bf run --preprocess`: text reused across a program, put together before it is
+ *  lexed.
+ *
+ *      @include "FILE"     the lines of FILE, a path from the directory of the file including it
+ *      @def NAME CODE      make `@NAME` stand for CODE from the next line on, `@def cell10 ++++++++++`
+ *      @NAME, @NAME*N      CODE, or CODE N times over
+ *
+ *  Directives are lines of their own. A name is made of letters, digits and `_`, a `@` not followed
+ *  by one is left as it is. Like the snippets of the REPL, a macro is expanded when it is defined:
+ *  one using another keeps what that one was then. A file can't include itself, even through others.
+ *
+ *  Every line of the expanded text comes from one line of a file, which its `Origin` keeps along
+ *  with the column in the file of each of its columns, the `@` of the use for the code of a macro.
+ *  Errors in the program point at the files as written, see `Source::push_expanded`.
+ */
+
+use std::{collections::HashMap, fs, iter, path::Path};
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::lexer::{self, TokenLoc};
+
+/// How deep includes can go.
+pub const MAX_DEPTH: usize = 64;
+
+/// A file with its directives carried out.
+#[derive(Debug, Default)]
+pub struct Expanded {
+    pub text: String,
+    /// The lines of the files the lines of `text` come from, as written.
+    pub shown: String,
+    /// Where each line of `text` comes from.
+    pub origins: Vec<Origin>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Origin {
+    pub file: String,
+    pub line: usize,
+    /// The column in the file of each column of the line.
+    cols: Vec<usize>,
+}
+
+impl Origin {
+    /// Where column `col` of the expanded line is in the file.
+    pub fn locate(&self, col: usize) -> TokenLoc {
+        let col = col
+            .checked_sub(1)
+            .and_then(|i| self.cols.get(i))
+            .copied()
+            .unwrap_or(col);
+
+        TokenLoc::from_col_line(col, self.line)
+    }
+}
+
+/// Expands `text`, the file `name`, reading the files it includes from the disk.
+pub fn expand(name: &str, text: &str) -> Result<Expanded> {
+    expand_with(name, text, &mut |path| Ok(fs::read_to_string(path)?))
+}
+
+fn expand_with(
+    name: &str,
+    text: &str,
+    read: &mut dyn FnMut(&Path) -> Result<String>,
+) -> Result<Expanded> {
+    let mut preprocessor = Preprocessor {
+        read,
+        macros: HashMap::new(),
+        including: vec![],
+        out: Expanded::default(),
+    };
+    preprocessor.file(name, text)?;
+
+    Ok(preprocessor.out)
+}
+
+struct Preprocessor<'a> {
+    read: &'a mut dyn FnMut(&Path) -> Result<String>,
+    /// The code of every macro, expanded.
+    macros: HashMap<String, String>,
+    /// The files being read, each included by the one before.
+    including: Vec<String>,
+    out: Expanded,
+}
+
+impl Preprocessor<'_> {
+    fn file(&mut self, name: &str, text: &str) -> Result<()> {
+        if self.including.iter().any(|file| file == name) {
+            bail!("`{}` includes itself", name);
+        }
+        if self.including.len() == MAX_DEPTH {
+            bail!(
+                "includes go more than {} files deep at `{}`",
+                MAX_DEPTH,
+                name
+            );
+        }
+        self.including.push(name.to_string());
+
+        // The `#!` line is left out like in `Source::push`, keeping its line break.
+        let text = &text[lexer::shebang_len(text.as_bytes())..];
+        let mut lines: Vec<&str> = text.split('\n').collect();
+        if lines.len() > 1 && lines.last() == Some(&"") {
+            lines.pop();
+        }
+
+        for (i, &line) in lines.iter().enumerate() {
+            let line_cols = lexer::columns(line);
+            // Where the byte at `offset` of the line is in the file.
+            let at = |offset: usize| {
+                let col = line_cols.get(offset).copied().unwrap_or(offset + 1);
+                format!("{}:{}:{}", name, i + 1, col)
+            };
+
+            if let Some(args) = directive(line, "@include") {
+                let path = args
+                    .strip_prefix('"')
+                    .and_then(|args| args.strip_suffix('"'))
+                    .ok_or_else(|| anyhow!("expected `@include \"FILE\"` at {}:{}", name, i + 1))?;
+                let path = Path::new(name).parent().unwrap_or(Path::new("")).join(path);
+                let text = (self.read)(&path).map_err(|err| {
+                    anyhow!(
+                        "can't include `{}` at {}:{}: {}",
+                        path.display(),
+                        name,
+                        i + 1,
+                        err
+                    )
+                })?;
+                self.file(&path.display().to_string(), &text)?;
+                continue;
+            }
+
+            let (text, offsets) = match directive(line, "@def") {
+                Some(args) => {
+                    self.define(args, line, &at)?;
+                    (String::new(), vec![])
+                }
+                None => self.expand(line, 0, &at)?,
+            };
+            let mut cols = vec![];
+            for (col, offset) in lexer::columns(&text).into_iter().zip(offsets) {
+                cols.resize(col, line_cols[offset]);
+            }
+            self.push(&text, line);
+            self.out.origins.push(Origin {
+                file: name.to_string(),
+                line: i + 1,
+                cols,
+            });
+        }
+
+        self.including.pop();
+        Ok(())
+    }
+
+    /// `@def NAME CODE`, `args` being the part of `line` after `@def`.
+    fn define(&mut self, args: &str, line: &str, at: &dyn Fn(usize) -> String) -> Result<()> {
+        let (name, code) = args
+            .split_once(char::is_whitespace)
+            .map(|(name, code)| (name, code.trim()))
+            .unwrap_or((args, &args[args.len()..]));
+        if name.is_empty() || !name.chars().all(is_name_char) {
+            bail!(
+                "bad macro name `{}` at {}, use letters, digits and `_`",
+                name,
+                at(offset(line, name))
+            );
+        }
+        if matches!(name, "include" | "def") {
+            bail!(
+                "`{}` is the name of a directive, at {}",
+                name,
+                at(offset(line, name))
+            );
+        }
+
+        let (code, _) = self.expand(code, offset(line, code), at)?;
+        self.macros.insert(name.to_string(), code);
+
+        Ok(())
+    }
+
+    /// `text` with the macros it uses expanded, and the byte of the line each byte of it comes from.
+    /// `text` starts `start` bytes into its line.
+    fn expand(
+        &self,
+        text: &str,
+        start: usize,
+        at: &dyn Fn(usize) -> String,
+    ) -> Result<(String, Vec<usize>)> {
+        let mut out = String::with_capacity(text.len());
+        let mut offsets = Vec::with_capacity(text.len());
+        let mut pos = 0;
+
+        while let Some(found) = text[pos..].find('@') {
+            let use_at = pos + found;
+            out.push_str(&text[pos..use_at]);
+            offsets.extend(start + pos..start + use_at);
+
+            let after = &text[use_at + 1..];
+            let len = after.find(|ch| !is_name_char(ch)).unwrap_or(after.len());
+            let name = &after[..len];
+            pos = use_at + 1 + len;
+            if name.is_empty() {
+                out.push('@');
+                offsets.push(start + use_at);
+                continue;
+            }
+
+            let digits = after[len..]
+                .strip_prefix('*')
+                .map(|count| {
+                    count
+                        .find(|ch: char| !ch.is_ascii_digit())
+                        .unwrap_or(count.len())
+                })
+                .unwrap_or(0);
+            let count = match digits {
+                0 => 1,
+                _ => {
+                    let count = &after[len + 1..len + 1 + digits];
+                    pos += 1 + digits;
+                    count.parse().map_err(|_| {
+                        anyhow!("`*{}` is too many times, at {}", count, at(start + use_at))
+                    })?
+                }
+            };
+
+            let code = self
+                .macros
+                .get(name)
+                .ok_or_else(|| anyhow!("undefined macro `@{}` at {}", name, at(start + use_at)))?
+                .repeat(count);
+            offsets.extend(iter::repeat_n(start + use_at, code.len()));
+            out.push_str(&code);
+        }
+        out.push_str(&text[pos..]);
+        offsets.extend(start + pos..start + text.len());
+
+        Ok((out, offsets))
+    }
+
+    /// Adds a line of expanded text, from the line `shown`.
+    fn push(&mut self, text: &str, shown: &str) {
+        if !self.out.origins.is_empty() {
+            self.out.text.push('\n');
+            self.out.shown.push('\n');
+        }
+        self.out.text.push_str(text);
+        self.out.shown.push_str(shown);
+    }
+}
+
+/// What follows the directive `word` if `line` is one.
+fn directive<'l>(line: &'l str, word: &str) -> Option<&'l str> {
+    let rest = line.trim_start().strip_prefix(word)?;
+
+    match rest.chars().next() {
+        None => Some(rest),
+        Some(ch) if ch.is_whitespace() => Some(rest.trim()),
+        Some(_) => None,
+    }
+}
+
+/// Where `part`, a slice of `line`, starts in it.
+fn offset(line: &str, part: &str) -> usize {
+    part.as_ptr() as usize - line.as_ptr() as usize
+}
+
+fn is_name_char(ch: char) -> bool {
+    ch.is_ascii_alphanumeric() || ch == '_'
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use anyhow::anyhow;
+
+    use crate::{
+        lexer::TokenLoc,
+        preprocess::{expand_with, Expanded},
+    };
+
+    fn expand(files: &[(&str, &str)]) -> anyhow::Result<Expanded> {
+        let mut read = |path: &Path| {
+            files
+                .iter()
+                .find(|(name, _)| Path::new(name) == path)
+                .map(|(_, text)| text.to_string())
+                .ok_or_else(|| anyhow!("no such file"))
+        };
+
+        expand_with(files[0].0, files[0].1, &mut read)
+    }
+
+    #[test]
+    fn expands_macros_and_includes() {
+        let expanded = expand(&[
+            (
+                "src/main.bf",
+                "@include \"lib/cells.bf\"\n@def two @one*2\n>@two*3. a @ b\n",
+            ),
+            ("src/lib/cells.bf", "#!/usr/bin/env bf\n@def one +\n@one-"),
+        ])
+        .unwrap();
+
+        assert_eq!(expanded.text, "\n\n+-\n\n>++++++. a @ b");
+        assert_eq!(
+            expanded.shown,
+            "\n@def one +\n@one-\n@def two @one*2\n>@two*3. a @ b"
+        );
+        let origins = &expanded.origins;
+        assert_eq!(origins[2].file, "src/lib/cells.bf");
+        assert_eq!(origins[2].locate(2), TokenLoc::from_col_line(5, 3));
+        assert_eq!(origins[4].file, "src/main.bf");
+        assert_eq!(origins[4].locate(7), TokenLoc::from_col_line(2, 3));
+        assert_eq!(origins[4].locate(8), TokenLoc::from_col_line(8, 3));
+
+        let err = |files| expand(files).unwrap_err().to_string();
+        assert_eq!(
+            err(&[("a.bf", "+\n @x")]),
+            "undefined macro `@x` at a.bf:2:2"
+        );
+        assert_eq!(
+            err(&[("a.bf", "@include \"b.bf\""), ("b.bf", "@include \"a.bf\"")]),
+            "`a.bf` includes itself"
+        );
+        assert!(expand(&[("a.bf", "@include b.bf")]).is_err());
+        assert!(expand(&[("a.bf", "@def include +")]).is_err());
+    }
+}
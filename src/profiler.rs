@@ -0,0 +1,334 @@
+/*
+ *  Execution profile, for `--profile`: how many times each instruction ran and how long it took,
+ *  summed up per opcode type and per pc.
+ *
+ *  `LoopProfiler`, for `--profile-loops`, does the same per loop of the source. It has to run the
+ *  program unoptimized so every loop is still there and maps to its brackets; loops the optimizer
+ *  removes (like `[-]`) show up too, with the time they take without it.
+ *
+ *  Time is measured between two instructions and goes to the first one, so it includes the cost of
+ *  measuring. The shares are what to look at, not the absolute times, which are a lot higher than in a
+ *  normal run.
+ */
+
+use std::{
+    fmt::Write,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+
+use crate::{
+    asm,
+    opcodes::{OpCode, OpCodeType},
+    parser::Span,
+    pgo::LoopCounts,
+    vm::{Observer, Vm},
+};
+
+/// Rows of the per-pc table.
+const TOP_PCS: usize = 20;
+
+#[derive(Default)]
+pub struct Profiler {
+    counts: Vec<u64>,
+    times: Vec<Duration>,
+    /// The instruction running since when.
+    running: Option<(usize, Instant)>,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The profile as two tables, per opcode type and for the slowest pcs, heaviest first.
+    pub fn report(&self, program: &[OpCode]) -> String {
+        let total = self
+            .times
+            .iter()
+            .sum::<Duration>()
+            .as_secs_f64()
+            .max(f64::MIN_POSITIVE);
+        let share = |time: Duration| 100.0 * time.as_secs_f64() / total;
+
+        let mut types: Vec<(OpCodeType, u64, Duration)> = OpCodeType::ALL
+            .into_iter()
+            .map(|ty| {
+                let pcs = (0..self.counts.len()).filter(|&pc| program[pc].ty == ty);
+                let (count, time) = pcs.fold((0, Duration::ZERO), |(count, time), pc| {
+                    (count + self.counts[pc], time + self.times[pc])
+                });
+                (ty, count, time)
+            })
+            .filter(|&(_, count, _)| count > 0)
+            .collect();
+        types.sort_by_key(|&(_, _, time)| std::cmp::Reverse(time));
+
+        let mut out = String::new();
+        writeln!(
+            out,
+            "{:<10} {:>14} {:>12} {:>7}",
+            "opcode", "count", "time", "share"
+        )
+        .unwrap();
+        for (ty, count, time) in types {
+            writeln!(
+                out,
+                "{:<10} {:>14} {:>12.3?} {:>6.1}%",
+                ty.mnemonic(),
+                count,
+                time,
+                share(time)
+            )
+            .unwrap();
+        }
+
+        let mut pcs: Vec<usize> = (0..self.counts.len())
+            .filter(|&pc| self.counts[pc] > 0)
+            .collect();
+        pcs.sort_by_key(|&pc| std::cmp::Reverse(self.times[pc]));
+
+        writeln!(out).unwrap();
+        writeln!(
+            out,
+            "{:<6} {:<18} {:>14} {:>12} {:>7}",
+            "pc", "instruction", "count", "time", "share"
+        )
+        .unwrap();
+        for pc in pcs.into_iter().take(TOP_PCS) {
+            writeln!(
+                out,
+                "{:06} {:<18} {:>14} {:>12.3?} {:>6.1}%",
+                pc,
+                asm::instruction(&program[pc]),
+                self.counts[pc],
+                self.times[pc],
+                share(self.times[pc])
+            )
+            .unwrap();
+        }
+
+        out
+    }
+
+    fn stop_running(&mut self, now: Instant) {
+        if let Some((pc, since)) = self.running.take() {
+            self.times[pc] += now - since;
+        }
+    }
+}
+
+impl Observer for Profiler {
+    fn instruction(&mut self, vm: &Vm) -> Result<()> {
+        let now = Instant::now();
+        self.stop_running(now);
+
+        let pc = vm.pc();
+        if self.counts.is_empty() {
+            self.counts = vec![0; vm.program().len()];
+            self.times = vec![Duration::ZERO; vm.program().len()];
+        }
+        self.counts[pc] += 1;
+        self.running = Some((pc, now));
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.stop_running(Instant::now());
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct LoopStats {
+    /// The pc of the `]`.
+    end: usize,
+    counts: LoopCounts,
+    /// Instructions run from the `[` until the loop was left, nested loops included.
+    instructions: u64,
+    time: Duration,
+}
+
+pub struct LoopProfiler {
+    /// Per pc of a `[`.
+    loops: Vec<Option<LoopStats>>,
+    instructions: u64,
+    /// The loops being run, innermost last, with when they were entered.
+    open: Vec<(usize, u64, Instant)>,
+    start: Instant,
+    total: Duration,
+}
+
+impl LoopProfiler {
+    /// For `program`, which has to be unoptimized.
+    pub fn new(program: &[OpCode]) -> Self {
+        let loops = program
+            .iter()
+            .map(|op| {
+                (op.ty == OpCodeType::JmpZero).then(|| LoopStats {
+                    end: op.data,
+                    ..LoopStats::default()
+                })
+            })
+            .collect();
+
+        Self {
+            loops,
+            instructions: 0,
+            open: vec![],
+            start: Instant::now(),
+            total: Duration::ZERO,
+        }
+    }
+
+    /// The `top` loops that took longest, with `spans` from `parser::spans` to say where they are.
+    pub fn report(&self, spans: &[Span], top: usize) -> String {
+        let total = self.total.as_secs_f64().max(f64::MIN_POSITIVE);
+
+        let mut loops: Vec<(usize, LoopStats)> = self
+            .loops
+            .iter()
+            .enumerate()
+            .filter_map(|(pc, stats)| Some((pc, (*stats)?)))
+            .filter(|(_, stats)| stats.counts.entries > 0)
+            .collect();
+        loops.sort_by_key(|(_, stats)| std::cmp::Reverse(stats.time));
+
+        let mut out = String::new();
+        writeln!(
+            out,
+            "{:<16} {:>12} {:>14} {:>12} {:>16} {:>12} {:>7}",
+            "loop", "entries", "iterations", "per entry", "instructions", "time", "share"
+        )
+        .unwrap();
+        for (pc, stats) in loops.into_iter().take(top) {
+            let LoopCounts {
+                entries,
+                iterations,
+            } = stats.counts;
+            writeln!(
+                out,
+                "{:<16} {:>12} {:>14} {:>12.1} {:>16} {:>12.3?} {:>6.1}%",
+                format!("{}-{}", spans[pc].0, spans[stats.end].1),
+                entries,
+                iterations,
+                iterations as f64 / entries as f64,
+                stats.instructions,
+                stats.time,
+                100.0 * stats.time.as_secs_f64() / total
+            )
+            .unwrap();
+        }
+
+        out
+    }
+
+    fn close(&mut self, now: Instant) {
+        if let Some((pc, instructions, since)) = self.open.pop() {
+            let stats = self.loops[pc].as_mut().unwrap();
+            stats.instructions += self.instructions - instructions;
+            stats.time += now - since;
+        }
+    }
+}
+
+impl Observer for LoopProfiler {
+    fn instruction(&mut self, vm: &Vm) -> Result<()> {
+        let now = Instant::now();
+        let op = vm.program()[vm.pc()];
+        let cell = vm.mem()[vm.mem_ptr()];
+        self.instructions += 1;
+
+        match op.ty {
+            OpCodeType::JmpZero => {
+                let stats = self.loops[vm.pc()].as_mut().unwrap();
+                stats.counts.entries += 1;
+                if cell != 0 {
+                    self.open.push((vm.pc(), self.instructions - 1, now));
+                }
+            }
+            OpCodeType::JmpNotZero => {
+                self.loops[op.data].as_mut().unwrap().counts.iterations += 1;
+                if cell == 0 {
+                    self.close(now);
+                }
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        let now = Instant::now();
+        while !self.open.is_empty() {
+            self.close(now);
+        }
+        self.total = now - self.start;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        lexer, parser,
+        profiler::{LoopProfiler, Profiler},
+        vm::Vm,
+    };
+
+    #[test]
+    fn counts_instructions() {
+        let mut vm = Vm::new("+++[>++<-]>.").unwrap();
+        let mut profiler = Profiler::new();
+        vm.run_observed(&mut &b""[..], &mut vec![], &mut profiler)
+            .unwrap();
+
+        let report = profiler.report(vm.program());
+        let counts: Vec<_> = report
+            .lines()
+            .skip(1)
+            .take_while(|line| !line.is_empty())
+            .map(|line| {
+                let words: Vec<_> = line.split_whitespace().collect();
+                (words[0], words[1])
+            })
+            .collect();
+
+        assert!(counts.contains(&("ADD", "4")));
+        assert!(counts.contains(&("OUT", "1")));
+        assert_eq!(
+            report.lines().filter(|line| line.starts_with("00")).count(),
+            vm.program().len()
+        );
+    }
+
+    #[test]
+    fn counts_loops() {
+        let src = "+++[>++[>+<-]<-]\n[never]";
+        let tokens = lexer::parse(src);
+        let spans = parser::spans(&tokens);
+        let program = parser::parse(tokens).unwrap();
+
+        let mut profiler = LoopProfiler::new(&program);
+        Vm::from_program(program)
+            .unwrap()
+            .run_observed(&mut &b""[..], &mut vec![], &mut profiler)
+            .unwrap();
+
+        let report = profiler.report(&spans, 10);
+        let rows: Vec<Vec<_>> = report
+            .lines()
+            .skip(1)
+            .map(|line| line.split_whitespace().take(5).collect())
+            .collect();
+
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[0], ["1:4-1:16", "1", "3", "3.0", "49"]);
+        assert_eq!(rows[1], ["1:8-1:13", "3", "6", "2.0", "33"]);
+        assert_eq!(rows[2][..3], ["2:1-2:7", "1", "0"]);
+    }
+}
@@ -0,0 +1,200 @@
+/*
+ *  Project manifests: a `bf.toml` in the working directory lists the programs of a project, the
+ *  flags every `bf run` in it starts with and where its golden tests are, so `bf run`, `bf check` and
+ *  `bf test` work without arguments. It is the small part of TOML the manifests of `bf run-batch`
+ *  are written in, with booleans.
+ *
+ *      tests = "tests"             directory of golden tests, `bf test` runs `bf test tests`
+ *
+ *      [run]                       flags of `bf run`, by their long names
+ *      tape_size = "64K"           --tape-size=64K
+ *      dialect = "brainfuck"
+ *      backend = "jit-x64"
+ *      quicken = true              --quicken, false leaves it out
+ *      opt_level = 1               --opt-level=1
+ *
+ *      [[program]]
+ *      name = "hello"              `bf run hello`, the file name without extension if left out
+ *      file = "src/hello.bf"       the first program is the one `bf run` runs
+ *
+ *  Flags given on the command line go over the ones of the manifest. Paths are from the directory of
+ *  the manifest.
+ */
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::batch::Value;
+
+pub const FILE_NAME: &str = "bf.toml";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+    pub name: String,
+    pub file: PathBuf,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Project {
+    /// Flags of `bf run` by their long names, with their values, None for switches.
+    pub flags: Vec<(String, Option<String>)>,
+    pub programs: Vec<Program>,
+    pub tests: Option<PathBuf>,
+}
+
+impl Project {
+    /// The program called `name`, the first one for None.
+    pub fn program(&self, name: Option<&str>) -> Option<&Program> {
+        match name {
+            Some(name) => self.programs.iter().find(|program| program.name == name),
+            None => self.programs.first(),
+        }
+    }
+}
+
+/// The project of the working directory, None if it has no manifest.
+pub fn find() -> Result<Option<Project>> {
+    let path = Path::new(FILE_NAME);
+    if !path.is_file() {
+        return Ok(None);
+    }
+
+    read(path).map(Some)
+}
+
+pub fn read(path: &Path) -> Result<Project> {
+    let text = fs::read_to_string(path)?;
+    let dir = path.parent().unwrap_or(Path::new(""));
+
+    parse(&text, dir).map_err(|err| anyhow!("{}: {}", path.display(), err))
+}
+
+enum Table {
+    Top,
+    Run,
+    /// The program, where it starts.
+    Program(usize),
+}
+
+/// The project of a manifest in `dir`.
+pub fn parse(text: &str, dir: &Path) -> Result<Project> {
+    let mut project = Project::default();
+    // The keys of each program, where it starts.
+    let mut programs: Vec<(usize, Vec<(String, Value)>)> = vec![];
+    let mut table = Table::Top;
+
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line {
+            "[run]" => {
+                table = Table::Run;
+                continue;
+            }
+            "[[program]]" => {
+                programs.push((i + 1, vec![]));
+                table = Table::Program(programs.len() - 1);
+                continue;
+            }
+            _ if line.starts_with('[') => bail!("unknown table `{}` at line {}", line, i + 1),
+            _ => {}
+        }
+
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| anyhow!("expected `key = value` or a table at line {}", i + 1))?;
+        let key = key.trim().to_string();
+        let value =
+            Value::parse(value.trim()).map_err(|err| anyhow!("{} at line {}", err, i + 1))?;
+        match table {
+            Table::Top if key == "tests" => project.tests = Some(dir.join(value.string(&key)?)),
+            Table::Top => bail!("unknown key `{}` at line {}", key, i + 1),
+            Table::Run => {
+                let flag = key.replace('_', "-");
+                match value {
+                    Value::String(string) => project.flags.push((flag, Some(string))),
+                    Value::Integer(integer) => {
+                        project.flags.push((flag, Some(integer.to_string())))
+                    }
+                    Value::Bool(true) => project.flags.push((flag, None)),
+                    Value::Bool(false) => {}
+                }
+            }
+            Table::Program(program) => programs[program].1.push((key, value)),
+        }
+    }
+
+    for (line, keys) in programs {
+        let (mut name, mut file) = (None, None);
+        for (key, value) in keys {
+            match key.as_str() {
+                "name" => name = Some(value.string(&key)?),
+                "file" => file = Some(dir.join(value.string(&key)?)),
+                _ => bail!("unknown key `{}` in the program at line {}", key, line),
+            }
+        }
+        let Some(file) = file else {
+            bail!("the program at line {} has no `file`", line);
+        };
+        let name = name.unwrap_or_else(|| {
+            let stem = file.file_stem().unwrap_or_default();
+            stem.to_string_lossy().into_owned()
+        });
+
+        project.programs.push(Program { name, file });
+    }
+
+    Ok(project)
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use crate::project::{self, Program, Project};
+
+    #[test]
+    fn reads_manifests() {
+        let manifest = "tests = \"tests\"\n\n[run]\ntape_size = \"64K\"  # more\neof = \"zero\"\nquicken = true\nopt_level = 1\npgo = false\n\n[[program]]\nfile = \"src/hello.bf\"\n\n[[program]]\nname = \"echo\"\nfile = \"cat.b\"\n";
+        let project = project::parse(manifest, Path::new("dir")).unwrap();
+
+        assert_eq!(
+            project,
+            Project {
+                flags: vec![
+                    ("tape-size".to_string(), Some("64K".to_string())),
+                    ("eof".to_string(), Some("zero".to_string())),
+                    ("quicken".to_string(), None),
+                    ("opt-level".to_string(), Some("1".to_string())),
+                ],
+                programs: vec![
+                    Program {
+                        name: "hello".to_string(),
+                        file: "dir/src/hello.bf".into(),
+                    },
+                    Program {
+                        name: "echo".to_string(),
+                        file: "dir/cat.b".into(),
+                    },
+                ],
+                tests: Some("dir/tests".into()),
+            }
+        );
+        assert_eq!(project.program(None), project.programs.first());
+        assert_eq!(project.program(Some("echo")), project.programs.get(1));
+
+        let err = |text| project::parse(text, Path::new("")).unwrap_err().to_string();
+        assert_eq!(err("[tests]"), "unknown table `[tests]` at line 1");
+        assert_eq!(err("tape_size = 1"), "unknown key `tape_size` at line 1");
+        assert_eq!(
+            err("[[program]]\nname = \"a\""),
+            "the program at line 1 has no `file`"
+        );
+    }
+}
@@ -0,0 +1,165 @@
+/*
+ *  Runtime opcode quickening.
+ *  The first time the VM reaches a loop it looks at the body and, when it can, swaps the `JmpZero` for a
+ *  specialized form which runs the whole loop at once. The body and the `JmpNotZero` stay in place, so a
+ *  quickened loop can always fall back to running as a plain loop.
+ */
+
+use std::sync::Arc;
+
+use crate::{
+    opcodes::{OpCode, OpCodeType},
+    parser::Program,
+};
+
+/// What the VM needs to quicken a program while running it.
+#[derive(Debug)]
+pub struct Quickening {
+    /// The program as it was before any rewrite, the first one making the VM a copy of its own.
+    pub original: Arc<Program>,
+    visited: Vec<bool>,
+}
+
+impl Quickening {
+    pub fn new(program: &Arc<Program>) -> Self {
+        Self {
+            original: program.clone(),
+            visited: vec![false; program.len()],
+        }
+    }
+
+    /// Quickens the loop at `start` the first time it is reached.
+    #[inline]
+    pub fn visit(&mut self, program: &mut Arc<Program>, start: usize) {
+        if !self.visited[start] {
+            self.visited[start] = true;
+
+            if let Some(ty) = quicken_loop(program, start) {
+                Arc::make_mut(program)[start].ty = ty;
+            }
+        }
+    }
+}
+
+/// Returns the quickened form of the `JmpZero` at `start`, if its loop has one.
+pub fn quicken_loop(program: &Program, start: usize) -> Option<OpCodeType> {
+    let OpCode {
+        ty,
+        data: end,
+        offset,
+    } = *program.get(start)?;
+    if ty != OpCodeType::JmpZero {
+        return None;
+    }
+
+    let body = program.get(start + 1..end)?;
+
+    if let [op] = body {
+        if matches!(op.ty, OpCodeType::Add | OpCodeType::Sub)
+            && op.offset == offset
+            && op.data % 2 == 1
+        {
+            return Some(OpCodeType::ClearLoop);
+        }
+    }
+
+    // Closed form needs the loop to come back where it started and step its cell by one.
+    let mut shift = 0isize;
+    let mut step = 0u8;
+
+    for op in body {
+        match op.ty {
+            OpCodeType::ShiftRight => shift += op.data as isize,
+            OpCodeType::ShiftLeft => shift -= op.data as isize,
+            OpCodeType::Add | OpCodeType::Sub if shift + op.offset as isize == offset as isize => {
+                step = match op.ty {
+                    OpCodeType::Add => step.wrapping_add(op.data as u8),
+                    _ => step.wrapping_sub(op.data as u8),
+                };
+            }
+            OpCodeType::Add | OpCodeType::Sub => {}
+            _ => return None,
+        }
+    }
+
+    (shift == 0 && matches!(step, 1 | 255)).then_some(OpCodeType::MulLoop)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io::empty, ptr, sync::Arc};
+
+    use crate::{
+        lexer,
+        opcodes::OpCodeType::*,
+        parser::{self, Program},
+        quicken::quicken_loop,
+        vm::Vm,
+    };
+
+    fn parse(src: &str) -> Program {
+        parser::parse(lexer::parse(src)).unwrap()
+    }
+
+    #[test]
+    fn quickened_forms() {
+        assert_eq!(quicken_loop(&parse("[-]"), 0), Some(ClearLoop));
+        assert_eq!(quicken_loop(&parse("[+++]"), 0), Some(ClearLoop));
+        assert_eq!(quicken_loop(&parse("[->++>+<<]"), 0), Some(MulLoop));
+        assert_eq!(quicken_loop(&parse("[>+<+]"), 0), Some(MulLoop));
+        assert_eq!(quicken_loop(&parse("[--]"), 0), None);
+        assert_eq!(quicken_loop(&parse("[->+]"), 0), None);
+        assert_eq!(quicken_loop(&parse("[->.<]"), 0), None);
+        assert_eq!(quicken_loop(&parse("[->[-]<]"), 0), None);
+    }
+
+    fn run(src: &str, quicken: bool) -> (Vec<u8>, Vm) {
+        let mut vm = Vm::from_program(parse(src)).unwrap();
+        if quicken {
+            vm.enable_quickening();
+        }
+
+        let mut output = vec![];
+        vm.run_with(&mut empty(), &mut output).unwrap();
+
+        (output, vm)
+    }
+
+    #[test]
+    fn quickened_program_keeps_behavior() {
+        let src = "+++++[>+++++++++++++<-]>[>+>++<<-]+[-]>>[-<+>]<<+[>+<+]>.>.";
+        let (output, vm) = run(src, true);
+
+        assert_eq!(output, run(src, false).0);
+        assert_eq!(output, [0xc2, 0]);
+
+        let quickened = vm
+            .program()
+            .iter()
+            .filter(|op| op.ty != JmpZero && op.ty.is_loop_start());
+        assert_eq!(quickened.count(), 5);
+        assert_eq!(vm.original_program(), &parse(src));
+    }
+
+    #[test]
+    fn quickening_leaves_a_shared_program_alone() {
+        let program = Arc::new(parse("++[-]>[-<+>]<."));
+        let mut vm = Vm::from_shared(program.clone()).unwrap();
+        vm.enable_quickening();
+        let other = Vm::from_shared(vm.shared_program()).unwrap();
+        assert!(ptr::eq(other.program(), &*program));
+
+        vm.run_with(&mut empty(), &mut vec![]).unwrap();
+        assert_eq!(vm.program()[1].ty, ClearLoop);
+        assert_eq!(other.program(), &*program);
+    }
+
+    #[test]
+    fn quickened_loop_falls_back_at_the_left_edge() {
+        // `<` stays on cell 0, so the first iteration gives back what it took and ends on cell 1.
+        let src = "+++[-<+>]<.";
+
+        assert_eq!(run(src, true).0, [3]);
+        assert_eq!(run(src, false).0, [3]);
+    }
+}
@@ -0,0 +1,398 @@
+/*
+ *  Reference interpreter: walks the loop tree of the IR, one node at a time, with every access bounds
+ *  checked. Slow on purpose and kept simple enough to be obviously right, it is the oracle the VM, the
+ *  optimizer and the native backends are tested against. Errors use the same messages as the VM.
+ */
+
+use std::io::{Read, Write};
+
+use anyhow::{bail, Result};
+
+use crate::{
+    ir::{self, Ir, ProgramIr},
+    lexer, parser,
+    vm::{self, MemoryError, DEFAULT_VM_MEM_SIZE},
+};
+
+/// Parses `src` without optimizing it and runs it.
+pub fn run_src(src: &str, input: &mut dyn Read, output: &mut dyn Write) -> Result<()> {
+    let program = parser::parse(lexer::parse(src))?;
+    let ir = ProgramIr::from_program(&program)?;
+
+    let mut reference = Reference::new();
+    reference.load(ir.body);
+    reference.run(input, output)
+}
+
+#[derive(Debug)]
+pub struct Reference {
+    mem: Vec<u8>,
+    ptr: usize,
+    /// Program loaded by `load`, run by `step`.
+    body: Vec<Ir>,
+    /// Position in each loop `step` is in, outermost first.
+    cursor: Vec<usize>,
+}
+
+impl Default for Reference {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Reference {
+    pub fn new() -> Self {
+        Self::with_tape_size(DEFAULT_VM_MEM_SIZE)
+    }
+
+    pub fn with_tape_size(size: usize) -> Self {
+        Self {
+            mem: vec![0; size],
+            ptr: 0,
+            body: vec![],
+            cursor: vec![0],
+        }
+    }
+
+    pub fn mem(&self) -> &[u8] {
+        &self.mem
+    }
+
+    pub fn ptr(&self) -> usize {
+        self.ptr
+    }
+
+    /// Puts `cells` at the start of the tape, which has to be long enough.
+    pub fn load_tape(&mut self, cells: &[u8]) -> Result<()> {
+        if cells.len() > self.mem.len() {
+            bail!(
+                "the tape has {} cells, too few for the {} to load",
+                self.mem.len(),
+                cells.len()
+            );
+        }
+
+        self.mem[..cells.len()].copy_from_slice(cells);
+        Ok(())
+    }
+
+    /// Replaces the program and resets the machine.
+    pub fn load(&mut self, body: Vec<Ir>) {
+        self.body = body;
+        self.reset();
+    }
+
+    /// Clears the tape and moves the pointer and `step` back to the start.
+    pub fn reset(&mut self) {
+        self.mem.fill(0);
+        self.ptr = 0;
+        self.cursor = vec![0];
+    }
+
+    /// Runs the loaded program to the end, from where `step` left it.
+    pub fn run(&mut self, input: &mut dyn Read, output: &mut dyn Write) -> Result<()> {
+        if self.cursor != [0] {
+            while self.step(input, output)? {}
+            return Ok(());
+        }
+
+        let body = std::mem::take(&mut self.body);
+        let result = self.run_block(&body, input, output);
+        if result.is_ok() {
+            self.cursor = vec![body.len()];
+        }
+        self.body = body;
+
+        result
+    }
+
+    /// Where the loaded program is, as the pc of the program `ProgramIr::to_program` lowers it to: the
+    /// node `step` runs next, or the one a failed run stopped at.
+    pub fn pc(&self) -> usize {
+        let (&pos, outer) = self.cursor.split_last().unwrap();
+
+        let mut pc = 0;
+        let mut block = &self.body[..];
+        for &i in outer {
+            // The nodes before the loop, then its `JmpZero`.
+            pc += ir::walk(&block[..i]).count() + 1;
+            let Ir::Loop { body, .. } = &block[i] else {
+                unreachable!("the cursor only enters loops");
+            };
+            block = body;
+        }
+
+        pc + ir::walk(&block[..pos.min(block.len())]).count()
+    }
+
+    /// Runs one node of the loaded program, testing a loop's condition counts as one.
+    /// Returns false if the program had already ended.
+    pub fn step(&mut self, input: &mut dyn Read, output: &mut dyn Write) -> Result<bool> {
+        let body = std::mem::take(&mut self.body);
+        let result = self.step_in(&body, input, output);
+        self.body = body;
+
+        result
+    }
+
+    fn step_in(
+        &mut self,
+        body: &[Ir],
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+    ) -> Result<bool> {
+        loop {
+            let (&pos, outer) = self.cursor.split_last().unwrap();
+
+            let mut block = body;
+            for &i in outer {
+                let Ir::Loop { body, .. } = &block[i] else {
+                    unreachable!("the cursor only enters loops");
+                };
+                block = body;
+            }
+
+            match block.get(pos) {
+                // Back at the loop, which checks its condition again.
+                None if !outer.is_empty() => {
+                    self.cursor.pop();
+                }
+                None => return Ok(false),
+                Some(&Ir::Loop { offset, .. }) => {
+                    if *self.cell(offset)? != 0 {
+                        self.cursor.push(0);
+                    } else {
+                        *self.cursor.last_mut().unwrap() += 1;
+                    }
+
+                    return Ok(true);
+                }
+                Some(node) => {
+                    self.run_node(node, input, output)?;
+                    *self.cursor.last_mut().unwrap() += 1;
+
+                    return Ok(true);
+                }
+            }
+        }
+    }
+
+    /// Runs `block`, the loaded program, from the start. A failed run leaves the cursor of `step` at
+    /// the node it failed at, the same as the VM leaves its pc.
+    fn run_block(
+        &mut self,
+        block: &[Ir],
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+    ) -> Result<()> {
+        // Loops nest as deep as the program does: each block being run, innermost last, with the
+        // position of the next node in it.
+        let mut stack = vec![(block, 0)];
+
+        while let Some(&(block, pos)) = stack.last() {
+            let result = match block.get(pos) {
+                // Back at the loop, which checks its condition again.
+                None => {
+                    stack.pop();
+                    continue;
+                }
+                Some(&Ir::Loop {
+                    offset, ref body, ..
+                }) => match self.cell(offset) {
+                    Ok(&mut 0) => Ok(()),
+                    Ok(_) => {
+                        stack.push((body, 0));
+                        continue;
+                    }
+                    Err(err) => Err(err),
+                },
+                Some(node) => self.run_node(node, input, output),
+            };
+
+            if let Err(err) = result {
+                self.cursor = stack.iter().map(|&(_, pos)| pos).collect();
+                return Err(err);
+            }
+            stack.last_mut().unwrap().1 += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Runs a node other than a loop.
+    fn run_node(&mut self, node: &Ir, input: &mut dyn Read, output: &mut dyn Write) -> Result<()> {
+        match *node {
+            Ir::Add { offset, value, .. } => {
+                let cell = self.cell(offset)?;
+                *cell = cell.wrapping_add(value);
+            }
+            Ir::Shift { amount, .. } if amount < 0 => {
+                self.ptr = self.ptr.saturating_sub(amount.unsigned_abs());
+            }
+            Ir::Shift { amount, .. } => {
+                self.ptr += amount as usize;
+                self.index(0)?;
+            }
+            Ir::Set { offset, value, .. } => *self.cell(offset)? = value,
+            Ir::MulAdd { offset, factor, .. } => {
+                let value = *self.cell(0)?;
+
+                // A fused loop would not have touched memory when not entered.
+                if value != 0 {
+                    let cell = self.cell(offset)?;
+                    *cell = cell.wrapping_add(value.wrapping_mul(factor));
+                }
+            }
+            Ir::Input { offset, .. } => {
+                let mut byte = [0];
+                input.read_exact(&mut byte)?;
+                *self.cell(offset)? = byte[0];
+            }
+            Ir::Output { offset, count, .. } => {
+                let ch = *self.cell(offset)?;
+                vm::write_repeated(output, ch, count)?;
+            }
+            Ir::Fill {
+                offset, len, value, ..
+            } => {
+                for i in 0..len as isize {
+                    *self.cell(offset + i)? = value;
+                }
+            }
+            Ir::Loop { .. } => unreachable!("loops are run by `run_block`"),
+        }
+
+        Ok(())
+    }
+
+    fn index(&self, offset: isize) -> Result<usize> {
+        let mem_count = self.mem.len();
+
+        match self.ptr.checked_add_signed(offset) {
+            Some(idx) if idx < mem_count => Ok(idx),
+            Some(idx) => Err(MemoryError::Overflow {
+                cells: mem_count,
+                past: idx - mem_count,
+            }
+            .into()),
+            None => Err(MemoryError::Underflow {
+                ptr: self.ptr,
+                offset,
+            }
+            .into()),
+        }
+    }
+
+    fn cell(&mut self, offset: isize) -> Result<&mut u8> {
+        let idx = self.index(offset)?;
+
+        Ok(&mut self.mem[idx])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{self, ErrorKind, Write},
+        thread,
+    };
+
+    use crate::{reference, vm::Vm};
+
+    const PROGRAMS: [&str; 10] = [
+        "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.",
+        "+++[->++<]>[->+>+<<]>>[-<<+>>]<<<,[.,]",
+        ">>>+++<<<[-]>>>[<+>-]<.>>>>>+++++[<<<<<++++++++++>>>>>-]<<<<<.",
+        "+++++[>+++++<-]>[>>+>+<<<-]>>>[<<<+>>>-]<[->+<]>.,,,,.",
+        ">>>+++[-<+>]<.",
+        "+[>+]",
+        "+<>.",
+        "+[<+>-].",
+        "++[<->-]>.",
+        "+<[-]>[-]>[-]>.",
+    ];
+
+    /// The optimized program on the VM has to behave exactly like the unoptimized one on the reference.
+    #[test]
+    fn vm_matches_reference() {
+        for src in PROGRAMS {
+            let input = b"abc";
+
+            let mut expected = vec![];
+            let expected_result = reference::run_src(src, &mut input.as_slice(), &mut expected)
+                .map_err(|e| e.to_string());
+
+            let mut output = vec![];
+            let result = Vm::new(src)
+                .unwrap()
+                .run_with(&mut input.as_slice(), &mut output)
+                .map_err(|err| err.to_string());
+
+            assert_eq!(output, expected, "{}", src);
+            assert_eq!(result, expected_result, "{}", src);
+        }
+    }
+
+    /// Takes `room` bytes, then fails like a closed pipe.
+    struct Pipe {
+        written: Vec<u8>,
+        room: usize,
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.written.len() == self.room {
+                return Err(ErrorKind::BrokenPipe.into());
+            }
+            let len = buf.len().min(self.room - self.written.len());
+            self.written.extend_from_slice(&buf[..len]);
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn stops_when_output_fails() {
+        // 200 bytes at once, more than there is room for.
+        let src = format!("+++++[>++++++++++<-]>{}", ".".repeat(200));
+        for run in [
+            |src: &str, pipe: &mut Pipe| reference::run_src(src, &mut &b""[..], pipe),
+            |src: &str, pipe: &mut Pipe| Vm::new(src)?.run_with(&mut &b""[..], pipe),
+        ] {
+            let mut pipe = Pipe {
+                written: vec![],
+                room: 150,
+            };
+            let err = run(&src, &mut pipe).unwrap_err();
+
+            assert_eq!(pipe.written, [50; 150]);
+            assert_eq!(
+                err.downcast::<io::Error>().unwrap().kind(),
+                ErrorKind::BrokenPipe
+            );
+        }
+    }
+
+    #[test]
+    fn runs_deep_nesting() {
+        // Far too little stack to take some for every loop.
+        let depth = 100_000;
+        let src = format!("+{}.-{}", "[".repeat(depth), "]".repeat(depth));
+        let run = move || {
+            let mut output = vec![];
+            reference::run_src(&src, &mut &b""[..], &mut output).unwrap();
+            output
+        };
+
+        let output = thread::Builder::new()
+            .stack_size(256 << 10)
+            .spawn(run)
+            .unwrap()
+            .join()
+            .unwrap();
+        assert_eq!(output, [1]);
+    }
+}
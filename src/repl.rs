@@ -0,0 +1,623 @@
+/*
+ *  Read-eval-print loop, for `bf repl`. Every line typed is parsed and run on one VM, after what
+ *  ran before, starting with the file given if there is one: the tape and the pointer stay from line
+ *  to line. A line that leaves a loop open waits
+ *  for the lines closing it before anything runs, the prompt shows how many loops are open. `:cancel`
+ *  (or Ctrl-C with the readline feature) drops those lines.
+ *
+ *      :tape     the pointer and the cells up to the last one used
+ *      :reset    clear the tape and the pointer, and forget the lines run so far
+ *      :cancel   drop the lines of a loop still open
+ *      :def NAME CODE
+ *                make `@NAME` stand for CODE in the lines typed after, `:def zero [-]`
+ *      :defs     the snippets defined so far
+ *      :disasm   the opcodes of the last piece run, as they ran
+ *      :opt      the same piece through the optimizer: the passes that changed it and what came out
+ *      :save FILE, :restore FILE
+ *                keep the tape, the pointer and the snippets in FILE, and take them back, for
+ *                another session
+ *      :quit
+ *
+ *  Other lines starting with `:` would be comments to Brainfuck, so they are taken as commands. A
+ *  snippet is expanded when it is defined, one using another keeps what that one was then, and they
+ *  stay through `:reset`. Files run are not expanded, `@` is a comment there like anywhere else.
+ *
+ *  A session file is text laid out like a crash dump: the pointer, the snippets a line each, then the
+ *  tape up to the last cell used as a hex dump. The lines run are not kept, only what they left.
+ *
+ *  Built with the readline feature, the prompt has line editing and a history, kept between sessions
+ *  in $BF_HISTORY, or $XDG_STATE_HOME/bf/history (~/.local/state/bf/history).
+ */
+
+use std::{
+    collections::BTreeMap,
+    env,
+    fmt::Write as _,
+    fs,
+    io::{self, BufRead, Read, StdinLock, Write},
+    path::{Path, PathBuf},
+    str::Lines,
+};
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::{
+    asm, hexdump,
+    ir::ProgramIr,
+    lexer::{self, Token},
+    optimizer::PassRegistry,
+    parser,
+    vm::{Snapshot, Vm},
+};
+
+/// The first line of a session file.
+const HEADER: &str = "bf repl session";
+
+/// What `Repl::line` wants next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Prompt {
+    /// A new piece of program.
+    Ready,
+    /// More of the last one, with this many loops still open.
+    More(usize),
+    Quit,
+}
+
+pub struct Repl {
+    vm: Vm,
+    /// Lines of a piece with a loop still open.
+    partial: String,
+    /// Snippets by name, expanded.
+    defs: BTreeMap<String, String>,
+    /// The last piece run, for `:disasm` and `:opt`.
+    last: String,
+}
+
+impl Repl {
+    pub fn new(tape_size: usize) -> Result<Self> {
+        let mut vm = Vm::from_program(vec![])?;
+        vm.set_tape_size(tape_size)?;
+
+        Ok(Self {
+            vm,
+            partial: String::new(),
+            defs: BTreeMap::new(),
+            last: String::new(),
+        })
+    }
+
+    pub fn vm(&self) -> &Vm {
+        &self.vm
+    }
+
+    /// Drops the lines of a piece with a loop still open, returns false if there were none.
+    pub fn cancel(&mut self) -> bool {
+        let open = !self.partial.is_empty();
+        self.partial.clear();
+
+        open
+    }
+
+    /// Takes one line typed at the prompt, `input` and `output` are the program's. Errors of the line
+    /// and of the program are written to `out`, the session goes on.
+    pub fn line<R: Read, W: Write>(
+        &mut self,
+        line: &str,
+        input: &mut R,
+        output: &mut W,
+        out: &mut dyn Write,
+    ) -> Result<Prompt> {
+        if line.trim() == ":cancel" {
+            self.cancel();
+            return Ok(Prompt::Ready);
+        }
+        if self.partial.is_empty() {
+            if let Some(command) = line.trim().strip_prefix(':') {
+                return self.command(command, out);
+            }
+        }
+
+        let line = match self.expand(line) {
+            Ok(line) => line,
+            Err(err) => {
+                writeln!(out, "error: {}", err)?;
+                return Ok(match open_loops(&self.partial) {
+                    0 => Prompt::Ready,
+                    open => Prompt::More(open as usize),
+                });
+            }
+        };
+        self.partial.push_str(&line);
+        self.partial.push('\n');
+        let open = open_loops(&self.partial);
+        if open > 0 {
+            return Ok(Prompt::More(open as usize));
+        }
+
+        let src = std::mem::take(&mut self.partial);
+        if let Err(err) = self.run(&src, input, output) {
+            writeln!(out, "error: {}", err)?;
+        }
+
+        Ok(Prompt::Ready)
+    }
+
+    /// Runs `src` after what ran so far, such as a file before the first prompt. A failed run leaves
+    /// the tape as it is for the next one.
+    pub fn run<R: Read, W: Write>(
+        &mut self,
+        src: &str,
+        input: &mut R,
+        output: &mut W,
+    ) -> Result<()> {
+        self.last = src.to_string();
+        // Unoptimized, the optimizer drops what has no effect by the end of the program, such as a
+        // last move of the pointer, which the next line would see here.
+        let result = parser::parse(lexer::parse(src))
+            .and_then(|program| self.vm.append(program))
+            .and_then(|()| self.vm.run_with(input, output));
+        output.flush()?;
+
+        result
+    }
+
+    fn command(&mut self, command: &str, out: &mut dyn Write) -> Result<Prompt> {
+        let (command, args) = command
+            .split_once(char::is_whitespace)
+            .map_or((command, ""), |(command, args)| (command, args.trim()));
+
+        match command {
+            "tape" => {
+                writeln!(out, "ptr = {}", self.vm.mem_ptr())?;
+                write!(out, "{}", hexdump::hexdump(self.used_cells(), 0))?;
+            }
+            "reset" => {
+                let cells = self.vm.mem().len();
+                self.vm = Vm::from_program(vec![])?;
+                self.vm.set_tape_size(cells)?;
+                self.last.clear();
+            }
+            "def" => {
+                if let Err(err) = self.define(args) {
+                    writeln!(out, "error: {}", err)?;
+                }
+            }
+            "defs" => {
+                for (name, code) in &self.defs {
+                    writeln!(out, "@{}  {}", name, code)?;
+                }
+            }
+            "disasm" | "opt" => {
+                if let Err(err) = self.disassemble(command == "opt", out) {
+                    writeln!(out, "error: {}", err)?;
+                }
+            }
+            "save" | "restore" if args.is_empty() => {
+                writeln!(out, "error: usage: :{} FILE", command)?
+            }
+            "save" => {
+                if let Err(err) = fs::write(args, self.session()) {
+                    writeln!(out, "error: cannot write {}: {}", args, err)?;
+                }
+            }
+            "restore" => {
+                let result = fs::read_to_string(args)
+                    .map_err(|err| anyhow!("cannot read {}: {}", args, err))
+                    .and_then(|text| self.restore(&text));
+                if let Err(err) = result {
+                    writeln!(out, "error: {}", err)?;
+                }
+            }
+            "quit" | "q" => return Ok(Prompt::Quit),
+            _ => writeln!(
+                out,
+                "error: unknown command `:{}`, there are :tape, :reset, :cancel, :def, :defs, :disasm, \
+                 :opt, :save, :restore and :quit",
+                command
+            )?,
+        }
+
+        Ok(Prompt::Ready)
+    }
+
+    /// The cells up to the last one used, or to the pointer if that is further.
+    fn used_cells(&self) -> &[u8] {
+        let mem = self.vm.mem();
+        let used = mem
+            .iter()
+            .rposition(|&cell| cell != 0)
+            .map_or(0, |i| i + 1)
+            .max(self.vm.mem_ptr() + 1);
+
+        &mem[..used]
+    }
+
+    /// The session file of `:save`.
+    pub fn session(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "{}", HEADER).unwrap();
+        writeln!(out, "pointer: {}", self.vm.mem_ptr()).unwrap();
+
+        writeln!(out, "\nsnippets:").unwrap();
+        for (name, code) in &self.defs {
+            writeln!(out, "@{}  {}", name, code).unwrap();
+        }
+
+        let used = self.used_cells();
+        writeln!(
+            out,
+            "\ntape, {} of {} cells:",
+            used.len(),
+            self.vm.mem().len()
+        )
+        .unwrap();
+        write!(out, "{}", hexdump::hexdump(used, 0)).unwrap();
+
+        out
+    }
+
+    /// Takes back the tape, the pointer and the snippets of a session file, in place of the ones
+    /// there are.
+    pub fn restore(&mut self, text: &str) -> Result<()> {
+        let mut lines = text
+            .strip_prefix(HEADER)
+            .ok_or_else(|| anyhow!("not a session file"))?
+            .lines();
+        let (mut ptr, mut tape, mut defs) = (None, None, BTreeMap::new());
+
+        while let Some(line) = lines.next() {
+            if let Some(text) = line.strip_prefix("pointer: ") {
+                ptr = text.parse::<usize>().ok();
+            } else if line == "snippets:" {
+                for line in section(&mut lines) {
+                    let (name, code) = line
+                        .strip_prefix('@')
+                        .and_then(|line| line.split_once("  "))
+                        .ok_or_else(|| anyhow!("bad snippet line `{}`", line))?;
+                    defs.insert(name.to_string(), code.to_string());
+                }
+            } else if let Some(text) = line.strip_prefix("tape, ") {
+                // `tape, USED of CELLS cells:`
+                let cells: usize = text
+                    .split(' ')
+                    .nth(2)
+                    .and_then(|cells| cells.parse().ok())
+                    .ok_or_else(|| anyhow!("bad tape line `{}`", line))?;
+                let mut used = hexdump::parse(&section(&mut lines).join("\n"))?;
+                if used.len() > cells {
+                    bail!("the tape has more than {} cells", cells);
+                }
+                used.resize(cells, 0);
+                tape = Some(used);
+            }
+        }
+
+        let ptr = ptr.ok_or_else(|| anyhow!("the session file has no pointer"))?;
+        let tape = tape.ok_or_else(|| anyhow!("the session file has no tape"))?;
+        if ptr >= tape.len() {
+            bail!("the pointer {} is past the end of the tape", ptr);
+        }
+
+        let mut vm = Vm::from_program(vec![])?;
+        vm.set_tape_size(tape.len())?;
+        vm.restore(&Snapshot::new(0, tape, ptr)?);
+        self.vm = vm;
+        self.defs = defs;
+        self.partial.clear();
+        self.last.clear();
+
+        Ok(())
+    }
+
+    /// `:disasm`, or `:opt` with `optimized`.
+    fn disassemble(&self, optimized: bool, out: &mut dyn Write) -> Result<()> {
+        if self.last.is_empty() {
+            bail!("nothing ran yet");
+        }
+        let mut program = parser::parse(lexer::parse(&self.last))?;
+
+        if optimized {
+            let passes = PassRegistry::default();
+            let (ir, changed) = passes.run_changed(ProgramIr::from_program(&program)?);
+            match changed.is_empty() {
+                true => writeln!(out, "passes: none changed it")?,
+                false => writeln!(out, "passes: {}", changed.join(", "))?,
+            }
+            program = ir.to_program();
+        }
+        write!(out, "{}", asm::listing(&program))?;
+
+        Ok(())
+    }
+
+    /// `:def NAME CODE`, a name made of letters, digits and `_`.
+    fn define(&mut self, args: &str) -> Result<()> {
+        let (name, code) = match args.split_once(char::is_whitespace) {
+            Some((name, code)) => (name, code.trim()),
+            None => bail!("usage: :def NAME CODE"),
+        };
+        if name.is_empty() || !name.chars().all(is_name_char) {
+            bail!("bad snippet name `{}`, use letters, digits and `_`", name);
+        }
+
+        let code = self.expand(code)?;
+        self.defs.insert(name.to_string(), code);
+
+        Ok(())
+    }
+
+    /// `line` with every `@NAME` replaced by its snippet. A lone `@` is left as it is.
+    fn expand(&self, line: &str) -> Result<String> {
+        let mut out = String::with_capacity(line.len());
+        let mut rest = line;
+
+        while let Some(at) = rest.find('@') {
+            out.push_str(&rest[..at]);
+            let after = &rest[at + 1..];
+            let len = after.find(|ch| !is_name_char(ch)).unwrap_or(after.len());
+            let name = &after[..len];
+
+            match self.defs.get(name) {
+                Some(code) => out.push_str(code),
+                None if name.is_empty() => out.push('@'),
+                None => bail!("no snippet `@{}`, see :defs", name),
+            }
+            rest = &after[len..];
+        }
+        out.push_str(rest);
+
+        Ok(out)
+    }
+}
+
+/// The lines up to the next empty one, which ends a part of a session file.
+fn section<'a>(lines: &mut Lines<'a>) -> Vec<&'a str> {
+    lines.take_while(|line| !line.is_empty()).collect()
+}
+
+fn is_name_char(ch: char) -> bool {
+    ch.is_alphanumeric() || ch == '_'
+}
+
+/// What `LineReader::read` got.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Typed {
+    Line(String),
+    /// Ctrl-C, which drops what was typed so far.
+    Interrupt,
+    /// The end of the input, Ctrl-D.
+    End,
+}
+
+/// Reads the lines typed at the prompt from standard input, which is also where the program reads its
+/// input from, between the lines.
+pub struct LineReader {
+    stdin: StdinLock<'static>,
+    #[cfg(feature = "readline")]
+    editor: Option<rustyline::DefaultEditor>,
+}
+
+impl LineReader {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            stdin: io::stdin().lock(),
+            // Line editing needs a terminal, piped lines are read as they are.
+            #[cfg(feature = "readline")]
+            editor: match io::IsTerminal::is_terminal(&io::stdin()) {
+                true => {
+                    let mut editor = rustyline::DefaultEditor::new()?;
+                    if let Some(path) = history_path() {
+                        // There is none the first time.
+                        let _ = editor.load_history(&path);
+                    }
+                    Some(editor)
+                }
+                false => None,
+            },
+        })
+    }
+
+    /// The next line after writing `prompt`, without the newline.
+    pub fn read(&mut self, prompt: &str) -> Result<Typed> {
+        #[cfg(feature = "readline")]
+        if let Some(editor) = &mut self.editor {
+            use rustyline::error::ReadlineError;
+
+            return match editor.readline(prompt) {
+                Ok(line) => {
+                    editor.add_history_entry(&line)?;
+                    Ok(Typed::Line(line))
+                }
+                Err(ReadlineError::Interrupted) => Ok(Typed::Interrupt),
+                Err(ReadlineError::Eof) => Ok(Typed::End),
+                Err(err) => Err(err.into()),
+            };
+        }
+
+        let mut out = io::stdout();
+        write!(out, "{}", prompt)?;
+        out.flush()?;
+
+        let mut line = String::new();
+        if self.stdin.read_line(&mut line)? == 0 {
+            return Ok(Typed::End);
+        }
+        line.truncate(line.trim_end_matches(['\n', '\r']).len());
+
+        Ok(Typed::Line(line))
+    }
+
+    /// Where the program reads its input.
+    pub fn input(&mut self) -> &mut impl BufRead {
+        &mut self.stdin
+    }
+
+    /// Keeps the history for the next session.
+    pub fn save(&mut self) -> Result<()> {
+        #[cfg(feature = "readline")]
+        if let (Some(editor), Some(path)) = (&mut self.editor, history_path()) {
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            editor.save_history(&path)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Where the history of the prompt is kept, see the top of the file.
+pub fn history_path() -> Option<PathBuf> {
+    if let Some(path) = env::var_os("BF_HISTORY") {
+        return Some(path.into());
+    }
+
+    let state = match env::var_os("XDG_STATE_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => Path::new(&env::var_os("HOME")?).join(".local/state"),
+    };
+
+    Some(state.join("bf").join("history"))
+}
+
+/// Loops `src` opens and does not close. Negative is left to the parser to report.
+fn open_loops(src: &str) -> isize {
+    lexer::parse(src)
+        .iter()
+        .map(|&(token, _)| match token {
+            Token::LBracket => 1,
+            Token::RBracket => -1,
+            _ => 0,
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::repl::{Prompt, Repl};
+
+    #[test]
+    fn keeps_the_tape_between_lines() {
+        let mut repl = Repl::new(16).unwrap();
+        let mut output = vec![];
+        let mut line = |repl: &mut Repl, line: &str| {
+            let mut out = vec![];
+            let prompt = repl
+                .line(line, &mut &b"A"[..], &mut output, &mut out)
+                .unwrap();
+            (prompt, String::from_utf8(out).unwrap())
+        };
+
+        repl.run("++>", &mut &b""[..], &mut vec![]).unwrap();
+        assert_eq!(line(&mut repl, "<+>"), (Prompt::Ready, String::new()));
+        assert_eq!(line(&mut repl, "<[->++"), (Prompt::More(1), String::new()));
+        assert_eq!(line(&mut repl, "<]>.,."), (Prompt::Ready, String::new()));
+        assert_eq!(repl.vm().mem()[..2], [0, b'A']);
+        assert_eq!(
+            line(&mut repl, ":tape"),
+            (
+                Prompt::Ready,
+                "ptr = 1\n00000000  00 41                                             |.A|\n"
+                    .to_string()
+            )
+        );
+        // A move past the last cell fails before it is made.
+        assert!(line(&mut repl, &">".repeat(20))
+            .1
+            .starts_with("error: memory overflowed"));
+        assert!(line(&mut repl, "]").1.starts_with("error: unexpected"));
+        assert_eq!(line(&mut repl, "+."), (Prompt::Ready, String::new()));
+        assert_eq!(repl.vm().mem_ptr(), 1);
+        assert_eq!(line(&mut repl, "[[").0, Prompt::More(2));
+        assert_eq!(line(&mut repl, ":tape").0, Prompt::More(2));
+        assert_eq!(line(&mut repl, ":cancel").0, Prompt::Ready);
+        assert_eq!(line(&mut repl, ":quit").0, Prompt::Quit);
+        assert_eq!(output, [6, b'A', b'B']);
+    }
+
+    #[test]
+    fn expands_snippets() {
+        let mut repl = Repl::new(16).unwrap();
+        let line = |repl: &mut Repl, line: &str| {
+            let mut out = vec![];
+            let prompt = repl
+                .line(line, &mut &b""[..], &mut vec![], &mut out)
+                .unwrap();
+            (prompt, String::from_utf8(out).unwrap())
+        };
+
+        assert_eq!(line(&mut repl, ":def zero [-]").1, "");
+        assert_eq!(line(&mut repl, ":def three +++").1, "");
+        assert_eq!(line(&mut repl, ":def six @three@three").1, "");
+        assert_eq!(line(&mut repl, ":def three ++").1, "");
+        assert_eq!(line(&mut repl, "@six>@three @ @zero").0, Prompt::Ready);
+        assert_eq!(repl.vm().mem()[..2], [6, 0]);
+        assert_eq!(line(&mut repl, "[@three").0, Prompt::More(1));
+        assert!(line(&mut repl, "@nine")
+            .1
+            .starts_with("error: no snippet `@nine`"));
+        assert_eq!(line(&mut repl, ":cancel").0, Prompt::Ready);
+        assert!(line(&mut repl, ":def a-b +").1.starts_with("error: bad"));
+        assert!(line(&mut repl, ":def zero").1.starts_with("error: usage"));
+        assert_eq!(
+            line(&mut repl, ":defs").1,
+            "@six  ++++++\n@three  ++\n@zero  [-]\n"
+        );
+    }
+
+    #[test]
+    fn shows_the_opcodes_of_the_last_piece() {
+        let mut repl = Repl::new(16).unwrap();
+        let mut line = |line: &str| {
+            let mut out = vec![];
+            repl.line(line, &mut &b""[..], &mut vec![], &mut out)
+                .unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!(line(":disasm"), "error: nothing ran yet\n");
+        line("++[-]>");
+        assert_eq!(
+            line(":disasm"),
+            "0000  ADD 2\n0001  JZ L0.end\n      L0:\n0002  SUB 1\n0003  JNZ L0\n      L0.end:\n\
+             0004  SHR 1\n"
+        );
+        assert_eq!(
+            line(":opt"),
+            "passes: clear-loops, contract\n0000  SET 0\n0001  SHR 1\n"
+        );
+        line(".");
+        assert_eq!(line(":opt"), "passes: none changed it\n0000  OUT 1\n");
+    }
+
+    #[test]
+    fn saves_and_restores_sessions() {
+        let mut repl = Repl::new(40).unwrap();
+        let mut out = vec![];
+        for line in [":def two ++", "+>>@two<"] {
+            repl.line(line, &mut &b""[..], &mut vec![], &mut out)
+                .unwrap();
+        }
+        let text = repl.session();
+        assert_eq!(
+            text,
+            "bf repl session\npointer: 1\n\nsnippets:\n@two  ++\n\ntape, 3 of 40 cells:\n\
+             00000000  01 00 02                                          |...|\n"
+        );
+
+        let mut restored = Repl::new(8).unwrap();
+        restored.restore(&text).unwrap();
+        assert_eq!(restored.session(), text);
+        assert_eq!(restored.vm().mem().len(), 40);
+        restored
+            .line("@two>+", &mut &b""[..], &mut vec![], &mut out)
+            .unwrap();
+        assert_eq!(restored.vm().mem()[..3], [1, 2, 3]);
+        assert!(out.is_empty());
+
+        assert!(restored.restore("pointer: 1\n").is_err());
+        assert!(restored
+            .restore(&text.replace("pointer: 1", "pointer: 40"))
+            .is_err());
+    }
+}
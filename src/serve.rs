@@ -0,0 +1,402 @@
+/*
+ *  HTTP server, for `bf serve`: programs posted to it are run and what they wrote sent back, a
+ *  backend for playgrounds on the web.
+ *
+ *      POST /run  {"program": ",.,.", "input": "hi"}
+ *
+ *  answers `{"outcome": "ran", "output": "hi", "error": null, "limit": null, "stats": {...}}`, the
+ *  outcome being `ran`, `error` with the error of the program or `limit` with the limit it stopped
+ *  at, `step`, `time` or `output`, like `bf run-batch`. The stats are the instructions run, the bytes
+ *  written and the seconds it took. Output that isn't UTF-8 is sent with the replacement character.
+ *  Requests that can't be run, such as programs with unmatched brackets or loops nested deeper than
+ *  the server takes, answer 400 and `{"error"}`.
+ *
+ *  Every run is held to the limits of the server. Requests are handled by a pool of threads, one
+ *  request on each connection, and connections sending nothing for a while are closed.
+ */
+
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::{
+    batch::{self, Limits},
+    json::Json,
+    parser, vm,
+};
+
+/// Bytes of the request line and headers.
+const MAX_HEAD: u64 = 8 << 10;
+/// How long a connection can wait on the client.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy)]
+pub struct Options {
+    pub limits: Limits,
+    /// Requests handled at once.
+    pub threads: usize,
+    /// Bytes of a request's body.
+    pub max_body: usize,
+    /// How deep the loops of a request's program can nest.
+    pub max_depth: usize,
+}
+
+/// Handles the connections of `listener` until accepting one fails.
+pub fn serve(listener: &TcpListener, options: &Options) -> Result<()> {
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..options.threads.max(1))
+            .map(|_| scope.spawn(|| accept(listener, options)))
+            .collect();
+
+        workers
+            .into_iter()
+            .try_for_each(|worker| worker.join().unwrap_or_else(|_| bail!("a worker panicked")))
+    })
+}
+
+fn accept(listener: &TcpListener, options: &Options) -> Result<()> {
+    loop {
+        let (stream, peer) = listener.accept()?;
+        if let Err(err) = connection(stream, options) {
+            crate::debug!("{}: {}", peer, err);
+        }
+    }
+}
+
+fn connection(stream: TcpStream, options: &Options) -> Result<()> {
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    stream.set_write_timeout(Some(IDLE_TIMEOUT))?;
+
+    let mut output = stream.try_clone()?;
+    handle(&mut BufReader::new(stream), &mut output, options)
+}
+
+/// Reads one request from `input` and writes the answer to `output`.
+pub fn handle(input: &mut dyn BufRead, output: &mut dyn Write, options: &Options) -> Result<()> {
+    let start = Instant::now();
+    let (status, body) = match read_request(input, output, options) {
+        Ok(request) => {
+            let (status, body) = respond(&request, options);
+            crate::info!(
+                "{} {} {} {:.3?}",
+                request.method,
+                request.path,
+                status,
+                start.elapsed()
+            );
+            (status, body)
+        }
+        Err(Refused(status, why)) => (status, Json::object([("error", why.into())])),
+    };
+
+    write_response(output, status, &body)
+}
+
+struct Request {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+}
+
+/// A request answered without being looked at, with the status and why.
+struct Refused(u16, String);
+
+impl From<io::Error> for Refused {
+    fn from(err: io::Error) -> Self {
+        Refused(400, err.to_string())
+    }
+}
+
+fn read_request(
+    input: &mut dyn BufRead,
+    output: &mut dyn Write,
+    options: &Options,
+) -> std::result::Result<Request, Refused> {
+    let refuse = |status, why: &str| Err(Refused(status, why.to_string()));
+
+    let mut head = input.take(MAX_HEAD);
+    let mut line = String::new();
+    head.read_line(&mut line)?;
+    let mut words = line.split_whitespace();
+    let (Some(method), Some(path), Some(_)) = (words.next(), words.next(), words.next()) else {
+        return refuse(400, "expected a request line");
+    };
+    let (method, path) = (method.to_string(), path.to_string());
+
+    let mut length = None;
+    let mut expects_continue = false;
+    loop {
+        line.clear();
+        head.read_line(&mut line)?;
+        if !line.ends_with('\n') {
+            return refuse(400, "the headers are too long");
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+
+        let Some((name, value)) = line.split_once(':') else {
+            return refuse(400, "expected `name: value` headers");
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            match value.parse::<usize>() {
+                Ok(value) => length = Some(value),
+                Err(_) => return refuse(400, "the content length isn't a number"),
+            }
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            return refuse(411, "bodies have to come with a content length");
+        } else if name.eq_ignore_ascii_case("expect") {
+            expects_continue = value.eq_ignore_ascii_case("100-continue");
+        }
+    }
+
+    let length = match (length, method.as_str()) {
+        (Some(length), _) => length,
+        (None, "POST") => return refuse(411, "bodies have to come with a content length"),
+        (None, _) => 0,
+    };
+    if length > options.max_body {
+        return refuse(413, "the body is too large");
+    }
+    if expects_continue {
+        output.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+        output.flush()?;
+    }
+
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+
+    Ok(Request { method, path, body })
+}
+
+fn respond(request: &Request, options: &Options) -> (u16, Json) {
+    let error = |status, why: String| (status, Json::object([("error", why.into())]));
+
+    match (request.method.as_str(), request.path.as_str()) {
+        ("POST", "/run") => match run(&request.body, options) {
+            Ok(body) => (200, body),
+            Err(err) => error(400, err.to_string()),
+        },
+        ("OPTIONS", "/run") => (204, Json::Null),
+        (_, "/run") => error(405, format!("{} can't be used on /run", request.method)),
+        (_, path) => error(404, format!("there is no {}", path)),
+    }
+}
+
+/// Runs the program of a `/run` body.
+fn run(body: &[u8], options: &Options) -> Result<Json> {
+    let body = std::str::from_utf8(body).map_err(|_| anyhow!("the body isn't UTF-8"))?;
+    let request = Json::parse(body)?;
+    let program = request
+        .get("program")
+        .as_str()
+        .ok_or_else(|| anyhow!("expected the program as a string in `program`"))?;
+    let input = match request.get("input") {
+        Json::Null => "",
+        input => input
+            .as_str()
+            .ok_or_else(|| anyhow!("expected the input as a string in `input`"))?,
+    };
+
+    let depth = parser::nesting(program);
+    if depth > options.max_depth {
+        bail!(
+            "the program nests loops {} deep, more than the {} taken",
+            depth,
+            options.max_depth
+        );
+    }
+
+    let start = Instant::now();
+    let program = Arc::new(vm::compile(program)?);
+    let run = batch::run_limited(&program, input.as_bytes(), &options.limits, start)?;
+    let elapsed = start.elapsed();
+
+    let (outcome, error) = match (&run.result, run.limit) {
+        (_, Some(_)) => ("limit", Json::Null),
+        (Err(err), None) => ("error", err.to_string().into()),
+        (Ok(()), None) => ("ran", Json::Null),
+    };
+
+    Ok(Json::object([
+        ("outcome", outcome.into()),
+        (
+            "output",
+            String::from_utf8_lossy(&run.output).into_owned().into(),
+        ),
+        ("error", error),
+        ("limit", run.limit.map_or(Json::Null, Json::from)),
+        (
+            "stats",
+            Json::object([
+                ("instructions", Json::Number(run.steps as f64)),
+                ("output_bytes", run.output.len().into()),
+                ("seconds", elapsed.as_secs_f64().into()),
+            ]),
+        ),
+    ]))
+}
+
+fn write_response(output: &mut dyn Write, status: u16, body: &Json) -> Result<()> {
+    let reason = match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        411 => "Length Required",
+        413 => "Content Too Large",
+        _ => "Internal Server Error",
+    };
+    let body = match body {
+        Json::Null => String::new(),
+        body => body.to_string(),
+    };
+
+    // Playgrounds call the server from pages served elsewhere.
+    write!(
+        output,
+        "HTTP/1.1 {} {}\r\n\
+         Content-Type: application/json\r\n\
+         Content-Length: {}\r\n\
+         Access-Control-Allow-Origin: *\r\n\
+         Access-Control-Allow-Methods: POST, OPTIONS\r\n\
+         Access-Control-Allow-Headers: Content-Type\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        reason,
+        body.len(),
+        body
+    )?;
+    output.flush()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{Read, Write},
+        net::{TcpListener, TcpStream},
+        thread,
+    };
+
+    use crate::{
+        batch::Limits,
+        json::Json,
+        serve::{self, Options},
+    };
+
+    const OPTIONS: Options = Options {
+        limits: Limits {
+            max_steps: Some(1000),
+            timeout: None,
+            max_output: Some(10),
+            tape_size: 100,
+        },
+        threads: 1,
+        max_body: 100,
+        max_depth: 10,
+    };
+
+    /// The status and body answering `request`.
+    fn send(request: &str) -> (String, Json) {
+        let mut output = vec![];
+        serve::handle(&mut request.as_bytes(), &mut output, &OPTIONS).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        let (head, body) = output.rsplit_once("\r\n\r\n").unwrap();
+        let status = head.lines().next().unwrap().to_string();
+        (status, Json::parse(body).unwrap_or(Json::Null))
+    }
+
+    fn post(body: &str) -> (String, Json) {
+        send(&post_request(body))
+    }
+
+    fn post_request(body: &str) -> String {
+        format!(
+            "POST /run HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    }
+
+    #[test]
+    fn runs_programs() {
+        let (status, body) = post(r#"{"program": ",.,.", "input": "hi"}"#);
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(body.get("outcome").as_str(), Some("ran"));
+        assert_eq!(body.get("output").as_str(), Some("hi"));
+        assert_eq!(body.get("stats").get("output_bytes").as_usize(), Some(2));
+
+        let (_, body) = post(r#"{"program": "+[]"}"#);
+        assert_eq!(body.get("outcome").as_str(), Some("limit"));
+        assert_eq!(body.get("limit").as_str(), Some("step"));
+        assert_eq!(body.get("stats").get("instructions").as_usize(), Some(1000));
+        let (_, body) = post(r#"{"program": "+[.]"}"#);
+        assert_eq!(body.get("limit").as_str(), Some("output"));
+        let (_, body) = post(r#"{"program": ",[.,]", "input": "a"}"#);
+        assert_eq!(body.get("outcome").as_str(), Some("error"));
+    }
+
+    #[test]
+    fn refuses_bad_requests() {
+        let status = |request: &str| send(request).0;
+        assert_eq!(post(r#"{"program": "["}"#).0, "HTTP/1.1 400 Bad Request");
+        assert_eq!(post(r#"{"input": ""}"#).0, "HTTP/1.1 400 Bad Request");
+        assert_eq!(
+            post(&format!(r#"{{"program": "{}"}}"#, "+".repeat(100))).0,
+            "HTTP/1.1 413 Content Too Large"
+        );
+        assert_eq!(
+            status("POST /run HTTP/1.1\r\n\r\n"),
+            "HTTP/1.1 411 Length Required"
+        );
+        assert_eq!(
+            status("GET /run HTTP/1.1\r\n\r\n"),
+            "HTTP/1.1 405 Method Not Allowed"
+        );
+        assert_eq!(status("GET / HTTP/1.1\r\n\r\n"), "HTTP/1.1 404 Not Found");
+        assert_eq!(
+            status("OPTIONS /run HTTP/1.1\r\n\r\n"),
+            "HTTP/1.1 204 No Content"
+        );
+    }
+
+    #[test]
+    fn refuses_deep_nesting() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || serve::serve(&listener, &OPTIONS));
+
+        let status = |body: &str| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(post_request(body).as_bytes()).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response.lines().next().unwrap().to_string()
+        };
+
+        let deep = format!("{}{}", "[".repeat(20), "]".repeat(20));
+        let (_, body) = post(&format!(r#"{{"program": "{}"}}"#, deep));
+        assert_eq!(
+            body.get("error").as_str(),
+            Some("the program nests loops 20 deep, more than the 10 taken")
+        );
+        assert_eq!(
+            status(&format!(r#"{{"program": "+{}"}}"#, deep)),
+            "HTTP/1.1 400 Bad Request"
+        );
+        // Still there.
+        assert_eq!(status(r#"{"program": "+[-]."}"#), "HTTP/1.1 200 OK");
+    }
+}
@@ -0,0 +1,256 @@
+/*
+ *  Program source made of several files.
+ *  The files are concatenated in order, each starting on a line of its own, and locations in the
+ *  concatenated text are mapped back to the file and line they came from for error messages.
+ *
+ *  Files in other languages, such as Ook!, are in `text` as Brainfuck laid out the same (see
+ *  `lexer::layout`), and errors quote their lines as they were written. So do preprocessed files,
+ *  pointing at the lines of the files they include, see `preprocess`.
+ */
+
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+};
+
+use crate::{
+    lexer::{self, TokenLoc},
+    parser::ParseError,
+    preprocess::{Expanded, Origin},
+    vm::RuntimeError,
+};
+
+#[derive(Debug, Default)]
+pub struct Source {
+    pub text: String,
+    /// `text` as it was written, the lines errors quote.
+    shown: String,
+    /// Name of each file and the line of `text` it starts on.
+    files: Vec<(String, usize)>,
+    /// Where the lines of each preprocessed file come from, by the line of `text` it starts on.
+    expanded: Vec<(usize, Vec<Origin>)>,
+    lines: usize,
+    /// Whether the one file is read again for the lines errors quote, see `streamed`.
+    streamed: bool,
+}
+
+impl Source {
+    /// Source not read from a file, such as `--eval`. Locations in it are left as they are.
+    pub fn unnamed(text: String) -> Self {
+        Self {
+            shown: text.clone(),
+            text,
+            ..Self::default()
+        }
+    }
+
+    /// Same as `unnamed` for a program in another language, see `push_translated`.
+    pub fn unnamed_translated(shown: String, text: String) -> Self {
+        Self {
+            text,
+            shown,
+            ..Self::default()
+        }
+    }
+
+    /// The file at `path`, lexed as it is read instead of being in `text`, see `vm::compile_reader`.
+    /// Errors quote their line from the file.
+    pub fn streamed(path: &str) -> Self {
+        Self {
+            files: vec![(path.to_string(), 1)],
+            streamed: true,
+            ..Self::default()
+        }
+    }
+
+    /// Appends a file. Its `#!` line is left out, keeping the line break so lines still count right.
+    pub fn push(&mut self, name: &str, text: &str) {
+        self.push_translated(name, text, text);
+    }
+
+    /// Same as `push` for a file in another language, `text` being it as Brainfuck laid out the same as
+    /// `shown`.
+    pub fn push_translated(&mut self, name: &str, shown: &str, text: &str) {
+        if !self.text.is_empty() && !self.text.ends_with('\n') {
+            self.text.push('\n');
+            self.shown.push('\n');
+            self.lines += 1;
+        }
+
+        self.files.push((name.to_string(), self.lines + 1));
+
+        // `text` can be shorter, having a byte for each character of `shown`.
+        let shebang = lexer::shebang_len(shown.as_bytes());
+        let text_shebang = match shebang {
+            0 => 0,
+            _ => text.find('\n').unwrap_or(text.len()),
+        };
+        self.text.push_str(&text[text_shebang..]);
+        self.shown.push_str(&shown[shebang..]);
+        self.lines += shown[shebang..].matches('\n').count();
+    }
+
+    /// Same as `push` for a preprocessed file, locations in it are in the files its lines come from.
+    pub fn push_expanded(&mut self, name: &str, expanded: Expanded) {
+        self.push_translated(name, &expanded.shown, &expanded.text);
+        let start = self.files.last().map_or(1, |(_, start)| *start);
+        self.expanded.push((start, expanded.origins));
+    }
+
+    /// The file a location in `text` is in, with the location in that file.
+    pub fn locate(&self, loc: TokenLoc) -> Option<(&str, TokenLoc)> {
+        let (name, start) = self
+            .files
+            .iter()
+            .rev()
+            .find(|(_, start)| *start <= loc.line())?;
+
+        let origin = self
+            .expanded
+            .iter()
+            .find(|(first, _)| first == start)
+            .and_then(|(_, origins)| origins.get(loc.line() - start));
+        if let Some(origin) = origin {
+            return Some((&origin.file, origin.locate(loc.col())));
+        }
+
+        Some((
+            name,
+            TokenLoc::from_col_line(loc.col(), loc.line() - start + 1),
+        ))
+    }
+
+    /// Points a parse error, or a runtime error from `vm::locate_error`, at the file and line it is in
+    /// and gives it the text of that line, other errors are returned as they are.
+    pub fn locate_error(&self, mut err: anyhow::Error) -> anyhow::Error {
+        if let Some(err) = err.downcast_mut::<RuntimeError>() {
+            err.snippet = self.line(err.loc);
+            (err.file, err.loc) = self.locate_in_file(err.loc);
+            for (file, loc) in &mut err.loops {
+                (*file, *loc) = self.locate_in_file(*loc);
+            }
+        }
+
+        match err.downcast::<ParseError>() {
+            Ok(mut err) => {
+                err.snippet = self.line(err.loc);
+                (err.file, err.loc) = self.locate_in_file(err.loc);
+                err.into()
+            }
+            Err(err) => err,
+        }
+    }
+
+    /// The line `loc` is on.
+    fn line(&self, loc: TokenLoc) -> Option<String> {
+        if self.streamed {
+            let file = File::open(&self.files[0].0).ok()?;
+            let line = BufReader::new(file)
+                .split(b'\n')
+                .nth(loc.line() - 1)?
+                .ok()?;
+            return Some(String::from_utf8_lossy(&line).into_owned());
+        }
+
+        self.shown.split('\n').nth(loc.line() - 1).map(Into::into)
+    }
+
+    /// Same as `locate`, `loc` as it is if it is not in a file.
+    fn locate_in_file(&self, loc: TokenLoc) -> (Option<String>, TokenLoc) {
+        match self.locate(loc) {
+            Some((name, loc)) => (Some(name.to_string()), loc),
+            None => (None, loc),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{env, fs, process};
+
+    use crate::{
+        lexer::{self, TokenLoc},
+        ook,
+        optimizer::PassRegistry,
+        parser::{self, ParseError},
+        preprocess,
+        source::Source,
+        vm,
+    };
+
+    #[test]
+    fn locates_errors_in_files() {
+        let mut source = Source::default();
+        source.push("lib.bf", "#!/usr/bin/env bf\n+[-]\n++");
+        source.push("main.bf", "\n>[\n.\n");
+        source.push("empty.bf", "");
+
+        assert_eq!(source.text, "\n+[-]\n++\n\n>[\n.\n");
+
+        let err = parser::parse(lexer::parse(&source.text)).unwrap_err();
+        assert_eq!(
+            source.locate_error(err).to_string(),
+            "unclosed delimiter '[' at main.bf:2:2."
+        );
+
+        let err = parser::parse(lexer::parse("\n]")).unwrap_err();
+        assert_eq!(
+            Source::unnamed("\n]".to_string())
+                .locate_error(err)
+                .to_string(),
+            "unexpected closing delimiter ']' at 2:1"
+        );
+    }
+
+    #[test]
+    fn quotes_ook_files_as_written() {
+        let mut source = Source::default();
+        source.push("a.bf", "+");
+        let ook = "Ook. Ook.\n  Ook? Ook!";
+        source.push_translated("b.ook", ook, &ook::to_brainfuck(ook).unwrap());
+        assert_eq!(source.text, "+\n+        \n  ]        ");
+
+        let err = parser::parse(lexer::parse(&source.text)).unwrap_err();
+        let err = source.locate_error(err).downcast::<ParseError>().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "unexpected closing delimiter ']' at b.ook:2:3"
+        );
+        assert_eq!(err.snippet.as_deref(), Some("  Ook? Ook!"));
+    }
+
+    #[test]
+    fn locates_errors_in_preprocessed_files() {
+        let mut source = Source::default();
+        source.push("a.bf", "+");
+        let expanded = preprocess::expand("b.bf", "@def open [[\n+@open").unwrap();
+        source.push_expanded("b.bf", expanded);
+        assert_eq!(source.text, "+\n\n+[[");
+
+        let err = parser::parse(lexer::parse(&source.text)).unwrap_err();
+        let err = source.locate_error(err).downcast::<ParseError>().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "unclosed delimiter '[' at b.bf:2:2. There are 2 unclosed delimiters."
+        );
+        assert_eq!(err.snippet.as_deref(), Some("+@open"));
+    }
+
+    #[test]
+    fn quotes_streamed_files_from_the_disk() {
+        let path = env::temp_dir().join(format!("bf-source-test-{}.bf", process::id()));
+        fs::write(&path, "#!/usr/bin/env bf\n+\n  ]+").unwrap();
+        let path = path.to_str().unwrap();
+
+        let err = vm::compile_reader(fs::File::open(path).unwrap(), &PassRegistry::default())
+            .unwrap_err();
+        let err = Source::streamed(path)
+            .locate_error(err)
+            .downcast::<ParseError>()
+            .unwrap();
+        assert_eq!(err.file.as_deref(), Some(path));
+        assert_eq!(err.loc, TokenLoc::from_col_line(3, 3));
+        assert_eq!(err.snippet.as_deref(), Some("  ]+"));
+        fs::remove_file(path).unwrap();
+    }
+}
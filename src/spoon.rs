@@ -0,0 +1,135 @@
+/*
+ *  Spoon: Brainfuck written in bits, a prefix code for each command, the shortest for the commonest.
+ *
+ *      1  +        000  -        010  >        011  <
+ *      0011  ]     00100  [      001010  .     0010110  ,
+ *      00101110  DEBUG           00101111  EXIT
+ *
+ *  Anything but `0` and `1` is a comment, a code can go on over spaces and lines. A command is at the
+ *  first bit of its code. DEBUG does nothing here, EXIT ends the program: what follows it is left out,
+ *  which is only the same as the program stopping there when it is outside of any loop.
+ *
+ *  Like Ook!, `to_brainfuck` writes every command where its code starts, see `lexer::layout`.
+ */
+
+use anyhow::{bail, Result};
+
+use crate::{
+    lexer::{self, Token, TokenLoc},
+    parser::TokenList,
+};
+
+/// What a code stands for.
+enum Code {
+    Command(Token),
+    Debug,
+    Exit,
+}
+
+const CODES: [(&str, Code); 10] = [
+    ("1", Code::Command(Token::Plus)),
+    ("000", Code::Command(Token::Minus)),
+    ("010", Code::Command(Token::Greater)),
+    ("011", Code::Command(Token::Less)),
+    ("0011", Code::Command(Token::RBracket)),
+    ("00100", Code::Command(Token::LBracket)),
+    ("001010", Code::Command(Token::Dot)),
+    ("0010110", Code::Command(Token::Comma)),
+    ("00101110", Code::Debug),
+    ("00101111", Code::Exit),
+];
+
+/// The tokens of a Spoon program, with where their codes start.
+pub fn parse(src: &str) -> Result<TokenList> {
+    Ok(commands(src)?
+        .into_iter()
+        .map(|(token, loc, _)| (token, loc))
+        .collect())
+}
+
+/// `src` as Brainfuck, see `lexer::layout`.
+pub fn to_brainfuck(src: &str) -> Result<String> {
+    let commands = commands(src)?;
+
+    Ok(lexer::layout(
+        src,
+        commands
+            .into_iter()
+            .map(|(token, _, offset)| (token, offset)),
+    ))
+}
+
+/// The commands with their locations and byte offsets.
+fn commands(src: &str) -> Result<Vec<(Token, TokenLoc, usize)>> {
+    let bytes = src.as_bytes();
+    let shebang = lexer::shebang_len(bytes);
+    let mut loc = TokenLoc::from_col_line(0, 1);
+
+    let mut commands = vec![];
+    // The bits of the code read so far, with where it starts.
+    let mut code = String::new();
+    let mut start = (loc, 0);
+
+    for (i, &ch) in bytes.iter().enumerate() {
+        loc.update_location(ch);
+        if i < shebang || !matches!(ch, b'0' | b'1') {
+            continue;
+        }
+        if code.is_empty() {
+            start = (loc, i);
+        }
+        code.push(ch as char);
+
+        // The codes are complete: any bits are the start of one.
+        let Some((_, found)) = CODES.iter().find(|(bits, _)| *bits == code) else {
+            continue;
+        };
+        code.clear();
+
+        match found {
+            Code::Command(token) => commands.push((*token, start.0, start.1)),
+            Code::Debug => {}
+            Code::Exit => return Ok(commands),
+        }
+    }
+
+    if !code.is_empty() {
+        bail!("`{}` at {} is the start of a command", code, start.0);
+    }
+
+    Ok(commands)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        lexer::{self, Token::*, TokenLoc},
+        spoon,
+    };
+
+    #[test]
+    fn reads_codes() {
+        // The `<` goes on to the next line, nothing is read after EXIT.
+        let src = "1 1 00100\n010 1 01\n1 000 0011 001010 00101111 1";
+        let tokens = spoon::parse(src).unwrap();
+        let at = |col, line| TokenLoc::from_col_line(col, line);
+
+        assert_eq!(
+            tokens,
+            [
+                (Plus, at(1, 1)),
+                (Plus, at(3, 1)),
+                (LBracket, at(5, 1)),
+                (Greater, at(1, 2)),
+                (Plus, at(5, 2)),
+                (Less, at(7, 2)),
+                (Minus, at(3, 3)),
+                (RBracket, at(7, 3)),
+                (Dot, at(12, 3)),
+            ]
+        );
+        assert_eq!(lexer::parse(&spoon::to_brainfuck(src).unwrap()), tokens);
+
+        assert!(spoon::parse("0010").is_err());
+    }
+}
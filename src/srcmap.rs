@@ -0,0 +1,53 @@
+/*
+ *  Source map: where in the source every opcode of a compiled program comes from.
+ *
+ *  Straight from the parser every opcode has the location of its first token. Every node of the IR
+ *  keeps the span of the source it was made from through the optimizer, and an opcode gets the start
+ *  of the span of its node: a run of `+` merged into one opcode is at its first `+`, a clear loop
+ *  turned into a `Set` at its `[`, and the end of a loop at its `]`.
+ */
+
+use crate::{lexer::TokenLoc, parser::Span};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceMap {
+    locs: Vec<Option<TokenLoc>>,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// For an unoptimized program, with `spans` from `parser::spans`.
+    pub fn from_spans(spans: &[Span]) -> Self {
+        Self {
+            locs: spans.iter().map(|&(start, _)| Some(start)).collect(),
+        }
+    }
+
+    pub fn push(&mut self, loc: Option<TokenLoc>) {
+        self.locs.push(loc);
+    }
+
+    /// Where the opcode at `pc` comes from, if known.
+    pub fn get(&self, pc: usize) -> Option<TokenLoc> {
+        self.locs.get(pc).copied().flatten()
+    }
+
+    pub fn len(&self) -> usize {
+        self.locs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.locs.is_empty()
+    }
+}
+
+impl FromIterator<Option<TokenLoc>> for SourceMap {
+    fn from_iter<I: IntoIterator<Item = Option<TokenLoc>>>(iter: I) -> Self {
+        Self {
+            locs: iter.into_iter().collect(),
+        }
+    }
+}
@@ -0,0 +1,151 @@
+/*
+ *  Static statistics of a program, for `bf stats`: what is in it, without running it.
+ *  The tape estimate follows the pointer through the unoptimized loop tree, assuming every loop body
+ *  runs. A loop moving the pointer by a different amount each time (`[>]`) leaves it somewhere that
+ *  depends on the data, after one the estimate is only a lower bound.
+ */
+
+use anyhow::Result;
+
+use crate::{
+    ir::{self, Ir, ProgramIr, Step},
+    lexer::{self, Token},
+    parser,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceStats {
+    /// How many of each command, in `+-<>[],.` order.
+    pub counts: [(Token, usize); 8],
+    pub loops: usize,
+    pub max_depth: usize,
+    /// Longest run of one command, `None` for a program without commands.
+    pub longest_run: Option<(Token, usize)>,
+    /// Cells the program uses at least.
+    pub tape: usize,
+    /// Whether `tape` is all the program can use.
+    pub tape_exact: bool,
+}
+
+pub fn analyze(src: &str) -> Result<SourceStats> {
+    let tokens = lexer::parse(src);
+    let ir = ProgramIr::from_program(&parser::parse(tokens.clone())?)?;
+
+    let mut counts = [
+        Token::Plus,
+        Token::Minus,
+        Token::Less,
+        Token::Greater,
+        Token::LBracket,
+        Token::RBracket,
+        Token::Comma,
+        Token::Dot,
+    ]
+    .map(|token| (token, 0));
+
+    let (mut depth, mut max_depth) = (0, 0);
+    let mut longest_run: Option<(Token, usize)> = None;
+    let mut run = (None, 0);
+
+    for &(token, _) in &tokens {
+        counts.iter_mut().find(|(t, _)| *t == token).unwrap().1 += 1;
+
+        match token {
+            Token::LBracket => {
+                depth += 1;
+                max_depth = max_depth.max(depth);
+            }
+            Token::RBracket => depth -= 1,
+            _ => {}
+        }
+
+        run = match run {
+            (Some(last), len) if last == token => (Some(token), len + 1),
+            _ => (Some(token), 1),
+        };
+        if longest_run.is_none_or(|(_, len)| run.1 > len) {
+            longest_run = Some((token, run.1));
+        }
+    }
+
+    let mut tape = Tape::default();
+    let tape_exact = tape.walk(&ir.body, 0).is_some();
+
+    Ok(SourceStats {
+        counts,
+        loops: counts[4].1,
+        max_depth,
+        longest_run,
+        tape: tape.highest + 1,
+        tape_exact,
+    })
+}
+
+#[derive(Default)]
+struct Tape {
+    highest: usize,
+}
+
+impl Tape {
+    /// Follows the pointer from `ptr` through `block`, returns where it ends or `None` if that depends
+    /// on the data.
+    fn walk(&mut self, block: &[Ir], mut ptr: usize) -> Option<usize> {
+        // Where the pointer was at the start of every loop gone into.
+        let mut starts = vec![];
+
+        for step in ir::walk(block) {
+            match step {
+                Step::Node(&Ir::Shift { amount, .. }) => {
+                    ptr = ptr.saturating_add_signed(amount);
+                    self.highest = self.highest.max(ptr);
+                }
+                Step::Node(Ir::Loop { .. }) => starts.push(ptr),
+                Step::End { .. } if starts.pop() != Some(ptr) => return None,
+                _ => {}
+            }
+        }
+
+        Some(ptr)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+
+    use crate::{lexer::Token, stats};
+
+    #[test]
+    fn analyzes_source() {
+        let stats = stats::analyze("+++[>++[>+<-]<-] comment >>>.").unwrap();
+
+        assert_eq!(stats.counts[0], (Token::Plus, 6));
+        assert_eq!(stats.counts[3], (Token::Greater, 5));
+        assert_eq!((stats.loops, stats.max_depth), (2, 2));
+        assert_eq!(stats.longest_run, Some((Token::Plus, 3)));
+        assert_eq!((stats.tape, stats.tape_exact), (4, true));
+
+        let stats = stats::analyze(">>+[>]<<").unwrap();
+        assert_eq!((stats.tape, stats.tape_exact), (4, false));
+
+        assert_eq!(stats::analyze("").unwrap().longest_run, None);
+        assert!(stats::analyze("]").is_err());
+    }
+
+    #[test]
+    fn analyzes_deep_nesting() {
+        // Far too little stack to take some for every loop.
+        let depth = 100_000;
+        let src = format!("+{}>-<{}", "[".repeat(depth), "]".repeat(depth));
+        let analyze = move || stats::analyze(&src).unwrap();
+
+        let stats = thread::Builder::new()
+            .stack_size(256 << 10)
+            .spawn(analyze)
+            .unwrap()
+            .join()
+            .unwrap();
+        assert_eq!((stats.loops, stats.max_depth), (depth, depth));
+        assert_eq!((stats.tape, stats.tape_exact), (2, true));
+    }
+}
@@ -0,0 +1,217 @@
+/*
+ *  Trivial Brainfuck Substitutions: languages that are Brainfuck with other words for the eight
+ *  commands. A `Substitution` holds the words, read from a file with a line for each command, the
+ *  command then its word:
+ *
+ *      # Blub
+ *      > Blub. Blub?
+ *      < Blub? Blub.
+ *
+ *  and set one at a time from `CMD=WORD` options. A command without a word is its own character.
+ *
+ *  Programs are read by longest match: at every byte, the longest word starting there is a command
+ *  and the source goes on after it, a byte no word starts at is a comment.
+ */
+
+use std::cmp::Reverse;
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::{
+    lexer::{self, Token, TokenLoc},
+    parser::TokenList,
+};
+
+const COMMANDS: &[u8] = b"+-<>[],.";
+
+#[derive(Debug, Clone)]
+pub struct Substitution {
+    /// A word for every command.
+    words: Vec<(Token, String)>,
+}
+
+impl Substitution {
+    /// Brainfuck itself, every command its own character.
+    pub fn new() -> Self {
+        Self {
+            words: COMMANDS
+                .iter()
+                .map(|&ch| (Token::from_u8(ch).unwrap(), (ch as char).to_string()))
+                .collect(),
+        }
+    }
+
+    /// The words of a file laid out like at the top of this file. Lines starting with `#` and empty
+    /// lines are left out.
+    pub fn read(text: &str) -> Result<Self> {
+        let mut substitution = Self::new();
+        let mut seen = vec![];
+
+        for (i, line) in text.lines().enumerate() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (token, word) = line
+                .split_once(char::is_whitespace)
+                .and_then(|(command, word)| Some((command_token(command)?, word.trim())))
+                .ok_or_else(|| {
+                    anyhow!(
+                        "line {}: expected a command and its word, such as `+ moo`",
+                        i + 1
+                    )
+                })?;
+            if seen.contains(&token) {
+                bail!("line {}: `{}` has a word already", i + 1, token.to_char());
+            }
+            seen.push(token);
+            substitution.insert(token, word)?;
+        }
+
+        Ok(substitution)
+    }
+
+    /// Sets the word of a command from `CMD=WORD`.
+    pub fn set(&mut self, spec: &str) -> Result<()> {
+        let (token, word) = spec
+            .split_once('=')
+            .and_then(|(command, word)| Some((command_token(command)?, word)))
+            .ok_or_else(|| anyhow!("expected `CMD=WORD`, such as `+=moo`, not `{}`", spec))?;
+
+        self.insert(token, word)
+    }
+
+    fn insert(&mut self, token: Token, word: &str) -> Result<()> {
+        if word.is_empty() {
+            bail!("the word for `{}` is empty", token.to_char());
+        }
+
+        for (command, old) in &mut self.words {
+            if *command == token {
+                *old = word.to_string();
+            }
+        }
+        // Longest first, for the longest match to be the first found.
+        self.words.sort_by_key(|(_, word)| Reverse(word.len()));
+
+        Ok(())
+    }
+
+    /// The tokens of `src`, at where their words start.
+    pub fn parse(&self, src: &str) -> Result<TokenList> {
+        Ok(self
+            .commands(src)?
+            .into_iter()
+            .map(|(token, loc, _)| (token, loc))
+            .collect())
+    }
+
+    /// `src` as Brainfuck, see `lexer::layout`.
+    pub fn to_brainfuck(&self, src: &str) -> Result<String> {
+        let commands = self.commands(src)?;
+
+        Ok(lexer::layout(
+            src,
+            commands
+                .into_iter()
+                .map(|(token, _, offset)| (token, offset)),
+        ))
+    }
+
+    /// The commands with their locations and byte offsets.
+    fn commands(&self, src: &str) -> Result<Vec<(Token, TokenLoc, usize)>> {
+        for (i, (token, word)) in self.words.iter().enumerate() {
+            if let Some((other, _)) = self.words[i + 1..].iter().find(|(_, w)| w == word) {
+                bail!(
+                    "`{}` and `{}` have the same word `{}`",
+                    token.to_char(),
+                    other.to_char(),
+                    word
+                );
+            }
+        }
+
+        let bytes = src.as_bytes();
+        let shebang = lexer::shebang_len(bytes);
+        let mut loc = TokenLoc::from_col_line(0, 1);
+        bytes[..shebang]
+            .iter()
+            .for_each(|&ch| loc.update_location(ch));
+
+        let mut commands = vec![];
+        let mut pos = shebang;
+        while pos < bytes.len() {
+            loc.update_location(bytes[pos]);
+            let found = self
+                .words
+                .iter()
+                .find(|(_, word)| bytes[pos..].starts_with(word.as_bytes()));
+
+            match found {
+                Some((token, word)) => {
+                    commands.push((*token, loc, pos));
+                    word.bytes().skip(1).for_each(|ch| loc.update_location(ch));
+                    pos += word.len();
+                }
+                None => pos += 1,
+            }
+        }
+
+        Ok(commands)
+    }
+}
+
+impl Default for Substitution {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The token of a command given as its character.
+fn command_token(command: &str) -> Option<Token> {
+    match command.as_bytes() {
+        &[ch] => Token::from_u8(ch),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        lexer::{self, Token::*, TokenLoc},
+        tbs::Substitution,
+    };
+
+    #[test]
+    fn reads_by_longest_match() {
+        let mut substitution =
+            Substitution::read("# Blub\n\n+ Blub. Blub.\n- Blub! Blub!\n. Blub! Blub. \n").unwrap();
+        substitution.set("[=Blub").unwrap();
+        substitution.set("]=Blu").unwrap();
+
+        let src = "Blub. Blub.Blub! Blub.\nBlub Blu x Blub! Blub!>";
+        let tokens = substitution.parse(src).unwrap();
+        assert_eq!(
+            tokens,
+            [
+                (Plus, TokenLoc::from_col_line(1, 1)),
+                (Dot, TokenLoc::from_col_line(12, 1)),
+                (LBracket, TokenLoc::from_col_line(1, 2)),
+                (RBracket, TokenLoc::from_col_line(6, 2)),
+                (Minus, TokenLoc::from_col_line(12, 2)),
+                (Greater, TokenLoc::from_col_line(23, 2)),
+            ]
+        );
+        assert_eq!(
+            lexer::parse(&substitution.to_brainfuck(src).unwrap()),
+            tokens
+        );
+
+        substitution.set("<=Blub").unwrap();
+        assert!(substitution.parse(src).is_err());
+        assert!(substitution.set("+=").is_err());
+        assert!(substitution.set("moo").is_err());
+        assert!(Substitution::read("+ a\n+ b").is_err());
+        assert!(Substitution::read("x a").is_err());
+    }
+}
@@ -0,0 +1,142 @@
+/*
+ *  `Arbitrary` for what programs are made of, with the `testing` feature: property tests of passes
+ *  and engines, here or in crates using bf, get tokens, opcodes and programs from any bytes, such as
+ *  those of cargo-fuzz.
+ *
+ *  A token is any of them, an opcode any type with any data and offset, so a program of them doesn't
+ *  often pass `parser::verify`. `Balanced` is a Brainfuck program that does: its brackets are
+ *  balanced and it is parsed like a program read from a file, unoptimized.
+ */
+
+use std::ops::ControlFlow;
+
+use arbitrary::{Arbitrary, Result, Unstructured};
+
+use crate::{
+    lexer::{self, Token},
+    opcodes::{OpCode, OpCodeType},
+    parser::{self, Program},
+};
+
+/// Commands in a `Balanced` program at most.
+pub const MAX_LEN: usize = 256;
+/// How deep loops go at most.
+pub const MAX_DEPTH: usize = 8;
+
+impl<'a> Arbitrary<'a> for Token {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        u.choose(&Token::ALL).copied()
+    }
+
+    fn size_hint(_depth: usize) -> (usize, Option<usize>) {
+        (1, Some(1))
+    }
+}
+
+impl<'a> Arbitrary<'a> for OpCodeType {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        u.choose(&OpCodeType::ALL).copied()
+    }
+
+    fn size_hint(_depth: usize) -> (usize, Option<usize>) {
+        (1, Some(1))
+    }
+}
+
+impl<'a> Arbitrary<'a> for OpCode {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(OpCode {
+            ty: u.arbitrary()?,
+            data: u.arbitrary()?,
+            offset: u.arbitrary()?,
+        })
+    }
+}
+
+/// A Brainfuck program of the eight commands with its brackets balanced, as source and parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Balanced {
+    pub src: String,
+    pub program: Program,
+}
+
+impl<'a> Arbitrary<'a> for Balanced {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let mut src = String::new();
+        let mut open = 0;
+
+        // Room is kept for the `]` of the loops open.
+        u.arbitrary_loop(None, Some((MAX_LEN - MAX_DEPTH) as u32), |u| {
+            match *u.choose(b"+-<>.,[]")? {
+                b'[' if open == MAX_DEPTH => {}
+                b'[' => {
+                    src.push('[');
+                    open += 1;
+                }
+                b']' if open == 0 => {}
+                b']' => {
+                    src.push(']');
+                    open -= 1;
+                }
+                ch => src.push(ch as char),
+            }
+            Ok(ControlFlow::Continue(()))
+        })?;
+        src.extend(std::iter::repeat_n(']', open));
+
+        let program = parser::parse(lexer::parse(&src)).expect("the brackets are balanced");
+
+        Ok(Self { src, program })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use arbitrary::{Arbitrary, Unstructured};
+
+    use crate::{
+        fuzz::Rng,
+        lexer::{self, Token},
+        optimizer, parser,
+        testing::{Balanced, MAX_LEN},
+    };
+
+    /// `len` bytes of `seed`.
+    fn bytes(seed: u64, len: usize) -> Vec<u8> {
+        let mut rng = Rng::new(seed);
+        (0..len).map(|_| rng.next_u64() as u8).collect()
+    }
+
+    #[test]
+    fn tokens_are_any_of_them() {
+        let data = bytes(1, 4096);
+        let mut u = Unstructured::new(&data);
+        let mut seen = vec![];
+        while let Ok(token) = Token::arbitrary(&mut u) {
+            if !seen.contains(&token) {
+                seen.push(token);
+            }
+            if u.is_empty() {
+                break;
+            }
+        }
+        assert_eq!(seen.len(), Token::ALL.len());
+    }
+
+    #[test]
+    fn balanced_programs_verify() {
+        for seed in 0..200 {
+            let data = bytes(seed, 512);
+            let balanced = Balanced::arbitrary(&mut Unstructured::new(&data)).unwrap();
+            assert!(balanced.src.len() <= MAX_LEN, "{}", balanced.src);
+            assert_eq!(
+                parser::parse(lexer::parse(&balanced.src)).unwrap(),
+                balanced.program
+            );
+            parser::verify(&balanced.program).unwrap();
+
+            let optimized = optimizer::optimize(balanced.program.clone()).unwrap();
+            parser::verify(&optimized).unwrap();
+        }
+    }
+}
@@ -0,0 +1,260 @@
+/*
+ *  Direct threaded engine: every opcode is resolved to its handler function once, before running, so
+ *  dispatch is an indirect call through the instruction instead of a `match` on its type.
+ *  Handlers return the pc of the next instruction.
+ *
+ *  On mandelbrot this is ~40% slower than the `match` loop of `Vm::run_inner` (4.6s against 3.2s): the
+ *  handlers can't be inlined into the loop, so every opcode pays for a call. Returning a sentinel pc
+ *  instead of going through `Result` made no difference.
+ */
+
+use std::io::{Read, Write};
+
+use anyhow::Result;
+
+use crate::{opcodes::OpCodeType, parser::Program, vm::Vm};
+
+struct Io<'a> {
+    input: &'a mut dyn Read,
+    output: &'a mut dyn Write,
+}
+
+type Handler = fn(&mut Vm, &mut Io, &Instr, usize) -> Result<usize>;
+
+struct Instr {
+    handler: Handler,
+    data: usize,
+    offset: i32,
+}
+
+pub struct ThreadedCode {
+    code: Vec<Instr>,
+}
+
+impl ThreadedCode {
+    pub fn new(program: &Program) -> Self {
+        let code = program
+            .iter()
+            .map(|op| Instr {
+                handler: handler(op.ty),
+                // `End` jumps past the last instruction.
+                data: match op.ty {
+                    OpCodeType::End => program.len(),
+                    _ => op.data,
+                },
+                offset: op.offset,
+            })
+            .collect();
+
+        Self { code }
+    }
+
+    /// Runs from `pc` to the end of the program, `pc` is left at the failing instruction on errors.
+    pub fn run(
+        &self,
+        vm: &mut Vm,
+        pc: &mut usize,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+    ) -> Result<()> {
+        let mut io = Io { input, output };
+
+        while let Some(instr) = self.code.get(*pc) {
+            *pc = (instr.handler)(vm, &mut io, instr, *pc)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn handler(ty: OpCodeType) -> Handler {
+    use OpCodeType::*;
+
+    match ty {
+        Add => add,
+        Sub => sub,
+        ShiftLeft => shift_left,
+        ShiftRight => shift_right,
+        // Quickened loops are still loops, this engine just runs them the plain way.
+        JmpZero | ClearLoop | MulLoop => jump_zero,
+        JmpNotZero => jump_not_zero,
+        InputChar => input_char,
+        PrintChar => print_chars,
+        Set => set,
+        MulAdd => mul_add,
+        FillRange => fill_range,
+        ClearRange => clear_range,
+        ProcStart => proc_start,
+        ProcEnd => proc_end,
+        Call => call,
+        End => end,
+        Store | Retrieve | BitsLeft | BitsRight | Not | Xor | And | Or => extended,
+        Fork => fork,
+        PrevTape | NextTape | CopyToTape => tape_command,
+        Random => random,
+        Dump => dump,
+        OpenFile | WriteFile | ReadFile => file_command,
+    }
+}
+
+fn add(vm: &mut Vm, _: &mut Io, instr: &Instr, pc: usize) -> Result<usize> {
+    vm.add_to_cell(instr.data, instr.offset)?;
+
+    Ok(pc + 1)
+}
+
+fn sub(vm: &mut Vm, _: &mut Io, instr: &Instr, pc: usize) -> Result<usize> {
+    vm.sub_to_cell(instr.data, instr.offset)?;
+
+    Ok(pc + 1)
+}
+
+fn shift_left(vm: &mut Vm, _: &mut Io, instr: &Instr, pc: usize) -> Result<usize> {
+    vm.shift_left(instr.data);
+
+    Ok(pc + 1)
+}
+
+fn shift_right(vm: &mut Vm, _: &mut Io, instr: &Instr, pc: usize) -> Result<usize> {
+    vm.shift_right(instr.data)?;
+
+    Ok(pc + 1)
+}
+
+fn proc_start(vm: &mut Vm, _: &mut Io, instr: &Instr, pc: usize) -> Result<usize> {
+    vm.define_procedure(pc);
+
+    Ok(instr.data + 1)
+}
+
+fn proc_end(vm: &mut Vm, _: &mut Io, _: &Instr, _: usize) -> Result<usize> {
+    Ok(vm.return_procedure()? + 1)
+}
+
+fn call(vm: &mut Vm, _: &mut Io, _: &Instr, pc: usize) -> Result<usize> {
+    Ok(vm.call_procedure(pc)? + 1)
+}
+
+fn end(_: &mut Vm, _: &mut Io, instr: &Instr, _: usize) -> Result<usize> {
+    Ok(instr.data)
+}
+
+fn extended(vm: &mut Vm, _: &mut Io, _: &Instr, pc: usize) -> Result<usize> {
+    vm.extended(vm.program()[pc].ty);
+
+    Ok(pc + 1)
+}
+
+fn fork(vm: &mut Vm, _: &mut Io, _: &Instr, pc: usize) -> Result<usize> {
+    vm.fork_unscheduled()?;
+
+    Ok(pc + 1)
+}
+
+fn tape_command(vm: &mut Vm, _: &mut Io, _: &Instr, pc: usize) -> Result<usize> {
+    vm.tape_command(vm.program()[pc].ty);
+
+    Ok(pc + 1)
+}
+
+fn random(vm: &mut Vm, _: &mut Io, _: &Instr, pc: usize) -> Result<usize> {
+    vm.random_cell();
+
+    Ok(pc + 1)
+}
+
+fn dump(vm: &mut Vm, io: &mut Io, _: &Instr, pc: usize) -> Result<usize> {
+    vm.dump_tape(&mut io.output)?;
+
+    Ok(pc + 1)
+}
+
+fn file_command(vm: &mut Vm, _: &mut Io, _: &Instr, pc: usize) -> Result<usize> {
+    vm.file_command(vm.program()[pc].ty)?;
+
+    Ok(pc + 1)
+}
+
+fn jump_zero(vm: &mut Vm, _: &mut Io, instr: &Instr, pc: usize) -> Result<usize> {
+    match *vm.cell_at_mut(instr.offset)? {
+        0 => Ok(instr.data + 1),
+        _ => Ok(pc + 1),
+    }
+}
+
+fn jump_not_zero(vm: &mut Vm, _: &mut Io, instr: &Instr, pc: usize) -> Result<usize> {
+    match *vm.cell_at_mut(instr.offset)? {
+        0 => Ok(pc + 1),
+        _ => Ok(instr.data + 1),
+    }
+}
+
+fn input_char(vm: &mut Vm, io: &mut Io, instr: &Instr, pc: usize) -> Result<usize> {
+    vm.input_char(instr.data, instr.offset, &mut io.input)?;
+
+    Ok(pc + 1)
+}
+
+fn print_chars(vm: &mut Vm, io: &mut Io, instr: &Instr, pc: usize) -> Result<usize> {
+    vm.print_chars(instr.data, instr.offset, &mut io.output)?;
+
+    Ok(pc + 1)
+}
+
+fn set(vm: &mut Vm, _: &mut Io, instr: &Instr, pc: usize) -> Result<usize> {
+    vm.set_cell(instr.data, instr.offset)?;
+
+    Ok(pc + 1)
+}
+
+fn mul_add(vm: &mut Vm, _: &mut Io, instr: &Instr, pc: usize) -> Result<usize> {
+    vm.mul_add(instr.data, instr.offset)?;
+
+    Ok(pc + 1)
+}
+
+fn fill_range(vm: &mut Vm, _: &mut Io, instr: &Instr, pc: usize) -> Result<usize> {
+    vm.fill_range(instr.data >> 8, instr.data as u8, instr.offset)?;
+
+    Ok(pc + 1)
+}
+
+fn clear_range(vm: &mut Vm, _: &mut Io, instr: &Instr, pc: usize) -> Result<usize> {
+    vm.fill_range(instr.data, 0, instr.offset)?;
+
+    Ok(pc + 1)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::vm::Vm;
+
+    const PROGRAMS: [&str; 4] = [
+        "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.",
+        "+++[->++<]>[->+>+<<]>>[-<<+>>]<<<,[.,]",
+        ">>>+++<<<[-]>>>[<+>-]<.",
+        "+[>+]",
+    ];
+
+    #[test]
+    fn threaded_matches_match_loop() {
+        for src in PROGRAMS {
+            let input = b"abc";
+
+            let mut expected = vec![];
+            let expected_result = Vm::new(src)
+                .unwrap()
+                .run_with(&mut input.as_slice(), &mut expected)
+                .map_err(|err| err.to_string());
+
+            let mut output = vec![];
+            let result = Vm::new(src)
+                .unwrap()
+                .run_threaded(&mut input.as_slice(), &mut output)
+                .map_err(|err| err.to_string());
+
+            assert_eq!(output, expected, "{}", src);
+            assert_eq!(result, expected_result, "{}", src);
+        }
+    }
+}
@@ -0,0 +1,112 @@
+/*
+ *  Tiered execution.
+ *  The interpreter counts how often every loop is reached (entered or jumped back to) and hands loops
+ *  over to the native code emitter once they get hot. Loops which cannot be compiled stay interpreted
+ *  and are not tried again.
+ */
+
+use crate::{
+    codegen::{self, NativeLoop},
+    parser::Program,
+};
+
+pub const DEFAULT_JIT_THRESHOLD: u32 = 1_000;
+
+enum LoopState {
+    Counting(u32),
+    Compiled(NativeLoop),
+    Interpreted,
+}
+
+pub struct Tiering {
+    threshold: u32,
+    /// Indexed by the pc of the loop's `JmpZero`.
+    loops: Vec<LoopState>,
+}
+
+impl Tiering {
+    pub fn new(program: &Program, threshold: u32) -> Self {
+        let loops = (0..program.len()).map(|_| LoopState::Counting(0)).collect();
+
+        Self { threshold, loops }
+    }
+
+    pub fn threshold(&self) -> u32 {
+        self.threshold
+    }
+
+    /// Records one more run of the loop starting at `start` and returns its native code once it is hot.
+    #[inline]
+    pub fn hit(&mut self, program: &Program, start: usize) -> Option<&NativeLoop> {
+        let state = &mut self.loops[start];
+
+        if let LoopState::Counting(count) = state {
+            *count += 1;
+
+            if *count < self.threshold {
+                return None;
+            }
+
+            *state = match codegen::compile_loop(program, start) {
+                Some(native) => LoopState::Compiled(native),
+                None => LoopState::Interpreted,
+            };
+        }
+
+        match state {
+            LoopState::Compiled(native) => Some(native),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Debug for Tiering {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let compiled = self
+            .loops
+            .iter()
+            .filter(|state| matches!(state, LoopState::Compiled(_)))
+            .count();
+
+        f.debug_struct("Tiering")
+            .field("threshold", &self.threshold)
+            .field("compiled", &compiled)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::empty;
+
+    use crate::vm::Vm;
+
+    fn run(src: &str, threshold: Option<u32>) -> anyhow::Result<Vec<u8>> {
+        let mut vm = Vm::new(src)?;
+        if let Some(threshold) = threshold {
+            vm.enable_tiering(threshold);
+        }
+
+        let mut output = vec![];
+        vm.run_with(&mut empty(), &mut output)?;
+
+        Ok(output)
+    }
+
+    #[test]
+    fn tiered_output_matches_interpreter() {
+        let src = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+
+        for threshold in [1, 2, 5] {
+            assert_eq!(run(src, Some(threshold)).unwrap(), run(src, None).unwrap());
+        }
+        assert_eq!(run(src, Some(1)).unwrap(), b"Hello World!\n");
+    }
+
+    #[test]
+    fn errors_still_come_from_the_interpreter() {
+        let err = run("+[>+]", Some(1)).unwrap_err();
+
+        assert!(err.to_string().starts_with("memory overflowed"));
+    }
+}
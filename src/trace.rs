@@ -0,0 +1,115 @@
+/*
+ *  Execution trace, for `--trace`: a record of every instruction the VM runs, taken before it runs.
+ *
+ *  The text format is a line per instruction:
+ *
+ *      000012  ADD 4              ptr 3      cell 0
+ *
+ *  The binary one is 9 bytes per instruction, the pc and the pointer as little-endian u32 and the cell
+ *  as a byte. The instruction is the one at the pc in the program, as `--dump-bytecode` prints it.
+ */
+
+use std::io::Write;
+
+use anyhow::Result;
+
+use crate::{
+    asm,
+    parser::Program,
+    vm::{Observer, Vm},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    Text,
+    Binary,
+}
+
+pub struct Trace<W: Write> {
+    out: W,
+    format: TraceFormat,
+    /// Instructions left to record, `None` for no limit.
+    left: Option<u64>,
+}
+
+impl<W: Write> Trace<W> {
+    /// Records at most `limit` instructions, the first ones.
+    pub fn new(out: W, format: TraceFormat, limit: Option<u64>) -> Self {
+        Self {
+            out,
+            format,
+            left: limit,
+        }
+    }
+}
+
+/// A record of the text format, without the newline.
+pub fn text_line(program: &Program, pc: usize, ptr: usize, cell: u8) -> String {
+    format!(
+        "{:06}  {:<18} ptr {:<6} cell {}",
+        pc,
+        asm::instruction(&program[pc]),
+        ptr,
+        cell
+    )
+}
+
+impl<W: Write> Observer for Trace<W> {
+    fn instruction(&mut self, vm: &Vm) -> Result<()> {
+        match &mut self.left {
+            Some(0) => return Ok(()),
+            Some(left) => *left -= 1,
+            None => {}
+        }
+
+        let (pc, ptr) = (vm.pc(), vm.mem_ptr());
+        let cell = vm.mem()[ptr];
+
+        match self.format {
+            TraceFormat::Text => writeln!(self.out, "{}", text_line(vm.program(), pc, ptr, cell))?,
+            TraceFormat::Binary => {
+                self.out.write_all(&(pc as u32).to_le_bytes())?;
+                self.out.write_all(&(ptr as u32).to_le_bytes())?;
+                self.out.write_all(&[cell])?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        Ok(self.out.flush()?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        trace::{Trace, TraceFormat},
+        vm::Vm,
+    };
+
+    #[test]
+    fn traces_instructions() {
+        let run = |format, limit| {
+            let mut trace = Trace::new(vec![], format, limit);
+            Vm::new("++>+.")
+                .unwrap()
+                .run_observed(&mut &b""[..], &mut vec![], &mut trace)
+                .unwrap();
+            trace.out
+        };
+
+        let text = String::from_utf8(run(TraceFormat::Text, None)).unwrap();
+        assert_eq!(text.lines().count(), 4);
+        assert_eq!(
+            text.lines().nth(2).unwrap(),
+            "000002  ADD 1              ptr 1      cell 0"
+        );
+
+        assert_eq!(
+            run(TraceFormat::Binary, Some(2)),
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 2]
+        );
+    }
+}
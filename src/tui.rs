@@ -0,0 +1,277 @@
+/*
+ *  Full-screen front end of the debugger, for `bf debug --tui`. Shows the source with the current
+ *  instruction highlighted, the tape around the pointer and the output so far. The keys run debugger
+ *  commands, `:` types any of them, so everything the prompt can do works here too.
+ *
+ *  The terminal is taken over, the program's input comes from a file or is empty.
+ */
+
+use anyhow::Result;
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind},
+    layout::{Constraint, Layout, Rect},
+    style::{Modifier, Style, Stylize},
+    text::{Line, Span},
+    widgets::{Block, Paragraph, Wrap},
+    DefaultTerminal, Frame,
+};
+
+use crate::{debugger::Debugger, lexer};
+
+const KEYS: &str = "s step  n next  c continue  b breakpoint  : command  q quit";
+
+struct App<'a> {
+    debugger: Debugger,
+    input: &'a [u8],
+    output: Vec<u8>,
+    /// What the last command wrote.
+    message: String,
+    /// The command being typed after `:`.
+    typing: Option<String>,
+}
+
+pub fn run(debugger: Debugger, input: &[u8]) -> Result<()> {
+    let mut app = App {
+        message: debugger.location(),
+        debugger,
+        input,
+        output: vec![],
+        typing: None,
+    };
+
+    let mut terminal = ratatui::init();
+    let result = app.event_loop(&mut terminal);
+    ratatui::restore();
+
+    result
+}
+
+impl App<'_> {
+    fn event_loop(&mut self, terminal: &mut DefaultTerminal) -> Result<()> {
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+
+            if let Some(typing) = &mut self.typing {
+                match key.code {
+                    KeyCode::Enter => {
+                        let line = self.typing.take().unwrap_or_default();
+                        if !self.command(&line)? {
+                            return Ok(());
+                        }
+                    }
+                    KeyCode::Esc => self.typing = None,
+                    KeyCode::Backspace => {
+                        typing.pop();
+                    }
+                    KeyCode::Char(ch) => typing.push(ch),
+                    _ => {}
+                }
+                continue;
+            }
+
+            let command = match key.code {
+                KeyCode::Char('s') => "step".to_string(),
+                KeyCode::Char('n') => "next".to_string(),
+                KeyCode::Char('c') => "continue".to_string(),
+                KeyCode::Char('b') => {
+                    let pc = self.debugger.vm().pc();
+                    match self.debugger.breakpoints().any(|at| at == pc) {
+                        true => format!("delete {}", pc),
+                        false => format!("break {}", pc),
+                    }
+                }
+                KeyCode::Char(':') => {
+                    self.typing = Some(String::new());
+                    continue;
+                }
+                KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                _ => continue,
+            };
+            self.command(&command)?;
+        }
+    }
+
+    /// Runs a debugger command, returns false on `quit`.
+    fn command(&mut self, line: &str) -> Result<bool> {
+        let mut out = vec![];
+        let go_on = self
+            .debugger
+            .command(line, &mut self.input, &mut self.output, &mut out)?;
+        self.message = String::from_utf8_lossy(&out).trim_end().to_string();
+
+        Ok(go_on)
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [main, output, status] = Layout::vertical([
+            Constraint::Fill(3),
+            Constraint::Fill(1),
+            Constraint::Length(3),
+        ])
+        .areas(frame.area());
+        let [source, tape] =
+            Layout::horizontal([Constraint::Fill(1), Constraint::Length(24)]).areas(main);
+
+        self.draw_source(frame, source);
+        self.draw_tape(frame, tape);
+
+        let text = String::from_utf8_lossy(&self.output);
+        let lines = text.lines().count() as u16;
+        let scroll = lines.saturating_sub(output.height.saturating_sub(2));
+        frame.render_widget(
+            Paragraph::new(text.into_owned())
+                .block(Block::bordered().title(" output "))
+                .scroll((scroll, 0)),
+            output,
+        );
+
+        let status_text = match &self.typing {
+            Some(typing) => format!(":{}", typing),
+            None => self.message.clone(),
+        };
+        frame.render_widget(
+            Paragraph::new(status_text)
+                .wrap(Wrap { trim: false })
+                .block(Block::bordered().title_bottom(KEYS)),
+            status,
+        );
+    }
+
+    fn draw_source(&self, frame: &mut Frame, area: Rect) {
+        let current = self.debugger.span(self.debugger.vm().pc());
+        let breakpoint_lines: Vec<_> = self
+            .debugger
+            .breakpoints()
+            .filter_map(|pc| self.debugger.span(pc))
+            .map(|(start, _)| start.line())
+            .collect();
+
+        let lines: Vec<_> = self
+            .debugger
+            .src()
+            .lines()
+            .enumerate()
+            .map(|(i, text)| {
+                let line = i + 1;
+                let marker = match breakpoint_lines.contains(&line) {
+                    true => "● ".red(),
+                    false => "  ".into(),
+                };
+                let mut spans = vec![marker, format!("{:>4} ", line).dark_gray()];
+
+                // Bytes of the current instruction on this line.
+                let highlight = current.and_then(|(start, end)| {
+                    (start.line()..=end.line()).contains(&line).then(|| {
+                        let from = if start.line() == line {
+                            lexer::col_offset(text, start.col())
+                        } else {
+                            0
+                        };
+                        let to = if end.line() == line {
+                            lexer::col_offset(text, end.col() + 1)
+                        } else {
+                            text.len()
+                        };
+                        (from.min(to), to)
+                    })
+                });
+                match highlight {
+                    Some((from, to)) => {
+                        spans.push(Span::raw(untab(&text[..from])));
+                        spans.push(Span::styled(
+                            untab(&text[from..to]),
+                            Style::new().add_modifier(Modifier::REVERSED),
+                        ));
+                        spans.push(Span::raw(untab(&text[to..])));
+                    }
+                    None => spans.push(Span::raw(untab(text))),
+                }
+
+                Line::from(spans)
+            })
+            .collect();
+
+        // Keeps the current line in the middle when it can.
+        let current_line = current.map_or(lines.len(), |(start, _)| start.line());
+        let scroll = current_line.saturating_sub(area.height as usize / 2) as u16;
+
+        frame.render_widget(
+            Paragraph::new(lines)
+                .block(Block::bordered().title(" source "))
+                .scroll((scroll, 0)),
+            area,
+        );
+    }
+
+    fn draw_tape(&self, frame: &mut Frame, area: Rect) {
+        let (mem, ptr) = (self.debugger.vm().mem(), self.debugger.vm().mem_ptr());
+        let rows = area.height.saturating_sub(2) as usize;
+        let start = ptr
+            .saturating_sub(rows / 2)
+            .min(mem.len().saturating_sub(rows));
+
+        let lines: Vec<_> = (start..(start + rows).min(mem.len()))
+            .map(|i| {
+                let ch = match mem[i] {
+                    0x20..=0x7e => mem[i] as char,
+                    _ => '.',
+                };
+                let text = format!("{:>7}  {:02x} {:>3} {}", i, mem[i], mem[i], ch);
+                match i == ptr {
+                    true => Line::from(text).reversed(),
+                    false => Line::from(text),
+                }
+            })
+            .collect();
+
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title(" tape ")),
+            area,
+        );
+    }
+}
+
+fn untab(text: &str) -> String {
+    text.replace('\t', "    ")
+}
+
+#[cfg(test)]
+mod test {
+    use ratatui::{backend::TestBackend, Terminal};
+
+    use crate::{debugger::Debugger, tui::App};
+
+    #[test]
+    fn draws_source_tape_and_output() {
+        let mut app = App {
+            debugger: Debugger::new("++++++++[>++++++++<-]>+.\n").unwrap(),
+            input: &[],
+            output: vec![],
+            message: String::new(),
+            typing: None,
+        };
+        app.command("continue").unwrap();
+
+        let mut terminal = Terminal::new(TestBackend::new(60, 16)).unwrap();
+        terminal.draw(|frame| app.draw(frame)).unwrap();
+        let screen: String = terminal
+            .backend()
+            .buffer()
+            .content()
+            .chunks(60)
+            .map(|row| row.iter().map(|cell| cell.symbol()).collect::<String>() + "\n")
+            .collect();
+
+        assert!(screen.contains("1 ++++++++[>++++++++<-]>+."));
+        assert!(screen.contains("1  41  65 A"));
+        assert!(screen.contains("│A"));
+        assert!(screen.contains("program ended"));
+    }
+}
@@ -1,39 +1,463 @@
 use std::{
-    array,
-    io::{stdin, stdout, Read, StdoutLock, Write},
+    array, fmt, hint,
+    io::{self, stdin, stdout, ErrorKind, Read, Write},
+    mem,
+    sync::Arc,
+    time::Instant,
 };
 
 use anyhow::{bail, Result};
 
 use crate::{
-    lexer,
+    blocks::{Block, BlockCode, Item},
+    fileio::FileIo,
+    hexdump,
+    lexer::{self, Lexer, TokenLoc, Tokens},
+    log,
     opcodes::{OpCode, OpCodeType},
-    parser::{self, Program},
+    optimizer::PassRegistry,
+    parser::{self, Parser, Program, Span, TokenData, TokenList},
+    quicken::Quickening,
+    srcmap::SourceMap,
+    threaded::ThreadedCode,
 };
 
+#[cfg(feature = "tiered")]
+use crate::tiered::Tiering;
+
 pub const DEFAULT_VM_MEM_SIZE: usize = 30_000;
+/// Tape size from which `clear` allocates the tape again instead of writing zeros over it.
+const LAZY_CLEAR_CELLS: usize = 1 << 20;
+/// Bytes `write_repeated` writes at once.
+const WRITE_CHUNK: usize = 64;
+/// Procedure calls of pbrain that can be running at once.
+pub const MAX_CALL_DEPTH: usize = 1 << 16;
+/// Seed of the random numbers of `?` unless `set_seed` is called.
+pub const DEFAULT_SEED: u64 = 0;
+/// Cells `#` prints unless `set_dump_cells` is called, a row of a hexdump.
+pub const DEFAULT_DUMP_CELLS: usize = 16;
+/// Cells a tape growing with `TapeEdge::Grow` can get to.
+pub const MAX_GROWN_TAPE: usize = 1 << 30;
+
+/// Lexes, parses and optimizes `src`, timing each stage for `-v`. The locations of the tokens are
+/// left out, a program that fails to parse is compiled again with them for the error.
+pub fn compile(src: &str) -> Result<Program> {
+    compile_with_passes(src, &PassRegistry::default())
+}
+
+/// Same as `compile`, optimizing with `passes` instead of the default ones.
+pub fn compile_with_passes(src: &str, passes: &PassRegistry) -> Result<Program> {
+    #[cfg(feature = "tracing")]
+    let _span = tracing::info_span!("compile", len = src.len()).entered();
+    let tokens = lex(src, false);
+
+    let start = Instant::now();
+    let Ok(program) = Parser::from_tokens(tokens.iter()).parse() else {
+        return Ok(compile_with_map_passes(src, passes)?.0);
+    };
+    log_parse(start, &program);
+    drop(tokens);
+
+    let start = Instant::now();
+    let program = passes.optimize(&program)?;
+    log::stage(
+        "optimize",
+        start.elapsed(),
+        &format!("{} opcodes", program.len()),
+    );
+
+    Ok(program)
+}
+
+/// Same as `compile`, with where in `src` every opcode comes from.
+pub fn compile_with_map(src: &str) -> Result<(Program, SourceMap)> {
+    compile_with_map_passes(src, &PassRegistry::default())
+}
+
+/// Same as `compile_with_map`, optimizing with `passes` instead of the default ones.
+pub fn compile_with_map_passes(src: &str, passes: &PassRegistry) -> Result<(Program, SourceMap)> {
+    #[cfg(feature = "tracing")]
+    let _span = tracing::info_span!("compile", len = src.len()).entered();
+    let tokens = lex(src, true);
+
+    let start = Instant::now();
+    let (program, spans) = Parser::from_tokens(tokens.iter()).parse_with_spans()?;
+    log_parse(start, &program);
+    drop(tokens);
+
+    optimize(&program, &spans, passes)
+}
+
+fn lex(src: &str, locs: bool) -> Tokens {
+    let start = Instant::now();
+    let tokens = Lexer::new(src).into_tokens(locs);
+    log::stage("lex", start.elapsed(), &format!("{} tokens", tokens.len()));
+
+    tokens
+}
+
+fn log_parse(start: Instant, program: &Program) {
+    log::stage(
+        "parse",
+        start.elapsed(),
+        &format!("{} opcodes", program.len()),
+    );
+}
+
+/// Same as `compile_with_map_passes` for a Brainfuck program read from `reader`, lexed and parsed as
+/// it is read, see `lexer::StreamLexer`.
+pub fn compile_reader<R: Read>(reader: R, passes: &PassRegistry) -> Result<(Program, SourceMap)> {
+    compile_stream(
+        lexer::StreamLexer::new(reader, lexer::Options::default()),
+        passes,
+    )
+}
+
+/// Same as `compile_reader` for a program already in memory, such as a file mapped into it, lexed
+/// from where it is.
+pub fn compile_bytes(src: &[u8], passes: &PassRegistry) -> Result<(Program, SourceMap)> {
+    compile_stream(lexer::Lexer::from_bytes(src).tokens().map(Ok), passes)
+}
+
+fn compile_stream(
+    tokens: impl Iterator<Item = io::Result<TokenData>>,
+    passes: &PassRegistry,
+) -> Result<(Program, SourceMap)> {
+    #[cfg(feature = "tracing")]
+    let _span = tracing::info_span!("compile").entered();
+    let start = Instant::now();
+    let (program, spans) = parser::parse_stream(tokens)?;
+    log::stage(
+        "lex and parse",
+        start.elapsed(),
+        &format!("{} opcodes", program.len()),
+    );
+
+    optimize(&program, &spans, passes)
+}
+
+fn optimize(
+    program: &Program,
+    spans: &[Span],
+    passes: &PassRegistry,
+) -> Result<(Program, SourceMap)> {
+    let start = Instant::now();
+    let (program, map) = passes.optimize_with_map(program, spans)?;
+    log::stage(
+        "optimize",
+        start.elapsed(),
+        &format!("{} opcodes", program.len()),
+    );
+
+    Ok((program, map))
+}
+
+/// Same as `compile_with_map` for the tokens of a dialect such as pbrain, left unoptimized: the
+/// optimizer only knows Brainfuck.
+pub fn compile_tokens_with_map(tokens: TokenList) -> Result<(Program, SourceMap)> {
+    let map = SourceMap::from_spans(&parser::spans(&tokens));
+
+    Ok((parser::parse(tokens)?, map))
+}
+
+/// What `,` does at the end of the input.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Eof {
+    /// Fail the run.
+    #[default]
+    Error,
+    /// Leave the cell as it is.
+    Unchanged,
+    Zero,
+    /// 255, -1 as a signed byte.
+    NegOne,
+}
+
+/// What moving past the last cell does. Moving left of the first one stays on it either way.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TapeEdge {
+    /// Fail the run with a `MemoryError`.
+    #[default]
+    Error,
+    /// Make the tape longer, up to `MAX_GROWN_TAPE` cells. Only moves grow it, a cell read at an
+    /// offset from the pointer has to be on the tape: programs run this way go unoptimized.
+    Grow,
+}
+
+/// The program went off the tape.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryError {
+    /// `past` cells past the end of a tape of `cells`.
+    Overflow { cells: usize, past: usize },
+    /// `offset` cells from the pointer at `ptr`, before the start.
+    Underflow { ptr: usize, offset: isize },
+}
+
+impl fmt::Display for MemoryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MemoryError::Overflow { cells, past } => {
+                write!(f, "memory overflowed: {cells} items => {past} items")
+            }
+            MemoryError::Underflow { ptr, offset } => {
+                write!(f, "memory underflowed: cell {ptr} with offset {offset}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for MemoryError {}
+
+/// Where a run failed: `loc` is where the instruction comes from as the source map tells, `loops` are
+/// the `[` of the loops it is in, innermost first. `Source::locate_error` fills in the files, and
+/// `snippet`, the line `loc` is on.
+///
+/// `locate_error` adds it to the error the run failed with as context, so that error can still be
+/// downcast to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuntimeError {
+    pub message: String,
+    pub loc: TokenLoc,
+    pub file: Option<String>,
+    pub loops: Vec<(Option<String>, TokenLoc)>,
+    pub snippet: Option<String>,
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {}", self.message, at(&self.file, self.loc))?;
+        for (file, loc) in &self.loops {
+            write!(f, ", in the loop at {}", at(file, *loc))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+/// `loc`, in `file` if known.
+fn at(file: &Option<String>, loc: TokenLoc) -> String {
+    match file {
+        Some(file) => format!("{}:{}", file, loc),
+        None => loc.to_string(),
+    }
+}
+
+/// Zeroes the cells of `mem`. Big tapes are allocated again instead: the system gives zeroed pages
+/// that are only written when first used, so a run starts as fast on 4G cells as on 30000.
+fn clear(mem: &mut Vec<u8>) {
+    if mem.len() < LAZY_CLEAR_CELLS {
+        mem.fill(0);
+    } else {
+        *mem = vec![0; mem.len()];
+    }
+}
+
+/// Writes `ch` `amount` times, a buffer of them at a time.
+pub fn write_repeated<W: Write + ?Sized>(output: &mut W, ch: u8, amount: usize) -> io::Result<()> {
+    if amount == 1 {
+        return output.write_all(&[ch]);
+    }
+
+    let chunk = [ch; WRITE_CHUNK];
+    let mut left = amount;
+    while left > 0 {
+        let len = left.min(WRITE_CHUNK);
+        output.write_all(&chunk[..len])?;
+        left -= len;
+    }
+
+    Ok(())
+}
+
+/// Adds where in the source the run failed to `err`, the error of the instruction at `pc` of
+/// `program`. `err` is returned as it is if `map` does not know.
+pub fn locate_error(
+    err: anyhow::Error,
+    program: &Program,
+    map: &SourceMap,
+    pc: usize,
+) -> anyhow::Error {
+    let Some(loc) = map.get(pc) else {
+        return err;
+    };
+
+    let loops = parser::enclosing_loops(program, pc)
+        .into_iter()
+        .filter_map(|start| Some((None, map.get(start)?)))
+        .collect();
+
+    let message = err.to_string();
+    err.context(RuntimeError {
+        message,
+        loc,
+        file: None,
+        loops,
+        snippet: None,
+    })
+}
+
+/// What `Vm::run_stats` counts.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct Stats {
+    pub instructions: u64,
+    pub peak_ptr: usize,
+    /// Instructions executed of each opcode type that ran, in the order of `OpCodeType::ALL`.
+    pub by_type: Vec<(OpCodeType, u64)>,
+    pub loops: Vec<LoopCount>,
+}
+
+/// A loop of the program run, by the pc of its `JmpZero`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct LoopCount {
+    pub pc: usize,
+    /// Times the program got to the loop, whether it went in or not.
+    pub reached: u64,
+    /// Times it went around.
+    pub iterations: u64,
+}
+
+/// Watches a run of `Vm::run_observed`, see `trace` and `profiler`.
+pub trait Observer {
+    /// Called before every instruction, with the machine as it is before running it.
+    fn instruction(&mut self, vm: &Vm) -> Result<()>;
+
+    /// Called once the run stopped, whether the program ended or failed.
+    fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// The state of a `Vm` between two instructions, see `Vm::snapshot`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pc: usize,
+    mem: Vec<u8>,
+    mem_ptr: usize,
+    procedures: Vec<Option<usize>>,
+    calls: Vec<usize>,
+    storage: u8,
+    random: u64,
+    tapes: Vec<Tape>,
+    tape: usize,
+}
+
+impl Snapshot {
+    /// A state to `restore`, such as one read back from a crash dump, with no pbrain procedures, an
+    /// empty storage cell, the random numbers of `DEFAULT_SEED` and a single tape. The pointer has to
+    /// be on the tape.
+    pub fn new(pc: usize, mem: Vec<u8>, mem_ptr: usize) -> Result<Self> {
+        if mem_ptr >= mem.len() {
+            bail!(
+                "the pointer at cell {} is off a tape of {} cells",
+                mem_ptr,
+                mem.len()
+            );
+        }
+
+        Ok(Self {
+            pc,
+            mem,
+            mem_ptr,
+            procedures: vec![],
+            calls: vec![],
+            storage: 0,
+            random: DEFAULT_SEED,
+            tapes: vec![],
+            tape: 0,
+        })
+    }
+}
+
+/// A tape of the multi-tape dialect other than the current one, with its pointer.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Tape {
+    mem: Vec<u8>,
+    ptr: usize,
+}
 
 #[derive(Debug)]
 pub struct Vm {
-    program: Vec<OpCode>,
+    /// Shared by the machines running it, each gets a copy of its own only to change it, when
+    /// quickening rewrites a loop or `append` adds to it.
+    program: Arc<Program>,
     pc: usize,
     mem: Vec<u8>,
+    /// Always below `mem.len()`, which the current cell is read and written without checking. It
+    /// only changes in:
+    ///
+    /// - `shift_left`, which stops at 0, and `shift_right`, which fails or grows the tape before
+    ///   moving past the last cell.
+    /// - `reset`, `set_tapes` and `set_tape_size`, back to 0 of a tape of at least one cell, `clear`
+    ///   keeping its size.
+    /// - `switch_tape`, swapped along with the tape it is on.
+    /// - `restore`, from a `Snapshot` of a machine or one checked by `Snapshot::new`.
+    /// - the native code of the `jit`, `jit-x64` and `tiered` features, see `check_ptr`.
+    ///
+    /// The cells at an offset and the closed form of scan loops check what they reach themselves.
     mem_ptr: usize,
+    /// Highest `mem_ptr` so far, only kept up to date by `run_counted`.
+    peak_ptr: usize,
+    /// Where the pbrain procedure of each number starts, empty until one is defined.
+    procedures: Vec<Option<usize>>,
+    /// The pcs of the pbrain calls running, to return to.
+    calls: Vec<usize>,
+    /// The storage cell of Extended Brainfuck.
+    storage: u8,
+    /// Where the random numbers of `?` start from, and how far along they are.
+    seed: u64,
+    random: u64,
+    /// Cells `#` prints.
+    dump_cells: usize,
+    /// The files of the file commands, None until `enable_file_io`.
+    files: Option<FileIo>,
+    eof: Eof,
+    tape_edge: TapeEdge,
+    /// Checks the pointer on every access to the current cell, see `enable_strict_bounds`.
+    strict_bounds: bool,
+    /// The tapes of the multi-tape dialect, empty with one tape. The current one is `mem` and
+    /// `mem_ptr`, its place here is left empty.
+    tapes: Vec<Tape>,
+    tape: usize,
+    quickening: Option<Quickening>,
+    #[cfg(feature = "tiered")]
+    tiering: Option<Tiering>,
 }
 
 impl Vm {
     pub fn new(src: &str) -> Result<Self> {
-        let tokens = lexer::parse(src);
-
-        parser::parse(tokens).and_then(Self::from_program)
+        compile(src).and_then(Self::from_program)
     }
 
     pub fn from_program(program: Vec<OpCode>) -> Result<Self> {
+        Self::from_shared(Arc::new(program))
+    }
+
+    /// Same as `from_program` for a program other machines run too, without copying it: one
+    /// compiled program for many runs at once, with their own tapes and input.
+    pub fn from_shared(program: Arc<Program>) -> Result<Self> {
         let vm = Self {
             program,
             pc: 0,
             mem: vec![0; DEFAULT_VM_MEM_SIZE],
             mem_ptr: 0,
+            peak_ptr: 0,
+            procedures: vec![],
+            calls: vec![],
+            storage: 0,
+            seed: DEFAULT_SEED,
+            random: DEFAULT_SEED,
+            dump_cells: DEFAULT_DUMP_CELLS,
+            files: None,
+            eof: Eof::Error,
+            tape_edge: TapeEdge::Error,
+            strict_bounds: false,
+            tapes: vec![],
+            tape: 0,
+            quickening: None,
+            #[cfg(feature = "tiered")]
+            tiering: None,
         };
 
         vm.verify_program()?;
@@ -41,58 +465,629 @@ impl Vm {
         Ok(vm)
     }
 
+    /// Replaces the program and resets the machine, quickening and tiering stay enabled if they were.
+    pub fn load(&mut self, program: Program) -> Result<()> {
+        let mut vm = Self::from_program(program)?;
+        vm.mem = vec![0; self.mem.len()];
+        vm.set_seed(self.seed);
+        vm.dump_cells = self.dump_cells;
+        vm.files = self.files.take();
+        vm.eof = self.eof;
+        vm.tape_edge = self.tape_edge;
+        vm.strict_bounds = self.strict_bounds;
+        vm.set_tapes(self.tapes.len().max(1));
+
+        if self.quickening.is_some() {
+            vm.enable_quickening();
+        }
+
+        #[cfg(feature = "tiered")]
+        if let Some(tiering) = &self.tiering {
+            vm.enable_tiering(tiering.threshold());
+        }
+
+        *self = vm;
+
+        Ok(())
+    }
+
+    /// Adds `program` after the one loaded and moves the pc to its start, the tape and the pointer stay
+    /// as they are. For running a program a piece at a time, like the REPL does.
+    pub fn append(&mut self, program: Program) -> Result<()> {
+        let start = self.program.len();
+        let mut program = program;
+        parser::verify(&program)?;
+        for op in &mut program {
+            if op.ty.is_loop_start()
+                || matches!(
+                    op.ty,
+                    OpCodeType::JmpNotZero | OpCodeType::ProcStart | OpCodeType::ProcEnd
+                )
+            {
+                op.data += start;
+            }
+        }
+
+        Arc::make_mut(&mut self.program).append(&mut program);
+        self.pc = start;
+
+        // Their tables are per pc, made again for the longer program.
+        if self.quickening.is_some() {
+            self.enable_quickening();
+        }
+        #[cfg(feature = "tiered")]
+        if let Some(tiering) = &self.tiering {
+            self.enable_tiering(tiering.threshold());
+        }
+
+        Ok(())
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            pc: self.pc,
+            mem: self.mem.clone(),
+            mem_ptr: self.mem_ptr,
+            procedures: self.procedures.clone(),
+            calls: self.calls.clone(),
+            storage: self.storage,
+            random: self.random,
+            tapes: self.tapes.clone(),
+            tape: self.tape,
+        }
+    }
+
+    /// Goes back to where `snapshot` was taken, the program has to be the same.
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        self.pc = snapshot.pc;
+        self.mem.clone_from(&snapshot.mem);
+        self.mem_ptr = snapshot.mem_ptr;
+        self.procedures.clone_from(&snapshot.procedures);
+        self.calls.clone_from(&snapshot.calls);
+        self.storage = snapshot.storage;
+        self.random = snapshot.random;
+        self.tapes.clone_from(&snapshot.tapes);
+        self.tape = snapshot.tape;
+    }
+
+    /// Clears the tape and moves the pointer and the pc back to the start.
+    pub fn reset(&mut self) {
+        self.switch_tape(0);
+        for tape in &mut self.tapes {
+            clear(&mut tape.mem);
+            tape.ptr = 0;
+        }
+        self.pc = 0;
+        clear(&mut self.mem);
+        self.mem_ptr = 0;
+        self.peak_ptr = 0;
+        self.procedures.clear();
+        self.calls.clear();
+        self.storage = 0;
+        self.random = self.seed;
+    }
+
+    /// Lets the file commands use `files`.
+    pub fn enable_file_io(&mut self, files: FileIo) {
+        self.files = Some(files);
+    }
+
+    /// Makes `#` print the first `cells` cells.
+    pub fn set_dump_cells(&mut self, cells: usize) {
+        self.dump_cells = cells;
+    }
+
+    /// Starts the random numbers of `?` over from `seed`, the same seed gives the same numbers.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.random = seed;
+    }
+
+    /// Sets what `,` does at the end of the input.
+    pub fn set_eof(&mut self, eof: Eof) {
+        self.eof = eof;
+    }
+
+    /// Sets what moving past the last cell does.
+    pub fn set_tape_edge(&mut self, edge: TapeEdge) {
+        self.tape_edge = edge;
+    }
+
+    /// Checks the pointer against the tape on every access to the current cell, which `mem_ptr` being
+    /// kept on the tape makes unneeded. For when it is in doubt, at some cost in speed.
+    pub fn enable_strict_bounds(&mut self) {
+        self.strict_bounds = true;
+    }
+
+    /// Gives the multi-tape dialect `count` tapes the size of the current one, all zeroed, and
+    /// switches to the first.
+    pub fn set_tapes(&mut self, count: usize) {
+        self.switch_tape(0);
+        self.tapes = match count {
+            0 | 1 => vec![],
+            // Each allocated on its own, cloning one would write all of their cells.
+            _ => (0..count)
+                .map(|i| Tape {
+                    mem: match i {
+                        0 => vec![],
+                        _ => vec![0; self.mem.len()],
+                    },
+                    ptr: 0,
+                })
+                .collect(),
+        };
+        clear(&mut self.mem);
+        self.mem_ptr = 0;
+    }
+
+    /// Replaces the tape with `size` zeroed cells and moves the pointer back to the start.
+    pub fn set_tape_size(&mut self, size: usize) -> Result<()> {
+        if size == 0 {
+            bail!("the tape needs at least one cell");
+        }
+
+        self.mem = vec![0; size];
+        self.mem_ptr = 0;
+        self.peak_ptr = 0;
+
+        Ok(())
+    }
+
+    /// Puts `cells` at the start of the tape, such as the ones a run before left, see `--persist-tape`.
+    /// A growing tape grows to take them, other tapes have to be long enough.
+    pub fn load_tape(&mut self, cells: &[u8]) -> Result<()> {
+        if cells.len() > self.mem.len() {
+            if self.tape_edge != TapeEdge::Grow || cells.len() > MAX_GROWN_TAPE {
+                bail!(
+                    "the tape has {} cells, too few for the {} to load",
+                    self.mem.len(),
+                    cells.len()
+                );
+            }
+            self.mem.resize(cells.len(), 0);
+        }
+
+        self.mem[..cells.len()].copy_from_slice(cells);
+        Ok(())
+    }
+
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn mem_ptr(&self) -> usize {
+        self.mem_ptr
+    }
+
+    pub fn mem(&self) -> &[u8] {
+        &self.mem
+    }
+
     #[allow(dead_code)]
     pub fn program(&self) -> &Program {
         &self.program
     }
 
-    pub fn verify_program(&self) -> Result<()> {
-        // TODO: verify program
-        //  - correct jump?
-        //  - data for Add and Sub instruction must less than u8::MAX
-        let iter = self.program.iter().map(OpCode::to_tuple);
-        for (inst, data) in iter {
-            match inst {
-                OpCodeType::Add | OpCodeType::Sub => {
-                    if data >= u8::MAX as _ {
-                        bail!("Add and Sub instruction must have data less than or equal to u8::MAX, data={}", data)
-                    }
-                }
-                _ => {}
+    /// The program, for other machines to run it too, see `from_shared`.
+    pub fn shared_program(&self) -> Arc<Program> {
+        self.program.clone()
+    }
+
+    /// The program before quickening rewrote any of it.
+    pub fn original_program(&self) -> &Program {
+        match &self.quickening {
+            Some(quickening) => &quickening.original,
+            None => &self.program,
+        }
+    }
+
+    /// Lets the VM rewrite loops into faster forms the first time it runs them.
+    pub fn enable_quickening(&mut self) {
+        self.quickening = Some(Quickening::new(&self.program));
+    }
+
+    /// Compiles loops to native code once they ran `threshold` times.
+    #[cfg(feature = "tiered")]
+    pub fn enable_tiering(&mut self, threshold: u32) {
+        self.tiering = Some(Tiering::new(&self.program, threshold));
+    }
+
+    /// Runs the loop starting at `start` natively if it is hot, returns false when it has to be interpreted.
+    #[cfg(feature = "tiered")]
+    #[inline]
+    fn run_native(&mut self, start: usize) -> bool {
+        let Some(tiering) = &mut self.tiering else {
+            return false;
+        };
+
+        match tiering.hit(&self.program, start) {
+            Some(native) => {
+                self.pc = native.run(&mut self.mem, &mut self.mem_ptr);
+                self.check_ptr();
+                true
             }
+            None => false,
         }
+    }
 
-        Ok(())
+    /// See `parser::verify`, every program is checked before it runs.
+    pub fn verify_program(&self) -> Result<()> {
+        parser::verify(&self.program)
     }
 
     #[inline]
     pub fn get_cell(&self) -> u8 {
-        // SAFETY:
-        //      self.mem_ptr is already checked in self.shift_right.
-        //      self.mem_ptr can only be increased in self.shift_right.
-        //      self.shift_left decreases self.mem_ptr but with smallest number is 0.
+        if self.strict_bounds {
+            return self.mem[self.mem_ptr];
+        }
+        debug_assert!(self.mem_ptr < self.mem.len());
+        // SAFETY: `mem_ptr` is always on the tape, see the field.
         unsafe { *self.mem.get_unchecked(self.mem_ptr) }
     }
 
     #[inline]
     pub fn get_cell_mut(&mut self) -> &mut u8 {
-        // SAFETY:
-        //      self.mem_ptr is already checked in self.shift_right.
-        //      self.mem_ptr can only be increased in self.shift_right.
-        //      self.shift_left decreases self.mem_ptr but with smallest number is 0.
+        if self.strict_bounds {
+            return &mut self.mem[self.mem_ptr];
+        }
+        debug_assert!(self.mem_ptr < self.mem.len());
+        // SAFETY: `mem_ptr` is always on the tape, see the field.
         unsafe { self.mem.get_unchecked_mut(self.mem_ptr) }
     }
 
+    /// The native code moves the pointer on its own, it has to have left it on the tape.
+    #[cfg(any(feature = "jit", feature = "jit-x64", feature = "tiered"))]
+    fn check_ptr(&self) {
+        assert!(
+            self.mem_ptr < self.mem.len(),
+            "native code left the pointer at cell {} of a tape of {} cells",
+            self.mem_ptr,
+            self.mem.len()
+        );
+    }
+
     #[inline]
-    pub fn add_to_cell(&mut self, amount: usize) {
-        let cell = self.get_cell_mut();
+    pub fn get_cell_at_mut(&mut self, offset: i32) -> Result<&mut u8> {
+        let idx = self.cell_index(offset as isize)?;
+
+        Ok(&mut self.mem[idx])
+    }
+
+    #[inline]
+    fn cell_index(&self, offset: isize) -> Result<usize> {
+        let mem_count = self.mem.len();
+        let mem_ptr = self.mem_ptr;
+
+        match mem_ptr.checked_add_signed(offset) {
+            Some(idx) if idx < mem_count => Ok(idx),
+            Some(idx) => Err(MemoryError::Overflow {
+                cells: mem_count,
+                past: idx - mem_count,
+            }
+            .into()),
+            None => Err(MemoryError::Underflow {
+                ptr: mem_ptr,
+                offset,
+            }
+            .into()),
+        }
+    }
+
+    /// Cell at `offset` from the pointer, without the bounds check when there is no offset.
+    #[inline]
+    pub fn cell_at_mut(&mut self, offset: i32) -> Result<&mut u8> {
+        if offset == 0 {
+            Ok(self.get_cell_mut())
+        } else {
+            self.get_cell_at_mut(offset)
+        }
+    }
+
+    #[inline]
+    pub fn add_to_cell(&mut self, amount: usize, offset: i32) -> Result<()> {
+        let cell = self.cell_at_mut(offset)?;
         *cell = cell.wrapping_add(amount as u8);
+
+        Ok(())
     }
 
     #[inline]
-    pub fn sub_to_cell(&mut self, amount: usize) {
-        let cell = self.get_cell_mut();
+    pub fn sub_to_cell(&mut self, amount: usize, offset: i32) -> Result<()> {
+        let cell = self.cell_at_mut(offset)?;
         *cell = cell.wrapping_sub(amount as u8);
+
+        Ok(())
+    }
+
+    #[inline]
+    pub fn set_cell(&mut self, value: usize, offset: i32) -> Result<()> {
+        *self.cell_at_mut(offset)? = value as u8;
+
+        Ok(())
+    }
+
+    #[inline]
+    pub fn mul_add(&mut self, factor: usize, offset: i32) -> Result<()> {
+        let value = self.get_cell();
+
+        // A fused loop must not touch memory when it would not have been entered.
+        if value != 0 {
+            let cell = self.get_cell_at_mut(offset)?;
+            *cell = cell.wrapping_add(value.wrapping_mul(factor as u8));
+        }
+
+        Ok(())
+    }
+
+    #[inline]
+    pub fn fill_range(&mut self, len: usize, value: u8, offset: i32) -> Result<()> {
+        if len > 0 {
+            let start = self.cell_index(offset as isize)?;
+            let end = self.cell_index(offset as isize + len as isize - 1)? + 1;
+
+            self.mem[start..end].fill(value);
+        }
+
+        Ok(())
+    }
+
+    /// Quickened loops are kept out of the main loop, adding them in there costs ~10% on mandelbrot.
+    #[inline(never)]
+    fn run_quickened(&mut self, ty: OpCodeType, end: usize, offset: i32) -> Result<()> {
+        match ty {
+            OpCodeType::ClearLoop => {
+                self.set_cell(0, offset)?;
+                self.pc = end;
+            }
+            _ => {
+                if !self.mul_loop(end, offset) {
+                    self.jump_zero(end, offset)?
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Runs a `MulLoop` all at once: the body is applied a single time with every add multiplied by
+    /// the number of iterations. Returns false, without touching memory, if the body would go outside of
+    /// it, in which case the loop has to run as usual.
+    #[inline]
+    fn mul_loop(&mut self, end: usize, offset: i32) -> bool {
+        let Ok(loop_cell) = self.cell_index(offset as isize) else {
+            return false;
+        };
+
+        let value = self.mem[loop_cell];
+        if value == 0 {
+            self.pc = end;
+            return true;
+        }
+
+        let mem_count = self.mem.len() as isize;
+        let body = &self.program[self.pc + 1..end];
+
+        let mut pos = self.mem_ptr as isize;
+        let mut step = 0u8;
+
+        for op in body {
+            match op.ty {
+                OpCodeType::ShiftRight => pos += op.data as isize,
+                OpCodeType::ShiftLeft => pos -= op.data as isize,
+                _ => {
+                    let idx = pos + op.offset as isize;
+                    if !(0..mem_count).contains(&idx) {
+                        return false;
+                    }
+
+                    if idx as usize == loop_cell {
+                        step = match op.ty {
+                            OpCodeType::Add => step.wrapping_add(op.data as u8),
+                            _ => step.wrapping_sub(op.data as u8),
+                        };
+                    }
+                }
+            }
+
+            // Going off the left edge is clamped, which the closed form can't do.
+            if !(0..mem_count).contains(&pos) {
+                return false;
+            }
+        }
+
+        // Counting up by one takes 256 - value iterations instead of value.
+        let times = if step == 1 {
+            value.wrapping_neg()
+        } else {
+            value
+        };
+
+        let mut pos = self.mem_ptr as isize;
+        for op in body {
+            match op.ty {
+                OpCodeType::ShiftRight => pos += op.data as isize,
+                OpCodeType::ShiftLeft => pos -= op.data as isize,
+                _ => {
+                    let cell = &mut self.mem[(pos + op.offset as isize) as usize];
+                    let amount = times.wrapping_mul(op.data as u8);
+
+                    *cell = match op.ty {
+                        OpCodeType::Add => cell.wrapping_add(amount),
+                        _ => cell.wrapping_sub(amount),
+                    };
+                }
+            }
+        }
+
+        self.pc = end;
+
+        true
+    }
+
+    /// pbrain `(` at `pc`: the procedure numbered by the current cell starts there.
+    #[inline(never)]
+    pub fn define_procedure(&mut self, pc: usize) {
+        if self.procedures.is_empty() {
+            self.procedures.resize(256, None);
+        }
+        let number = self.get_cell();
+        self.procedures[number as usize] = Some(pc);
+    }
+
+    /// pbrain `:` at `pc`, calls the procedure numbered by the current cell. Returns the pc of its `(`.
+    #[inline(never)]
+    pub fn call_procedure(&mut self, pc: usize) -> Result<usize> {
+        let number = self.get_cell();
+        let Some(start) = self.procedures.get(number as usize).copied().flatten() else {
+            bail!("procedure {} is not defined", number);
+        };
+        if self.calls.len() == MAX_CALL_DEPTH {
+            bail!("call stack overflowed: {} calls deep", MAX_CALL_DEPTH);
+        }
+        self.calls.push(pc);
+
+        Ok(start)
+    }
+
+    /// pbrain `)`, returns the pc of the call to go back to.
+    #[inline(never)]
+    pub fn return_procedure(&mut self) -> Result<usize> {
+        match self.calls.pop() {
+            Some(pc) => Ok(pc),
+            None => bail!("return outside of a procedure"),
+        }
+    }
+
+    /// The Extended Brainfuck opcodes on the storage cell and the bits of the current cell, `End`
+    /// aside.
+    #[inline(never)]
+    pub fn extended(&mut self, ty: OpCodeType) {
+        let storage = self.storage;
+        let cell = self.get_cell_mut();
+        match ty {
+            OpCodeType::Store => {
+                let value = *cell;
+                self.storage = value;
+            }
+            OpCodeType::Retrieve => *cell = storage,
+            OpCodeType::BitsLeft => *cell <<= 1,
+            OpCodeType::BitsRight => *cell >>= 1,
+            OpCodeType::Not => *cell = !*cell,
+            OpCodeType::Xor => *cell ^= storage,
+            OpCodeType::And => *cell &= storage,
+            OpCodeType::Or => *cell |= storage,
+            _ => unreachable!("{:?} is not an Extended Brainfuck opcode", ty),
+        }
+    }
+
+    /// `?`: sets the current cell to the next random byte, from splitmix64.
+    #[inline(never)]
+    pub fn random_cell(&mut self) {
+        self.random = self.random.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.random;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+
+        *self.get_cell_mut() = (z >> 56) as u8;
+    }
+
+    /// `#`: prints the pointer and the first cells to standard error, after what `output` holds
+    /// back so the two come in the order the program ran.
+    #[inline(never)]
+    pub fn dump_tape<W: Write>(&self, output: &mut W) -> Result<()> {
+        output.flush()?;
+        let cells = &self.mem[..self.dump_cells.min(self.mem.len())];
+        eprint!(
+            "pointer at cell {}\n{}",
+            self.mem_ptr,
+            hexdump::hexdump(cells, 0)
+        );
+
+        Ok(())
+    }
+
+    /// `%`, `^` or `!` of file I/O.
+    #[inline(never)]
+    pub fn file_command(&mut self, ty: OpCodeType) -> Result<()> {
+        let Some(files) = &mut self.files else {
+            bail!("file commands need a file directory, see `Vm::enable_file_io`");
+        };
+        let ptr = self.mem_ptr;
+
+        match ty {
+            OpCodeType::OpenFile => {
+                let len = self.mem[ptr..].iter().position(|&ch| ch == 0);
+                let Some(len) = len else {
+                    bail!("the file name at cell {} has no zero cell after it", ptr);
+                };
+                files.toggle(&self.mem[ptr..ptr + len])?;
+            }
+            OpCodeType::WriteFile => files.write(self.mem[ptr])?,
+            OpCodeType::ReadFile => self.mem[ptr] = files.read()?,
+            _ => unreachable!("{:?} is not a file command", ty),
+        }
+
+        Ok(())
+    }
+
+    /// `{`, `}` or `=` of the multi-tape dialect. With a single tape, it is its own next one.
+    #[inline(never)]
+    pub fn tape_command(&mut self, ty: OpCodeType) {
+        let count = self.tapes.len().max(1);
+        let next = (self.tape + 1) % count;
+
+        match ty {
+            OpCodeType::PrevTape => self.switch_tape((self.tape + count - 1) % count),
+            OpCodeType::NextTape => self.switch_tape(next),
+            OpCodeType::CopyToTape if next != self.tape => {
+                let tape = &mut self.tapes[next];
+                tape.mem[tape.ptr] = self.mem[self.mem_ptr];
+            }
+            OpCodeType::CopyToTape => {}
+            _ => unreachable!("{:?} is not a tape command", ty),
+        }
+    }
+
+    /// Puts the current tape back in its place and takes out tape `to`.
+    fn switch_tape(&mut self, to: usize) {
+        if to == self.tape {
+            return;
+        }
+
+        let from = &mut self.tapes[self.tape];
+        mem::swap(&mut self.mem, &mut from.mem);
+        from.ptr = self.mem_ptr;
+
+        let to_tape = &mut self.tapes[to];
+        mem::swap(&mut self.mem, &mut to_tape.mem);
+        self.mem_ptr = to_tape.ptr;
+        self.tape = to;
+    }
+
+    /// Brainfork `Y` at the pc: returns the new thread, a copy of this one with its own tape past
+    /// the `Y`, the pointer one cell to the right and that cell set to 1. The cell of this thread is
+    /// cleared. See `brainfork`.
+    pub fn fork(&mut self) -> Result<Vm> {
+        let mut child = Vm::from_shared(self.program.clone())?;
+        child.restore(&self.snapshot());
+        child.eof = self.eof;
+        child.tape_edge = self.tape_edge;
+        child.strict_bounds = self.strict_bounds;
+        child.pc += 1;
+        child.shift_right(1)?;
+        *child.get_cell_mut() = 1;
+
+        *self.get_cell_mut() = 0;
+        self.pc += 1;
+
+        Ok(child)
+    }
+
+    /// Brainfork `Y` anywhere but in the scheduler, which forks before the engines see it.
+    #[inline(never)]
+    pub fn fork_unscheduled(&self) -> Result<()> {
+        bail!("FORK is only run by the Brainfork scheduler")
     }
 
     #[inline]
@@ -100,77 +1095,462 @@ impl Vm {
         self.mem_ptr = self.mem_ptr.saturating_sub(amount);
     }
 
+    /// Moves the pointer right, failing where it is if that is past the last cell.
     #[inline]
     pub fn shift_right(&mut self, amount: usize) -> Result<()> {
-        self.mem_ptr += amount;
+        let ptr = self.mem_ptr.saturating_add(amount);
 
-        if self.mem_ptr >= self.mem.len() {
-            let mem_count = self.mem.len();
-            let overflowed_count = self.mem_ptr - mem_count;
-            bail!("memory overflowed: {mem_count} items => {overflowed_count} items")
+        if ptr >= self.mem.len() {
+            self.past_end(ptr)
         } else {
+            self.mem_ptr = ptr;
             Ok(())
         }
     }
 
-    #[inline]
-    #[allow(dead_code)]
-    pub fn shift_right_alt(&mut self, amount: usize) {
-        self.mem_ptr += amount;
+    /// The pointer is moving to `ptr`, past the last cell: grows the tape to have it if it can.
+    #[cold]
+    fn past_end(&mut self, ptr: usize) -> Result<()> {
+        if self.tape_edge == TapeEdge::Grow && ptr < MAX_GROWN_TAPE {
+            let cells = (self.mem.len() * 2).clamp(ptr + 1, MAX_GROWN_TAPE);
+            self.mem.resize(cells, 0);
+            self.mem_ptr = ptr;
+            return Ok(());
+        }
+
+        Err(MemoryError::Overflow {
+            cells: self.mem.len(),
+            past: ptr - self.mem.len(),
+        }
+        .into())
     }
 
+    // The cold paths are there to keep jumps as real branches. A branchless `cmov` makes fetching the
+    // next instruction wait for the cell load, which costs ~40% on mandelbrot.
     #[inline]
-    pub fn jump_zero(&mut self, to: usize) {
-        if self.get_cell() == 0 {
+    pub fn jump_zero(&mut self, to: usize, offset: i32) -> Result<()> {
+        if *self.cell_at_mut(offset)? == 0 {
+            hint::cold_path();
             self.pc = to;
         }
+
+        Ok(())
     }
 
     #[inline]
-    pub fn jump_not_zero(&mut self, to: usize) {
-        if self.get_cell() != 0 {
+    pub fn jump_not_zero(&mut self, to: usize, offset: i32) -> Result<()> {
+        if *self.cell_at_mut(offset)? != 0 {
             self.pc = to;
+        } else {
+            hint::cold_path();
         }
+
+        Ok(())
     }
 
     #[inline]
-    pub fn print_chars(&mut self, amount: usize, stdout: &mut StdoutLock<'_>) {
-        let ch = self.get_cell();
-        for _ in 0..amount {
-            let _ = stdout.write(&[ch]);
-        }
+    pub fn print_chars<W: Write>(
+        &mut self,
+        amount: usize,
+        offset: i32,
+        output: &mut W,
+    ) -> Result<()> {
+        let ch = *self.cell_at_mut(offset)?;
+
+        Ok(write_repeated(output, ch, amount)?)
     }
 
     #[inline]
-    pub fn input_char(&mut self, _: usize) -> Result<()> {
+    pub fn input_char<R: Read>(&mut self, _: usize, offset: i32, input: &mut R) -> Result<()> {
         // self.input_chars ignores repetives.
-        let ch = self.get_cell_mut();
+        let eof = self.eof;
+        let ch = self.cell_at_mut(offset)?;
+
+        match input.read_exact(array::from_mut(ch)) {
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof && eof != Eof::Error => {
+                match eof {
+                    Eof::Zero => *ch = 0,
+                    Eof::NegOne => *ch = u8::MAX,
+                    _ => {}
+                }
+                Ok(())
+            }
+            result => Ok(result?),
+        }
+    }
+
+    pub fn run(&mut self) -> Result<()> {
+        let stdin = stdin();
+        let stdout = stdout();
+
+        self.run_with(&mut stdin.lock(), &mut stdout.lock())
+    }
+
+    pub fn run_with<R: Read, W: Write>(&mut self, input: &mut R, output: &mut W) -> Result<()> {
+        #[cfg(feature = "tiered")]
+        let tiered = self.tiering.is_some();
+        #[cfg(not(feature = "tiered"))]
+        let tiered = false;
+
+        // Every extra check in the loop costs, so only the modes in use get compiled in.
+        match (self.quickening.is_some(), tiered) {
+            (false, false) => self.run_inner::<_, _, false, false, false>(input, output, &mut []),
+            (true, false) => self.run_inner::<_, _, false, true, false>(input, output, &mut []),
+            (false, true) => self.run_inner::<_, _, false, false, true>(input, output, &mut []),
+            (true, true) => self.run_inner::<_, _, false, true, true>(input, output, &mut []),
+        }
+    }
+
+    /// Same as `run_with`, dispatching through pre-resolved handler functions instead, see `threaded`.
+    /// Quickening and tiering are not used by this engine.
+    pub fn run_threaded<R: Read, W: Write>(&mut self, input: &mut R, output: &mut W) -> Result<()> {
+        let code = ThreadedCode::new(&self.program);
+        let mut pc = self.pc;
+
+        let result = code.run(self, &mut pc, input, output);
+        self.pc = pc;
 
-        stdin().read_exact(array::from_mut(ch))?;
+        result
+    }
+
+    /// Same as `run_with`, running the straight runs of opcodes as blocks, see `blocks`. Quickening
+    /// and tiering are not used by this engine.
+    pub fn run_blocks<R: Read, W: Write>(&mut self, input: &mut R, output: &mut W) -> Result<()> {
+        let code = BlockCode::new(&self.program);
+
+        // Started inside a block, such as after stepping into one.
+        let mut i = loop {
+            match code.item(self.pc) {
+                Some(i) => break i,
+                None => _ = self.step(input, output)?,
+            }
+        };
+
+        while let Some(item) = code.items().get(i) {
+            match item {
+                Item::Block(block) => {
+                    if !self.run_block(block) {
+                        // Off the tape, its opcodes fail or move the pointer as they do alone.
+                        self.pc = code.pc(i);
+                        while self.pc < block.end() {
+                            self.step(input, output)?;
+                        }
+                    }
+                    i += 1;
+                }
+                Item::Op(op) if op.ty == OpCodeType::JmpZero => match self.cell_at_mut(op.offset) {
+                    Ok(&mut 0) => i = op.data + 1,
+                    Ok(_) => i += 1,
+                    Err(err) => {
+                        self.pc = code.pc(i);
+                        return Err(err);
+                    }
+                },
+                Item::Op(op) if op.ty == OpCodeType::JmpNotZero => {
+                    match self.cell_at_mut(op.offset) {
+                        Ok(&mut cell) if cell != 0 => i = op.data + 1,
+                        Ok(_) => i += 1,
+                        Err(err) => {
+                            self.pc = code.pc(i);
+                            return Err(err);
+                        }
+                    }
+                }
+                Item::Op(_) => {
+                    self.pc = code.pc(i);
+                    self.step(input, output)?;
+                    // The procedures of pbrain and the end of Extended Brainfuck jump elsewhere.
+                    if self.pc == code.pc(i + 1) {
+                        i += 1;
+                        continue;
+                    }
+                    i = match code.item(self.pc) {
+                        Some(i) => i,
+                        None => return self.run_blocks(input, output),
+                    };
+                }
+            }
+        }
+        self.pc = self.program.len();
 
         Ok(())
     }
 
+    /// Runs `block` at the pc if all of its cells are on the tape, returns false if not.
+    #[inline]
+    fn run_block(&mut self, block: &Block) -> bool {
+        let ptr = self.mem_ptr as isize;
+        let (min, max) = block.reach();
+        let (Ok(start), Ok(end)) = (usize::try_from(ptr + min), usize::try_from(ptr + max + 1))
+        else {
+            return false;
+        };
+        let Some(cells) = self.mem.get_mut(start..end) else {
+            return false;
+        };
+
+        block.run(cells);
+        // Between the cells it reached, on the tape.
+        self.mem_ptr = (ptr + block.shift()) as usize;
+        self.pc = block.end();
+
+        true
+    }
+
+    /// Same as `run_threaded` with handlers tail calling each other, see `tailcall`. Falls back to
+    /// `run_with` when built without the `tail-call` feature.
+    pub fn run_tail_call<R: Read, W: Write>(
+        &mut self,
+        input: &mut R,
+        output: &mut W,
+    ) -> Result<()> {
+        #[cfg(feature = "tail-call")]
+        {
+            let code = crate::tailcall::TailCallCode::new(&self.program);
+            let mut pc = self.pc;
+
+            let result = code.run(self, &mut pc, input, output);
+            self.pc = pc;
+
+            result
+        }
+
+        #[cfg(not(feature = "tail-call"))]
+        self.run_with(input, output)
+    }
+
+    /// Compiles the whole program to native code and runs it, or only interprets it when built without
+    /// the `jit` feature or when compilation fails.
+    pub fn run_jit<R: Read, W: Write>(&mut self, input: &mut R, output: &mut W) -> Result<()> {
+        #[cfg(feature = "jit")]
+        if self.pc == 0 {
+            if let Ok(jit) = crate::jit::JitProgram::compile(&self.program) {
+                self.pc = jit.run(&mut self.mem, &mut self.mem_ptr, input, output);
+                self.check_ptr();
+            }
+        }
+
+        // Picks up where the native code stopped, if it did not run to the end.
+        self.run_with(input, output)
+    }
+
+    /// Same as `run_jit`, using the built-in x86-64 code generator of the `jit-x64` feature instead.
+    pub fn run_jit_x64<R: Read, W: Write>(&mut self, input: &mut R, output: &mut W) -> Result<()> {
+        #[cfg(feature = "jit-x64")]
+        if self.pc == 0 {
+            if let Some(native) = crate::codegen::compile_program(&self.program) {
+                self.pc = native.run(&mut self.mem, &mut self.mem_ptr, input, output);
+                self.check_ptr();
+            }
+        }
+
+        self.run_with(input, output)
+    }
+
+    /// Runs the instruction at the pc, returns false if the program had already ended.
+    /// Quickening and tiering are not used.
+    pub fn step<R: Read, W: Write>(&mut self, input: &mut R, output: &mut W) -> Result<bool> {
+        use OpCodeType::*;
+
+        let Some(&OpCode { ty, data, offset }) = self.program.get(self.pc) else {
+            return Ok(false);
+        };
+
+        match ty {
+            Add => self.add_to_cell(data, offset)?,
+            Sub => self.sub_to_cell(data, offset)?,
+            ShiftLeft => self.shift_left(data),
+            ShiftRight => self.shift_right(data)?,
+            JmpZero => self.jump_zero(data, offset)?,
+            JmpNotZero => self.jump_not_zero(data, offset)?,
+            PrintChar => self.print_chars(data, offset, output)?,
+            InputChar => self.input_char(data, offset, input)?,
+            Set => self.set_cell(data, offset)?,
+            MulAdd => self.mul_add(data, offset)?,
+            FillRange => self.fill_range(data >> 8, data as u8, offset)?,
+            ClearRange => self.fill_range(data, 0, offset)?,
+            ClearLoop | MulLoop => self.run_quickened(ty, data, offset)?,
+            ProcStart => {
+                self.define_procedure(self.pc);
+                self.pc = data;
+            }
+            ProcEnd => self.pc = self.return_procedure()?,
+            Call => self.pc = self.call_procedure(self.pc)?,
+            // Past the last instruction once the pc moves on.
+            End => self.pc = self.program.len() - 1,
+            Store | Retrieve | BitsLeft | BitsRight | Not | Xor | And | Or => self.extended(ty),
+            Fork => self.fork_unscheduled()?,
+            PrevTape | NextTape | CopyToTape => self.tape_command(ty),
+            Random => self.random_cell(),
+            Dump => self.dump_tape(output)?,
+            OpenFile | WriteFile | ReadFile => self.file_command(ty)?,
+        }
+
+        self.pc += 1;
+
+        Ok(true)
+    }
+
+    /// Same as `run_with`, one `step` at a time, telling `observer` about every instruction.
+    pub fn run_observed<R: Read, W: Write>(
+        &mut self,
+        input: &mut R,
+        output: &mut W,
+        observer: &mut dyn Observer,
+    ) -> Result<()> {
+        let mut result = Ok(());
+        while self.pc < self.program.len() && result.is_ok() {
+            result = observer
+                .instruction(self)
+                .and_then(|()| self.step(input, output).map(drop));
+        }
+        observer.finish()?;
+
+        result
+    }
+
+    /// Same as `run_with`, but also counts how many times each instruction was executed.
+    /// `hits` is resized to the program length and keeps the counts even when the run fails.
+    pub fn run_counted<R: Read, W: Write>(
+        &mut self,
+        input: &mut R,
+        output: &mut W,
+        hits: &mut Vec<u64>,
+    ) -> Result<()> {
+        hits.resize(self.program.len(), 0);
+
+        self.run_inner::<_, _, true, false, false>(input, output, hits)
+    }
+
+    /// Same as `run_with`, counting the instructions executed and the highest pointer on the way.
+    pub fn run_stats<R: Read, W: Write>(&mut self, input: &mut R, output: &mut W) -> Result<Stats> {
+        let mut hits = vec![];
+        self.run_counted(input, output, &mut hits)?;
+
+        let by_type = OpCodeType::ALL
+            .iter()
+            .map(|&ty| {
+                let count = (self.program.iter().zip(&hits))
+                    .filter(|(op, _)| op.ty == ty)
+                    .map(|(_, hits)| hits)
+                    .sum();
+                (ty, count)
+            })
+            .filter(|&(_, count)| count > 0)
+            .collect();
+        let loops = (self.program.iter().enumerate())
+            .filter(|(_, op)| op.ty == OpCodeType::JmpZero)
+            .map(|(pc, op)| LoopCount {
+                pc,
+                reached: hits[pc],
+                iterations: hits[op.data],
+            })
+            .collect();
+
+        Ok(Stats {
+            instructions: hits.iter().sum(),
+            peak_ptr: self.peak_ptr,
+            by_type,
+            loops,
+        })
+    }
+
     /// Somehow using #[inline(never)] with #[inline] for all match arms instead of inline into the match
     /// yields better performance?! Maybe it compiled down into a jump table. I dunno man.
     #[inline(never)]
-    pub fn run(&mut self) -> Result<()> {
+    fn run_inner<
+        R: Read,
+        W: Write,
+        const COUNT_HITS: bool,
+        const QUICKEN: bool,
+        const TIERED: bool,
+    >(
+        &mut self,
+        input: &mut R,
+        output: &mut W,
+        hits: &mut [u64],
+    ) -> Result<()> {
         use OpCodeType::*;
-        let stdout = stdout();
-        let mut stdout = stdout.lock();
 
-        while self.pc < self.program.len() {
-            let (inst, data) = self.program[self.pc].to_tuple();
+        // A handle of its own, the opcodes are read without going through `self` and the `Arc`.
+        // Quickening rewrites `self.program`, see `JmpZero`.
+        let program = self.program.clone();
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("run", opcodes = program.len()).entered();
+
+        while self.pc < program.len() {
+            // SAFETY: the pc was just checked, and `program` doesn't change while running.
+            let OpCode { ty, data, offset } = unsafe { *program.get_unchecked(self.pc) };
 
-            match inst {
-                Add => self.add_to_cell(data),
-                Sub => self.sub_to_cell(data),
+            if COUNT_HITS {
+                hits[self.pc] += 1;
+            }
+
+            match ty {
+                Add => self.add_to_cell(data, offset)?,
+                Sub => self.sub_to_cell(data, offset)?,
                 ShiftLeft => self.shift_left(data),
-                ShiftRight => self.shift_right(data)?,
-                JmpZero => self.jump_zero(data),
-                JmpNotZero => self.jump_not_zero(data),
-                PrintChar => self.print_chars(data, &mut stdout),
-                InputChar => self.input_char(data)?,
+                ShiftRight => {
+                    self.shift_right(data)?;
+
+                    if COUNT_HITS {
+                        self.peak_ptr = self.peak_ptr.max(self.mem_ptr);
+                    }
+                }
+                JmpZero => {
+                    if QUICKEN {
+                        if let Some(quickening) = &mut self.quickening {
+                            quickening.visit(&mut self.program, self.pc);
+                        }
+
+                        // Runs the quickened form, the loop doesn't see it in its copy.
+                        let ty = self.program[self.pc].ty;
+                        if ty != JmpZero {
+                            self.run_quickened(ty, data, offset)?;
+                            self.pc += 1;
+                            continue;
+                        }
+                    }
+
+                    #[cfg(feature = "tiered")]
+                    if TIERED && self.run_native(self.pc) {
+                        continue;
+                    }
+
+                    #[cfg(feature = "tracing")]
+                    tracing::debug!(target: "bf::loop", pc = self.pc, ptr = self.mem_ptr, "loop");
+
+                    self.jump_zero(data, offset)?
+                }
+                JmpNotZero => {
+                    self.jump_not_zero(data, offset)?;
+
+                    // Jumping back is the only way to notice a single long running loop.
+                    #[cfg(feature = "tiered")]
+                    if TIERED && self.pc == data && self.run_native(data) {
+                        continue;
+                    }
+                }
+                PrintChar => self.print_chars(data, offset, output)?,
+                InputChar => self.input_char(data, offset, input)?,
+                Set => self.set_cell(data, offset)?,
+                MulAdd => self.mul_add(data, offset)?,
+                FillRange => self.fill_range(data >> 8, data as u8, offset)?,
+                ClearRange => self.fill_range(data, 0, offset)?,
+                ClearLoop | MulLoop => self.run_quickened(ty, data, offset)?,
+                ProcStart => {
+                    self.define_procedure(self.pc);
+                    self.pc = data;
+                }
+                ProcEnd => self.pc = self.return_procedure()?,
+                Call => self.pc = self.call_procedure(self.pc)?,
+                End => self.pc = self.program.len() - 1,
+                Store | Retrieve | BitsLeft | BitsRight | Not | Xor | And | Or => self.extended(ty),
+                Fork => self.fork_unscheduled()?,
+                PrevTape | NextTape | CopyToTape => self.tape_command(ty),
+                Random => self.random_cell(),
+                Dump => self.dump_tape(output)?,
+                OpenFile | WriteFile | ReadFile => self.file_command(ty)?,
             }
 
             self.pc += 1;
@@ -0,0 +1,227 @@
+/*
+ *  Output written by a thread of its own, for `bf run --output-thread`: the program hands what it
+ *  prints to the thread and goes on running, instead of waiting on a slow terminal or pipe.
+ *
+ *  What the program writes goes to a buffer the thread takes whole whenever it is done with the last
+ *  one, so a prompt is shown while the program waits on input like it is without the thread. The
+ *  program only waits when `MAX_PENDING` bytes are still to be written, and on `flush`, which waits
+ *  for everything to be written. A write error stops the thread, the next write or flush returns it.
+ */
+
+use std::{
+    io::{self, Write},
+    mem,
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    thread::{self, JoinHandle},
+};
+
+/// How much output can wait to be written before the program waits too.
+pub const MAX_PENDING: usize = 1 << 20;
+
+#[derive(Default)]
+struct State {
+    /// Written by the program, not yet taken by the thread.
+    pending: Vec<u8>,
+    /// The thread is writing what it took.
+    writing: bool,
+    /// No more output is coming.
+    done: bool,
+    error: Option<io::Error>,
+}
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn wait<'a>(&self, state: MutexGuard<'a, State>) -> MutexGuard<'a, State> {
+        self.changed
+            .wait(state)
+            .unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+pub struct WriterThread {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl WriterThread {
+    /// Starts a thread writing to `inner`, flushing it after every write.
+    pub fn spawn<W: Write + Send + 'static>(mut inner: W) -> Self {
+        let shared = Arc::new(Shared::default());
+        let thread = thread::spawn({
+            let shared = shared.clone();
+            move || {
+                let mut chunk = vec![];
+                loop {
+                    let mut state = shared.lock();
+                    state.writing = false;
+                    shared.changed.notify_all();
+                    while state.pending.is_empty() && !state.done {
+                        state = shared.wait(state);
+                    }
+                    if state.pending.is_empty() {
+                        return;
+                    }
+                    mem::swap(&mut state.pending, &mut chunk);
+                    state.writing = true;
+                    shared.changed.notify_all();
+                    drop(state);
+
+                    let result = inner.write_all(&chunk).and_then(|()| inner.flush());
+                    chunk.clear();
+                    if let Err(err) = result {
+                        let mut state = shared.lock();
+                        state.error = Some(err);
+                        state.writing = false;
+                        shared.changed.notify_all();
+                        return;
+                    }
+                }
+            }
+        });
+
+        Self {
+            shared,
+            thread: Some(thread),
+        }
+    }
+
+    /// Waits for everything written to be written out, and for the thread to end.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.stop()
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        let Some(thread) = self.thread.take() else {
+            return Ok(());
+        };
+        self.shared.lock().done = true;
+        self.shared.changed.notify_all();
+        let _ = thread.join();
+
+        match &self.shared.lock().error {
+            Some(err) => Err(copy(err)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The same error again, `io::Error` isn't `Clone`.
+fn copy(err: &io::Error) -> io::Error {
+    io::Error::new(err.kind(), err.to_string())
+}
+
+impl Write for WriterThread {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.shared.lock();
+        while state.pending.len() >= MAX_PENDING && state.error.is_none() {
+            state = self.shared.wait(state);
+        }
+        if let Some(err) = &state.error {
+            return Err(copy(err));
+        }
+
+        // The thread only waits for output when there is none.
+        if state.pending.is_empty() {
+            self.shared.changed.notify_all();
+        }
+        state.pending.extend_from_slice(buf);
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut state = self.shared.lock();
+        while (state.writing || !state.pending.is_empty()) && state.error.is_none() {
+            state = self.shared.wait(state);
+        }
+
+        match &state.error {
+            Some(err) => Err(copy(err)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for WriterThread {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{self, Write},
+        sync::{Arc, Mutex},
+    };
+
+    use crate::writer::WriterThread;
+
+    /// Keeps what is written, failing once it holds `limit` bytes.
+    struct Sink {
+        out: Arc<Mutex<Vec<u8>>>,
+        limit: usize,
+    }
+
+    impl Write for Sink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut out = self.out.lock().unwrap();
+            if out.len() >= self.limit {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            out.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn spawn(limit: usize) -> (WriterThread, Arc<Mutex<Vec<u8>>>) {
+        let out = Arc::new(Mutex::new(vec![]));
+        let sink = Sink {
+            out: out.clone(),
+            limit,
+        };
+
+        (WriterThread::spawn(sink), out)
+    }
+
+    #[test]
+    fn writes_in_order() {
+        let (mut writer, out) = spawn(usize::MAX);
+        for i in 0..10_000u32 {
+            write!(writer, "{} ", i).unwrap();
+        }
+        writer.flush().unwrap();
+        assert!(out.lock().unwrap().starts_with(b"0 1 2 "));
+        writer.write_all(b"end").unwrap();
+        writer.finish().unwrap();
+
+        let out = out.lock().unwrap();
+        assert!(out.ends_with(b"9998 9999 end"));
+    }
+
+    #[test]
+    fn stops_when_output_fails() {
+        let (mut writer, _) = spawn(1);
+        writer.write_all(b"a").unwrap();
+        writer.flush().unwrap();
+        writer.write_all(b"b").unwrap();
+
+        let err = writer.flush().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        assert!(writer.write_all(b"c").is_err());
+        assert!(writer.finish().is_err());
+    }
+}